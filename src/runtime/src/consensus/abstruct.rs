@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt, fmt::Debug, time::Duration};
+use std::{collections::HashMap, fmt, fmt::Debug, sync::Mutex, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
@@ -19,17 +19,34 @@ pub enum ProtocolMetricsKind {
     FastPath,
     /// slow paths of all commands
     SlowPath,
-    /// fast paths of read only commands
+    /// fast paths of read only commands, not told apart by EPaxos
     FastPathReads,
-    /// slow paths of read only commands
+    /// slow paths of read only commands, not told apart by EPaxos
     SlowPathReads,
+    /// commands stable at all the replicas, not tracked by EPaxos
     Stable,
     CommitLatency,
+    /// the delay waiting on the execution conditions, not tracked by EPaxos
     WaitConditionDelay,
     CommittedDepsLen,
+    /// distinct keys of the proposed commands
     CommandKeyCount,
 }
 
+impl ProtocolMetricsKind {
+    pub const ALL: [ProtocolMetricsKind; 9] = [
+        ProtocolMetricsKind::FastPath,
+        ProtocolMetricsKind::SlowPath,
+        ProtocolMetricsKind::FastPathReads,
+        ProtocolMetricsKind::SlowPathReads,
+        ProtocolMetricsKind::Stable,
+        ProtocolMetricsKind::CommitLatency,
+        ProtocolMetricsKind::WaitConditionDelay,
+        ProtocolMetricsKind::CommittedDepsLen,
+        ProtocolMetricsKind::CommandKeyCount,
+    ];
+}
+
 impl Debug for ProtocolMetricsKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -54,6 +71,38 @@ impl Debug for ProtocolMetricsKind {
     }
 }
 
+/// The counters of a protocol, each aggregating the values of a `ProtocolMetricsKind`.
+/// Latencies and delays are aggregated in microseconds. The kinds never aggregated are
+/// reported as 0.
+#[derive(Debug, Default)]
+pub struct ProtocolMetrics {
+    metrics: Mutex<HashMap<ProtocolMetricsKind, u64>>,
+}
+
+impl ProtocolMetrics {
+    pub fn aggregate(&self, kind: ProtocolMetricsKind, by: u64) {
+        *self.metrics.lock().unwrap().entry(kind).or_default() += by;
+    }
+
+    pub fn get(&self, kind: ProtocolMetricsKind) -> u64 {
+        self.metrics
+            .lock()
+            .unwrap()
+            .get(&kind)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of every kind in the order of `ProtocolMetricsKind::ALL`
+    pub fn all(&self) -> Vec<(ProtocolMetricsKind, u64)> {
+        let metrics = self.metrics.lock().unwrap();
+        ProtocolMetricsKind::ALL
+            .iter()
+            .map(|kind| (*kind, metrics.get(kind).copied().unwrap_or_default()))
+            .collect()
+    }
+}
+
 #[allow(dead_code)]
 #[async_trait::async_trait]
 pub(crate) trait ConsensusProtocol {
@@ -1,12 +1,25 @@
-use std::ops::Index;
+use std::{ops::Index, path::PathBuf, time::Duration};
 
 use yaml_rust::YamlLoader;
 
+// How long an instance may block the execution before it is recovered
+const DEFAULT_RECOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+// How often the replicas exchange the execution watermarks and truncate the instances
+const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+// How long a replica waits for a lagging replica to install the snapshot before sending it again
+const DEFAULT_SNAPSHOT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct Configure {
     pub(crate) peer_cnt: usize,
     pub(crate) peer: Vec<String>,
     pub(crate) index: usize,
     pub(crate) epoch: usize,
+    pub(crate) recovery_timeout: Duration,
+    pub(crate) data_dir: Option<PathBuf>,
+    pub(crate) gc_interval: Duration,
+    pub(crate) snapshot_interval: Duration,
+    pub(crate) snapshot_retry_interval: Duration,
 }
 
 impl Configure {
@@ -23,8 +36,52 @@ impl Configure {
             peer,
             index,
             epoch,
+            recovery_timeout: DEFAULT_RECOVERY_TIMEOUT,
+            data_dir: None,
+            gc_interval: DEFAULT_GC_INTERVAL,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            snapshot_retry_interval: DEFAULT_SNAPSHOT_RETRY_INTERVAL,
         }
     }
+
+    /// Sets how long the execution waits on an uncommitted instance before taking over the
+    /// instance from its command leader, which might have crashed
+    #[must_use]
+    pub fn with_recovery_timeout(mut self, recovery_timeout: Duration) -> Self {
+        self.recovery_timeout = recovery_timeout;
+        self
+    }
+
+    /// Sets the directory the durable instance space keeps the instances of the replica in
+    #[must_use]
+    pub fn with_data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(data_dir.into());
+        self
+    }
+
+    /// Sets how often the replica tells the others how far it has executed, and truncates the
+    /// instances all the replicas have executed
+    #[must_use]
+    pub fn with_gc_interval(mut self, gc_interval: Duration) -> Self {
+        self.gc_interval = gc_interval;
+        self
+    }
+
+    /// Sets how often the replica takes a snapshot of the application state. The instances are
+    /// only truncated up to the latest snapshot.
+    #[must_use]
+    pub fn with_snapshot_interval(mut self, snapshot_interval: Duration) -> Self {
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+
+    /// Sets how long the replica waits before sending the snapshot again to a replica which still
+    /// reports being behind the truncated instances
+    #[must_use]
+    pub fn with_snapshot_retry_interval(mut self, snapshot_retry_interval: Duration) -> Self {
+        self.snapshot_retry_interval = snapshot_retry_interval;
+        self
+    }
 }
 
 impl Index<usize> for Configure {
@@ -72,11 +129,35 @@ impl ConfigureSrc for YamlConfigureSrc {
                 let index = yaml["index"].as_i64().unwrap() as usize;
 
                 let epoch = yaml["epoch"].as_i64().unwrap() as usize;
+                let recovery_timeout = yaml["recovery_timeout_ms"]
+                    .as_i64()
+                    .map_or(DEFAULT_RECOVERY_TIMEOUT, |ms| {
+                        Duration::from_millis(ms as u64)
+                    });
+                let data_dir = yaml["data_dir"].as_str().map(PathBuf::from);
+                let gc_interval = yaml["gc_interval_ms"]
+                    .as_i64()
+                    .map_or(DEFAULT_GC_INTERVAL, |ms| Duration::from_millis(ms as u64));
+                let snapshot_interval = yaml["snapshot_interval_ms"]
+                    .as_i64()
+                    .map_or(DEFAULT_SNAPSHOT_INTERVAL, |ms| {
+                        Duration::from_millis(ms as u64)
+                    });
+                let snapshot_retry_interval = yaml["snapshot_retry_interval_ms"]
+                    .as_i64()
+                    .map_or(DEFAULT_SNAPSHOT_RETRY_INTERVAL, |ms| {
+                        Duration::from_millis(ms as u64)
+                    });
                 Configure {
                     peer_cnt,
                     peer,
                     index,
                     epoch,
+                    recovery_timeout,
+                    data_dir,
+                    gc_interval,
+                    snapshot_interval,
+                    snapshot_retry_interval,
                 }
             }
             Err(e) => {
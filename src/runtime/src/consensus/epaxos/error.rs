@@ -11,6 +11,8 @@ pub enum ExecuteError {
     InvalidCommand(String),
     #[error("meet io related error")]
     IOError(#[from] io::Error),
+    #[error("the application does not support snapshots")]
+    SnapshotUnsupported,
 }
 
 #[derive(Debug, Error)]
@@ -18,3 +20,15 @@ pub enum RpcError {
     #[error("meet io related error")]
     IOError(#[from] io::Error),
 }
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("no data directory is configured for the durable instance space")]
+    NoDataDir,
+    #[error("meet db related error, {0}")]
+    DBError(String),
+    #[error("invalid instance record")]
+    InvalidRecord(#[from] bincode::Error),
+    #[error("restore the application from the snapshot failed, {0}")]
+    RestoreError(#[from] ExecuteError),
+}
@@ -3,15 +3,22 @@ use std::{
     fmt::Debug,
     marker::PhantomData,
     sync::Arc,
+    time::Duration,
 };
 
+use log::warn;
 use petgraph::{
     algo::tarjan_scc,
     graph::{DiGraph, NodeIndex},
 };
-use tokio::sync::mpsc;
+use tokio::{
+    sync::{mpsc, RwLock},
+    time::timeout,
+};
 
-use super::types::{Command, CommandExecutor, InstanceSpace, InstanceStatus, SharedInstance};
+use super::types::{
+    Command, CommandExecutor, InstanceID, InstanceSpace, InstanceStatus, SharedInstance,
+};
 
 pub(crate) struct Executor<C, E, S>
 where
@@ -21,6 +28,11 @@ where
 {
     space: Arc<S>,
     cmd_exe: E,
+    // Asks the server to recover the instances blocking the execution for `recovery_timeout`
+    recover_send: mpsc::Sender<InstanceID>,
+    recovery_timeout: Duration,
+    // Held for writing while the application state is snapshotted or restored
+    exec_lock: Arc<RwLock<()>>,
     _phantomdata: PhantomData<C>,
 }
 
@@ -30,10 +42,19 @@ where
     E: CommandExecutor<C> + Debug + Clone + Send + Sync + 'static,
     S: InstanceSpace<C> + Send + Sync + 'static,
 {
-    pub(crate) fn new(space: Arc<S>, cmd_exe: E) -> Self {
+    pub(crate) fn new(
+        space: Arc<S>,
+        cmd_exe: E,
+        recover_send: mpsc::Sender<InstanceID>,
+        recovery_timeout: Duration,
+        exec_lock: Arc<RwLock<()>>,
+    ) -> Self {
         Self {
             space,
             cmd_exe,
+            recover_send,
+            recovery_timeout,
+            exec_lock,
             _phantomdata: PhantomData,
         }
     }
@@ -50,10 +71,14 @@ where
                 Arc::<S>::clone(&self.space),
                 &self.cmd_exe,
                 instance.unwrap(),
+                self.recover_send.clone(),
+                self.recovery_timeout,
             );
+            let exec_lock = self.exec_lock.clone();
             tokio::spawn(async move {
                 let scc = inner.build_scc().await;
                 if let Some(scc) = scc {
+                    let _executing = exec_lock.read().await;
                     inner.execute(scc).await;
                 }
             });
@@ -70,6 +95,8 @@ where
     space: Arc<S>,
     cmd_exe: E,
     start_instance: SharedInstance<C>,
+    recover_send: mpsc::Sender<InstanceID>,
+    recovery_timeout: Duration,
     map: Option<HashMap<SharedInstance<C>, NodeIndex>>,
     graph: Option<DiGraph<SharedInstance<C>, ()>>,
 }
@@ -80,11 +107,19 @@ where
     E: CommandExecutor<C> + Clone + Send + Sync,
     S: InstanceSpace<C> + Send + Sync + 'static,
 {
-    fn new(space: Arc<S>, cmd_exe: &E, start_instance: SharedInstance<C>) -> Self {
+    fn new(
+        space: Arc<S>,
+        cmd_exe: &E,
+        start_instance: SharedInstance<C>,
+        recover_send: mpsc::Sender<InstanceID>,
+        recovery_timeout: Duration,
+    ) -> Self {
         Self {
             space,
             cmd_exe: cmd_exe.clone(),
             start_instance,
+            recover_send,
+            recovery_timeout,
             map: None,
             graph: None,
         }
@@ -132,6 +167,9 @@ where
     /// - the instance's status is NOT COMMITTED and NOT EXECUTED.
     /// - the instance is empty.
     ///
+    /// Every `recovery_timeout` waited on an instance asks the server to recover it, since its
+    /// command leader might have crashed.
+    ///
     /// The return value is None if there's no instance to execute.
     /// The return value is Some(Vec<...>), which is the scc vec, if there are instances to execute.
     async fn build_scc(&mut self) -> Option<Vec<Vec<NodeIndex>>>
@@ -179,7 +217,9 @@ where
                 let (d_ins, notify) = self.space.get_instance_or_notify(&r, d).await;
 
                 let d_ins = if let Some(n) = notify {
-                    n.notified().await;
+                    while timeout(self.recovery_timeout, n.notified()).await.is_err() {
+                        let _ = self.recover_send.send(InstanceID::new(r, *d)).await;
+                    }
                     self.space.get_instance(&r, d).await
                 } else {
                     d_ins
@@ -236,16 +276,35 @@ where
 
             for (id, _) in sort_vec {
                 let ins = &g[each_scc[id]];
+                // A snapshot installed since the graph was built might have truncated or
+                // replaced the instance, whose commands are applied to the state then
+                let instance_id = ins.get_instance_read().await.as_ref().unwrap().id;
+                if let Some(current) = self
+                    .space
+                    .get_instance(&instance_id.replica, &instance_id.local)
+                    .await
+                {
+                    if current != *ins && current.match_status(&[InstanceStatus::Executed]).await {
+                        continue;
+                    }
+                }
                 let mut instance_write = ins.get_instance_write().await;
                 let instance_write_inner = instance_write.as_mut().unwrap();
 
                 // It may be executed by other execution tasks
-                if matches!(instance_write_inner.status, InstanceStatus::Executed) {
+                if !matches!(instance_write_inner.status, InstanceStatus::Executed) {
                     for c in &instance_write_inner.cmds {
                         // FIXME: handle execute error
                         let _ = c.execute(&self.cmd_exe).await;
                     }
                     instance_write_inner.status = InstanceStatus::Executed;
+                    if let Err(e) = self.space.persist(instance_write_inner).await {
+                        // The commands are executed again after a restart
+                        warn!(
+                            "persist executed instance {:?} failed, {e}",
+                            instance_write_inner.id
+                        );
+                    }
                 }
             }
         }
@@ -1,6 +1,12 @@
 use serde::{Deserialize, Serialize};
 
-use super::types::{Ballot, Command, CommandLeaderID, InstanceID, LocalInstanceID, Seq};
+use super::{
+    snapshot::Snapshot,
+    types::{
+        Ballot, Command, CommandLeaderID, InstanceID, InstanceStatus, LocalInstanceID, ReplicaID,
+        Seq,
+    },
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct PreAccept<C>
@@ -68,6 +74,67 @@ pub(crate) struct CommitShort {
     deps: Vec<InstanceID>,
 }
 
+/// Asks the replicas to promise `ballot` for an instance being recovered by `leader_id`
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Prepare {
+    pub(crate) leader_id: CommandLeaderID,
+    pub(crate) instance_id: InstanceID,
+    pub(crate) ballot: Ballot,
+}
+
+/// The state of an instance on `acceptor_id`. A rejected reply carries the larger ballot the
+/// acceptor has promised.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PrepareReply<C>
+where
+    C: Command,
+{
+    pub(crate) acceptor_id: ReplicaID,
+    pub(crate) instance_id: InstanceID,
+    pub(crate) ok: bool,
+    pub(crate) ballot: Ballot,
+    pub(crate) accepted_ballot: Ballot,
+    pub(crate) status: InstanceStatus,
+    pub(crate) seq: Seq,
+    pub(crate) cmds: Vec<C>,
+    pub(crate) deps: Vec<Option<LocalInstanceID>>,
+    /// The instance is truncated by the acceptor, the recovering replica has to catch up with a
+    /// snapshot rather than learn the commands
+    pub(crate) truncated: bool,
+}
+
+/// Asks the replicas to pre-accept the attributes some replicas have pre-accepted already
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TryPreAccept<C>
+where
+    C: Command,
+{
+    pub(crate) leader_id: CommandLeaderID,
+    pub(crate) instance_id: InstanceID,
+    pub(crate) ballot: Ballot,
+    pub(crate) seq: Seq,
+    pub(crate) cmds: Vec<C>,
+    pub(crate) deps: Vec<Option<LocalInstanceID>>,
+}
+
+/// A rejected reply carries either the larger ballot promised or the conflicting instance which
+/// is not ordered with the one being recovered
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TryPreAcceptReply {
+    pub(crate) acceptor_id: ReplicaID,
+    pub(crate) instance_id: InstanceID,
+    pub(crate) ok: bool,
+    pub(crate) ballot: Ballot,
+    pub(crate) conflict: Option<(InstanceID, InstanceStatus)>,
+}
+
+/// The instance of every replica up to which `replica_id` has executed all the instances
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Watermark {
+    pub(crate) replica_id: ReplicaID,
+    pub(crate) executed_upto: Vec<Option<LocalInstanceID>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Propose<C>
 where
@@ -89,4 +156,35 @@ where
     Commit(Commit<C>),
     CommitShort(CommitShort),
     Propose(Propose<C>),
+    Prepare(Prepare),
+    PrepareReply(PrepareReply<C>),
+    TryPreAccept(TryPreAccept<C>),
+    TryPreAcceptReply(TryPreAcceptReply),
+    Watermark(Watermark),
+    Snapshot(Snapshot<C>),
+}
+
+impl<C> Message<C>
+where
+    C: Command + Serialize,
+{
+    /// Returns the name the handling of the message is measured by
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Message::PreAccept(_) => "PreAccept",
+            Message::PreAcceptReply(_) => "PreAcceptReply",
+            Message::PreAcceptOk(_) => "PreAcceptOk",
+            Message::Accept(_) => "Accept",
+            Message::AcceptReply(_) => "AcceptReply",
+            Message::Commit(_) => "Commit",
+            Message::CommitShort(_) => "CommitShort",
+            Message::Propose(_) => "Propose",
+            Message::Prepare(_) => "Prepare",
+            Message::PrepareReply(_) => "PrepareReply",
+            Message::TryPreAccept(_) => "TryPreAccept",
+            Message::TryPreAcceptReply(_) => "TryPreAcceptReply",
+            Message::Watermark(_) => "Watermark",
+            Message::Snapshot(_) => "Snapshot",
+        }
+    }
 }
@@ -2,7 +2,12 @@ pub mod config;
 pub mod error;
 pub mod execute;
 pub mod message;
+pub mod recovery;
 pub mod replica;
 pub mod server;
+pub mod snapshot;
+pub mod storage;
+#[cfg(test)]
+mod test_util;
 pub mod types;
 pub mod util;
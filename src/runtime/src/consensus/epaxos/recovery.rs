@@ -0,0 +1,251 @@
+use std::time::Instant;
+
+use super::{
+    message::PrepareReply,
+    types::{Ballot, Command, Instance, InstanceStatus, ReplicaID},
+};
+
+/// What a recovering replica does with an instance after a quorum answered its prepare
+#[derive(Debug)]
+pub(crate) enum Recovery<C: Command> {
+    /// The instance is committed on some replica, commit it on all of them
+    Commit(PrepareReply<C>),
+    /// Run the Paxos accept phase for the attributes at the new ballot
+    Accept(PrepareReply<C>),
+    /// The attributes are pre-accepted by `agreed` only, try to pre-accept them on the others
+    TryPreAccept(PrepareReply<C>, Vec<ReplicaID>),
+    /// Run the pre-accept phase as the command leader, for the commands of the reply or a no-op
+    PreAccept(Option<PrepareReply<C>>),
+}
+
+/// The progress of the recovery of an instance on the recovering replica
+pub(crate) struct RecoveryBook<C: Command> {
+    pub(crate) ballot: Ballot,
+    pub(crate) started: Instant,
+    pub(crate) replies: Vec<PrepareReply<C>>,
+    // The largest ballot the other replicas rejected the recovery with
+    pub(crate) max_ballot: Ballot,
+    pub(crate) decided: bool,
+    pub(crate) try_preaccept: Option<TryPreAcceptBook<C>>,
+}
+
+impl<C: Command> RecoveryBook<C> {
+    pub(crate) fn new(ballot: Ballot) -> Self {
+        Self {
+            ballot,
+            started: Instant::now(),
+            replies: vec![],
+            max_ballot: ballot,
+            decided: false,
+            try_preaccept: None,
+        }
+    }
+}
+
+pub(crate) struct TryPreAcceptBook<C: Command> {
+    pub(crate) attrs: PrepareReply<C>,
+    // The replicas having pre-accepted the attributes
+    pub(crate) agreed: Vec<ReplicaID>,
+    pub(crate) replied: Vec<ReplicaID>,
+}
+
+impl<C: Command + Clone> PrepareReply<C> {
+    pub(crate) fn new(
+        acceptor_id: ReplicaID,
+        ok: bool,
+        ballot: Ballot,
+        instance: &Instance<C>,
+    ) -> Self {
+        Self {
+            acceptor_id,
+            instance_id: instance.id,
+            ok,
+            ballot,
+            accepted_ballot: instance.accepted_ballot,
+            status: instance.status,
+            seq: instance.seq,
+            cmds: instance.cmds.clone(),
+            deps: instance.deps.clone(),
+            truncated: false,
+        }
+    }
+}
+
+/// Decides how to recover an instance of `leader` from the replies of a quorum, following the
+/// explicit prepare of EPaxos.
+///
+/// Pre-accepted attributes can only have been committed on the fast path at the default ballot,
+/// without `leader` answering and by replicas pre-accepting them unchanged (`PreAcceptedEq`), so
+/// they are accepted directly when `peer_cnt / 2` such replicas agree on them and tried on the
+/// other replicas when at least half of that agree.
+pub(crate) fn decide<C: Command + Clone>(
+    replies: &[PrepareReply<C>],
+    leader: ReplicaID,
+    peer_cnt: usize,
+) -> Recovery<C> {
+    if let Some(r) = replies.iter().find(|r| {
+        matches!(
+            r.status,
+            InstanceStatus::Committed | InstanceStatus::Executed
+        )
+    }) {
+        return Recovery::Commit(r.clone());
+    }
+    if let Some(r) = replies
+        .iter()
+        .filter(|r| r.status == InstanceStatus::Accepted)
+        .max_by_key(|r| r.accepted_ballot)
+    {
+        return Recovery::Accept(r.clone());
+    }
+
+    let preaccepted: Vec<_> = replies
+        .iter()
+        .filter(|r| {
+            matches!(
+                r.status,
+                InstanceStatus::PreAccepted | InstanceStatus::PreAcceptedEq
+            )
+        })
+        .collect();
+    let leader_replied = preaccepted.iter().any(|r| r.acceptor_id == leader);
+    if !leader_replied {
+        let candidates: Vec<_> = preaccepted
+            .iter()
+            .filter(|r| r.status == InstanceStatus::PreAcceptedEq && r.accepted_ballot.is_init())
+            .collect();
+        let agreed = candidates
+            .iter()
+            .map(|c| {
+                candidates
+                    .iter()
+                    .filter(|r| r.seq == c.seq && r.deps == c.deps)
+                    .map(|r| r.acceptor_id)
+                    .collect::<Vec<_>>()
+            })
+            .max_by_key(Vec::len)
+            .unwrap_or_default();
+        let f = peer_cnt / 2;
+        if !agreed.is_empty() {
+            let attrs = candidates
+                .iter()
+                .find(|r| r.acceptor_id == agreed[0])
+                .map(|r| (**r).clone())
+                .unwrap();
+            if agreed.len() >= f {
+                return Recovery::Accept(attrs);
+            }
+            if agreed.len() >= (f + 1) / 2 {
+                return Recovery::TryPreAccept(attrs, agreed);
+            }
+        }
+    }
+    Recovery::PreAccept(preaccepted.first().map(|r| (*r).clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::epaxos::{
+        test_util::{command, TestCommand},
+        types::{InstanceID, LocalInstanceID, Seq},
+    };
+
+    fn reply(
+        acceptor: usize,
+        status: InstanceStatus,
+        accepted_ballot: Ballot,
+        seq: usize,
+    ) -> PrepareReply<TestCommand> {
+        PrepareReply {
+            acceptor_id: acceptor.into(),
+            instance_id: InstanceID::new(0.into(), 0.into()),
+            ok: true,
+            ballot: Ballot::default().next(1.into()),
+            accepted_ballot,
+            status,
+            seq: Seq::from(seq),
+            cmds: vec![command("a", "1")],
+            deps: vec![None::<LocalInstanceID>; 5],
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_decide() {
+        let init = Ballot::default();
+        let higher = init.next(2.into());
+        let leader: ReplicaID = 0.into();
+
+        let replies = vec![
+            reply(1, InstanceStatus::PreAccepted, init, 1),
+            reply(2, InstanceStatus::Committed, init, 2),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 3),
+            Recovery::Commit(r) if r.acceptor_id == 2.into()
+        ));
+
+        let replies = vec![
+            reply(1, InstanceStatus::Accepted, init, 1),
+            reply(2, InstanceStatus::Accepted, higher, 2),
+            reply(3, InstanceStatus::PreAccepted, init, 3),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 5),
+            Recovery::Accept(r) if r.acceptor_id == 2.into()
+        ));
+
+        // Enough replicas might have committed it on the fast path
+        let replies = vec![
+            reply(1, InstanceStatus::PreAcceptedEq, init, 1),
+            reply(2, InstanceStatus::PreAcceptedEq, init, 1),
+            reply(3, InstanceStatus::Prepared, init, 0),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 5),
+            Recovery::Accept(r) if r.seq == Seq::from(1)
+        ));
+
+        // A replica changing the attributes could not have been part of a fast path
+        let replies = vec![
+            reply(1, InstanceStatus::PreAcceptedEq, init, 1),
+            reply(2, InstanceStatus::PreAccepted, init, 1),
+            reply(3, InstanceStatus::Prepared, init, 0),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 5),
+            Recovery::TryPreAccept(r, agreed) if agreed == vec![ReplicaID::from(1)] && r.seq == Seq::from(1)
+        ));
+
+        let replies = vec![
+            reply(1, InstanceStatus::PreAccepted, init, 1),
+            reply(2, InstanceStatus::PreAccepted, init, 1),
+            reply(3, InstanceStatus::Prepared, init, 0),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 5),
+            Recovery::PreAccept(Some(_))
+        ));
+
+        // The leader has not decided anything on the fast path while it answers
+        let replies = vec![
+            reply(0, InstanceStatus::PreAccepted, init, 1),
+            reply(1, InstanceStatus::PreAccepted, init, 1),
+            reply(2, InstanceStatus::PreAccepted, init, 1),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 5),
+            Recovery::PreAccept(Some(_))
+        ));
+
+        let replies = vec![
+            reply(1, InstanceStatus::Prepared, init, 0),
+            reply(2, InstanceStatus::Prepared, init, 0),
+        ];
+        assert!(matches!(
+            decide(&replies, leader, 3),
+            Recovery::PreAccept(None)
+        ));
+    }
+}
@@ -1,13 +1,15 @@
-use std::{collections::HashMap, fmt::Debug, iter, marker::PhantomData, sync::Arc};
+use std::{collections::HashMap, fmt::Debug, iter, marker::PhantomData, sync::Arc, time::Duration};
 
 use itertools::Itertools;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, RwLock};
 
 use super::{
+    error::StorageError,
     execute::Executor,
+    snapshot::Snapshot,
     types::{
-        Command, CommandExecutor, Instance, InstanceID, InstanceSpace, LocalInstanceID, ReplicaID,
-        Seq, SharedInstance,
+        Command, CommandExecutor, Instance, InstanceID, InstanceSpace, InstanceStatus,
+        LocalInstanceID, ReplicaID, Seq, SharedInstance,
     },
 };
 
@@ -26,6 +28,8 @@ where
     pub(crate) conflicts: Conflict<C>,
     pub(crate) max_seq: Seq,
     pub(crate) exec_send: mpsc::Sender<SharedInstance<C>>,
+    // Held for reading while executing the instances
+    pub(crate) exec_lock: Arc<RwLock<()>>,
     _phantom: PhantomData<E>,
 }
 
@@ -35,8 +39,16 @@ where
     E: CommandExecutor<C> + Debug + Clone + Sync + Send + 'static,
     S: InstanceSpace<C> + Send + Sync + 'static,
 {
-    pub(crate) fn new(id: usize, peer_cnt: usize, cmd_exe: E) -> Self {
-        let instance_space = Arc::new(S::new(peer_cnt));
+    pub(crate) async fn new(
+        id: usize,
+        peer_cnt: usize,
+        instance_space: S,
+        snapshot: Option<&Snapshot<C>>,
+        cmd_exe: E,
+        recover_send: mpsc::Sender<InstanceID>,
+        recovery_timeout: Duration,
+    ) -> Self {
+        let instance_space = Arc::new(instance_space);
         let mut cur_max_instances = Vec::with_capacity(peer_cnt);
         let mut commited_upto = Vec::with_capacity(peer_cnt);
         let mut conflicts = Vec::with_capacity(peer_cnt);
@@ -49,12 +61,20 @@ where
 
         let (sender, receiver) = mpsc::channel(10);
         let space_clone = instance_space.clone();
+        let exec_lock = Arc::new(RwLock::new(()));
+        let exec_lock_clone = exec_lock.clone();
         tokio::spawn(async move {
-            let executor = Executor::new(space_clone, cmd_exe);
+            let executor = Executor::new(
+                space_clone,
+                cmd_exe,
+                recover_send,
+                recovery_timeout,
+                exec_lock_clone,
+            );
             executor.execute(receiver).await;
         });
 
-        Self {
+        let mut replica = Self {
             id: id.into(),
             peer_cnt,
             instance_space,
@@ -63,7 +83,124 @@ where
             conflicts,
             max_seq: 0.into(),
             exec_send: sender,
+            exec_lock,
             _phantom: PhantomData,
+        };
+        replica.restore(snapshot).await;
+        replica
+    }
+
+    // Rebuilds the counters and the conflicts from the instances the space has restored, and
+    // executes the committed instances the replica had not executed before it restarted. The
+    // application state is restored from `snapshot` if there is one, so whether an instance is
+    // executed is decided by the snapshot rather than by the instance.
+    async fn restore(&mut self, snapshot: Option<&Snapshot<C>>) {
+        if let Some(snapshot) = snapshot {
+            self.advance_to(snapshot);
+        }
+        for shared in self.instance_space.instances().await {
+            let mut instance = shared.get_instance_write().await;
+            let Some(inner) = instance.as_mut() else {
+                continue;
+            };
+            let id = inner.id;
+            let seq = inner.seq;
+            if let Some(snapshot) = snapshot {
+                if snapshot.covers(&id) {
+                    inner.status = InstanceStatus::Executed;
+                } else if inner.status == InstanceStatus::Executed {
+                    inner.status = InstanceStatus::Committed;
+                }
+            }
+            let cmds = inner.cmds.clone();
+            let committed = inner.status == InstanceStatus::Committed;
+            drop(instance);
+            self.advance(&id, &seq);
+
+            self.update_conflicts(&id.replica, &cmds, shared.clone())
+                .await;
+            if committed {
+                let _ = self.exec_send.send(shared).await;
+            }
+        }
+    }
+
+    /// Catches up with a snapshot the application state has been restored from, while the
+    /// execution is held off by `exec_lock`. The instances it covers become executed, and the
+    /// ones executed here without being covered by it are executed again on top of it.
+    pub(crate) async fn install_snapshot(
+        &mut self,
+        snapshot: &Snapshot<C>,
+    ) -> Result<(), StorageError> {
+        // Saved first, the instances it covers are executed after a restart in the middle
+        self.instance_space.save_snapshot(snapshot).await?;
+        for (r, upto) in snapshot.executed_upto.iter().enumerate() {
+            if let Some(upto) = upto {
+                self.instance_space.truncate(&r.into(), upto).await?;
+            }
+        }
+        self.advance_to(snapshot);
+
+        // The slots of the instances executed beyond the watermarks are replaced rather than
+        // written, since the execution might be holding the ones committed here
+        for record in &snapshot.executed {
+            let id = record.id;
+            let instance: Instance<C> = record.clone().into();
+            self.instance_space.persist(&instance).await?;
+            let orig = self
+                .instance_space
+                .get_instance(&id.replica, &id.local)
+                .await;
+            let shared = SharedInstance::new(Some(instance), None);
+            self.instance_space
+                .insert_instance(&id.replica, &id.local, shared.clone())
+                .await;
+            self.update_conflicts(&id.replica, &record.cmds, shared)
+                .await;
+            if let Some(orig) = orig {
+                if !orig
+                    .match_status(&[InstanceStatus::Committed, InstanceStatus::Executed])
+                    .await
+                {
+                    orig.notify_commit().await;
+                }
+            }
+        }
+
+        for shared in self.instance_space.instances().await {
+            let mut instance = shared.get_instance_write().await;
+            let Some(inner) = instance.as_mut() else {
+                continue;
+            };
+            if inner.status == InstanceStatus::Executed && !snapshot.covers(&inner.id) {
+                inner.status = InstanceStatus::Committed;
+                self.instance_space.persist(inner).await?;
+                drop(instance);
+                let _ = self.exec_send.send(shared).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Makes the counters go past an instance known to the replica
+    fn advance(&mut self, id: &InstanceID, seq: &Seq) {
+        let next: LocalInstanceID = (*id.local + 1).into();
+        if next > self.cur_max_instances[*id.replica] {
+            self.cur_max_instances[*id.replica] = next;
+        }
+        if *seq >= self.max_seq {
+            self.max_seq = (**seq + 1).into();
+        }
+    }
+
+    fn advance_to(&mut self, snapshot: &Snapshot<C>) {
+        for (r, upto) in snapshot.executed_upto.iter().enumerate() {
+            if let Some(upto) = upto {
+                self.advance(&InstanceID::new(r.into(), *upto), &0.into());
+            }
+        }
+        for record in &snapshot.executed {
+            self.advance(&record.id, &record.seq);
         }
     }
 
@@ -214,7 +351,7 @@ where
             };
 
             if let Some(ninst) = new_inst {
-                self.conflicts[*self.id].insert(c.key().clone(), ninst);
+                self.conflicts[**replica].insert(c.key().clone(), ninst);
             }
         }
     }
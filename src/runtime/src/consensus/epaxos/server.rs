@@ -1,31 +1,52 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    io, iter,
+    sync::{Arc, Mutex as SyncMutex, Weak},
+    time::{Duration, Instant},
+};
 
 use futures::{stream, StreamExt};
-use log::trace;
+use log::{error, info, trace, warn};
 use serde::{de::DeserializeOwned, Serialize};
+use storage_engine::metrics::{serve_metrics, MetricType, MetricsRegistry};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::{Mutex, RwLock},
+    sync::{mpsc, Mutex, RwLock},
     task::JoinHandle,
 };
 
 use super::{
     config::Configure,
-    error::RpcError,
-    message::{Message, Propose},
+    error::{ExecuteError, RpcError, StorageError},
+    message::{
+        Message, Prepare, PrepareReply, Propose, TryPreAccept, TryPreAcceptReply, Watermark,
+    },
+    recovery::{decide, Recovery, RecoveryBook, TryPreAcceptBook},
     replica::Replica,
+    snapshot::{behind, executed_watermarks, Snapshot},
+    storage::DurableInstanceSpace,
     types::{
-        Ballot, Command, CommandExecutor, CommandLeaderID, InstanceSpace, LeaderBook, ReplicaID,
-        VecInstanceSpace,
+        Ballot, Command, CommandExecutor, CommandLeaderID, InstanceSpace, LeaderBook,
+        LocalInstanceID, ReplicaID, VecInstanceSpace,
     },
     util::{send_message_arc, send_message_arc2},
 };
-use crate::consensus::epaxos::{
-    message::{Accept, AcceptReply, Commit, PreAccept, PreAcceptOk, PreAcceptReply},
-    types::{Instance, InstanceID, InstanceStatus, SharedInstance},
-    util::{instance_exist, recv_message},
+use crate::consensus::{
+    abstruct::{ProtocolMetrics, ProtocolMetricsKind},
+    epaxos::{
+        message::{Accept, AcceptReply, Commit, PreAccept, PreAcceptOk, PreAcceptReply},
+        types::{Instance, InstanceID, InstanceStatus, SharedInstance},
+        util::{instance_exist, recv_message},
+    },
 };
 
+// The number of the instances waiting for recovery
+const RECOVERY_QUEUE_SIZE: usize = 64;
+
+// The peer and the connection a message failed to be sent over
+type SendFailure = (usize, Arc<Mutex<TcpStream>>, io::Error);
+
 pub(crate) struct RpcServer<C, E, S>
 where
     C: Command + Clone + Send + Sync + 'static,
@@ -57,7 +78,14 @@ where
             tokio::spawn(async move {
                 trace!("Got a connection");
                 loop {
-                    let message = recv_message(&mut stream).await;
+                    let message = match recv_message(&mut stream).await {
+                        Ok(message) => message,
+                        // The peer has gone away, and connects again once it is back
+                        Err(e) => {
+                            trace!("Close the connection, {e}");
+                            break;
+                        }
+                    };
                     server.handle_message(message).await;
                 }
             });
@@ -74,6 +102,19 @@ where
     conf: Configure,
     conns: RwLock<Vec<Option<Arc<Mutex<TcpStream>>>>>,
     replica: Arc<Mutex<Replica<C, E, S>>>,
+    metrics: Arc<MetricsRegistry>,
+    protocol_metrics: Arc<ProtocolMetrics>,
+    // When the instances led by this replica were proposed, for the commit latency
+    proposed_at: SyncMutex<BTreeMap<InstanceID, Instant>>,
+    // The instances of other command leaders this replica is taking over
+    recoveries: SyncMutex<BTreeMap<InstanceID, RecoveryBook<C>>>,
+    cmd_exe: E,
+    // The execution watermarks reported by every replica, this one included
+    watermarks: SyncMutex<Vec<Vec<Option<LocalInstanceID>>>>,
+    // The latest snapshot, which covers every instance truncated by this replica
+    snapshot: SyncMutex<Option<Arc<Snapshot<C>>>>,
+    // When the snapshot was last sent to a replica behind the truncated instances
+    snapshot_sent: SyncMutex<BTreeMap<ReplicaID, Instant>>,
 }
 
 impl<C, E, S> InnerServer<C, E, S>
@@ -82,13 +123,179 @@ where
     E: CommandExecutor<C> + Clone + Send + Sync + 'static + Debug,
     S: InstanceSpace<C> + Send + Sync + 'static,
 {
-    pub(crate) async fn new(conf: Configure, cmd_exe: E) -> Self {
+    pub(crate) async fn new(conf: Configure, cmd_exe: E) -> Result<Arc<Self>, StorageError> {
         let peer_cnt = conf.peer_cnt;
         let id = conf.index;
-        Self {
+        let instance_space = S::open(&conf).await?;
+        let snapshot = instance_space.load_snapshot().await?;
+        // The instances covered by the snapshot are gone, so the replica can not go on without it
+        if let Some(snapshot) = snapshot.as_ref() {
+            cmd_exe.restore(&snapshot.state).await?;
+        }
+        let (recover_send, recover_recv) = mpsc::channel(RECOVERY_QUEUE_SIZE);
+        let replica = Replica::new(
+            id,
+            peer_cnt,
+            instance_space,
+            snapshot.as_ref(),
+            cmd_exe.clone(),
+            recover_send,
+            conf.recovery_timeout,
+        )
+        .await;
+        let protocol_metrics = Arc::new(ProtocolMetrics::default());
+        let metrics = Arc::new(MetricsRegistry::default());
+        let collected = protocol_metrics.clone();
+        metrics.register(move |w| {
+            w.header(
+                "epaxos_protocol_total",
+                "The protocol metrics of the replica, latencies in microseconds",
+                MetricType::Counter,
+            );
+            for (kind, value) in collected.all() {
+                w.sample(
+                    "epaxos_protocol_total",
+                    &[("kind", &format!("{:?}", kind))],
+                    value,
+                );
+            }
+        });
+        let server = Arc::new(Self {
             conf,
             conns: RwLock::new(vec![]),
-            replica: Arc::new(Mutex::new(Replica::new(id, peer_cnt, cmd_exe))),
+            replica: Arc::new(Mutex::new(replica)),
+            metrics,
+            protocol_metrics,
+            proposed_at: SyncMutex::new(BTreeMap::new()),
+            recoveries: SyncMutex::new(BTreeMap::new()),
+            cmd_exe,
+            watermarks: SyncMutex::new(vec![vec![]; peer_cnt]),
+            snapshot: SyncMutex::new(snapshot.map(Arc::new)),
+            snapshot_sent: SyncMutex::new(BTreeMap::new()),
+        });
+        tokio::spawn(Self::recover_blocked(Arc::downgrade(&server), recover_recv));
+        tokio::spawn(Self::collect_garbage(
+            Arc::downgrade(&server),
+            server.conf.gc_interval,
+            server.conf.snapshot_interval,
+        ));
+        Ok(server)
+    }
+
+    // Recovers the instances the execution has been blocked on for too long
+    async fn recover_blocked(server: Weak<Self>, mut recv: mpsc::Receiver<InstanceID>) {
+        while let Some(instance_id) = recv.recv().await {
+            match server.upgrade() {
+                Some(server) => server.recover(instance_id).await,
+                None => break,
+            }
+        }
+    }
+
+    // Reconnects to the unreachable peers, exchanges the execution watermarks, snapshots the
+    // application every `snapshot_interval` and truncates the instances executed by all the
+    // replicas every `gc_interval`
+    async fn collect_garbage(
+        server: Weak<Self>,
+        gc_interval: Duration,
+        snapshot_interval: Duration,
+    ) {
+        let mut snapshot_at = Instant::now();
+        loop {
+            tokio::time::sleep(gc_interval).await;
+            let Some(server) = server.upgrade() else {
+                break;
+            };
+            server.reconnect().await;
+            server.broadcast_watermark().await;
+            if snapshot_at.elapsed() >= snapshot_interval {
+                snapshot_at = Instant::now();
+                server.take_snapshot().await;
+            }
+            server.truncate().await;
+        }
+    }
+
+    async fn broadcast_watermark(&self) {
+        let replica = self.replica.lock().await;
+        let (executed_upto, _) = executed_watermarks(replica.instance_space.as_ref()).await;
+        self.watermarks.lock().unwrap()[*replica.id] = executed_upto.clone();
+        self.broadcast_message(
+            replica.id,
+            Message::Watermark(Watermark {
+                replica_id: replica.id,
+                executed_upto,
+            }),
+        )
+        .await;
+    }
+
+    // Snapshots the application with the execution held off, so that the instances executed
+    // are exactly the ones applied to the state
+    async fn take_snapshot(&self) {
+        let (space, exec_lock) = {
+            let replica = self.replica.lock().await;
+            (replica.instance_space.clone(), replica.exec_lock.clone())
+        };
+        // Held until the snapshot replaces the latest one, which an installed snapshot might
+        // have replaced meanwhile otherwise
+        let _executing = exec_lock.write().await;
+        let state = match self.cmd_exe.snapshot().await {
+            Ok(Some(state)) => state,
+            // The application keeps all the instances then
+            Ok(None) => return,
+            Err(e) => {
+                error!("snapshot the application failed, {e}");
+                return;
+            }
+        };
+        let (executed_upto, executed) = executed_watermarks(space.as_ref()).await;
+
+        let snapshot = Snapshot {
+            executed_upto,
+            executed,
+            state,
+        };
+        if let Err(e) = space.save_snapshot(&snapshot).await {
+            error!("save the snapshot failed, {e}");
+            return;
+        }
+        *self.snapshot.lock().unwrap() = Some(Arc::new(snapshot));
+    }
+
+    // Truncates the instances executed by all the replicas and covered by the latest snapshot,
+    // which a replica missing them catches up with instead
+    async fn truncate(&self) {
+        let Some(snapshot) = self.snapshot.lock().unwrap().clone() else {
+            return;
+        };
+        let points: Vec<_> = {
+            let watermarks = self.watermarks.lock().unwrap();
+            (0..self.conf.peer_cnt)
+                .map(|r| {
+                    watermarks
+                        .iter()
+                        .map(|w| w.get(r).copied().flatten())
+                        .chain(iter::once(snapshot.executed_upto[r]))
+                        .min()
+                        .flatten()
+                })
+                .collect()
+        };
+        let replica = self.replica.lock().await;
+        for (r, upto) in points.into_iter().enumerate() {
+            let Some(upto) = upto else {
+                continue;
+            };
+            if let Err(e) = replica.instance_space.truncate(&r.into(), &upto).await {
+                error!("truncate the instances of replica {r} failed, {e}");
+                continue;
+            }
+            // The instances committed by others after the proposal of this replica
+            self.proposed_at
+                .lock()
+                .unwrap()
+                .retain(|id, _| *id.replica != r || id.local > upto);
         }
     }
 
@@ -96,18 +303,55 @@ where
         &self.conf
     }
 
-    pub(crate) async fn new_leaderbook(&self) -> LeaderBook {
-        LeaderBook::new(self.replica.lock().await.id, &self.conf)
+    pub(crate) fn metrics(&self) -> &Arc<MetricsRegistry> {
+        &self.metrics
     }
 
-    pub(crate) async fn new_ballot(&self) -> Ballot {
-        Ballot::new(self.replica.lock().await.id, &self.conf)
+    // Counts a commit decided by this replica as the command leader
+    fn record_commit(&self, instance: &Instance<C>, fast: bool) {
+        let path = if fast {
+            ProtocolMetricsKind::FastPath
+        } else {
+            ProtocolMetricsKind::SlowPath
+        };
+        self.protocol_metrics.aggregate(path, 1);
+        let deps = instance.deps.iter().filter(|d| d.is_some()).count();
+        self.protocol_metrics
+            .aggregate(ProtocolMetricsKind::CommittedDepsLen, deps as u64);
+        self.recoveries.lock().unwrap().remove(&instance.id);
+        if let Some(at) = self.proposed_at.lock().unwrap().remove(&instance.id) {
+            self.protocol_metrics.aggregate(
+                ProtocolMetricsKind::CommitLatency,
+                at.elapsed().as_micros() as u64,
+            );
+        }
+    }
+
+    // The replica id is the index of the replica in the configure, which saves locking the
+    // replica being locked by the handlers already
+    pub(crate) fn new_leaderbook(&self) -> LeaderBook {
+        LeaderBook::new(self.conf.index.into(), &self.conf)
+    }
+
+    pub(crate) fn new_ballot(&self) -> Ballot {
+        Ballot::new(self.conf.index.into(), &self.conf)
+    }
+
+    // Persists the state of the instance before other replicas learn about it. A replica unable
+    // to persist can not keep its promises after restarting, so it gives up instead.
+    async fn persist(&self, replica: &Replica<C, E, S>, instance: &Instance<C>) {
+        if let Err(e) = replica.instance_space.persist(instance).await {
+            panic!("persist instance {:?} failed, {e}", instance.id);
+        }
     }
 
     pub(crate) async fn handle_message(&self, message: Message<C>)
     where
         C: Command + Serialize,
     {
+        let name = message.name();
+        let start = Instant::now();
+        let mut ok = true;
         match message {
             Message::PreAccept(preaccept) => self.handle_preaccept(preaccept).await,
             Message::PreAcceptReply(preacceptreply) => {
@@ -119,7 +363,20 @@ where
             Message::Commit(commit) => self.handle_commit(commit).await,
             Message::CommitShort(_) => todo!(),
             Message::Propose(propose) => self.handle_propose(propose).await,
+            Message::Prepare(prepare) => self.handle_prepare(prepare).await,
+            Message::PrepareReply(prepare_reply) => {
+                self.handle_prepare_reply(prepare_reply).await;
+            }
+            Message::TryPreAccept(try_preaccept) => {
+                self.handle_try_preaccept(try_preaccept).await;
+            }
+            Message::TryPreAcceptReply(try_preaccept_reply) => {
+                self.handle_try_preaccept_reply(try_preaccept_reply).await;
+            }
+            Message::Watermark(watermark) => self.handle_watermark(watermark).await,
+            Message::Snapshot(snapshot) => ok = self.handle_snapshot(snapshot).await.is_ok(),
         }
+        self.metrics.observe_rpc(name, start.elapsed(), ok);
     }
 
     async fn broadcast_message(&self, replica: ReplicaID, message: Message<C>) {
@@ -132,23 +389,28 @@ where
 
         let cnt = self.conf.peer.len();
         let message = Arc::new(message);
-        let tasks: Vec<JoinHandle<()>> = conn
+        let tasks: Vec<JoinHandle<Option<SendFailure>>> = conn
             .iter()
-            .filter(|c| c.is_some())
-            .map(|c| {
+            .enumerate()
+            .filter(|(_, c)| c.is_some())
+            .map(|(peer, c)| {
                 let c = c.as_ref().unwrap().clone();
                 let message = message.clone();
                 tokio::spawn(async move {
-                    send_message_arc2(&c, &message).await;
+                    let res = send_message_arc2(&c, &message).await;
+                    res.err().map(|e| (peer, c, e))
                 })
             })
             .collect();
+        drop(conn);
 
         let stream_of_futures = stream::iter(tasks);
         let mut buffered = stream_of_futures.buffer_unordered(self.conf.peer.len());
 
         for _ in 0..cnt {
-            buffered.next().await;
+            if let Some(Ok(Some((peer, c, e)))) = buffered.next().await {
+                self.disconnect(peer, &c, e).await;
+            }
         }
     }
 
@@ -172,25 +434,62 @@ where
             **command_leader
         );
 
-        let conn = all_connect.get(**command_leader).unwrap();
-        assert!(!conn.is_none(), "should not reply to self");
-
-        send_message_arc(conn.as_ref().unwrap(), &message).await;
+        let conn = all_connect.get(**command_leader).unwrap().clone();
+        drop(all_connect);
+        match conn {
+            Some(conn) => {
+                if let Err(e) = send_message_arc(&conn, &message).await {
+                    self.disconnect(**command_leader, &conn, e).await;
+                }
+            }
+            // The leader is this replica or unreachable
+            None => trace!("drop the reply to {:?}", command_leader),
+        }
     }
 
     async fn init_connections(&self, current_replica: &ReplicaID) {
         let mut conn_write = self.conns.write().await;
         if conn_write.is_empty() {
             for (id, p) in self.conf.peer.iter().enumerate() {
-                let stream = TcpStream::connect(p)
-                    .await
-                    .map_err(|e| panic!("connect to {p} failed, {e}"))
-                    .unwrap();
-                conn_write.push(if id == **current_replica {
-                    None
-                } else {
-                    Some(Arc::new(Mutex::new(stream)))
-                });
+                if id == **current_replica {
+                    conn_write.push(None);
+                    continue;
+                }
+                // A crashed replica is left out, its instances are recovered by the others
+                match TcpStream::connect(p).await {
+                    Ok(stream) => conn_write.push(Some(Arc::new(Mutex::new(stream)))),
+                    Err(e) => {
+                        warn!("connect to {p} failed, {e}");
+                        conn_write.push(None);
+                    }
+                }
+            }
+        }
+    }
+
+    // Forgets the connection to a peer the messages can not be sent to, which `reconnect`
+    // connects to again
+    async fn disconnect(&self, peer: usize, conn: &Arc<Mutex<TcpStream>>, e: io::Error) {
+        warn!("send to {} failed, {e}", self.conf.peer[peer]);
+        let mut conns = self.conns.write().await;
+        // Unless connected again meanwhile
+        if conns[peer].as_ref().is_some_and(|c| Arc::ptr_eq(c, conn)) {
+            conns[peer] = None;
+        }
+    }
+
+    // Connects to the peers unreachable so far, a crashed replica being back on its address
+    async fn reconnect(&self) {
+        let unreachable: Vec<usize> = {
+            let conns = self.conns.read().await;
+            (0..conns.len())
+                .filter(|peer| *peer != self.conf.index && conns[*peer].is_none())
+                .collect()
+        };
+        for peer in unreachable {
+            if let Ok(stream) = TcpStream::connect(&self.conf.peer[peer]).await {
+                info!("Connect to {} again", self.conf.peer[peer]);
+                self.conns.write().await[peer] = Some(Arc::new(Mutex::new(stream)));
             }
         }
     }
@@ -201,6 +500,16 @@ where
         let instance_id = *replica.local_cur_instance();
         replica.inc_local_cur_instance();
         let (seq, deps) = replica.get_seq_deps(&propose.cmds).await;
+        let keys: HashSet<_> = propose.cmds.iter().map(|cmd| cmd.key()).collect();
+        self.protocol_metrics
+            .aggregate(ProtocolMetricsKind::CommandKeyCount, keys.len() as u64);
+        self.proposed_at.lock().unwrap().insert(
+            InstanceID {
+                local: instance_id,
+                replica: replica.id,
+            },
+            Instant::now(),
+        );
 
         let new_instance = SharedInstance::new(
             Some(Instance {
@@ -209,17 +518,19 @@ where
                     replica: replica.id,
                 },
                 seq,
-                ballot: self.new_ballot().await,
+                ballot: self.new_ballot(),
+                accepted_ballot: self.new_ballot(),
                 cmds: propose.cmds,
                 deps,
                 status: InstanceStatus::PreAccepted,
-                lb: self.new_leaderbook().await,
+                lb: self.new_leaderbook(),
             }),
             None,
         );
 
         let new_instance_read = new_instance.get_instance_read().await;
         let new_instance_read_inner = new_instance_read.as_ref().unwrap();
+        self.persist(&replica, new_instance_read_inner).await;
         let replica_id = replica.id;
         replica
             .update_conflicts(
@@ -239,7 +550,6 @@ where
             replica.max_seq = (*seq + 1).into();
         }
 
-        // TODO: Flush the content to disk
         self.broadcast_message(
             replica.id,
             Message::PreAccept(PreAccept {
@@ -249,7 +559,7 @@ where
                     local: instance_id,
                 },
                 seq,
-                ballot: self.new_ballot().await,
+                ballot: self.new_ballot(),
                 cmds: new_instance_read_inner.cmds.clone(),
                 deps: new_instance_read_inner.deps.clone(),
             }),
@@ -274,15 +584,19 @@ where
             // We have got accept or commit before, do not reply
             if matches!(
                 instance_read_inner.status,
-                InstanceStatus::Committed | InstanceStatus::Accepted
+                InstanceStatus::Committed | InstanceStatus::Accepted | InstanceStatus::Executed
             ) {
-                // Later message may not contain commands, we should fill it here
-                if instance_read_inner.cmds.is_empty() {
+                // Later message may not contain commands, we should fill it here. An executed
+                // instance without commands is a truncated one.
+                if instance_read_inner.cmds.is_empty()
+                    && instance_read_inner.status != InstanceStatus::Executed
+                {
                     drop(instance_read);
                     // TODO: abstract to a macro
                     let mut instance_write = instance.get_instance_write().await;
                     let instance_write_inner = instance_write.as_mut().unwrap();
                     instance_write_inner.cmds = preaccept.cmds;
+                    self.persist(&replica, instance_write_inner).await;
                 }
                 return;
             }
@@ -340,26 +654,20 @@ where
             .count()
             > 0;
 
-        let new_instance = SharedInstance::new(
-            Some(Instance {
-                id: preaccept.instance_id,
-                seq,
-                ballot: preaccept.ballot,
-                // TODO: cmds and deps should not copy
-                cmds: preaccept.cmds.clone(),
-                deps: deps.clone(),
-                status,
-                lb: self.new_leaderbook().await,
-            }),
-            None,
-        );
-
-        replica
-            .instance_space
-            .insert_instance(
-                &preaccept.instance_id.replica,
-                &preaccept.instance_id.local,
-                new_instance.clone(),
+        let new_instance = self
+            .put_instance(
+                &replica,
+                Instance {
+                    id: preaccept.instance_id,
+                    seq,
+                    ballot: preaccept.ballot,
+                    accepted_ballot: preaccept.ballot,
+                    // TODO: cmds and deps should not copy
+                    cmds: preaccept.cmds.clone(),
+                    deps: deps.clone(),
+                    status,
+                    lb: self.new_leaderbook(),
+                },
             )
             .await;
         replica
@@ -370,8 +678,6 @@ where
             )
             .await;
 
-        // TODO: sync to disk
-
         // Send reply
         if changed
             || uncommited_deps
@@ -458,7 +764,8 @@ where
             && instance_w_inner.ballot.is_init()
         {
             instance_w_inner.status = InstanceStatus::Committed;
-            // TODO: sync to disk
+            self.persist(&replica, instance_w_inner).await;
+            self.record_commit(instance_w_inner, true);
             self.broadcast_message(
                 replica.id,
                 Message::<C>::Commit(Commit {
@@ -475,6 +782,7 @@ where
             orig.notify_commit().await;
         } else if instance_w_inner.lb.preaccept_ok >= replica.peer_cnt / 2 {
             instance_w_inner.status = InstanceStatus::Accepted;
+            self.persist(&replica, instance_w_inner).await;
             self.broadcast_message(
                 replica.id,
                 Message::<C>::Accept(Accept {
@@ -529,7 +837,8 @@ where
             && instance_write_inner.ballot.is_init()
         {
             instance_write_inner.status = InstanceStatus::Committed;
-            // TODO: sync to disk
+            self.persist(&replica, instance_write_inner).await;
+            self.record_commit(instance_write_inner, true);
             self.broadcast_message(
                 replica.id,
                 Message::<C>::Commit(Commit {
@@ -546,6 +855,7 @@ where
             instance.notify_commit().await;
         } else if instance_write_inner.lb.preaccept_ok >= replica.peer_cnt / 2 {
             instance_write_inner.status = InstanceStatus::Accepted;
+            self.persist(&replica, instance_write_inner).await;
             self.broadcast_message(
                 replica.id,
                 Message::<C>::Accept(Accept {
@@ -612,34 +922,29 @@ where
             let instance_write_inner = instance_write.as_mut().unwrap();
 
             instance_write_inner.status = InstanceStatus::Accepted;
+            instance_write_inner.ballot = accept.ballot;
+            instance_write_inner.accepted_ballot = accept.ballot;
             instance_write_inner.seq = accept.seq;
             instance_write_inner.deps = accept.deps;
+            self.persist(&replica, instance_write_inner).await;
         } else {
             // FIXME: Message reordering?
-            let new_instance = SharedInstance::new(
-                Some(Instance {
+            self.put_instance(
+                &replica,
+                Instance {
                     id: accept.instance_id,
                     seq: accept.seq,
                     ballot: accept.ballot,
+                    accepted_ballot: accept.ballot,
                     cmds: vec![],
                     deps: accept.deps,
                     status: InstanceStatus::Accepted,
-                    lb: self.new_leaderbook().await,
-                }),
-                None,
-            );
-            replica
-                .instance_space
-                .insert_instance(
-                    &accept.instance_id.replica,
-                    &accept.instance_id.local,
-                    new_instance,
-                )
-                .await;
+                    lb: self.new_leaderbook(),
+                },
+            )
+            .await;
         }
 
-        // TODO: sync to disk
-
         self.reply(
             replica.id,
             &accept.leader_id,
@@ -676,6 +981,11 @@ where
         let mut instance_write = instance.get_instance_write().await;
         let instance_write_inner = instance_write.as_mut().unwrap();
 
+        if !matches!(instance_write_inner.status, InstanceStatus::Accepted) {
+            // We have translated to the later states
+            return;
+        }
+
         if !accept_reply.ok {
             instance_write_inner.lb.nack += 1;
             if accept_reply.ballot > instance_write_inner.lb.max_ballot {
@@ -684,11 +994,17 @@ where
             return;
         }
 
+        if accept_reply.ballot != instance_write_inner.ballot {
+            // A reply to an earlier leader of the instance
+            return;
+        }
+
         instance_write_inner.lb.accept_ok += 1;
 
         if instance_write_inner.lb.accept_ok >= replica.peer_cnt / 2 {
             instance_write_inner.status = InstanceStatus::Committed;
-            // TODO: sync to disk
+            self.persist(&replica, instance_write_inner).await;
+            self.record_commit(instance_write_inner, false);
 
             self.broadcast_message(
                 replica.id,
@@ -703,7 +1019,6 @@ where
             .await;
 
             drop(instance_write);
-            // TODO: sync to disk
             let _ = replica.exec_send.send(instance.clone()).await;
             instance.notify_commit().await;
         }
@@ -727,28 +1042,47 @@ where
             .await;
         let exist = instance_exist(&instance).await;
 
+        // Someone else has finished the recovery of the instance
+        self.recoveries.lock().unwrap().remove(&commit.instance_id);
+        self.proposed_at.lock().unwrap().remove(&commit.instance_id);
+
         let instance = if exist {
             let instance = instance.unwrap();
             let mut instance_write = instance.get_instance_write().await;
             let instance_write_inner = instance_write.as_mut().unwrap();
+            if matches!(
+                instance_write_inner.status,
+                InstanceStatus::Committed | InstanceStatus::Executed
+            ) {
+                // A recovering replica commits the instance again
+                return;
+            }
             instance_write_inner.seq = commit.seq;
             instance_write_inner.deps = commit.deps;
             instance_write_inner.status = InstanceStatus::Committed;
+            // The instance might be known from an accept or a prepare only
+            if instance_write_inner.cmds.is_empty() {
+                instance_write_inner.cmds = commit.cmds;
+            }
+            self.persist(&replica, instance_write_inner).await;
             drop(instance_write);
             instance
         } else {
-            let new_instance = SharedInstance::new(
-                Some(Instance {
-                    id: commit.instance_id,
-                    seq: commit.seq,
-                    ballot: self.new_ballot().await,
-                    cmds: commit.cmds.clone(),
-                    deps: commit.deps,
-                    status: InstanceStatus::Committed,
-                    lb: self.new_leaderbook().await,
-                }),
-                None,
-            );
+            let new_instance = self
+                .put_instance(
+                    &replica,
+                    Instance {
+                        id: commit.instance_id,
+                        seq: commit.seq,
+                        ballot: self.new_ballot(),
+                        accepted_ballot: self.new_ballot(),
+                        cmds: commit.cmds.clone(),
+                        deps: commit.deps,
+                        status: InstanceStatus::Committed,
+                        lb: self.new_leaderbook(),
+                    },
+                )
+                .await;
             replica
                 .update_conflicts(
                     &commit.instance_id.replica,
@@ -759,12 +1093,552 @@ where
             new_instance
         };
 
-        // TODO: sync to disk
         // TODO: handle errors
-
         let _ = replica.exec_send.send(instance.clone()).await;
         instance.notify_commit().await;
     }
+
+    // Persists `instance` and writes it into its slot of the instance space, which keeps the
+    // execution waiting on the slot notified
+    async fn put_instance(
+        &self,
+        replica: &Replica<C, E, S>,
+        instance: Instance<C>,
+    ) -> SharedInstance<C> {
+        let id = instance.id;
+        self.persist(replica, &instance).await;
+        match replica
+            .instance_space
+            .get_instance(&id.replica, &id.local)
+            .await
+        {
+            Some(shared) => {
+                *shared.get_instance_write().await = Some(instance);
+                shared
+            }
+            None => {
+                let shared = SharedInstance::new(Some(instance), None);
+                replica
+                    .instance_space
+                    .insert_instance(&id.replica, &id.local, shared.clone())
+                    .await;
+                shared
+            }
+        }
+    }
+
+    /// Takes over an instance from its command leader, which might have crashed, with a larger
+    /// ballot. The instance ends up committed with the commands it might have been committed
+    /// with somewhere, or with a no-op if it can not have been.
+    pub(crate) async fn recover(&self, instance_id: InstanceID) {
+        let mut replica = self.replica.lock().await;
+        let max_ballot = {
+            let recoveries = self.recoveries.lock().unwrap();
+            match recoveries.get(&instance_id) {
+                // Give the recovery in progress the time to finish
+                Some(book) if book.started.elapsed() < self.conf.recovery_timeout => return,
+                Some(book) => book.max_ballot,
+                None => self.new_ballot(),
+            }
+        };
+        let mut ballot = max_ballot;
+        if let Some(shared) = replica
+            .instance_space
+            .get_instance(&instance_id.replica, &instance_id.local)
+            .await
+        {
+            if let Some(instance) = shared.get_instance_read().await.as_ref() {
+                if matches!(
+                    instance.status,
+                    InstanceStatus::Committed | InstanceStatus::Executed
+                ) {
+                    return;
+                }
+                ballot = ballot.max(instance.ballot);
+            }
+        }
+        let ballot = ballot.next(replica.id);
+        info!("Recover instance {:?} at ballot {:?}", instance_id, ballot);
+
+        self.recoveries
+            .lock()
+            .unwrap()
+            .insert(instance_id, RecoveryBook::new(ballot));
+        let own = self.prepare_instance(&replica, instance_id, ballot).await;
+        self.broadcast_message(
+            replica.id,
+            Message::<C>::Prepare(Prepare {
+                leader_id: replica.id.into(),
+                instance_id,
+                ballot,
+            }),
+        )
+        .await;
+        self.collect_prepare_reply(&mut replica, own).await;
+    }
+
+    // Promises `ballot` for the instance unless a larger one is promised, and returns what is
+    // known about the instance
+    async fn prepare_instance(
+        &self,
+        replica: &Replica<C, E, S>,
+        instance_id: InstanceID,
+        ballot: Ballot,
+    ) -> PrepareReply<C> {
+        let truncated = replica.instance_space.truncated().await[*instance_id.replica];
+        if truncated.is_some_and(|t| instance_id.local <= t) {
+            // Only a snapshot tells the commands executed by all the replicas
+            let placeholder = Instance {
+                id: instance_id,
+                seq: 0.into(),
+                ballot,
+                accepted_ballot: ballot,
+                cmds: vec![],
+                deps: vec![None; replica.peer_cnt],
+                status: InstanceStatus::Executed,
+                lb: self.new_leaderbook(),
+            };
+            let mut reply = PrepareReply::new(replica.id, true, ballot, &placeholder);
+            reply.truncated = true;
+            return reply;
+        }
+        if let Some(shared) = replica
+            .instance_space
+            .get_instance(&instance_id.replica, &instance_id.local)
+            .await
+        {
+            let mut instance = shared.get_instance_write().await;
+            if let Some(instance) = instance.as_mut() {
+                if matches!(
+                    instance.status,
+                    InstanceStatus::Committed | InstanceStatus::Executed
+                ) {
+                    // The decision is final whatever the ballot is
+                    return PrepareReply::new(replica.id, true, ballot, instance);
+                }
+                if ballot < instance.ballot {
+                    return PrepareReply::new(replica.id, false, instance.ballot, instance);
+                }
+                instance.ballot = ballot;
+                self.persist(replica, instance).await;
+                return PrepareReply::new(replica.id, true, ballot, instance);
+            }
+        }
+        let placeholder = Instance {
+            id: instance_id,
+            seq: 0.into(),
+            ballot,
+            accepted_ballot: self.new_ballot(),
+            cmds: vec![],
+            deps: vec![None; replica.peer_cnt],
+            status: InstanceStatus::Prepared,
+            lb: self.new_leaderbook(),
+        };
+        let reply = PrepareReply::new(replica.id, true, ballot, &placeholder);
+        self.put_instance(replica, placeholder).await;
+        reply
+    }
+
+    async fn handle_prepare(&self, prepare: Prepare) {
+        trace!("handle prepare {:?}", prepare);
+        let replica = self.replica.lock().await;
+        let reply = self
+            .prepare_instance(&replica, prepare.instance_id, prepare.ballot)
+            .await;
+        self.reply(
+            replica.id,
+            &prepare.leader_id,
+            Message::<C>::PrepareReply(reply),
+        )
+        .await;
+    }
+
+    async fn handle_prepare_reply(&self, prepare_reply: PrepareReply<C>) {
+        trace!("handle prepare reply");
+        let mut replica = self.replica.lock().await;
+        self.collect_prepare_reply(&mut replica, prepare_reply)
+            .await;
+    }
+
+    // Decides how to recover the instance once a quorum has promised the ballot
+    async fn collect_prepare_reply(
+        &self,
+        replica: &mut Replica<C, E, S>,
+        prepare_reply: PrepareReply<C>,
+    ) {
+        let instance_id = prepare_reply.instance_id;
+        let (ballot, recovery) = {
+            let mut recoveries = self.recoveries.lock().unwrap();
+            let Some(book) = recoveries.get_mut(&instance_id) else {
+                return;
+            };
+            if book.decided {
+                return;
+            }
+            if prepare_reply.truncated {
+                // Executed by all the replicas, this replica catches up with a snapshot
+                info!(
+                    "Instance {:?} is truncated, give up the recovery",
+                    instance_id
+                );
+                recoveries.remove(&instance_id);
+                return;
+            }
+            if !prepare_reply.ok {
+                // Another replica is recovering the instance with a larger ballot
+                book.max_ballot = book.max_ballot.max(prepare_reply.ballot);
+                return;
+            }
+            if prepare_reply.ballot != book.ballot
+                || book
+                    .replies
+                    .iter()
+                    .any(|r| r.acceptor_id == prepare_reply.acceptor_id)
+            {
+                return;
+            }
+            book.replies.push(prepare_reply);
+            if book.replies.len() <= replica.peer_cnt / 2 {
+                return;
+            }
+            book.decided = true;
+            let recovery = decide(&book.replies, instance_id.replica, replica.peer_cnt);
+            if let Recovery::TryPreAccept(attrs, agreed) = &recovery {
+                book.try_preaccept = Some(TryPreAcceptBook {
+                    attrs: attrs.clone(),
+                    agreed: agreed.clone(),
+                    replied: vec![],
+                });
+            }
+            (book.ballot, recovery)
+        };
+        self.apply_recovery(replica, instance_id, ballot, recovery)
+            .await;
+    }
+
+    async fn apply_recovery(
+        &self,
+        replica: &mut Replica<C, E, S>,
+        instance_id: InstanceID,
+        ballot: Ballot,
+        recovery: Recovery<C>,
+    ) {
+        trace!("recover instance {:?} by {:?}", instance_id, recovery);
+        match recovery {
+            Recovery::Commit(attrs) => {
+                self.recoveries.lock().unwrap().remove(&instance_id);
+                let instance = self
+                    .put_instance(
+                        replica,
+                        Instance {
+                            id: instance_id,
+                            seq: attrs.seq,
+                            ballot,
+                            accepted_ballot: ballot,
+                            cmds: attrs.cmds.clone(),
+                            deps: attrs.deps.clone(),
+                            status: InstanceStatus::Committed,
+                            lb: self.new_leaderbook(),
+                        },
+                    )
+                    .await;
+                replica
+                    .update_conflicts(&instance_id.replica, &attrs.cmds, instance.clone())
+                    .await;
+                self.broadcast_message(
+                    replica.id,
+                    Message::<C>::Commit(Commit {
+                        command_leader_id: replica.id.into(),
+                        instance_id,
+                        seq: attrs.seq,
+                        cmds: attrs.cmds,
+                        deps: attrs.deps,
+                    }),
+                )
+                .await;
+                let _ = replica.exec_send.send(instance.clone()).await;
+                instance.notify_commit().await;
+            }
+            Recovery::Accept(attrs) => {
+                let instance = self
+                    .put_instance(
+                        replica,
+                        Instance {
+                            id: instance_id,
+                            seq: attrs.seq,
+                            ballot,
+                            accepted_ballot: ballot,
+                            cmds: attrs.cmds.clone(),
+                            deps: attrs.deps.clone(),
+                            status: InstanceStatus::Accepted,
+                            lb: self.new_leaderbook(),
+                        },
+                    )
+                    .await;
+                replica
+                    .update_conflicts(&instance_id.replica, &attrs.cmds, instance)
+                    .await;
+                self.broadcast_message(
+                    replica.id,
+                    Message::<C>::Accept(Accept {
+                        leader_id: replica.id.into(),
+                        instance_id,
+                        ballot,
+                        seq: attrs.seq,
+                        cmd_cnt: attrs.cmds.len(),
+                        deps: attrs.deps,
+                    }),
+                )
+                .await;
+            }
+            Recovery::TryPreAccept(attrs, _) => {
+                self.broadcast_message(
+                    replica.id,
+                    Message::<C>::TryPreAccept(TryPreAccept {
+                        leader_id: replica.id.into(),
+                        instance_id,
+                        ballot,
+                        seq: attrs.seq,
+                        cmds: attrs.cmds,
+                        deps: attrs.deps,
+                    }),
+                )
+                .await;
+            }
+            Recovery::PreAccept(attrs) => {
+                // Order the commands, or a no-op, again as the command leader
+                let cmds = attrs.as_ref().map(|a| a.cmds.clone()).unwrap_or_default();
+                let (seq, deps) = match attrs {
+                    Some(attrs) => {
+                        let (seq, deps, _) =
+                            replica.update_seq_deps(attrs.seq, attrs.deps, &cmds).await;
+                        (seq, deps)
+                    }
+                    None => replica.get_seq_deps(&cmds).await,
+                };
+                let instance = self
+                    .put_instance(
+                        replica,
+                        Instance {
+                            id: instance_id,
+                            seq,
+                            ballot,
+                            accepted_ballot: ballot,
+                            cmds: cmds.clone(),
+                            deps: deps.clone(),
+                            status: InstanceStatus::PreAccepted,
+                            lb: self.new_leaderbook(),
+                        },
+                    )
+                    .await;
+                replica
+                    .update_conflicts(&instance_id.replica, &cmds, instance)
+                    .await;
+                self.broadcast_message(
+                    replica.id,
+                    Message::<C>::PreAccept(PreAccept {
+                        command_leader_id: replica.id.into(),
+                        instance_id,
+                        seq,
+                        ballot,
+                        cmds,
+                        deps,
+                    }),
+                )
+                .await;
+            }
+        }
+    }
+
+    async fn handle_try_preaccept(&self, try_preaccept: TryPreAccept<C>) {
+        trace!("handle try preaccept {:?}", try_preaccept);
+        let mut replica = self.replica.lock().await;
+        let instance_id = try_preaccept.instance_id;
+        let mut reply = TryPreAcceptReply {
+            acceptor_id: replica.id,
+            instance_id,
+            ok: true,
+            ballot: try_preaccept.ballot,
+            conflict: None,
+        };
+        if let Some(shared) = replica
+            .instance_space
+            .get_instance(&instance_id.replica, &instance_id.local)
+            .await
+        {
+            if let Some(instance) = shared.get_instance_read().await.as_ref() {
+                if try_preaccept.ballot < instance.ballot {
+                    reply.ok = false;
+                    reply.ballot = instance.ballot;
+                } else if matches!(
+                    instance.status,
+                    InstanceStatus::Accepted | InstanceStatus::Committed | InstanceStatus::Executed
+                ) {
+                    // The attributes are decided, which the recovering replica has to learn
+                    reply.ok = false;
+                    reply.conflict = Some((instance.id, instance.status));
+                }
+            }
+        }
+        if reply.ok {
+            reply.conflict = Self::find_conflict(&replica, &try_preaccept).await;
+            reply.ok = reply.conflict.is_none();
+        }
+        if reply.ok {
+            let instance = self
+                .put_instance(
+                    &replica,
+                    Instance {
+                        id: instance_id,
+                        seq: try_preaccept.seq,
+                        ballot: try_preaccept.ballot,
+                        accepted_ballot: try_preaccept.ballot,
+                        cmds: try_preaccept.cmds.clone(),
+                        deps: try_preaccept.deps,
+                        status: InstanceStatus::PreAccepted,
+                        lb: self.new_leaderbook(),
+                    },
+                )
+                .await;
+            replica
+                .update_conflicts(&instance_id.replica, &try_preaccept.cmds, instance)
+                .await;
+        }
+        self.reply(
+            replica.id,
+            &try_preaccept.leader_id,
+            Message::<C>::TryPreAcceptReply(reply),
+        )
+        .await;
+    }
+
+    // Finds an instance interfering with the commands which is neither a dependency of them nor
+    // depending on them, so that the two might be executed in different orders
+    async fn find_conflict(
+        replica: &Replica<C, E, S>,
+        try_preaccept: &TryPreAccept<C>,
+    ) -> Option<(InstanceID, InstanceStatus)> {
+        let id = try_preaccept.instance_id;
+        for (r, conflicts) in replica.conflicts.iter().enumerate() {
+            for cmd in &try_preaccept.cmds {
+                let Some(other) = conflicts.get(cmd.key()) else {
+                    continue;
+                };
+                let other = other.get_instance_read().await;
+                let Some(other) = other.as_ref() else {
+                    continue;
+                };
+                if other.id == id
+                    || try_preaccept.deps[r].is_some_and(|d| d >= other.id.local)
+                    || other.deps[*id.replica].is_some_and(|d| d >= id.local)
+                {
+                    continue;
+                }
+                return Some((other.id, other.status));
+            }
+        }
+        None
+    }
+
+    async fn handle_try_preaccept_reply(&self, try_preaccept_reply: TryPreAcceptReply) {
+        trace!("handle try preaccept reply");
+        let mut replica = self.replica.lock().await;
+        let reply = try_preaccept_reply;
+        let instance_id = reply.instance_id;
+        let (ballot, recovery) = {
+            let mut recoveries = self.recoveries.lock().unwrap();
+            let Some(book) = recoveries.get_mut(&instance_id) else {
+                return;
+            };
+            let Some(mut tp) = book.try_preaccept.take() else {
+                return;
+            };
+            if tp.replied.contains(&reply.acceptor_id) || (reply.ok && reply.ballot != book.ballot)
+            {
+                book.try_preaccept = Some(tp);
+                return;
+            }
+            tp.replied.push(reply.acceptor_id);
+            if !reply.ok && reply.conflict.is_none() {
+                // Another replica is recovering the instance with a larger ballot. The recovery
+                // is retried above it after the timeout.
+                book.max_ballot = book.max_ballot.max(reply.ballot);
+                book.try_preaccept = Some(tp);
+                return;
+            }
+            if reply.ok && !tp.agreed.contains(&reply.acceptor_id) {
+                tp.agreed.push(reply.acceptor_id);
+            }
+            if tp.agreed.len() > replica.peer_cnt / 2 {
+                (book.ballot, Recovery::Accept(tp.attrs))
+            } else if !reply.ok || tp.replied.len() + 1 >= replica.peer_cnt {
+                // The attributes might not order the commands with a conflicting instance, so
+                // order them again
+                (book.ballot, Recovery::PreAccept(Some(tp.attrs)))
+            } else {
+                book.try_preaccept = Some(tp);
+                return;
+            }
+        };
+        self.apply_recovery(&mut replica, instance_id, ballot, recovery)
+            .await;
+    }
+
+    // Sends the latest snapshot to a replica which has not executed some truncated instances
+    async fn handle_watermark(&self, watermark: Watermark) {
+        trace!("handle watermark {:?}", watermark);
+        let replica_id = watermark.replica_id;
+        self.watermarks.lock().unwrap()[*replica_id] = watermark.executed_upto.clone();
+        let Some(snapshot) = self.snapshot.lock().unwrap().clone() else {
+            return;
+        };
+        let replica = self.replica.lock().await;
+        let truncated = replica.instance_space.truncated().await;
+        if !behind(&watermark.executed_upto, &truncated) {
+            return;
+        }
+        {
+            // The replica keeps reporting until it installs the snapshot
+            let mut sent = self.snapshot_sent.lock().unwrap();
+            if sent
+                .get(&replica_id)
+                .is_some_and(|at| at.elapsed() < self.conf.snapshot_retry_interval)
+            {
+                return;
+            }
+            sent.insert(replica_id, Instant::now());
+        }
+        info!("Send the snapshot to replica {:?}", replica_id);
+        self.reply(
+            replica.id,
+            &replica_id.into(),
+            Message::Snapshot((*snapshot).clone()),
+        )
+        .await;
+    }
+
+    async fn handle_snapshot(&self, snapshot: Snapshot<C>) -> Result<(), ExecuteError> {
+        trace!("handle snapshot");
+        let mut replica = self.replica.lock().await;
+        let (executed_upto, _) = executed_watermarks(replica.instance_space.as_ref()).await;
+        if !behind(&executed_upto, &snapshot.executed_upto) {
+            return Ok(());
+        }
+        info!("Install the snapshot up to {:?}", snapshot.executed_upto);
+        let exec_lock = replica.exec_lock.clone();
+        let _executing = exec_lock.write().await;
+        if let Err(e) = self.cmd_exe.restore(&snapshot.state).await {
+            error!("restore the application from the snapshot failed, {e}");
+            return Err(e);
+        }
+        // The application state is replaced already, so the replica can not go on without the
+        // instances matching it
+        if let Err(e) = replica.install_snapshot(&snapshot).await {
+            panic!("install the snapshot failed, {e}");
+        }
+        *self.snapshot.lock().unwrap() = Some(Arc::new(snapshot));
+        Ok(())
+    }
 }
 
 pub(crate) struct Server<C, E, S>
@@ -773,6 +1647,7 @@ where
     E: CommandExecutor<C> + Clone,
     S: InstanceSpace<C>,
 {
+    inner: Arc<InnerServer<C, E, S>>,
     rpc_server: RpcServer<C, E, S>,
 }
 
@@ -782,15 +1657,31 @@ where
     E: CommandExecutor<C> + Debug + Clone + Send + Sync + 'static,
     S: InstanceSpace<C> + Send + Sync + 'static,
 {
-    pub async fn new(conf: Configure, cmd_exe: E) -> Self {
-        let inner = Arc::new(InnerServer::new(conf, cmd_exe).await);
+    pub async fn new(conf: Configure, cmd_exe: E) -> Result<Self, StorageError> {
+        let inner = InnerServer::new(conf, cmd_exe).await?;
         let rpc_server = RpcServer::new(inner.conf(), inner.clone()).await;
-        Self { rpc_server }
+        Ok(Self { inner, rpc_server })
     }
 
     pub async fn run(&self) {
         let _ = self.rpc_server.serve().await;
     }
+
+    pub fn metrics(&self) -> &Arc<MetricsRegistry> {
+        self.inner.metrics()
+    }
+
+    /// Serves the metrics for Prometheus at `http://<addr>/metrics` in the background
+    pub async fn serve_metrics(&self, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let registry = self.metrics().clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listener, registry).await {
+                error!("metrics endpoint of the epaxos server failed, {e}");
+            }
+        });
+        Ok(())
+    }
 }
 
 pub struct DefaultServer<C, E>
@@ -808,11 +1699,409 @@ where
 {
     pub async fn new(conf: Configure, cmd_exe: E) -> Self {
         Self {
-            inner: Server::new(conf, cmd_exe).await,
+            inner: Server::new(conf, cmd_exe)
+                .await
+                .expect("the in-memory instance space never fails to open"),
         }
     }
 
     pub async fn run(&self) {
         self.inner.run().await;
     }
+
+    /// Returns the request and protocol metrics of the replica
+    pub fn metrics(&self) -> &Arc<MetricsRegistry> {
+        self.inner.metrics()
+    }
+
+    /// Serves the metrics for Prometheus at `http://<addr>/metrics` in the background
+    pub async fn serve_metrics(&self, addr: &str) -> io::Result<()> {
+        self.inner.serve_metrics(addr).await
+    }
+}
+
+/// A server keeping the instances in `Configure::data_dir`, which restarts with the instances it
+/// had promised or committed before
+pub struct DurableServer<C, E>
+where
+    C: Command + Clone + Send + Sync + 'static,
+    E: CommandExecutor<C> + Clone,
+{
+    inner: Server<C, E, DurableInstanceSpace<C>>,
+}
+
+impl<C, E> DurableServer<C, E>
+where
+    C: Command + Debug + Clone + Send + Sync + Serialize + DeserializeOwned + 'static,
+    E: CommandExecutor<C> + Debug + Clone + Send + Sync + 'static,
+{
+    pub async fn new(conf: Configure, cmd_exe: E) -> Result<Self, StorageError> {
+        Ok(Self {
+            inner: Server::new(conf, cmd_exe).await?,
+        })
+    }
+
+    pub async fn run(&self) {
+        self.inner.run().await;
+    }
+
+    /// Returns the request and protocol metrics of the replica
+    pub fn metrics(&self) -> &Arc<MetricsRegistry> {
+        self.inner.metrics()
+    }
+
+    /// Serves the metrics for Prometheus at `http://<addr>/metrics` in the background
+    pub async fn serve_metrics(&self, addr: &str) -> io::Result<()> {
+        self.inner.serve_metrics(addr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::consensus::epaxos::{
+        error::ExecuteError,
+        test_util::{command, TestCommand},
+    };
+
+    // Records the values of the commands executed
+    #[derive(Debug, Clone, Default)]
+    struct RecordExecutor(Arc<SyncMutex<Vec<String>>>);
+
+    #[async_trait::async_trait]
+    impl CommandExecutor<TestCommand> for RecordExecutor {
+        async fn execute(&self, cmd: &TestCommand) -> Result<(), ExecuteError> {
+            self.0.lock().unwrap().push(cmd.value.clone());
+            Ok(())
+        }
+
+        async fn snapshot(&self) -> Result<Option<Vec<u8>>, ExecuteError> {
+            Ok(Some(bincode::serialize(&*self.0.lock().unwrap()).unwrap()))
+        }
+
+        async fn restore(&self, snapshot: &[u8]) -> Result<(), ExecuteError> {
+            *self.0.lock().unwrap() = bincode::deserialize(snapshot).unwrap();
+            Ok(())
+        }
+    }
+
+    type TestServer = Server<TestCommand, RecordExecutor, VecInstanceSpace<TestCommand>>;
+
+    const PEER_CNT: usize = 3;
+
+    // Picks the addresses of the replicas, where nothing listens until a replica is started
+    async fn peers() -> Vec<String> {
+        let mut listeners = vec![];
+        for _ in 0..PEER_CNT {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().to_string())
+            .collect()
+    }
+
+    async fn start(peers: &[String], index: usize) -> (Arc<TestServer>, RecordExecutor) {
+        let exe = RecordExecutor::default();
+        let conf = Configure::new(PEER_CNT, peers.to_vec(), index, 0)
+            .with_recovery_timeout(Duration::from_millis(200))
+            .with_gc_interval(Duration::from_millis(100))
+            .with_snapshot_interval(Duration::from_millis(100))
+            .with_snapshot_retry_interval(Duration::from_millis(200));
+        let server = Arc::new(TestServer::new(conf, exe.clone()).await.unwrap());
+        let s = server.clone();
+        tokio::spawn(async move { s.run().await });
+        (server, exe)
+    }
+
+    // The first instance of replica 0, which crashes after pre-accepting it on replica 2 only
+    fn crashed_instance() -> InstanceID {
+        InstanceID::new(0.into(), 0.into())
+    }
+
+    fn preaccept_of_crashed(cmd: TestCommand) -> Message<TestCommand> {
+        Message::PreAccept(PreAccept {
+            command_leader_id: 0.into(),
+            instance_id: crashed_instance(),
+            seq: 0.into(),
+            ballot: Ballot::default(),
+            cmds: vec![cmd],
+            deps: vec![None; PEER_CNT],
+        })
+    }
+
+    // A commit of replica 1 depending on the instance of the crashed replica
+    fn commit_depending_on_crashed(cmd: TestCommand) -> Message<TestCommand> {
+        let mut deps: Vec<Option<LocalInstanceID>> = vec![None; PEER_CNT];
+        deps[0] = Some(crashed_instance().local);
+        Message::Commit(Commit {
+            command_leader_id: 1.into(),
+            instance_id: InstanceID::new(1.into(), 0.into()),
+            seq: 1.into(),
+            cmds: vec![cmd],
+            deps,
+        })
+    }
+
+    async fn wait_executed(exe: &RecordExecutor, expected: &[&str]) {
+        for _ in 0..100 {
+            if *exe.0.lock().unwrap() == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!(
+            "executed {:?} instead of {:?}",
+            exe.0.lock().unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recover_crashed_leader() {
+        let peers = peers().await;
+        let (s1, exe1) = start(&peers, 1).await;
+        let (s2, exe2) = start(&peers, 2).await;
+
+        s2.inner
+            .handle_message(preaccept_of_crashed(command("a", "1")))
+            .await;
+        s1.inner.recover(crashed_instance()).await;
+        wait_executed(&exe1, &["1"]).await;
+        wait_executed(&exe2, &["1"]).await;
+    }
+
+    #[tokio::test]
+    async fn test_recover_blocked_execution() {
+        let peers = peers().await;
+        let (s1, exe1) = start(&peers, 1).await;
+        let (s2, exe2) = start(&peers, 2).await;
+
+        s2.inner
+            .handle_message(preaccept_of_crashed(command("a", "1")))
+            .await;
+        // The execution waits on the instance of the crashed replica until it is recovered
+        s1.inner
+            .handle_message(commit_depending_on_crashed(command("a", "2")))
+            .await;
+        wait_executed(&exe1, &["1", "2"]).await;
+        wait_executed(&exe2, &["1"]).await;
+    }
+
+    #[tokio::test]
+    async fn test_recover_noop() {
+        let peers = peers().await;
+        let (s1, exe1) = start(&peers, 1).await;
+        let (_s2, _exe2) = start(&peers, 2).await;
+
+        // No replica has seen the instance, which can only be committed as a no-op
+        s1.inner
+            .handle_message(commit_depending_on_crashed(command("a", "2")))
+            .await;
+        wait_executed(&exe1, &["2"]).await;
+        let instance = s1
+            .inner
+            .replica
+            .lock()
+            .await
+            .instance_space
+            .get_instance(&crashed_instance().replica, &crashed_instance().local)
+            .await
+            .unwrap();
+        let instance = instance.get_instance_read().await;
+        let instance = instance.as_ref().unwrap();
+        assert!(instance.cmds.is_empty());
+        assert!(matches!(
+            instance.status,
+            InstanceStatus::Committed | InstanceStatus::Executed
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_protocol_metrics() {
+        let peers = peers().await;
+        let (s0, _exe0) = start(&peers, 0).await;
+        s0.inner
+            .handle_message(Message::Propose(Propose {
+                cmds: vec![command("a", "1"), command("b", "2"), command("a", "3")],
+            }))
+            .await;
+        let metrics = s0.inner.protocol_metrics.all();
+        assert_eq!(metrics.len(), ProtocolMetricsKind::ALL.len());
+        assert!(metrics.contains(&(ProtocolMetricsKind::CommandKeyCount, 2)));
+        // Not recorded by EPaxos
+        assert!(metrics.contains(&(ProtocolMetricsKind::FastPathReads, 0)));
+    }
+
+    type DurableTestServer = Server<TestCommand, RecordExecutor, DurableInstanceSpace<TestCommand>>;
+
+    async fn start_durable(
+        peers: &[String],
+        index: usize,
+        data_dir: &std::path::Path,
+    ) -> (Arc<DurableTestServer>, RecordExecutor) {
+        let exe = RecordExecutor::default();
+        // Nothing is recovered or truncated while the test runs
+        let conf = Configure::new(PEER_CNT, peers.to_vec(), index, 0)
+            .with_recovery_timeout(Duration::from_secs(60))
+            .with_data_dir(data_dir);
+        let server = Arc::new(DurableTestServer::new(conf, exe.clone()).await.unwrap());
+        let s = server.clone();
+        tokio::spawn(async move { s.run().await });
+        (server, exe)
+    }
+
+    fn commit(
+        leader: usize,
+        local: usize,
+        seq: usize,
+        cmd: TestCommand,
+        deps: Vec<Option<LocalInstanceID>>,
+    ) -> Message<TestCommand> {
+        Message::Commit(Commit {
+            command_leader_id: leader.into(),
+            instance_id: InstanceID::new(leader.into(), local.into()),
+            seq: seq.into(),
+            cmds: vec![cmd],
+            deps,
+        })
+    }
+
+    #[test]
+    fn test_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let peers = tokio::runtime::Runtime::new().unwrap().block_on(peers());
+
+        // The runtime going away stops every task of the replica and closes its instance space
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (cur_max_instances, max_seq, proposed) = rt.block_on(async {
+            let (s1, exe1) = start_durable(&peers, 1, dir.path()).await;
+            s1.inner
+                .handle_message(commit(0, 0, 1, command("a", "1"), vec![None; PEER_CNT]))
+                .await;
+            wait_executed(&exe1, &["1"]).await;
+            // Pre-accepted by this replica only, since the others are down
+            s1.inner
+                .handle_message(Message::Propose(Propose {
+                    cmds: vec![command("a", "2")],
+                }))
+                .await;
+            let replica = s1.inner.replica.lock().await;
+            let proposed = replica
+                .instance_space
+                .get_instance(&1.into(), &0.into())
+                .await
+                .unwrap();
+            let proposed = proposed.get_instance_read().await.clone().unwrap();
+            drop(replica);
+            // Committed but blocked on the pre-accepted instance
+            s1.inner
+                .handle_message(commit(
+                    2,
+                    0,
+                    *proposed.seq + 1,
+                    command("a", "3"),
+                    vec![Some(0.into()), Some(0.into()), None],
+                ))
+                .await;
+            let replica = s1.inner.replica.lock().await;
+            (replica.cur_max_instances.clone(), replica.max_seq, proposed)
+        });
+        drop(rt);
+        assert_eq!(cur_max_instances, vec![1.into(); PEER_CNT]);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (s1, exe1) = start_durable(&peers, 1, dir.path()).await;
+            {
+                let replica = s1.inner.replica.lock().await;
+                assert_eq!(replica.cur_max_instances, cur_max_instances);
+                // The sequences of the instances of the others count as well after a restart
+                assert!(replica.max_seq >= max_seq);
+                // The conflicts on the key are restored as well
+                let (seq, deps) = replica.get_seq_deps(&[command("a", "4")]).await;
+                assert_eq!(deps, vec![Some(0.into()), None, Some(0.into())]);
+                assert!(*seq > *proposed.seq + 1);
+            }
+
+            // The instance executed before the restart is not executed again, while the
+            // committed one goes on once the instance it waits on is committed
+            s1.inner
+                .handle_message(commit(
+                    1,
+                    0,
+                    *proposed.seq,
+                    command("a", "2"),
+                    proposed.deps.clone(),
+                ))
+                .await;
+            wait_executed(&exe1, &["2", "3"]).await;
+        });
+    }
+
+    async fn wait_truncated(server: &TestServer, expected: &[Option<LocalInstanceID>]) {
+        for _ in 0..100 {
+            let replica = server.inner.replica.lock().await;
+            if replica.instance_space.truncated().await == expected {
+                return;
+            }
+            drop(replica);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("the instances are not truncated up to {:?}", expected);
+    }
+
+    #[test]
+    fn test_truncate_and_catch_up() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let peers = rt.block_on(peers());
+        let (s0, exe0) = rt.block_on(start(&peers, 0));
+        let (_s1, exe1) = rt.block_on(start(&peers, 1));
+        // Replica 2 runs on a runtime of its own, which crashes it once it goes away
+        let rt2 = tokio::runtime::Runtime::new().unwrap();
+        let (s2, exe2) = rt2.block_on(start(&peers, 2));
+
+        // Executed by all the replicas and covered by the snapshot
+        let truncated = vec![Some(2.into()), None, None];
+        rt.block_on(async {
+            let mut executed = vec![];
+            for value in ["1", "2", "3"] {
+                s0.inner
+                    .handle_message(Message::Propose(Propose {
+                        cmds: vec![command("a", value)],
+                    }))
+                    .await;
+                executed.push(value);
+                for exe in [&exe0, &exe1, &exe2] {
+                    wait_executed(exe, &executed).await;
+                }
+            }
+            wait_truncated(&s0, &truncated).await;
+        });
+        drop(s2);
+        drop(rt2);
+
+        rt.block_on(async {
+            // A replica replacing replica 2 on its address reports being behind the truncated
+            // instances, and catches up with the snapshot the others send it
+            let (fresh, fresh_exe) = start(&peers, 2).await;
+            wait_executed(&fresh_exe, &["1", "2", "3"]).await;
+            wait_truncated(&fresh, &truncated).await;
+
+            // And executes the instances depending on the truncated ones
+            fresh
+                .inner
+                .handle_message(Message::Commit(Commit {
+                    command_leader_id: 0.into(),
+                    instance_id: InstanceID::new(0.into(), 3.into()),
+                    seq: 3.into(),
+                    cmds: vec![command("a", "4")],
+                    deps: vec![Some(2.into()), None, None],
+                }))
+                .await;
+            wait_executed(&fresh_exe, &["1", "2", "3", "4"]).await;
+        });
+    }
 }
@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+use super::types::{
+    Command, InstanceID, InstanceRecord, InstanceSpace, InstanceStatus, LocalInstanceID,
+};
+
+/// The application state after executing the instances of every replica up to `executed_upto`,
+/// and the instances in `executed` which are executed beyond them.
+///
+/// A replica catching up with the snapshot gets the instances in `executed` as they are, since
+/// their commands must not be executed again while the replica may not know about them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Snapshot<C> {
+    pub(crate) executed_upto: Vec<Option<LocalInstanceID>>,
+    pub(crate) executed: Vec<InstanceRecord<C>>,
+    pub(crate) state: Vec<u8>,
+}
+
+impl<C> Snapshot<C> {
+    /// Whether the commands of the instance are applied to the state
+    pub(crate) fn covers(&self, id: &InstanceID) -> bool {
+        self.executed_upto[*id.replica].is_some_and(|upto| id.local <= upto)
+            || self.executed.iter().any(|i| i.id == *id)
+    }
+}
+
+/// Returns whether some instance is executed according to `other` but not to `executed_upto`
+pub(crate) fn behind(
+    executed_upto: &[Option<LocalInstanceID>],
+    other: &[Option<LocalInstanceID>],
+) -> bool {
+    other
+        .iter()
+        .enumerate()
+        .any(|(r, o)| *o > executed_upto.get(r).copied().flatten())
+}
+
+/// Returns the instance of every replica up to which all the instances are executed, which is
+/// the execution watermark of the replica, and the instances executed after the watermarks
+pub(crate) async fn executed_watermarks<C, S>(
+    space: &S,
+) -> (Vec<Option<LocalInstanceID>>, Vec<InstanceRecord<C>>)
+where
+    C: Command + Clone + Send + Sync + 'static,
+    S: InstanceSpace<C>,
+{
+    let mut watermarks = space.truncated().await;
+    let mut executed = vec![];
+    for (r, watermark) in watermarks.iter_mut().enumerate() {
+        let r = r.into();
+        let mut contiguous = true;
+        let mut local: LocalInstanceID = watermark.map_or(0, |w| *w + 1).into();
+        while let Some(shared) = space.get_instance(&r, &local).await {
+            let instance = shared.get_instance_read().await;
+            match instance.as_ref() {
+                Some(i) if i.status == InstanceStatus::Executed => {
+                    if contiguous {
+                        *watermark = Some(local);
+                    } else {
+                        executed.push(i.into());
+                    }
+                }
+                _ => contiguous = false,
+            }
+            local += 1;
+        }
+    }
+    (watermarks, executed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::epaxos::{
+        test_util::{command, TestCommand},
+        types::{Ballot, Instance, LeaderBook, ReplicaID, SharedInstance, VecInstanceSpace},
+    };
+
+    async fn insert(
+        space: &VecInstanceSpace<TestCommand>,
+        replica: usize,
+        local: usize,
+        status: InstanceStatus,
+    ) {
+        let id = InstanceID::new(replica.into(), local.into());
+        let instance = Instance {
+            id,
+            seq: 0.into(),
+            ballot: Ballot::default(),
+            accepted_ballot: Ballot::default(),
+            cmds: vec![command(&format!("{replica}-{local}"), "v")],
+            deps: vec![None; 3],
+            status,
+            lb: LeaderBook::default(),
+        };
+        space
+            .insert_instance(
+                &id.replica,
+                &id.local,
+                SharedInstance::new(Some(instance), None),
+            )
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_watermarks_and_truncate() {
+        let space = VecInstanceSpace::<TestCommand>::new(3);
+        insert(&space, 0, 0, InstanceStatus::Executed).await;
+        insert(&space, 0, 1, InstanceStatus::Executed).await;
+        insert(&space, 0, 2, InstanceStatus::Committed).await;
+        insert(&space, 0, 3, InstanceStatus::Executed).await;
+        // Instance (1, 0) is unknown yet
+        insert(&space, 1, 1, InstanceStatus::Executed).await;
+
+        let (watermarks, executed) = executed_watermarks(&space).await;
+        assert_eq!(watermarks, vec![Some(1.into()), None, None]);
+        let executed: Vec<_> = executed.iter().map(|i| i.id).collect();
+        assert_eq!(
+            executed,
+            vec![
+                InstanceID::new(0.into(), 3.into()),
+                InstanceID::new(1.into(), 1.into())
+            ]
+        );
+
+        let replica: ReplicaID = 0.into();
+        space.truncate(&replica, &1.into()).await.unwrap();
+        assert_eq!(space.truncated().await, vec![Some(1.into()), None, None]);
+        assert_eq!(space.instances().await.len(), 3);
+        let truncated = space.get_instance(&replica, &0.into()).await.unwrap();
+        let truncated = truncated.get_instance_read().await;
+        let truncated = truncated.as_ref().unwrap();
+        assert_eq!(truncated.status, InstanceStatus::Executed);
+        assert!(truncated.cmds.is_empty());
+        // The instances after the truncated ones keep their ids
+        let kept = space.get_instance(&replica, &3.into()).await.unwrap();
+        assert_eq!(
+            kept.get_instance_read().await.as_ref().unwrap().cmds[0].key,
+            "0-3"
+        );
+        let (watermarks, _) = executed_watermarks(&space).await;
+        assert_eq!(watermarks, vec![Some(1.into()), None, None]);
+
+        // Truncating beyond the known instances wakes up the execution waiting on them
+        let (_, notify) = space.get_instance_or_notify(&1.into(), &0.into()).await;
+        space.truncate(&1.into(), &5.into()).await.unwrap();
+        notify.unwrap().notified().await;
+        assert_eq!(
+            space.truncated().await,
+            vec![Some(1.into()), Some(5.into()), None]
+        );
+        assert!(
+            space
+                .get_instance(&1.into(), &5.into())
+                .await
+                .unwrap()
+                .match_status(&[InstanceStatus::Executed])
+                .await
+        );
+        assert!(space.get_instance(&1.into(), &6.into()).await.is_none());
+    }
+
+    #[test]
+    fn test_behind() {
+        let upto = vec![Some(1.into()), None, Some(3.into())];
+        assert!(!behind(&upto, &[Some(1.into()), None, Some(2.into())]));
+        assert!(behind(&upto, &[Some(1.into()), Some(0.into()), None]));
+        assert!(behind(&upto, &[Some(2.into()), None, None]));
+    }
+}
@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
+use storage_engine::{
+    db_impl::template_impl::TemplateDB,
+    db_trait::{AsyncDB, DB},
+    iterator::Iterator as _,
+    memtable::batch::WriteBatch,
+    options::{Options, ReadOptions, WriteOptions},
+    storage::file::FileStorage,
+    util::comparator::BytewiseComparator,
+};
+use tokio::sync::Notify;
+
+use super::{
+    config::Configure,
+    error::StorageError,
+    snapshot::Snapshot,
+    types::{
+        Command, Instance, InstanceID, InstanceRecord, InstanceSpace, LocalInstanceID, ReplicaID,
+        SharedInstance, VecInstanceSpace,
+    },
+};
+
+// The keys of the instances, ordered by replica and then by instance id
+const INSTANCE_PREFIX: u8 = b'i';
+// The keys of the instance every replica is truncated up to
+const TRUNCATED_PREFIX: u8 = b't';
+const SNAPSHOT_KEY: &[u8] = b"s";
+
+fn instance_key(id: &InstanceID) -> Vec<u8> {
+    let mut key = vec![INSTANCE_PREFIX];
+    key.extend_from_slice(&(*id.replica as u64).to_be_bytes());
+    key.extend_from_slice(&(*id.local as u64).to_be_bytes());
+    key
+}
+
+fn truncated_key(replica: &ReplicaID) -> Vec<u8> {
+    let mut key = vec![TRUNCATED_PREFIX];
+    key.extend_from_slice(&(**replica as u64).to_be_bytes());
+    key
+}
+
+fn sync_write() -> WriteOptions {
+    WriteOptions {
+        sync: true,
+        ..Default::default()
+    }
+}
+
+fn db_error(e: impl std::fmt::Display) -> StorageError {
+    StorageError::DBError(e.to_string())
+}
+
+/// An instance space whose instances survive a restart of the replica.
+///
+/// Every state transition of an instance is written to a `TemplateDB` in `Configure::data_dir`
+/// with a synchronous write before the replica replies, while the reads are served from memory.
+/// The truncation of the instances and the latest snapshot are kept in the db as well.
+pub struct DurableInstanceSpace<C: Command + Clone + Send + 'static> {
+    space: VecInstanceSpace<C>,
+    db: TemplateDB<FileStorage, BytewiseComparator>,
+}
+
+#[async_trait]
+impl<C> InstanceSpace<C> for DurableInstanceSpace<C>
+where
+    C: Command + Clone + Send + Sync + Serialize + DeserializeOwned + 'static,
+{
+    async fn open(conf: &Configure) -> Result<Self, StorageError> {
+        let data_dir = conf.data_dir.as_ref().ok_or(StorageError::NoDataDir)?;
+        let db =
+            TemplateDB::open_db(Options::default(), data_dir, FileStorage).map_err(db_error)?;
+        let space = VecInstanceSpace::new(conf.peer_cnt);
+
+        let mut records = AsyncDB::iter(&db, ReadOptions::default()).map_err(db_error)?;
+        while let Some(kv) = records.next().await {
+            let (key, value) = kv.map_err(db_error)?;
+            match key.first() {
+                Some(&INSTANCE_PREFIX) => {
+                    let instance: Instance<C> =
+                        bincode::deserialize::<InstanceRecord<C>>(&value)?.into();
+                    let id = instance.id;
+                    space
+                        .insert_instance(
+                            &id.replica,
+                            &id.local,
+                            SharedInstance::new(Some(instance), None),
+                        )
+                        .await;
+                }
+                Some(&TRUNCATED_PREFIX) => {
+                    let replica = u64::from_be_bytes(key[1..].try_into().unwrap()) as usize;
+                    let upto: LocalInstanceID = bincode::deserialize(&value)?;
+                    space.drain(&replica.into(), &upto).await;
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { space, db })
+    }
+
+    async fn get_instance_or_notify(
+        &self,
+        replica: &ReplicaID,
+        instance_id: &LocalInstanceID,
+    ) -> (Option<SharedInstance<C>>, Option<Arc<Notify>>) {
+        self.space
+            .get_instance_or_notify(replica, instance_id)
+            .await
+    }
+
+    async fn get_instance(
+        &self,
+        replica: &ReplicaID,
+        instance_id: &LocalInstanceID,
+    ) -> Option<SharedInstance<C>> {
+        self.space.get_instance(replica, instance_id).await
+    }
+
+    async fn insert_instance(
+        &self,
+        replica: &ReplicaID,
+        instance_id: &LocalInstanceID,
+        instance: SharedInstance<C>,
+    ) {
+        self.space
+            .insert_instance(replica, instance_id, instance)
+            .await;
+    }
+
+    async fn instances(&self) -> Vec<SharedInstance<C>> {
+        self.space.instances().await
+    }
+
+    async fn persist(&self, instance: &Instance<C>) -> Result<(), StorageError> {
+        let record = bincode::serialize(&InstanceRecord::from(instance))?;
+        AsyncDB::put(&self.db, sync_write(), &instance_key(&instance.id), &record)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn truncate(
+        &self,
+        replica: &ReplicaID,
+        upto: &LocalInstanceID,
+    ) -> Result<(), StorageError> {
+        let truncated = self.space.truncated().await[**replica];
+        if truncated >= Some(*upto) {
+            return Ok(());
+        }
+        // Written before the instances are dropped from memory, which are kept if it fails
+        let mut batch = WriteBatch::default();
+        batch.put(&truncated_key(replica), &bincode::serialize(upto)?);
+        {
+            // Only the instances persisted are deleted, a replica might skip many ids
+            let last = instance_key(&InstanceID::new(*replica, *upto));
+            let mut iter = DB::iter(&self.db, ReadOptions::default()).map_err(db_error)?;
+            iter.seek(&instance_key(&InstanceID::new(*replica, 0.into())));
+            while iter.valid() && iter.key() <= last.as_slice() {
+                batch.delete(iter.key());
+                iter.next();
+            }
+            iter.status().map_err(db_error)?;
+        }
+        AsyncDB::write(&self.db, sync_write(), batch)
+            .await
+            .map_err(db_error)?;
+        for instance in self.space.drain(replica, upto).await {
+            instance.notify_commit().await;
+        }
+        Ok(())
+    }
+
+    async fn truncated(&self) -> Vec<Option<LocalInstanceID>> {
+        self.space.truncated().await
+    }
+
+    async fn save_snapshot(&self, snapshot: &Snapshot<C>) -> Result<(), StorageError> {
+        let snapshot = bincode::serialize(snapshot)?;
+        AsyncDB::put(&self.db, sync_write(), SNAPSHOT_KEY, &snapshot)
+            .await
+            .map_err(db_error)
+    }
+
+    async fn load_snapshot(&self) -> Result<Option<Snapshot<C>>, StorageError> {
+        let snapshot = AsyncDB::get(&self.db, ReadOptions::default(), SNAPSHOT_KEY)
+            .await
+            .map_err(db_error)?;
+        Ok(match snapshot {
+            Some(snapshot) => Some(bincode::deserialize(&snapshot)?),
+            None => None,
+        })
+    }
+}
+
+impl<C: Command + Clone + Send + 'static> Drop for DurableInstanceSpace<C> {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.close() {
+            error!("close the db of the instance space failed, {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::epaxos::{
+        test_util::{command, TestCommand},
+        types::{Ballot, InstanceStatus, LeaderBook, Seq},
+    };
+
+    fn instance(replica: usize, local: usize, status: InstanceStatus) -> Instance<TestCommand> {
+        Instance {
+            id: InstanceID::new(replica.into(), local.into()),
+            seq: Seq::from(local + 1),
+            ballot: Ballot::default().next(replica.into()),
+            accepted_ballot: Ballot::default(),
+            cmds: vec![command(&format!("{replica}-{local}"), "v")],
+            deps: vec![Some(0.into()), None, None],
+            status,
+            lb: LeaderBook::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configure::new(3, vec![], 0, 0).with_data_dir(dir.path());
+
+        let space = DurableInstanceSpace::<TestCommand>::open(&conf)
+            .await
+            .unwrap();
+        let mut committed = instance(1, 2, InstanceStatus::Accepted);
+        for i in [
+            instance(0, 0, InstanceStatus::PreAccepted),
+            committed.clone(),
+            instance(1, 0, InstanceStatus::Executed),
+        ] {
+            space.persist(&i).await.unwrap();
+        }
+        // The latest state of an instance wins
+        committed.status = InstanceStatus::Committed;
+        space.persist(&committed).await.unwrap();
+        drop(space);
+
+        let space = DurableInstanceSpace::<TestCommand>::open(&conf)
+            .await
+            .unwrap();
+        let mut restored = vec![];
+        for shared in space.instances().await {
+            let i = shared.get_instance_read().await;
+            let i = i.as_ref().unwrap();
+            restored.push((
+                i.id,
+                i.status,
+                i.seq,
+                i.ballot,
+                i.cmds.clone(),
+                i.deps.clone(),
+            ));
+        }
+        let expected: Vec<_> = [
+            instance(0, 0, InstanceStatus::PreAccepted),
+            instance(1, 0, InstanceStatus::Executed),
+            committed,
+        ]
+        .into_iter()
+        .map(|i| (i.id, i.status, i.seq, i.ballot, i.cmds, i.deps))
+        .collect();
+        assert_eq!(restored, expected);
+        assert!(space
+            .get_instance(&1.into(), &1.into())
+            .await
+            .unwrap()
+            .get_instance_read()
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_open_without_data_dir() {
+        let conf = Configure::new(3, vec![], 0, 0);
+        assert!(matches!(
+            DurableInstanceSpace::<TestCommand>::open(&conf).await,
+            Err(StorageError::NoDataDir)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restore_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = Configure::new(3, vec![], 0, 0).with_data_dir(dir.path());
+
+        let space = DurableInstanceSpace::<TestCommand>::open(&conf)
+            .await
+            .unwrap();
+        for local in 0..3 {
+            space
+                .persist(&instance(0, local, InstanceStatus::Executed))
+                .await
+                .unwrap();
+        }
+        space.truncate(&0.into(), &1.into()).await.unwrap();
+        // Only the instance after the truncation is left in the db
+        let mut keys = vec![];
+        let mut records = AsyncDB::iter(&space.db, ReadOptions::default()).unwrap();
+        while let Some(kv) = records.next().await {
+            let (key, _) = kv.unwrap();
+            if key.first() == Some(&INSTANCE_PREFIX) {
+                keys.push(key);
+            }
+        }
+        assert_eq!(
+            keys,
+            vec![instance_key(&InstanceID::new(0.into(), 2.into()))]
+        );
+        let snapshot = Snapshot {
+            executed_upto: vec![Some(1.into()), None, None],
+            executed: vec![InstanceRecord::from(&instance(
+                0,
+                2,
+                InstanceStatus::Executed,
+            ))],
+            state: b"state".to_vec(),
+        };
+        space.save_snapshot(&snapshot).await.unwrap();
+        drop(space);
+
+        let space = DurableInstanceSpace::<TestCommand>::open(&conf)
+            .await
+            .unwrap();
+        assert_eq!(space.truncated().await, vec![Some(1.into()), None, None]);
+        let instances = space.instances().await;
+        assert_eq!(instances.len(), 1);
+        assert_eq!(
+            instances[0].get_instance_read().await.as_ref().unwrap().id,
+            InstanceID::new(0.into(), 2.into())
+        );
+        let restored = space.load_snapshot().await.unwrap().unwrap();
+        assert_eq!(restored.executed_upto, snapshot.executed_upto);
+        assert_eq!(restored.executed[0].id, snapshot.executed[0].id);
+        assert_eq!(restored.state, snapshot.state);
+    }
+}
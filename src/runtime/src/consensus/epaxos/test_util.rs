@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+use super::types::Command;
+
+// The command used by the tests, which carries the value written to `key`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TestCommand {
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+impl Command for TestCommand {
+    type K = String;
+
+    fn key(&self) -> &Self::K {
+        &self.key
+    }
+}
+
+pub(crate) fn command(key: &str, value: &str) -> TestCommand {
+    TestCommand {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
@@ -5,11 +5,27 @@ use pro_macro::FromInner;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{Notify, RwLock, RwLockMappedWriteGuard, RwLockReadGuard, RwLockWriteGuard};
 
-use super::{config::Configure, error::ExecuteError, util::instance_exist};
+use super::{
+    config::Configure,
+    error::{ExecuteError, StorageError},
+    snapshot::Snapshot,
+    util::instance_exist,
+};
 
 #[async_trait]
 pub trait CommandExecutor<C: Command> {
     async fn execute(&self, cmd: &C) -> Result<(), ExecuteError>;
+
+    /// Take a snapshot of the application state, with all the commands executed so far applied.
+    /// An application returning `None` takes no snapshot, and then no instance is truncated.
+    async fn snapshot(&self) -> Result<Option<Vec<u8>>, ExecuteError> {
+        Ok(None)
+    }
+
+    /// Replace the application state with a snapshot taken on this or another replica
+    async fn restore(&self, _snapshot: &[u8]) -> Result<(), ExecuteError> {
+        Err(ExecuteError::SnapshotUnsupported)
+    }
 }
 
 #[async_trait]
@@ -57,6 +73,15 @@ impl Ballot {
         self.base == 0
     }
 
+    /// Returns a ballot of `replica` larger than this one, to take over an instance with
+    pub(crate) fn next(&self, replica: ReplicaID) -> Self {
+        Ballot {
+            epoch: self.epoch,
+            base: self.base + 1,
+            replica,
+        }
+    }
+
     // pub(crate) fn new_with_epoch(replica: ReplicaID, epoch: usize) -> Ballot {
     //     Ballot {
     //         replica,
@@ -90,8 +115,10 @@ impl InstanceID {
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize)]
 pub(crate) enum InstanceStatus {
+    /// Only the ballot promised to a recovering replica is known
+    Prepared,
     PreAccepted,
     PreAcceptedEq,
     Accepted,
@@ -125,6 +152,8 @@ pub struct Instance<C: Command> {
     pub(crate) id: InstanceID,
     pub(crate) seq: Seq,
     pub(crate) ballot: Ballot,
+    /// The ballot `seq` and `deps` were pre-accepted or accepted at
+    pub(crate) accepted_ballot: Ballot,
     pub(crate) cmds: Vec<C>,
     pub(crate) deps: Vec<Option<LocalInstanceID>>,
     pub(crate) status: InstanceStatus,
@@ -137,6 +166,50 @@ impl<C: Command> Instance<C> {
     }
 }
 
+/// The state of an instance kept on disk or sent to other replicas, everything but the books of
+/// the command leader
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct InstanceRecord<C> {
+    pub(crate) id: InstanceID,
+    pub(crate) seq: Seq,
+    pub(crate) ballot: Ballot,
+    pub(crate) accepted_ballot: Ballot,
+    pub(crate) cmds: Vec<C>,
+    pub(crate) deps: Vec<Option<LocalInstanceID>>,
+    pub(crate) status: InstanceStatus,
+}
+
+impl<C: Command + Clone> From<&Instance<C>> for InstanceRecord<C> {
+    fn from(instance: &Instance<C>) -> Self {
+        Self {
+            id: instance.id,
+            seq: instance.seq,
+            ballot: instance.ballot,
+            accepted_ballot: instance.accepted_ballot,
+            cmds: instance.cmds.clone(),
+            deps: instance.deps.clone(),
+            status: instance.status,
+        }
+    }
+}
+
+impl<C: Command> From<InstanceRecord<C>> for Instance<C> {
+    fn from(record: InstanceRecord<C>) -> Self {
+        Self {
+            id: record.id,
+            seq: record.seq,
+            ballot: record.ballot,
+            accepted_ballot: record.accepted_ballot,
+            cmds: record.cmds,
+            deps: record.deps,
+            status: record.status,
+            // The replies counted by the command leader are not kept, the instance is recovered
+            // if it is not decided
+            lb: LeaderBook::default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct SharedInstanceInner<C: Command + Clone> {
     instance: Option<Instance<C>>,
@@ -227,8 +300,10 @@ impl<C: Command + Clone> SharedInstance<C> {
 
 #[async_trait]
 pub(crate) trait InstanceSpace<C: Command + Clone + Send + Sync + 'static> {
-    /// Construct a instance space
-    fn new(peer_cnt: usize) -> Self;
+    /// Open the instance space of the replica, with the instances persisted before it restarts
+    async fn open(conf: &Configure) -> Result<Self, StorageError>
+    where
+        Self: Sized;
 
     /// Get the instance, if the it returns a notify it means the instance
     /// is not ready, and the notify is stored in the notify vec. The returned
@@ -254,13 +329,62 @@ pub(crate) trait InstanceSpace<C: Command + Clone + Send + Sync + 'static> {
         instance_id: &LocalInstanceID,
         instance: SharedInstance<C>,
     );
+
+    /// All the instances in the space, in the order of the replicas and then the instance ids
+    async fn instances(&self) -> Vec<SharedInstance<C>>;
+
+    /// Persist the state of the instance, which has to survive a restart of the replica before
+    /// the replica tells others about it. Nothing is kept by default.
+    async fn persist(&self, _instance: &Instance<C>) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Drop the instances of `replica` up to `upto`, which all the replicas have executed. They
+    /// are got as executed instances without commands afterwards.
+    async fn truncate(
+        &self,
+        replica: &ReplicaID,
+        upto: &LocalInstanceID,
+    ) -> Result<(), StorageError>;
+
+    /// The instance of every replica up to which the instances are truncated
+    async fn truncated(&self) -> Vec<Option<LocalInstanceID>>;
+
+    /// Persist the latest snapshot of the replica, which the application state is restored from
+    /// after a restart. Nothing is kept by default.
+    async fn save_snapshot(&self, _snapshot: &Snapshot<C>) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// The latest snapshot saved before the replica restarts
+    async fn load_snapshot(&self) -> Result<Option<Snapshot<C>>, StorageError> {
+        Ok(None)
+    }
+}
+
+struct ReplicaInstances<C: Command + Clone> {
+    // The instances up to it are truncated
+    truncated: Option<LocalInstanceID>,
+    // The instances following the truncated ones
+    instances: Vec<SharedInstance<C>>,
+}
+
+impl<C: Command + Clone> ReplicaInstances<C> {
+    // The instance id of `instances[0]`
+    fn offset(&self) -> usize {
+        self.truncated.map_or(0, |t| *t + 1)
+    }
+
+    fn is_truncated(&self, instance_id: &LocalInstanceID) -> bool {
+        self.truncated.is_some_and(|t| *instance_id <= t)
+    }
 }
 
 // TODO: Maybe hashmap or others are more fit in this case.
 // (replica_id, instance_id) pair to instance mapping, and
 // a big rwlock it not efficient here.
 pub struct VecInstanceSpace<C: Command + Clone + Send + 'static> {
-    inner: RwLock<Vec<Vec<SharedInstance<C>>>>,
+    inner: RwLock<Vec<ReplicaInstances<C>>>,
 }
 
 #[async_trait]
@@ -268,15 +392,8 @@ impl<C> InstanceSpace<C> for VecInstanceSpace<C>
 where
     C: Command + Clone + Send + Sync + 'static,
 {
-    fn new(peer_cnt: usize) -> Self {
-        let mut peer_vec = Vec::with_capacity(peer_cnt);
-        (0..peer_cnt).for_each(|_| {
-            peer_vec.push(vec![]);
-        });
-
-        Self {
-            inner: RwLock::new(peer_vec),
-        }
+    async fn open(conf: &Configure) -> Result<Self, StorageError> {
+        Ok(Self::new(conf.peer_cnt))
     }
 
     /// Get the instance, if the it returns a notify it means the instance
@@ -289,17 +406,21 @@ where
     ) -> (Option<SharedInstance<C>>, Option<Arc<Notify>>) {
         // TODO: Lock is huge!!!
         let mut space = self.inner.write().await;
+        let space = &mut space[**replica];
+        if space.is_truncated(instance_id) {
+            return (Some(Self::truncated_instance(replica, instance_id)), None);
+        }
         // ** means get the usize(index).
-        let instance_id = **instance_id;
-        let replica_id = **replica;
+        let instance_id = **instance_id - space.offset();
 
-        let space_len = space[replica_id].len();
+        let space_len = space.instances.len();
         if instance_id >= space_len {
             if Self::need_notify(&None).await {
-                space[replica_id]
+                space
+                    .instances
                     .extend((space_len..(instance_id + 1)).map(|_| SharedInstance::none()));
                 let notify = Arc::new(Notify::new());
-                space[replica_id][instance_id]
+                space.instances[instance_id]
                     .add_notify(notify.clone())
                     .await;
                 (None, Some(notify))
@@ -308,7 +429,7 @@ where
             }
         } else {
             // We have check the bound in the if branch
-            let instance = space[replica_id][instance_id].clone();
+            let instance = space.instances[instance_id].clone();
             if Self::need_notify(&Some(instance.clone())).await {
                 let notify = Arc::new(Notify::new());
                 instance.add_notify(notify.clone()).await;
@@ -325,14 +446,11 @@ where
         instance_id: &LocalInstanceID,
     ) -> Option<SharedInstance<C>> {
         let space = self.inner.read().await;
-        let instance_id = **instance_id;
-        let replica_id = **replica;
-
-        if instance_id >= space[replica_id].len() {
-            None
-        } else {
-            Some(space[replica_id][instance_id].clone())
+        let space = &space[**replica];
+        if space.is_truncated(instance_id) {
+            return Some(Self::truncated_instance(replica, instance_id));
         }
+        space.instances.get(**instance_id - space.offset()).cloned()
     }
 
     async fn insert_instance(
@@ -342,26 +460,110 @@ where
         instance: SharedInstance<C>,
     ) {
         let mut space = self.inner.write().await;
-        let instance_id = **instance_id;
-        let replica_id = **replica;
-        let space_len = space[replica_id].len();
+        let space = &mut space[**replica];
+        if space.is_truncated(instance_id) {
+            // A late message about an instance executed everywhere
+            return;
+        }
+        let instance_id = **instance_id - space.offset();
+        let space_len = space.instances.len();
         match instance_id.partial_cmp(&space_len) {
             Some(std::cmp::Ordering::Greater) => {
-                space[replica_id]
-                    .extend((space_len..(instance_id + 1)).map(|_| SharedInstance::none()));
+                space
+                    .instances
+                    .extend((space_len..instance_id).map(|_| SharedInstance::none()));
+                space.instances.push(instance);
             }
             Some(std::cmp::Ordering::Less) => {
-                space[replica_id][instance_id] = instance;
+                space.instances[instance_id] = instance;
             }
             Some(std::cmp::Ordering::Equal) => {
-                space[replica_id].push(instance);
+                space.instances.push(instance);
             }
             None => {}
         }
     }
+
+    async fn instances(&self) -> Vec<SharedInstance<C>> {
+        let space = self.inner.read().await;
+        let mut instances = vec![];
+        for instance in space.iter().flat_map(|s| s.instances.iter()) {
+            if instance.get_instance_read().await.is_some() {
+                instances.push(instance.clone());
+            }
+        }
+        instances
+    }
+
+    async fn truncate(
+        &self,
+        replica: &ReplicaID,
+        upto: &LocalInstanceID,
+    ) -> Result<(), StorageError> {
+        for dropped in self.drain(replica, upto).await {
+            // Wake up the execution waiting on the dropped instance
+            dropped.notify_commit().await;
+        }
+        Ok(())
+    }
+
+    async fn truncated(&self) -> Vec<Option<LocalInstanceID>> {
+        let space = self.inner.read().await;
+        space.iter().map(|s| s.truncated).collect()
+    }
 }
 
 impl<C: Command + Clone + Send + Sync + 'static> VecInstanceSpace<C> {
+    pub(crate) fn new(peer_cnt: usize) -> Self {
+        let mut peer_vec = Vec::with_capacity(peer_cnt);
+        (0..peer_cnt).for_each(|_| {
+            peer_vec.push(ReplicaInstances {
+                truncated: None,
+                instances: vec![],
+            });
+        });
+
+        Self {
+            inner: RwLock::new(peer_vec),
+        }
+    }
+
+    /// Removes the instances of `replica` up to `upto`, and returns the removed ones. The
+    /// execution waiting on them has to be notified without the space locked, since it reads
+    /// the space holding the instance it waits for.
+    pub(crate) async fn drain(
+        &self,
+        replica: &ReplicaID,
+        upto: &LocalInstanceID,
+    ) -> Vec<SharedInstance<C>> {
+        let mut space = self.inner.write().await;
+        let space = &mut space[**replica];
+        if space.is_truncated(upto) {
+            return vec![];
+        }
+        let end = (**upto + 1 - space.offset()).min(space.instances.len());
+        let dropped = space.instances.drain(..end).collect();
+        space.truncated = Some(*upto);
+        dropped
+    }
+
+    // What a truncated instance is got as, it is executed by all the replicas
+    fn truncated_instance(replica: &ReplicaID, instance_id: &LocalInstanceID) -> SharedInstance<C> {
+        SharedInstance::new(
+            Some(Instance {
+                id: InstanceID::new(*replica, *instance_id),
+                seq: 0.into(),
+                ballot: Ballot::default(),
+                accepted_ballot: Ballot::default(),
+                cmds: vec![],
+                deps: vec![],
+                status: InstanceStatus::Executed,
+                lb: LeaderBook::default(),
+            }),
+            None,
+        )
+    }
+
     async fn need_notify(instance: &Option<SharedInstance<C>>) -> bool {
         if !instance_exist(instance).await {
             true
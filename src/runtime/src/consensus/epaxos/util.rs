@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
@@ -21,35 +21,23 @@ pub(crate) async fn instance_exist<C: Command + Clone>(
     }
 }
 
-async fn read_from_stream(stream: &mut TcpStream, buf: &mut [u8]) {
-    let expected_len = buf.len();
-    let mut has_read = 0;
-    while has_read != expected_len {
-        let read_size = stream
-            .read(&mut buf[has_read..])
-            .await
-            .map_err(|e| panic!("link should read {expected_len} bytes message, {e}"))
-            .unwrap();
-        has_read += read_size;
-    }
+async fn read_from_stream(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<()> {
+    stream.read_exact(buf).await?;
+    Ok(())
 }
 
-pub(crate) async fn recv_message<M: DeserializeOwned>(conn: &mut TcpStream) -> M {
+/// Receives a message, failing once the peer has gone away
+pub(crate) async fn recv_message<M: DeserializeOwned>(conn: &mut TcpStream) -> io::Result<M> {
     let mut len_buf: [u8; 8] = [0; 8];
-    read_from_stream(conn, &mut len_buf).await;
+    read_from_stream(conn, &mut len_buf).await?;
 
     let expected_len = u64::from_be_bytes(len_buf);
-    let mut buf = Vec::with_capacity(expected_len as usize);
-    buf.spare_capacity_mut();
-    unsafe { buf.set_len(expected_len as usize) };
-
-    read_from_stream(conn, &mut buf).await;
-    bincode::deserialize(&buf)
-        .map_err(|e| panic!("Deserialize message failed, {e}"))
-        .unwrap()
+    let mut buf = vec![0; expected_len as usize];
+    read_from_stream(conn, &mut buf).await?;
+    bincode::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-pub(crate) async fn send_message<M>(conn: &mut TcpStream, message: &M)
+pub(crate) async fn send_message<M>(conn: &mut TcpStream, message: &M) -> io::Result<()>
 where
     M: Serialize,
 {
@@ -59,24 +47,26 @@ where
         .unwrap();
     let len = (content.len() as u64).to_be_bytes();
 
-    // TODO: Handle network error
-    let _ = conn.write(&len).await;
-    let _ = conn.write(&content).await;
+    conn.write_all(&len).await?;
+    conn.write_all(&content).await
 }
 
-pub(crate) async fn send_message_arc<M>(conn: &Arc<Mutex<TcpStream>>, message: &M)
+pub(crate) async fn send_message_arc<M>(conn: &Arc<Mutex<TcpStream>>, message: &M) -> io::Result<()>
 where
     M: Serialize,
 {
     let mut conn = conn.lock().await;
     let conn = &mut *conn;
 
-    send_message(conn, message).await;
+    send_message(conn, message).await
 }
 
-pub(crate) async fn send_message_arc2<M>(conn: &Arc<Mutex<TcpStream>>, message: &Arc<M>)
+pub(crate) async fn send_message_arc2<M>(
+    conn: &Arc<Mutex<TcpStream>>,
+    message: &Arc<M>,
+) -> io::Result<()>
 where
     M: Serialize,
 {
-    send_message_arc(conn, message.as_ref()).await;
+    send_message_arc(conn, message.as_ref()).await
 }
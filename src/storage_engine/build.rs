@@ -15,6 +15,13 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile(&["proto/memtable.proto"], &["proto"])?;
+        .compile(
+            &[
+                "proto/memtable.proto",
+                "proto/kv.proto",
+                "proto/admin.proto",
+            ],
+            &["proto"],
+        )?;
     Ok(())
 }
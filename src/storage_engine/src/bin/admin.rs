@@ -0,0 +1,145 @@
+use std::{error::Error, process};
+
+use clap::{Parser, Subcommand};
+use storage_engine::{
+    admin_service::{
+        admin_service_client::AdminServiceClient, CompactRangeRequest, CreateCheckpointRequest,
+        FlushMemTableRequest, GetApproximateSizeRequest, GetLevelSummaryRequest,
+        ListLiveFilesRequest, SetLogLevelRequest,
+    },
+    tools::escape,
+};
+use tonic::transport::Channel;
+
+/// Operates a running db node through its `AdminService`
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// The address of the admin service, like `http://[::1]:50053`
+    #[arg(long, default_value = "http://[::1]:50053")]
+    addr: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compact the key range `[from, to]`, or the whole db if the bounds are omitted
+    Compact {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        /// Only compact the files in this level into the next level
+        #[arg(long)]
+        level: Option<u32>,
+    },
+    /// Flush the memtable into an sst file
+    Flush,
+    /// Print the approximate size of the key range `[from, to)` on disk
+    Size { from: String, to: String },
+    /// Print the number of files and bytes in every level
+    Levels,
+    /// Print the live sst files with their key ranges
+    Files,
+    /// Create a checkpoint of the db in a directory on the node
+    Checkpoint { dir: String },
+    /// Change the log level of the node to one of off, error, warn, info, debug and trace
+    LogLevel { level: String },
+}
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+async fn run(mut client: AdminServiceClient<Channel>, command: Command) -> Result<()> {
+    match command {
+        Command::Compact { from, to, level } => {
+            client
+                .compact_range(CompactRangeRequest {
+                    begin: from.map(String::into_bytes),
+                    end: to.map(String::into_bytes),
+                    level,
+                })
+                .await?;
+        }
+        Command::Flush => {
+            client.flush_mem_table(FlushMemTableRequest {}).await?;
+        }
+        Command::Size { from, to } => {
+            let resp = client
+                .get_approximate_size(GetApproximateSizeRequest {
+                    start: from.into_bytes(),
+                    end: to.into_bytes(),
+                })
+                .await?
+                .into_inner();
+            println!("{}", resp.size);
+        }
+        Command::Levels => {
+            let resp = client
+                .get_level_summary(GetLevelSummaryRequest {})
+                .await?
+                .into_inner();
+            println!("{}", resp.summary);
+            for l in resp.levels {
+                println!(
+                    "level {}: {} files, {} bytes",
+                    l.level, l.num_files, l.total_bytes
+                );
+            }
+        }
+        Command::Files => {
+            let resp = client
+                .list_live_files(ListLiveFilesRequest {})
+                .await?
+                .into_inner();
+            for f in resp.files {
+                println!(
+                    "level {} #{}: {} bytes, {} entries, {} deletions ['{}' .. '{}']",
+                    f.level,
+                    f.number,
+                    f.file_size,
+                    f.num_entries,
+                    f.num_deletions,
+                    escape(&f.smallest_key),
+                    escape(&f.largest_key)
+                );
+            }
+        }
+        Command::Checkpoint { dir } => {
+            let resp = client
+                .create_checkpoint(CreateCheckpointRequest { dir: dir.clone() })
+                .await?
+                .into_inner();
+            println!(
+                "checkpoint {} at sequence {}: {} table files, {} log files",
+                dir,
+                resp.last_sequence,
+                resp.table_files.len(),
+                resp.log_files.len()
+            );
+        }
+        Command::LogLevel { level } => {
+            let resp = client
+                .set_log_level(SetLogLevelRequest {
+                    level: level.clone(),
+                })
+                .await?
+                .into_inner();
+            println!("{} -> {}", resp.previous_level, level);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let res = runtime.block_on(async {
+        let client = AdminServiceClient::connect(cli.addr).await?;
+        run(client, cli.command).await
+    });
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::{Parser, ValueEnum};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use storage_engine::{
+    cache::{bloom_filter_cache::BloomFilter, clock_cache::ClockCache},
+    db_impl::template_impl::TemplateDB,
+    db_trait::DB,
+    iterator::Iterator,
+    memtable::batch::WriteBatch,
+    options::{Options, ReadOptions, WriteOptions},
+    statistics::Statistics,
+    storage::{file::FileStorage, mem::MemStorage, Storage},
+    util::comparator::BytewiseComparator,
+};
+
+const BENCHMARKS: &str =
+    "fillseq,fillrandom,overwrite,readrandom,readseq,seekrandom,readwhilewriting";
+
+/// Runs the benchmarks against a TemplateDB in the given order and reports the throughput, the
+/// latency percentiles and the compaction stats
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Comma-separated benchmarks to run in order. `fillseq` and `fillrandom` start from a
+    /// fresh db while the others reuse the db left by the previous ones.
+    #[arg(long, default_value = BENCHMARKS)]
+    benchmarks: String,
+    #[arg(long, value_enum, default_value_t = StorageKind::Mem)]
+    storage: StorageKind,
+    /// The db directory
+    #[arg(long, default_value = "/tmp/templatedb_bench")]
+    db: PathBuf,
+    /// The number of keys written by the fill benchmarks
+    #[arg(long, default_value_t = 1_000_000)]
+    num: usize,
+    /// The number of reads. Defaults to `num`.
+    #[arg(long)]
+    reads: Option<usize>,
+    /// The number of concurrent threads sharing the operations of a benchmark
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+    /// The number of entries per `WriteBatch`
+    #[arg(long, default_value_t = 1)]
+    batch_size: usize,
+    /// How much the values shrink when compressed
+    #[arg(long, default_value_t = 0.5)]
+    compression_ratio: f64,
+    #[arg(long, default_value_t = 4 << 20)]
+    write_buffer_size: usize,
+    #[arg(long, default_value_t = 2 << 20)]
+    max_file_size: usize,
+    /// The capacity of the block cache in bytes. No block cache if 0.
+    #[arg(long, default_value_t = 8 << 20)]
+    cache_size: usize,
+    /// The bits per key of the bloom filters. No filter if 0.
+    #[arg(long, default_value_t = 10)]
+    bloom_bits: usize,
+    /// Sync the WAL on every write
+    #[arg(long)]
+    sync: bool,
+    /// The seed of the random keys. Thread `i` uses `seed + i`.
+    #[arg(long, default_value_t = 301)]
+    seed: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StorageKind {
+    Mem,
+    File,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+fn key(k: usize) -> String {
+    format!("{:016}", k)
+}
+
+// Produces the values compressible by `compression_ratio` from a pre-generated pool
+struct ValueGenerator {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl ValueGenerator {
+    fn new(rng: &mut StdRng, compression_ratio: f64) -> Self {
+        let pool_size = 1 << 20;
+        let fragment = 100;
+        let raw = ((fragment as f64 * compression_ratio) as usize).clamp(1, fragment);
+        let mut data = Vec::with_capacity(pool_size + fragment);
+        while data.len() < pool_size {
+            let piece: Vec<u8> = (0..raw).map(|_| rng.gen_range(b' ', b'~' + 1)).collect();
+            data.extend(piece.iter().cycle().take(fragment));
+        }
+        Self { data, pos: 0 }
+    }
+
+    fn generate(&mut self, len: usize) -> &[u8] {
+        if self.pos + len > self.data.len() {
+            self.pos = 0;
+        }
+        self.pos += len;
+        &self.data[self.pos - len..self.pos]
+    }
+}
+
+// The measurement of a benchmark in one thread
+#[derive(Default)]
+struct ThreadStats {
+    ops: usize,
+    bytes: usize,
+    found: usize,
+    // nanoseconds per operation
+    latencies: Vec<u64>,
+    elapsed: Duration,
+}
+
+impl ThreadStats {
+    fn record<T>(&mut self, op: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = op();
+        self.latencies.push(start.elapsed().as_nanos() as u64);
+        res
+    }
+
+    fn merge(&mut self, other: ThreadStats) {
+        self.ops += other.ops;
+        self.bytes += other.bytes;
+        self.found += other.found;
+        self.latencies.extend(other.latencies);
+        self.elapsed = self.elapsed.max(other.elapsed);
+    }
+
+    fn report(mut self, name: &str, show_found: bool) {
+        self.latencies.sort_unstable();
+        let secs = self.elapsed.as_secs_f64().max(1e-9);
+        let percentile = |p: f64| -> f64 {
+            if self.latencies.is_empty() {
+                return 0.0;
+            }
+            let i = ((self.latencies.len() as f64 * p) as usize).min(self.latencies.len() - 1);
+            self.latencies[i] as f64 / 1000.0
+        };
+        let mut line = format!(
+            "{:<16} : {:>10.3} micros/op; {:>10.0} ops/sec;",
+            name,
+            secs * 1e6 / self.ops.max(1) as f64,
+            self.ops as f64 / secs
+        );
+        if self.bytes > 0 {
+            line.push_str(&format!(
+                " {:>7.1} MB/s;",
+                self.bytes as f64 / 1048576.0 / secs
+            ));
+        }
+        if show_found {
+            line.push_str(&format!(" ({} of {} found)", self.found, self.ops));
+        }
+        println!("{}", line);
+        println!(
+            "{:<16}   latency(micros) p50 {:.2}, p95 {:.2}, p99 {:.2}, p99.9 {:.2}, max {:.2}",
+            "",
+            percentile(0.5),
+            percentile(0.95),
+            percentile(0.99),
+            percentile(0.999),
+            percentile(1.0)
+        );
+    }
+}
+
+struct Bench<S: Storage + Clone + 'static> {
+    cli: Cli,
+    storage: S,
+    statistics: Arc<Statistics>,
+    db: Option<TemplateDB<S, BytewiseComparator>>,
+}
+
+impl<S: Storage + Clone + 'static> Bench<S> {
+    fn new(cli: Cli, storage: S) -> Self {
+        Self {
+            cli,
+            storage,
+            statistics: Arc::new(Statistics::default()),
+            db: None,
+        }
+    }
+
+    fn options(&self) -> Options<BytewiseComparator> {
+        let mut options = Options::<BytewiseComparator>::default();
+        options.write_buffer_size = self.cli.write_buffer_size;
+        options.max_file_size = self.cli.max_file_size;
+        if self.cli.cache_size > 0 {
+            options.block_cache = Some(Arc::new(ClockCache::new(self.cli.cache_size, 4096, false)));
+        }
+        if self.cli.bloom_bits > 0 {
+            options.filter_policy = Some(Arc::new(BloomFilter::new(self.cli.bloom_bits)));
+        }
+        options.statistics = Some(self.statistics.clone());
+        options
+    }
+
+    fn open(&mut self, fresh: bool) -> Result<()> {
+        let path: &Path = &self.cli.db;
+        if fresh {
+            match self.db.take() {
+                Some(mut db) => db.destroy()?,
+                None => {
+                    if self.storage.exists(path) {
+                        self.storage.remove_dir(path, true)?;
+                    }
+                }
+            }
+            // Only report the compactions of the last db
+            self.statistics = Arc::new(Statistics::default());
+        }
+        if self.db.is_none() {
+            self.db = Some(TemplateDB::open_db(
+                self.options(),
+                path,
+                self.storage.clone(),
+            )?);
+        }
+        Ok(())
+    }
+
+    // Runs `op` in `threads` threads and reports the merged measurement
+    fn run<F>(&self, name: &str, show_found: bool, op: F) -> Result<()>
+    where
+        F: Fn(&TemplateDB<S, BytewiseComparator>, usize, &mut ThreadStats) -> Result<()> + Sync,
+    {
+        let db = self.db.as_ref().unwrap();
+        let results: Vec<Result<ThreadStats>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..self.cli.threads)
+                .map(|id| {
+                    let op = &op;
+                    s.spawn(move || {
+                        let mut stats = ThreadStats::default();
+                        let start = Instant::now();
+                        op(db, id, &mut stats)?;
+                        stats.elapsed = start.elapsed();
+                        Ok(stats)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| {
+                    h.join()
+                        .unwrap_or_else(|_| Err("benchmark thread panicked".into()))
+                })
+                .collect()
+        });
+        let mut total = ThreadStats::default();
+        for res in results {
+            total.merge(res?);
+        }
+        total.report(name, show_found);
+        Ok(())
+    }
+
+    fn rng(&self, id: usize) -> StdRng {
+        StdRng::seed_from_u64(self.cli.seed + id as u64)
+    }
+
+    fn write_options(&self) -> WriteOptions {
+        WriteOptions {
+            sync: self.cli.sync,
+            ..Default::default()
+        }
+    }
+
+    // Writes `num / threads` entries per thread, with sequential keys partitioned by the
+    // threads if `seq` or random keys otherwise
+    fn fill(&self, name: &str, seq: bool) -> Result<()> {
+        let per_thread = self.cli.num / self.cli.threads;
+        let batch_size = self.cli.batch_size.max(1);
+        self.run(name, false, |db, id, stats| {
+            let mut rng = self.rng(id);
+            let mut values = ValueGenerator::new(&mut rng, self.cli.compression_ratio);
+            let mut i = 0;
+            while i < per_thread {
+                let mut batch = WriteBatch::default();
+                let n = batch_size.min(per_thread - i);
+                for j in 0..n {
+                    let k = if seq {
+                        id * per_thread + i + j
+                    } else {
+                        rng.gen_range(0, self.cli.num)
+                    };
+                    let k = key(k);
+                    let value = values.generate(self.cli.value_size);
+                    stats.bytes += k.len() + value.len();
+                    batch.put(k.as_bytes(), value);
+                }
+                stats.record(|| db.write(self.write_options(), batch))?;
+                stats.ops += n;
+                i += n;
+            }
+            Ok(())
+        })
+    }
+
+    fn read_random(
+        db: &TemplateDB<S, BytewiseComparator>,
+        rng: &mut StdRng,
+        num: usize,
+        stats: &mut ThreadStats,
+    ) -> Result<()> {
+        let k = key(rng.gen_range(0, num));
+        if let Some(value) = stats.record(|| db.get(ReadOptions::default(), k.as_bytes()))? {
+            stats.found += 1;
+            stats.bytes += k.len() + value.len();
+        }
+        stats.ops += 1;
+        Ok(())
+    }
+
+    fn reads_per_thread(&self) -> usize {
+        self.cli.reads.unwrap_or(self.cli.num) / self.cli.threads
+    }
+
+    fn run_benchmark(&mut self, name: &str) -> Result<()> {
+        match name {
+            "fillseq" => {
+                self.open(true)?;
+                self.fill(name, true)
+            }
+            "fillrandom" => {
+                self.open(true)?;
+                self.fill(name, false)
+            }
+            "overwrite" => {
+                self.open(false)?;
+                self.fill(name, false)
+            }
+            "readrandom" => {
+                self.open(false)?;
+                let reads = self.reads_per_thread();
+                self.run(name, true, |db, id, stats| {
+                    let mut rng = self.rng(id);
+                    for _ in 0..reads {
+                        Self::read_random(db, &mut rng, self.cli.num, stats)?;
+                    }
+                    Ok(())
+                })
+            }
+            "readseq" => {
+                self.open(false)?;
+                let reads = self.reads_per_thread();
+                self.run(name, false, |db, _, stats| {
+                    let mut iter = db.iter(ReadOptions::default())?;
+                    stats.record(|| iter.seek_to_first());
+                    while iter.valid() && stats.ops < reads {
+                        stats.bytes += iter.key().len() + iter.value().len();
+                        stats.ops += 1;
+                        stats.record(|| iter.next());
+                    }
+                    Ok(iter.status()?)
+                })
+            }
+            "seekrandom" => {
+                self.open(false)?;
+                let reads = self.reads_per_thread();
+                self.run(name, true, |db, id, stats| {
+                    let mut rng = self.rng(id);
+                    let mut iter = db.iter(ReadOptions::default())?;
+                    for _ in 0..reads {
+                        let k = key(rng.gen_range(0, self.cli.num));
+                        stats.record(|| iter.seek(k.as_bytes()));
+                        if iter.valid() && iter.key() == k.as_bytes() {
+                            stats.found += 1;
+                        }
+                        stats.ops += 1;
+                    }
+                    Ok(iter.status()?)
+                })
+            }
+            "readwhilewriting" => {
+                self.open(false)?;
+                let reads = self.reads_per_thread();
+                let readers_done = AtomicBool::new(false);
+                let db = self.db.as_ref().unwrap();
+                thread::scope(|s| {
+                    // The writer keeps overwriting random keys until all the readers finish
+                    let writer = s.spawn(|| -> Result<()> {
+                        let mut rng = self.rng(self.cli.threads);
+                        let mut values = ValueGenerator::new(&mut rng, self.cli.compression_ratio);
+                        while !readers_done.load(Ordering::Acquire) {
+                            let k = key(rng.gen_range(0, self.cli.num));
+                            let value = values.generate(self.cli.value_size);
+                            db.put(self.write_options(), k.as_bytes(), value)?;
+                        }
+                        Ok(())
+                    });
+                    let res = self.run(name, true, |db, id, stats| {
+                        let mut rng = self.rng(id);
+                        for _ in 0..reads {
+                            Self::read_random(db, &mut rng, self.cli.num, stats)?;
+                        }
+                        Ok(())
+                    });
+                    readers_done.store(true, Ordering::Release);
+                    let written = writer
+                        .join()
+                        .unwrap_or_else(|_| Err("writer thread panicked".into()));
+                    res.and(written)
+                })
+            }
+            _ => Err(format!("unknown benchmark '{}'", name).into()),
+        }
+    }
+
+    fn report_compactions(&self) {
+        println!("\nCompactions:");
+        print!("{}", self.statistics.report());
+        if let Some(db) = &self.db {
+            println!("Level  Files  Size(MB)");
+            for (level, (files, bytes)) in db.level_file_stats().into_iter().enumerate() {
+                println!(
+                    "{:>5}  {:>5}  {:>8.1}",
+                    level,
+                    files,
+                    bytes as f64 / 1048576.0
+                );
+            }
+        }
+    }
+
+    fn run_all(mut self) -> Result<()> {
+        let benchmarks = self.cli.benchmarks.clone();
+        println!(
+            "Keys: 16 bytes each; Values: {} bytes each; Entries: {}; Threads: {}",
+            self.cli.value_size, self.cli.num, self.cli.threads
+        );
+        for name in benchmarks
+            .split(',')
+            .map(str::trim)
+            .filter(|b| !b.is_empty())
+        {
+            self.run_benchmark(name)?;
+        }
+        self.report_compactions();
+        if let Some(mut db) = self.db.take() {
+            db.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn main() {
+    let mut cli = Cli::parse();
+    cli.threads = cli.threads.max(1);
+    let res = match cli.storage {
+        StorageKind::Mem => Bench::new(cli, MemStorage::default()).run_all(),
+        StorageKind::File => Bench::new(cli, FileStorage).run_all(),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
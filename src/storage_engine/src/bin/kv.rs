@@ -0,0 +1,69 @@
+use std::{path::PathBuf, process, sync::Arc, time::Duration};
+
+use actix::{Actor, System};
+use clap::Parser;
+use storage_engine::{
+    db_impl::template_impl::TemplateDB,
+    db_trait::DB,
+    metrics::MetricsRegistry,
+    options::Options,
+    servers::{admin_server::AdminServer, kv_server::KvServer, metrics_server::MetricsServer},
+    services::{admin_service::AdminServiceHandler, kv_service::KvServiceHandler},
+    storage::file::FileStorage,
+    util::comparator::BytewiseComparator,
+};
+
+/// Serves a db directory over gRPC with `KvService`
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// The db directory, created if missing
+    #[arg(long)]
+    db: PathBuf,
+    /// The address to listen on
+    #[arg(long, default_value = "[::1]:50052")]
+    addr: String,
+    /// The address to serve `AdminService` on. The service is off if omitted.
+    #[arg(long)]
+    admin_addr: Option<String>,
+    /// The address to serve the Prometheus metrics of the db on. The endpoint is off if omitted.
+    #[arg(long)]
+    metrics_addr: Option<String>,
+    /// The seconds a snapshot acquired by a client lives without being used
+    #[arg(long, default_value_t = 600)]
+    snapshot_ttl_secs: u64,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let options = Options::<BytewiseComparator>::default();
+    let mut db = match TemplateDB::open_db(options, &cli.db, FileStorage) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let system = System::new();
+    let handler = KvServiceHandler::new(db.clone())
+        .with_snapshot_ttl(Duration::from_secs(cli.snapshot_ttl_secs));
+    let registry = Arc::new(MetricsRegistry::default());
+    system.block_on(async {
+        KvServer::new(handler, cli.addr)
+            .with_metrics(registry.clone())
+            .start();
+        if let Some(admin_addr) = cli.admin_addr {
+            AdminServer::new(AdminServiceHandler::new(db.clone()), admin_addr).start();
+        }
+        if let Some(metrics_addr) = cli.metrics_addr {
+            registry.register_db(db.clone());
+            MetricsServer::new(registry, metrics_addr).start();
+        }
+        // Serve until interrupted
+        let _ = tokio::signal::ctrl_c().await;
+    });
+    if let Err(e) = db.close() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::{Parser, Subcommand};
+use storage_engine::{
+    db_impl::template_impl::TemplateDB,
+    db_trait::DB,
+    iterator::Iterator,
+    options::{Options, ReadOptions, WriteOptions},
+    storage::file::FileStorage,
+    tools::{dump_manifest, dump_table, dump_wal, escape, TableDumpOptions},
+    util::comparator::{BytewiseComparator, Comparator},
+};
+
+/// Inspects the files of a db offline and runs simple commands against a db directory
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Dump the properties, the index and the entries of an sstable
+    Sst {
+        file: PathBuf,
+        /// Print the metaindex entries and the statistics of the table
+        #[arg(long)]
+        properties: bool,
+        /// Print the handle of every data block
+        #[arg(long)]
+        index: bool,
+        /// Print every key/value entry. This is the default if nothing else is chosen.
+        #[arg(long)]
+        entries: bool,
+        /// Verify the checksum of every data block
+        #[arg(long)]
+        verify_checksums: bool,
+    },
+    /// Dump the `WriteBatch` records of a WAL file
+    Wal { file: PathBuf },
+    /// Dump the `VersionEdit` records of a MANIFEST file
+    Manifest { file: PathBuf },
+    /// Print the value of a key
+    Get {
+        #[arg(long)]
+        db: PathBuf,
+        key: String,
+    },
+    /// Print the key/value pairs in `[from, to)`
+    Scan {
+        #[arg(long)]
+        db: PathBuf,
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        /// The max number of pairs to print
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Set the value of a key
+    Put {
+        #[arg(long)]
+        db: PathBuf,
+        key: String,
+        value: String,
+    },
+    /// Delete a key
+    Delete {
+        #[arg(long)]
+        db: PathBuf,
+        key: String,
+    },
+    /// Compact the key range `[from, to]`, or the whole db if the bounds are omitted
+    Compact {
+        #[arg(long)]
+        db: PathBuf,
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+    },
+}
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+type Db = TemplateDB<FileStorage, BytewiseComparator>;
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// Runs `f` against the db at `path` and closes the db whatever `f` returns
+fn with_db<T, F>(path: &Path, f: F) -> Result<T>
+where
+    F: FnOnce(&Db) -> Result<T>,
+{
+    let mut options = Options::<BytewiseComparator>::default();
+    // The tool never creates a db by accident
+    options.create_if_missing = false;
+    let mut db = TemplateDB::open_db(options, path, FileStorage)?;
+    let res = f(&db);
+    db.close()?;
+    res
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Sst {
+            file,
+            properties,
+            index,
+            entries,
+            verify_checksums,
+        } => {
+            let options = TableDumpOptions {
+                properties,
+                index,
+                entries: entries || !(properties || index),
+                verify_checksums,
+            };
+            dump_table(
+                &FileStorage,
+                &path_str(&file),
+                options,
+                &mut io::stdout().lock(),
+            )?;
+        }
+        Command::Wal { file } => {
+            dump_wal(&FileStorage, &path_str(&file), &mut io::stdout().lock())?
+        }
+        Command::Manifest { file } => {
+            dump_manifest(&FileStorage, &path_str(&file), &mut io::stdout().lock())?
+        }
+        Command::Get { db, key } => with_db(&db, |db| {
+            match db.get(ReadOptions::default(), key.as_bytes())? {
+                Some(value) => println!("{}", escape(&value)),
+                None => println!("(not found)"),
+            }
+            Ok(())
+        })?,
+        Command::Scan {
+            db,
+            from,
+            to,
+            limit,
+        } => with_db(&db, |db| {
+            let cmp = BytewiseComparator::default();
+            let mut iter = db.iter(ReadOptions::default())?;
+            match &from {
+                Some(from) => iter.seek(from.as_bytes()),
+                None => iter.seek_to_first(),
+            }
+            let mut count = 0;
+            while iter.valid() && count < limit.unwrap_or(usize::MAX) {
+                if let Some(to) = &to {
+                    if cmp.compare(iter.key(), to.as_bytes()).is_ge() {
+                        break;
+                    }
+                }
+                println!("{} => {}", escape(iter.key()), escape(iter.value()));
+                count += 1;
+                iter.next();
+            }
+            Ok(iter.status()?)
+        })?,
+        Command::Put { db, key, value } => with_db(&db, |db| {
+            Ok(db.put(WriteOptions::default(), key.as_bytes(), value.as_bytes())?)
+        })?,
+        Command::Delete { db, key } => with_db(&db, |db| {
+            Ok(db.delete(WriteOptions::default(), key.as_bytes())?)
+        })?,
+        Command::Compact { db, from, to } => with_db(&db, |db| {
+            Ok(db.compact_range(
+                from.as_ref().map(|s| s.as_bytes()),
+                to.as_ref().map(|s| s.as_bytes()),
+            )?)
+        })?,
+    }
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli.command) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
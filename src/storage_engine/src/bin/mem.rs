@@ -1,19 +1,35 @@
 use actix::{Actor, System};
+use clap::Parser;
 use storage_engine::{
     memtable::{key_format::InternalKeyComparator, memtable::MemTable},
-    servers::memtable_server::MemTableServer,
+    servers::{memtable_server::MemTableServer, metrics_server::MetricsServer},
     services::memtable_service::MemtableServiceHandler,
     util::comparator::BytewiseComparator,
 };
 
+/// Serves a memtable per tenant over gRPC with `MemtableService`
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// The address to listen on
+    #[arg(long, default_value = "[::1]:50051")]
+    addr: String,
+    /// The address to serve the Prometheus metrics on. The endpoint is off if omitted.
+    #[arg(long)]
+    metrics_addr: Option<String>,
+}
+
 fn main() {
+    let cli = Cli::parse();
     let system = System::new();
     system.block_on(async {
-        let add = "[::1]:50051".to_string();
         let icmp = InternalKeyComparator::new(BytewiseComparator::default());
         let mem = MemTable::new(1 << 32, icmp);
         let memtable_handler = MemtableServiceHandler::new_with_memtable(mem);
-        let memtable_server = MemTableServer::new(memtable_handler, add);
+        if let Some(metrics_addr) = cli.metrics_addr {
+            MetricsServer::new(memtable_handler.metrics().clone(), metrics_addr).start();
+        }
+        let memtable_server = MemTableServer::new(memtable_handler, cli.addr);
         memtable_server.start();
         futures::future::pending::<()>().await;
     });
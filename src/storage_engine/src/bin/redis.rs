@@ -0,0 +1,45 @@
+use std::{path::PathBuf, process};
+
+use actix::{Actor, System};
+use clap::Parser;
+use storage_engine::{
+    db_impl::template_impl::TemplateDB, db_trait::DB, options::Options,
+    servers::redis_server::RedisServer, services::redis_service::RedisHandler,
+    storage::file::FileStorage, util::comparator::BytewiseComparator,
+};
+
+/// Serves a db directory over the Redis protocol, so that `redis-cli` and the redis
+/// clients can talk to it
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// The db directory, created if missing
+    #[arg(long)]
+    db: PathBuf,
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:6380")]
+    addr: String,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let options = Options::<BytewiseComparator>::default();
+    let mut db = match TemplateDB::open_db(options, &cli.db, FileStorage) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let system = System::new();
+    let handler = RedisHandler::new(db.clone());
+    system.block_on(async {
+        RedisServer::new(handler, cli.addr).start();
+        // Serve until interrupted
+        let _ = tokio::signal::ctrl_c().await;
+    });
+    if let Err(e) = db.close() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    cell::UnsafeCell,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+
+use super::{CachePriority, CacheSync};
+use crate::error::{TemplateKVError, TemplateResult};
+
+// The layout of `Slot::meta`:
+//
+// +---------------+---------------+-------------------+----------------+-------------+
+// | state (63-62) | priority (61) | countdown (60-59) | unused (58-32) | refs (31-0) |
+// +---------------+---------------+-------------------+----------------+-------------+
+const STATE_SHIFT: u64 = 62;
+const PRIORITY_BIT: u64 = 1 << 61;
+const COUNTDOWN_SHIFT: u64 = 59;
+const COUNTDOWN_MASK: u64 = 0b11 << COUNTDOWN_SHIFT;
+const REFS_MASK: u64 = (1 << 32) - 1;
+
+// The slot holds nothing
+const STATE_EMPTY: u64 = 0;
+// The slot is exclusively owned by the thread filling or freeing it
+const STATE_CONSTRUCTION: u64 = 1;
+// The entry can be found by lookups
+const STATE_VISIBLE: u64 = 2;
+// The entry has been erased or replaced and is freed once the last reference is dropped
+const STATE_INVISIBLE: u64 = 3;
+
+// An entry is evicted only if the clock hand meets it with a zero countdown, and every pass
+// of the hand decreases the countdown by one. High priority entries start with more passes
+// and get more back on being hit.
+const HIGH_PRIORITY_INSERT_COUNTDOWN: u64 = 2;
+const HIGH_PRIORITY_HIT_COUNTDOWN: u64 = 3;
+const LOW_PRIORITY_INSERT_COUNTDOWN: u64 = 0;
+const LOW_PRIORITY_HIT_COUNTDOWN: u64 = 1;
+
+const MIN_SLOTS: usize = 16;
+
+#[inline]
+fn state(meta: u64) -> u64 {
+    meta >> STATE_SHIFT
+}
+
+#[inline]
+fn refs(meta: u64) -> u64 {
+    meta & REFS_MASK
+}
+
+#[inline]
+fn countdown(meta: u64) -> u64 {
+    (meta & COUNTDOWN_MASK) >> COUNTDOWN_SHIFT
+}
+
+struct Slot<K, V> {
+    meta: AtomicU64,
+    // The number of the entries whose probing sequences pass through this slot.
+    // A lookup stops at a slot without displacements.
+    displacements: AtomicU32,
+    // The fields below are only written in `STATE_CONSTRUCTION` and only read with a reference
+    // acquired in `STATE_VISIBLE` or `STATE_INVISIBLE`
+    hash: UnsafeCell<u64>,
+    charge: UnsafeCell<usize>,
+    entry: UnsafeCell<MaybeUninit<(K, V)>>,
+}
+
+/// A lock-free cache using the CLOCK eviction algorithm, in the style of the HyperClockCache of
+/// RocksDB.
+///
+/// The entries live in a fixed-size open addressing hash table. Every slot is controlled by an
+/// atomic word holding its state, a reference count and a clock countdown, so lookups, inserts
+/// and evictions never take a lock. A lookup only bumps the countdown of the entry hit instead
+/// of reordering a list like `LRUCache` does.
+///
+/// Entries inserted with `CachePriority::High` survive more passes of the clock hand than the
+/// `CachePriority::Low` ones.
+pub struct ClockCache<K, V> {
+    slots: Box<[Slot<K, V>]>,
+    // slots.len() - 1
+    mask: usize,
+    capacity: usize,
+    // Fails the inserts instead of exceeding the capacity
+    strict_capacity: bool,
+    // The max number of entries the table holds
+    occupancy_limit: usize,
+    usage: AtomicUsize,
+    occupancy: AtomicUsize,
+    // The clock hand
+    clock: AtomicUsize,
+}
+
+impl<K: Hash + Eq, V: Clone> ClockCache<K, V> {
+    /// Creates a `ClockCache` holding entries up to `capacity` in total charge.
+    ///
+    /// The hash table is sized by `estimated_entry_charge` and never grows, so an estimation
+    /// much bigger than the actual charges leaves the cache underused. If `strict_capacity`
+    /// is true, an insert fails with `TemplateKVError::MemoryLimit` instead of exceeding the
+    /// capacity when not enough entries could be evicted.
+    pub fn new(capacity: usize, estimated_entry_charge: usize, strict_capacity: bool) -> Self {
+        let entries = capacity / estimated_entry_charge.max(1);
+        // Keep the load factor about 0.7
+        let n = (entries * 10 / 7).max(MIN_SLOTS).next_power_of_two();
+        let slots = (0..n)
+            .map(|_| Slot {
+                meta: AtomicU64::new(0),
+                displacements: AtomicU32::new(0),
+                hash: UnsafeCell::new(0),
+                charge: UnsafeCell::new(0),
+                entry: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Self {
+            slots,
+            mask: n - 1,
+            capacity,
+            strict_capacity,
+            occupancy_limit: n * 7 / 8,
+            usage: AtomicUsize::new(0),
+            occupancy: AtomicUsize::new(0),
+            clock: AtomicUsize::new(0),
+        }
+    }
+
+    fn hash_of(key: &K) -> u64 {
+        let mut s = DefaultHasher::new();
+        key.hash(&mut s);
+        s.finish()
+    }
+
+    // Returns the probing sequence of `hash`, which visits every slot once
+    fn probe(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let mask = self.mask;
+        let home = hash as usize & mask;
+        // An odd step walks through all the slots since the table size is a power of two
+        let step = ((hash >> 32) as usize | 1) & mask;
+        (0..=mask).map(move |i| home.wrapping_add(i.wrapping_mul(step)) & mask)
+    }
+
+    // Finds the entries of `key` and calls `f` with the index of every matched slot while
+    // holding a reference. Stops once `f` returns false.
+    fn lookup(&self, key: &K, hash: u64, mut f: impl FnMut(usize) -> bool) {
+        for i in self.probe(hash) {
+            let slot = &self.slots[i];
+            if state(slot.meta.load(Ordering::Acquire)) == STATE_VISIBLE {
+                let old = slot.meta.fetch_add(1, Ordering::Acquire);
+                // SAFETY: the entry is initialized and can not be freed while we hold a
+                // reference acquired in `STATE_VISIBLE`
+                let matched = state(old) == STATE_VISIBLE
+                    && unsafe {
+                        *slot.hash.get() == hash && (*slot.entry.get()).assume_init_ref().0 == *key
+                    };
+                let more = !matched || f(i);
+                self.release(i);
+                if !more {
+                    return;
+                }
+            }
+            if slot.displacements.load(Ordering::Acquire) == 0 {
+                return;
+            }
+        }
+    }
+
+    // Drops a reference to the slot `i`. Frees the entry if it is the last reference to an
+    // erased entry.
+    fn release(&self, i: usize) {
+        let old = self.slots[i].meta.fetch_sub(1, Ordering::Release);
+        if state(old) == STATE_INVISIBLE && refs(old) == 1 {
+            self.try_free(i, old - 1);
+        }
+    }
+
+    // Frees the entry in the slot `i` if its meta is still `expected`.
+    // Returns the freed charge or `None` if the slot is not taken.
+    fn try_free(&self, i: usize, expected: u64) -> Option<usize> {
+        let slot = &self.slots[i];
+        slot.meta
+            .compare_exchange(
+                expected,
+                STATE_CONSTRUCTION << STATE_SHIFT,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        // SAFETY: the slot is exclusively owned in `STATE_CONSTRUCTION`
+        let (hash, charge) = unsafe {
+            ptr::drop_in_place((*slot.entry.get()).as_mut_ptr());
+            (*slot.hash.get(), *slot.charge.get())
+        };
+        for j in self.probe(hash) {
+            if j == i {
+                break;
+            }
+            self.slots[j].displacements.fetch_sub(1, Ordering::Release);
+        }
+        self.usage.fetch_sub(charge, Ordering::AcqRel);
+        self.occupancy.fetch_sub(1, Ordering::AcqRel);
+        // Keep the references of the lookups racing with us
+        slot.meta
+            .fetch_sub(STATE_CONSTRUCTION << STATE_SHIFT, Ordering::Release);
+        Some(charge)
+    }
+
+    // Moves the clock hand until both `charge` and `entries` are freed, or every entry has had
+    // its countdown exhausted. Returns true if enough is freed.
+    fn evict(&self, charge: usize, entries: usize) -> bool {
+        let (mut freed_charge, mut freed_entries) = (0, 0);
+        let max_steps = self.slots.len() * (HIGH_PRIORITY_HIT_COUNTDOWN as usize + 1);
+        for _ in 0..max_steps {
+            if freed_charge >= charge && freed_entries >= entries {
+                return true;
+            }
+            let i = self.clock.fetch_add(1, Ordering::Relaxed) & self.mask;
+            let slot = &self.slots[i];
+            let meta = slot.meta.load(Ordering::Acquire);
+            let s = state(meta);
+            if (s != STATE_VISIBLE && s != STATE_INVISIBLE) || refs(meta) > 0 {
+                continue;
+            }
+            if s == STATE_VISIBLE && countdown(meta) > 0 {
+                let _ = slot.meta.compare_exchange(
+                    meta,
+                    meta - (1 << COUNTDOWN_SHIFT),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            } else if let Some(c) = self.try_free(i, meta) {
+                freed_charge += c;
+                freed_entries += 1;
+            }
+        }
+        freed_charge >= charge && freed_entries >= entries
+    }
+
+    // Marks all the entries of `key` invisible and returns the value of the first one
+    fn erase_entry(&self, key: &K, hash: u64) -> Option<V> {
+        let mut erased = None;
+        self.lookup(key, hash, |i| {
+            let slot = &self.slots[i];
+            if erased.is_none() {
+                // SAFETY: a reference is held
+                erased = Some(unsafe { (*slot.entry.get()).assume_init_ref().1.clone() });
+            }
+            // STATE_VISIBLE | 1 == STATE_INVISIBLE
+            slot.meta.fetch_or(1 << STATE_SHIFT, Ordering::AcqRel);
+            true
+        });
+        erased
+    }
+
+    // Takes back the charge and the occupancy reserved by a failed insert
+    fn insert_failed(&self, charge: usize, old: Option<V>) -> TemplateResult<Option<V>> {
+        self.usage.fetch_sub(charge, Ordering::AcqRel);
+        self.occupancy.fetch_sub(1, Ordering::AcqRel);
+        if self.strict_capacity {
+            Err(TemplateKVError::MemoryLimit(format!(
+                "clock cache is full: capacity {}, usage {}",
+                self.capacity,
+                self.usage.load(Ordering::Acquire)
+            )))
+        } else {
+            Ok(old)
+        }
+    }
+}
+
+impl<K, V> CacheSync<K, V> for ClockCache<K, V>
+where
+    K: Send + Sync + Hash + Eq,
+    V: Send + Sync + Clone,
+{
+    fn insert(&self, key: K, value: V, charge: usize) -> Option<V> {
+        self.insert_with_priority(key, value, charge, CachePriority::Low)
+            .unwrap_or_default()
+    }
+
+    fn insert_with_priority(
+        &self,
+        key: K,
+        value: V,
+        charge: usize,
+        priority: CachePriority,
+    ) -> TemplateResult<Option<V>> {
+        let hash = Self::hash_of(&key);
+        let old = self.erase_entry(&key, hash);
+        if charge > self.capacity {
+            // Never fits
+            self.usage.fetch_add(charge, Ordering::AcqRel);
+            self.occupancy.fetch_add(1, Ordering::AcqRel);
+            return self.insert_failed(charge, old);
+        }
+        let usage = self.usage.fetch_add(charge, Ordering::AcqRel) + charge;
+        let occupancy = self.occupancy.fetch_add(1, Ordering::AcqRel) + 1;
+        let need_charge = usage.saturating_sub(self.capacity);
+        let need_entries = occupancy.saturating_sub(self.occupancy_limit);
+        if (need_charge > 0 || need_entries > 0)
+            && !self.evict(need_charge, need_entries)
+            && (self.strict_capacity || need_entries > 0)
+        {
+            // Exceeding the capacity is tolerated unless in strict mode, but the table itself
+            // can not hold more entries
+            return self.insert_failed(charge, old);
+        }
+
+        let (insert_countdown, flags) = match priority {
+            CachePriority::High => (HIGH_PRIORITY_INSERT_COUNTDOWN, PRIORITY_BIT),
+            CachePriority::Low => (LOW_PRIORITY_INSERT_COUNTDOWN, 0),
+        };
+        let mut entry = Some((key, value));
+        let mut displaced = 0;
+        for i in self.probe(hash) {
+            let slot = &self.slots[i];
+            let taken = slot.meta.compare_exchange(
+                STATE_EMPTY,
+                STATE_CONSTRUCTION << STATE_SHIFT,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            );
+            if taken.is_err() {
+                slot.displacements.fetch_add(1, Ordering::Release);
+                displaced += 1;
+                continue;
+            }
+            // SAFETY: the slot is exclusively owned in `STATE_CONSTRUCTION`
+            unsafe {
+                *slot.hash.get() = hash;
+                *slot.charge.get() = charge;
+                (*slot.entry.get()).write(entry.take().unwrap());
+            }
+            // Only the references of the racing lookups could have changed
+            slot.meta.fetch_add(
+                ((STATE_VISIBLE - STATE_CONSTRUCTION) << STATE_SHIFT)
+                    | flags
+                    | (insert_countdown << COUNTDOWN_SHIFT),
+                Ordering::Release,
+            );
+            return Ok(old);
+        }
+        for i in self.probe(hash).take(displaced) {
+            self.slots[i].displacements.fetch_sub(1, Ordering::Release);
+        }
+        self.insert_failed(charge, old)
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let mut value = None;
+        self.lookup(key, Self::hash_of(key), |i| {
+            let slot = &self.slots[i];
+            // SAFETY: a reference is held
+            value = Some(unsafe { (*slot.entry.get()).assume_init_ref().1.clone() });
+            let _ = slot
+                .meta
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |m| {
+                    let hit_countdown = if m & PRIORITY_BIT != 0 {
+                        HIGH_PRIORITY_HIT_COUNTDOWN
+                    } else {
+                        LOW_PRIORITY_HIT_COUNTDOWN
+                    };
+                    if countdown(m) < hit_countdown {
+                        Some((m & !COUNTDOWN_MASK) | (hit_countdown << COUNTDOWN_SHIFT))
+                    } else {
+                        None
+                    }
+                });
+            false
+        });
+        value
+    }
+
+    fn erase(&self, key: &K) {
+        self.erase_entry(key, Self::hash_of(key));
+    }
+
+    #[inline]
+    fn total_charge(&self) -> usize {
+        self.usage.load(Ordering::Acquire)
+    }
+}
+
+impl<K, V> Drop for ClockCache<K, V> {
+    fn drop(&mut self) {
+        for slot in self.slots.iter_mut() {
+            let s = state(*slot.meta.get_mut());
+            if s == STATE_VISIBLE || s == STATE_INVISIBLE {
+                // SAFETY: the entry is initialized in these states
+                unsafe { ptr::drop_in_place(slot.entry.get_mut().as_mut_ptr()) }
+            }
+        }
+    }
+}
+
+unsafe impl<K: Send, V: Send> Send for ClockCache<K, V> {}
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for ClockCache<K, V> {}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    const CACHE_SIZE: usize = 100;
+
+    fn new_cache(cap: usize) -> ClockCache<u32, u32> {
+        ClockCache::new(cap, 1, false)
+    }
+
+    #[test]
+    fn test_hit_and_miss() {
+        let cache = new_cache(CACHE_SIZE);
+        assert_eq!(None, cache.get(&100));
+        assert_eq!(None, cache.insert(100, 101, 1));
+        assert_eq!(Some(101), cache.get(&100));
+        assert_eq!(None, cache.get(&200));
+
+        cache.insert(200, 201, 1);
+        assert_eq!(Some(101), cache.get(&100));
+        assert_eq!(Some(201), cache.get(&200));
+
+        assert_eq!(Some(101), cache.insert(100, 102, 1));
+        assert_eq!(Some(102), cache.get(&100));
+        assert_eq!(Some(201), cache.get(&200));
+        assert_eq!(cache.total_charge(), 2);
+    }
+
+    #[test]
+    fn test_erase() {
+        let cache = new_cache(CACHE_SIZE);
+        cache.erase(&200);
+        cache.insert(100, 101, 1);
+        cache.insert(200, 201, 1);
+        cache.erase(&100);
+        assert_eq!(None, cache.get(&100));
+        assert_eq!(Some(201), cache.get(&200));
+        assert_eq!(cache.total_charge(), 1);
+        cache.erase(&100);
+        assert_eq!(Some(201), cache.get(&200));
+    }
+
+    #[test]
+    fn test_erase_referenced_entry() {
+        let cache = new_cache(CACHE_SIZE);
+        cache.insert(100, 101, 1);
+        let hash = ClockCache::<u32, u32>::hash_of(&100);
+        cache.lookup(&100, hash, |i| {
+            // Still charged while referenced
+            cache.erase(&100);
+            assert_eq!(None, cache.get(&100));
+            assert_eq!(cache.total_charge(), 1);
+            assert_eq!(
+                state(cache.slots[i].meta.load(Ordering::Acquire)),
+                STATE_INVISIBLE
+            );
+            false
+        });
+        assert_eq!(cache.total_charge(), 0);
+        assert_eq!(cache.occupancy.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_eviction_keeps_hit_entries() {
+        let cache = new_cache(CACHE_SIZE);
+        cache.insert(100, 101, 1);
+        cache.insert(200, 201, 1);
+        for i in 0..(CACHE_SIZE * 3) as u32 {
+            cache.insert(1000 + i, 2000 + i, 1);
+            assert_eq!(Some(101), cache.get(&100));
+            assert!(cache.total_charge() <= CACHE_SIZE);
+        }
+        assert_eq!(Some(101), cache.get(&100));
+        assert_eq!(None, cache.get(&200));
+    }
+
+    #[test]
+    fn test_high_priority_entries_retained() {
+        let cache = new_cache(CACHE_SIZE);
+        for i in 0..10 {
+            cache
+                .insert_with_priority(i, i, 1, CachePriority::High)
+                .unwrap();
+        }
+        for i in 0..CACHE_SIZE as u32 {
+            cache
+                .insert_with_priority(1000 + i, i, 1, CachePriority::Low)
+                .unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(Some(i), cache.get(&i));
+        }
+        let low_retained = (0..CACHE_SIZE as u32)
+            .filter(|i| cache.get(&(1000 + i)).is_some())
+            .count();
+        assert_eq!(low_retained, CACHE_SIZE - 10);
+    }
+
+    #[test]
+    fn test_heavy_entries() {
+        let cache = new_cache(CACHE_SIZE);
+        let mut added = 0;
+        let mut index = 0;
+        while added < 2 * CACHE_SIZE {
+            let weight = if index & 1 == 0 { 1 } else { 10 };
+            cache.insert(index, 1000 + index, weight);
+            added += weight;
+            index += 1;
+        }
+        assert!(cache.total_charge() <= CACHE_SIZE);
+        for i in 0..index {
+            if let Some(val) = cache.get(&i) {
+                assert_eq!(1000 + i, val);
+            }
+        }
+    }
+
+    #[test]
+    fn test_strict_capacity() {
+        let cache = ClockCache::<u32, u32>::new(10, 1, true);
+        assert!(matches!(
+            cache.insert_with_priority(1, 1, 11, CachePriority::Low),
+            Err(TemplateKVError::MemoryLimit(_))
+        ));
+        for i in 0..10 {
+            cache
+                .insert_with_priority(i, i, 1, CachePriority::Low)
+                .unwrap();
+        }
+        // All the entries are in use so nothing could be evicted
+        let mut referenced = vec![];
+        for i in 0..10 {
+            cache.lookup(&i, ClockCache::<u32, u32>::hash_of(&i), |slot| {
+                cache.slots[slot].meta.fetch_add(1, Ordering::Acquire);
+                referenced.push(slot);
+                false
+            });
+        }
+        assert!(cache
+            .insert_with_priority(10, 10, 1, CachePriority::Low)
+            .is_err());
+        assert_eq!(cache.total_charge(), 10);
+        for slot in referenced {
+            cache.release(slot);
+        }
+        cache
+            .insert_with_priority(10, 10, 1, CachePriority::Low)
+            .unwrap();
+        assert_eq!(cache.total_charge(), 10);
+        assert_eq!(Some(10), cache.get(&10));
+
+        // An entry larger than the capacity is silently dropped in the non-strict mode
+        let cache = new_cache(10);
+        assert_eq!(None, cache.insert(1, 1, 11));
+        assert_eq!(None, cache.get(&1));
+    }
+
+    #[test]
+    fn test_zero_size_cache() {
+        let cache = new_cache(0);
+        cache.insert(100, 101, 1);
+        assert_eq!(None, cache.get(&100));
+    }
+
+    #[test]
+    fn test_concurrent_insert_and_get() {
+        let cache = Arc::new(ClockCache::<u32, Arc<u32>>::new(1000, 1, false));
+        let mut hs = vec![];
+        for t in 0..4u32 {
+            let cache = cache.clone();
+            hs.push(thread::spawn(move || {
+                for i in 0..10000u32 {
+                    let k = (i * 7 + t) % 3000;
+                    match cache.get(&k) {
+                        Some(v) => assert_eq!(*v, k),
+                        None => {
+                            cache.insert(k, Arc::new(k), 1);
+                        }
+                    }
+                    if i % 100 == 0 {
+                        cache.erase(&((i + t) % 3000));
+                    }
+                }
+            }));
+        }
+        for h in hs {
+            h.join().unwrap();
+        }
+        assert!(cache.total_charge() <= 1000);
+        assert_eq!(
+            cache.total_charge(),
+            cache.occupancy.load(Ordering::Acquire)
+        );
+    }
+}
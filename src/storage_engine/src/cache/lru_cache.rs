@@ -9,7 +9,11 @@ use std::{
     },
 };
 
-use crate::{cache::CacheSync, util::collection::HashMap};
+use crate::{
+    cache::{CachePriority, CacheSync},
+    error::TemplateResult,
+    util::collection::HashMap,
+};
 
 #[derive(Copy, Clone)]
 struct Key<K> {
@@ -42,14 +46,16 @@ struct LRUEntry<K, V> {
     prev: *mut LRUEntry<K, V>,
     next: *mut LRUEntry<K, V>,
     charge: usize,
+    priority: CachePriority,
 }
 
 impl<K, V> LRUEntry<K, V> {
-    fn new(key: K, value: V, charge: usize) -> Self {
+    fn new(key: K, value: V, charge: usize, priority: CachePriority) -> Self {
         LRUEntry {
             key: MaybeUninit::new(key),
             value: MaybeUninit::new(value),
             charge,
+            priority,
             next: ptr::null_mut(),
             prev: ptr::null_mut(),
         }
@@ -59,6 +65,7 @@ impl<K, V> LRUEntry<K, V> {
             key: MaybeUninit::uninit(),
             value: MaybeUninit::uninit(),
             charge: 0,
+            priority: CachePriority::Low,
             next: ptr::null_mut(),
             prev: ptr::null_mut(),
         }
@@ -67,7 +74,15 @@ impl<K, V> LRUEntry<K, V> {
 
 pub type EvictHook<K, V> = Option<Box<dyn Fn(&K, &V)>>;
 
-/// LRU cache implementation
+/// Called with the entry evicted from a full `LRUCache` to make room for a new one and the
+/// priority it was inserted with
+pub type EvictListener<K, V> = Box<dyn Fn(&K, &V, CachePriority) + Send + Sync>;
+
+/// LRU cache implementation.
+///
+/// The `CachePriority::High` entries and the `CachePriority::Low` ones are kept in two LRU
+/// lists, and a full cache always evicts the least recently used `Low` entry first. A `High`
+/// entry is only evicted when there is no `Low` entry left.
 pub struct LRUCache<K, V: Clone> {
     // The capacity of LRU
     capacity: usize,
@@ -76,13 +91,17 @@ pub struct LRUCache<K, V: Clone> {
     usage: Arc<AtomicUsize>,
     // Only for tests
     evict_hook: EvictHook<K, V>,
+    evict_listener: Option<EvictListener<K, V>>,
 }
 
 struct LRUInner<K, V> {
     table: HashMap<Key<K>, Box<LRUEntry<K, V>>>,
-    // head.next is the newest entry
+    // The list of the `Low` entries. head.next is the newest entry
     head: *mut LRUEntry<K, V>,
     tail: *mut LRUEntry<K, V>,
+    // The list of the `High` entries
+    high_head: *mut LRUEntry<K, V>,
+    high_tail: *mut LRUEntry<K, V>,
 }
 
 impl<K, V> LRUInner<K, V> {
@@ -93,44 +112,86 @@ impl<K, V> LRUInner<K, V> {
         }
     }
 
+    // Attaches the entry to the head of the list of its priority
     fn attach(&mut self, n: *mut LRUEntry<K, V>) {
+        let head = match unsafe { (*n).priority } {
+            CachePriority::High => self.high_head,
+            CachePriority::Low => self.head,
+        };
         unsafe {
-            (*n).next = (*self.head).next;
-            (*n).prev = self.head;
-            (*self.head).next = n;
+            (*n).next = (*head).next;
+            (*n).prev = head;
+            (*head).next = n;
             (*(*n).next).prev = n;
         }
     }
+
+    // Returns the entry to evict: the oldest `Low` entry, or the oldest `High` one if there
+    // is no `Low` entry
+    fn victim(&self) -> *mut LRUEntry<K, V> {
+        unsafe {
+            if (*self.tail).prev != self.head {
+                (*self.tail).prev
+            } else {
+                (*self.high_tail).prev
+            }
+        }
+    }
+}
+
+// Returns a pair of linked sentinels of an empty list
+fn new_list<K, V>() -> (*mut LRUEntry<K, V>, *mut LRUEntry<K, V>) {
+    let head = Box::into_raw(Box::new(LRUEntry::new_empty()));
+    let tail = Box::into_raw(Box::new(LRUEntry::new_empty()));
+    unsafe {
+        (*head).next = tail;
+        (*tail).prev = head;
+    }
+    (head, tail)
 }
 
 impl<K: Hash + Eq, V: Clone> LRUCache<K, V> {
     pub fn new(cap: usize) -> Self {
+        let (head, tail) = new_list();
+        let (high_head, high_tail) = new_list();
         let l = LRUInner {
             table: HashMap::default(),
-            head: Box::into_raw(Box::new(LRUEntry::new_empty())),
-            tail: Box::into_raw(Box::new(LRUEntry::new_empty())),
+            head,
+            tail,
+            high_head,
+            high_tail,
         };
 
-        unsafe {
-            (*l.head).next = l.tail;
-            (*l.tail).prev = l.head;
-        }
-
         LRUCache {
             usage: Arc::new(AtomicUsize::new(0)),
             capacity: cap,
             inner: Arc::new(Mutex::new(l)),
             evict_hook: None,
+            evict_listener: None,
         }
     }
+
+    /// Sets a listener to be notified of the entries evicted by capacity.
+    /// The entries replaced or erased explicitly are not reported.
+    #[must_use]
+    pub fn with_evict_listener(mut self, listener: EvictListener<K, V>) -> Self {
+        self.evict_listener = Some(listener);
+        self
+    }
 }
 
-impl<K, V> CacheSync<K, V> for LRUCache<K, V>
+impl<K, V> LRUCache<K, V>
 where
     K: Send + Sync + Hash + Eq + Debug,
     V: Send + Sync + Clone,
 {
-    fn insert(&self, key: K, mut value: V, charge: usize) -> Option<V> {
+    fn insert_entry(
+        &self,
+        key: K,
+        mut value: V,
+        charge: usize,
+        priority: CachePriority,
+    ) -> Option<V> {
         let mut l = self.inner.lock().unwrap();
         if self.capacity > 0 {
             match l.table.get_mut(&Key {
@@ -140,6 +201,10 @@ where
                     let old_p = h as *mut Box<LRUEntry<K, V>>;
                     unsafe { std::ptr::swap(&mut value, (*old_p).value.as_mut_ptr()) };
                     let p: *mut LRUEntry<K, V> = h.as_mut();
+                    self.usage.fetch_sub(h.charge, Ordering::Relaxed);
+                    self.usage.fetch_add(charge, Ordering::Relaxed);
+                    h.charge = charge;
+                    h.priority = priority;
                     l.detach(p);
                     l.attach(p);
                     if let Some(hk) = &self.evict_hook {
@@ -151,7 +216,7 @@ where
                     let mut node = {
                         if self.usage.load(Ordering::Acquire) >= self.capacity {
                             let prev_key = Key {
-                                k: unsafe { (*(*l.tail).prev).key.as_ptr() },
+                                k: unsafe { (*l.victim()).key.as_ptr() },
                             };
                             let mut n = l.table.remove(&prev_key).unwrap();
                             self.usage.fetch_sub(n.charge, Ordering::Relaxed);
@@ -160,16 +225,23 @@ where
                                     hk(&(*n.key.as_ptr()), &(*n.value.as_ptr()));
                                 }
                             }
+                            if let Some(listener) = &self.evict_listener {
+                                unsafe {
+                                    listener(&(*n.key.as_ptr()), &(*n.value.as_ptr()), n.priority);
+                                }
+                            }
                             unsafe {
                                 ptr::drop_in_place(n.key.as_mut_ptr());
                                 ptr::drop_in_place(n.value.as_mut_ptr());
                             }
                             n.key = MaybeUninit::new(key);
                             n.value = MaybeUninit::new(value);
+                            n.charge = charge;
+                            n.priority = priority;
                             l.detach(n.as_mut());
                             n
                         } else {
-                            Box::new(LRUEntry::new(key, value, charge))
+                            Box::new(LRUEntry::new(key, value, charge, priority))
                         }
                     };
                     self.usage.fetch_add(charge, Ordering::Relaxed);
@@ -187,6 +259,26 @@ where
             None
         }
     }
+}
+
+impl<K, V> CacheSync<K, V> for LRUCache<K, V>
+where
+    K: Send + Sync + Hash + Eq + Debug,
+    V: Send + Sync + Clone,
+{
+    fn insert(&self, key: K, value: V, charge: usize) -> Option<V> {
+        self.insert_entry(key, value, charge, CachePriority::Low)
+    }
+
+    fn insert_with_priority(
+        &self,
+        key: K,
+        value: V,
+        charge: usize,
+        priority: CachePriority,
+    ) -> TemplateResult<Option<V>> {
+        Ok(self.insert_entry(key, value, charge, priority))
+    }
 
     fn get(&self, key: &K) -> Option<V> {
         let k = Key { k: key as *const K };
@@ -231,6 +323,8 @@ impl<K, V: Clone> Drop for LRUCache<K, V> {
         unsafe {
             let _head = *Box::from_raw(l.head);
             let _tail = *Box::from_raw(l.tail);
+            let _high_head = *Box::from_raw(l.high_head);
+            let _high_tail = *Box::from_raw(l.high_tail);
         }
     }
 }
@@ -425,4 +519,56 @@ mod tests {
         cache.insert(100, 101);
         assert_eq!(None, cache.get(100));
     }
+
+    #[test]
+    fn test_evict_listener() {
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let cloned = evicted.clone();
+        let cache = LRUCache::<u32, u32>::new(2).with_evict_listener(Box::new(move |k, v, _| {
+            cloned.lock().unwrap().push((*k, *v));
+        }));
+        cache.insert(1, 101, 1);
+        cache.insert(2, 102, 1);
+        // Replacing and erasing are not evictions
+        cache.insert(2, 202, 1);
+        cache.erase(&2);
+        cache.insert(3, 103, 1);
+        assert!(evicted.lock().unwrap().is_empty());
+        cache.insert(4, 104, 1);
+        assert_eq!(*evicted.lock().unwrap(), vec![(1, 101)]);
+    }
+
+    #[test]
+    fn test_priority() {
+        let cache = LRUCache::<u32, u32>::new(4);
+        cache
+            .insert_with_priority(1, 101, 1, CachePriority::High)
+            .unwrap();
+        cache
+            .insert_with_priority(2, 102, 1, CachePriority::High)
+            .unwrap();
+        for i in 3..100 {
+            cache.insert(i, 100 + i, 1);
+        }
+        // The low priority entries are always evicted first
+        assert_eq!(Some(101), cache.get(&1));
+        assert_eq!(Some(102), cache.get(&2));
+        assert_eq!(Some(198), cache.get(&98));
+        assert_eq!(Some(199), cache.get(&99));
+        assert_eq!(None, cache.get(&97));
+        assert_eq!(4, cache.total_charge());
+
+        // The high priority entries are evicted in LRU order when there is nothing else
+        for i in 200..204 {
+            cache
+                .insert_with_priority(i, 100 + i, 1, CachePriority::High)
+                .unwrap();
+        }
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(None, cache.get(&2));
+        assert_eq!(None, cache.get(&99));
+        for i in 200..204 {
+            assert_eq!(Some(100 + i), cache.get(&i));
+        }
+    }
 }
@@ -1,9 +1,23 @@
+pub mod blocked_bloom_filter;
 pub mod bloom_filter_cache;
+pub mod clock_cache;
 pub mod lru_cache;
 pub mod new_lru_cache;
+pub mod ribbon_filter;
+pub mod secondary_cache;
 pub mod sharded_cache;
 pub mod table_cache;
 
+use crate::error::TemplateResult;
+
+/// The priority of a cache entry. `LRUCache`, `ClockCache` and the caches built on them
+/// (`ShardedCache`, `TieredCache`) retain the `High` entries preferentially over the `Low` ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePriority {
+    High,
+    Low,
+}
+
 pub trait CacheSync<K, V>: Sync + Send
 where
     K: Sync + Send,
@@ -13,6 +27,21 @@ where
     /// the specified charge against the total cache capacity.
     fn insert(&self, key: K, value: V, charge: usize) -> Option<V>;
 
+    /// Like `insert` but with a `CachePriority`. A cache with a strict capacity limit returns
+    /// `TemplateKVError::MemoryLimit` if the entry can not fit in.
+    ///
+    /// The default implementation ignores the priority.
+    fn insert_with_priority(
+        &self,
+        key: K,
+        value: V,
+        charge: usize,
+        priority: CachePriority,
+    ) -> TemplateResult<Option<V>> {
+        let _ = priority;
+        Ok(self.insert(key, value, charge))
+    }
+
     /// If the cache has no mapping for `key`, returns `None`.
     fn get(&self, key: &K) -> Option<V>;
 
@@ -0,0 +1,279 @@
+use super::FilterPolicy;
+use crate::util::{
+    coding::{decode_fixed_32, decode_fixed_64, put_fixed_32, put_fixed_64},
+    hash::hash,
+};
+
+// The width of the coefficient row of a key
+const COEFF_BITS: usize = 64;
+// num_starts (4B) + seed (1B) + result bits (1B)
+const TRAILER_SIZE: usize = 6;
+// Grows the slots after failing to solve with this many seeds
+const SEEDS_PER_SIZE: u8 = 4;
+
+/// A standard Ribbon filter.
+///
+/// Every key maps to one linear equation over GF(2): a 64-bit coefficient row starting at a
+/// hashed slot and an r-bit fingerprint as the result. Building the filter solves the
+/// system by banding and back-substitution, and a query checks that the solution
+/// reproduces the fingerprint, which gives a false positive rate of about 2^-r with only
+/// a few percent of space overhead. It uses about 30% less memory than a `BloomFilter`
+/// with the same false positive rate in exchange for a slower building.
+///
+/// The filter is encoded as:
+///
+/// ```text
+/// +--------------+-----+----------------+-----------------+-----------+-----------------+
+/// | result bit 0 | ... | result bit r-1 | num_starts (4B) | seed (1B) | r (1B)          |
+/// +--------------+-----+----------------+-----------------+-----------+-----------------+
+/// ```
+///
+/// where each `result bit` is a bit vector of `num_starts + 63` slots in 64-bit words.
+pub struct RibbonFilter {
+    // the fingerprint bits of a key
+    result_bits: u32,
+}
+
+impl RibbonFilter {
+    /// Creates a `RibbonFilter` with about the same false positive rate as a `BloomFilter`
+    /// using `bloom_equivalent_bits_per_key` bits per key.
+    #[must_use]
+    pub fn new(bloom_equivalent_bits_per_key: usize) -> Self {
+        // A bloom filter of b bits per key has a false positive rate of about
+        // 0.6185^b =~ 2^(-0.69 * b)
+        let r = (bloom_equivalent_bits_per_key as f64 * 0.69).round() as u32;
+        Self {
+            result_bits: r.clamp(1, 32),
+        }
+    }
+}
+
+// The equation of a key
+struct Row {
+    start: usize,
+    coeff: u64,
+    result: u32,
+}
+
+fn result_mask(result_bits: u32) -> u32 {
+    if result_bits >= 32 {
+        u32::MAX
+    } else {
+        (1 << result_bits) - 1
+    }
+}
+
+fn ribbon_row(key: &[u8], seed: u8, num_starts: usize, result_bits: u32) -> Row {
+    let seed = u32::from(seed);
+    let h =
+        (u64::from(hash(key, 0xc6a4_a793 ^ seed)) << 32) | u64::from(hash(key, 0x9e37_79b9 ^ seed));
+    // The coefficient row always starts with 1 so that the slot `start` is its pivot
+    let coeff = h.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let result = (h.wrapping_mul(0xc2b2_ae3d_27d4_eb4f) >> 32) as u32 & result_mask(result_bits);
+    Row {
+        start: ((u128::from(h) * num_starts as u128) >> 64) as usize,
+        coeff,
+        result,
+    }
+}
+
+// Bands the equations of `keys` and solves them by back-substitution.
+// Returns the solution as `result_bits` bit vectors, or `None` if the system has no solution.
+fn solve(keys: &[Vec<u8>], seed: u8, num_starts: usize, result_bits: u32) -> Option<Vec<u64>> {
+    let slots = num_starts + COEFF_BITS - 1;
+    let mut coeffs = vec![0u64; slots];
+    let mut results = vec![0u32; slots];
+    for key in keys {
+        let Row {
+            mut start,
+            mut coeff,
+            mut result,
+        } = ribbon_row(key, seed, num_starts, result_bits);
+        // Every row `coeff` at `start` covers slots in [start, start + 63] which are all
+        // less than `slots`. The elimination keeps this invariant.
+        loop {
+            if coeffs[start] == 0 {
+                coeffs[start] = coeff;
+                results[start] = result;
+                break;
+            }
+            coeff ^= coeffs[start];
+            result ^= results[start];
+            if coeff == 0 {
+                if result == 0 {
+                    // A duplicated key
+                    break;
+                }
+                return None;
+            }
+            let tz = coeff.trailing_zeros() as usize;
+            start += tz;
+            coeff >>= tz;
+        }
+    }
+
+    let words = slots.div_ceil(64);
+    let mut solution = vec![0u64; result_bits as usize * words];
+    // The bit j of `window[b]` is the bit b of the solution at slot i + j
+    let mut window = vec![0u64; result_bits as usize];
+    for i in (0..slots).rev() {
+        for (b, w) in window.iter_mut().enumerate() {
+            *w <<= 1;
+            let bit = if coeffs[i] == 0 {
+                // A free slot
+                0
+            } else {
+                ((results[i] >> b) ^ (*w & coeffs[i]).count_ones()) & 1
+            };
+            *w |= u64::from(bit);
+            solution[b * words + i / 64] |= u64::from(bit) << (i % 64);
+        }
+    }
+    Some(solution)
+}
+
+// Reads the 64 bits starting at `start` from a bit vector
+fn bits_at(column: &[u8], start: usize) -> u64 {
+    let word = |i: usize| {
+        if (i + 1) * 8 <= column.len() {
+            decode_fixed_64(&column[i * 8..])
+        } else {
+            0
+        }
+    };
+    let shift = start % 64;
+    let lo = word(start / 64) >> shift;
+    if shift == 0 {
+        lo
+    } else {
+        lo | (word(start / 64 + 1) << (64 - shift))
+    }
+}
+
+impl FilterPolicy for RibbonFilter {
+    fn name(&self) -> &str {
+        "TemplateDB.RibbonFilter"
+    }
+
+    fn may_contain(&self, filter: &[u8], key: &[u8]) -> bool {
+        if filter.len() < TRAILER_SIZE {
+            return false;
+        }
+        let n = filter.len() - TRAILER_SIZE;
+        let num_starts = decode_fixed_32(&filter[n..]) as usize;
+        let seed = filter[n + 4];
+        let result_bits = u32::from(filter[n + 5]);
+        if num_starts == 0 {
+            // No key at all
+            return false;
+        }
+        let column_size = (num_starts + COEFF_BITS - 1).div_ceil(64) * 8;
+        if result_bits == 0 || result_bits > 32 || n != column_size * result_bits as usize {
+            // Reserved for potentially new encodings.
+            // Consider it a match.
+            return true;
+        }
+        let row = ribbon_row(key, seed, num_starts, result_bits);
+        filter[..n]
+            .chunks(column_size)
+            .enumerate()
+            .all(|(b, column)| {
+                (bits_at(column, row.start) & row.coeff).count_ones() & 1 == (row.result >> b) & 1
+            })
+    }
+
+    fn create_filter(&self, keys: &[Vec<u8>]) -> Vec<u8> {
+        let mut dst = vec![];
+        if keys.is_empty() {
+            put_fixed_32(&mut dst, 0);
+            dst.extend_from_slice(&[0, self.result_bits as u8]);
+            return dst;
+        }
+        // About 5% space overhead for a good chance to solve
+        let mut num_starts = keys.len() + keys.len() / 20 + 1;
+        let mut seed = 0u8;
+        let solution = loop {
+            if let Some(solution) = solve(keys, seed, num_starts, self.result_bits) {
+                break solution;
+            }
+            seed = seed.wrapping_add(1);
+            if seed % SEEDS_PER_SIZE == 0 {
+                num_starts += num_starts / 20 + 1;
+            }
+        };
+        dst.reserve(solution.len() * 8 + TRAILER_SIZE);
+        for word in solution {
+            put_fixed_64(&mut dst, word);
+        }
+        put_fixed_32(&mut dst, num_starts as u32);
+        dst.extend_from_slice(&[seed, self.result_bits as u8]);
+        dst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::bloom_filter_cache::BloomFilter, util::coding::encode_fixed_32};
+
+    fn num_key(i: u32) -> Vec<u8> {
+        let mut k = vec![0; 4];
+        encode_fixed_32(&mut k, i);
+        k
+    }
+
+    #[test]
+    fn test_ribbon_filter_empty() {
+        let policy = RibbonFilter::new(10);
+        let filter = policy.create_filter(&[]);
+        assert!(!policy.may_contain(&filter, b"hello"));
+        assert!(!policy.may_contain(&filter, b"world"));
+    }
+
+    #[test]
+    fn test_ribbon_filter_small() {
+        let policy = RibbonFilter::new(10);
+        let keys = vec![b"hello".to_vec(), b"world".to_vec(), b"hello".to_vec()];
+        let filter = policy.create_filter(&keys);
+        assert!(policy.may_contain(&filter, b"hello"));
+        assert!(policy.may_contain(&filter, b"world"));
+        assert!(!policy.may_contain(&filter, b"x"));
+        assert!(!policy.may_contain(&filter, b"foo"));
+    }
+
+    #[test]
+    fn test_ribbon_filter_varying_lengths() {
+        let policy = RibbonFilter::new(10);
+        let mut n = 1;
+        while n <= 20000 {
+            let keys: Vec<Vec<u8>> = (0..n).map(num_key).collect();
+            let filter = policy.create_filter(&keys);
+            for key in keys.iter() {
+                assert!(
+                    policy.may_contain(&filter, key),
+                    "false negative at len {}",
+                    n
+                );
+            }
+            let false_positives = (0..10000)
+                .filter(|i| policy.may_contain(&filter, &num_key(i + 1_000_000_000)))
+                .count();
+            let rate = false_positives as f64 / 10000.0;
+            assert!(rate <= 0.02, "false positive rate {} at len {}", rate, n);
+            n *= 3;
+        }
+    }
+
+    #[test]
+    fn test_ribbon_filter_saves_memory() {
+        let keys: Vec<Vec<u8>> = (0..10000).map(num_key).collect();
+        let bloom = BloomFilter::new(10).create_filter(&keys);
+        let ribbon = RibbonFilter::new(10).create_filter(&keys);
+        assert!(
+            ribbon.len() * 100 <= bloom.len() * 80,
+            "ribbon {} bytes vs bloom {} bytes",
+            ribbon.len(),
+            bloom.len()
+        );
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use snap::raw::max_compress_len;
+
+use super::{lru_cache::LRUCache, sharded_cache::ShardedCache, CachePriority, CacheSync};
+use crate::{
+    error::{TemplateKVError, TemplateResult},
+    sstable::block::Block,
+    storage::{File, Storage},
+    util::{
+        coding::{decode_fixed_32, encode_fixed_32},
+        collection::HashMap,
+        crc32::{hash, mask, unmask},
+    },
+};
+
+// The on-disk space is split into this many segment files which are dropped in FIFO order
+const NUM_SEGMENTS: usize = 8;
+// The masked crc32 of the rest of the record
+const RECORD_CRC_SIZE: usize = 4;
+// The crc and the flags of the block
+const RECORD_HEADER_SIZE: usize = RECORD_CRC_SIZE + 1;
+// The record flag of a block made by `Block::new_raw`, like a filter block
+const FLAG_RAW: u8 = 1;
+// The record flag of a block cached with `CachePriority::High`
+const FLAG_HIGH_PRIORITY: u8 = 1 << 1;
+// The evicted blocks waiting to be written. More are dropped instead of blocking the reads.
+const ADMISSION_QUEUE_SIZE: usize = 1024;
+// Used to size the ghost cache of `AdmissionPolicy::SecondEviction`
+const ESTIMATED_BLOCK_SIZE: usize = 4 << 10;
+
+/// Decides which of the blocks evicted from the memory are written into a `SecondaryCache`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdmissionPolicy {
+    /// Admits every evicted block
+    All,
+    /// Admits a block only when it's evicted the second time in a while, so the blocks read
+    /// only once (e.g. by a scan) do not churn the disk
+    SecondEviction,
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: usize,
+}
+
+struct Segment<F: File> {
+    number: u64,
+    reader: Arc<F>,
+    size: usize,
+    // the keys written into this segment
+    keys: Vec<Vec<u8>>,
+}
+
+struct SegmentSet<F: File> {
+    index: HashMap<Vec<u8>, Location>,
+    // ordered by the segment number
+    segments: VecDeque<Segment<F>>,
+}
+
+struct Shared<S: Storage> {
+    storage: S,
+    dir: PathBuf,
+    segment_size: usize,
+    state: Mutex<SegmentSet<S::F>>,
+    // the admitted blocks not written yet
+    pending: AtomicUsize,
+}
+
+/// A block cache tier on local disk behind an in-memory block cache.
+///
+/// The blocks evicted from the memory are compressed and appended into segment files under
+/// a dedicated directory of a `Storage` by a background thread, and an in-memory index maps
+/// the block cache keys to their records. The oldest segment is dropped as a whole once the
+/// cache is full. The cache is not persistent: the directory is cleared on creation.
+///
+/// Each record is encoded as:
+///
+/// ```text
+/// +-----------------+------------+----------------------------+
+/// | masked crc (4B) | flags (1B) | snappy compressed contents |
+/// +-----------------+------------+----------------------------+
+/// ```
+///
+/// The flags keep whether the block is in the block format and the priority it was cached
+/// with, which it is promoted into the memory again with.
+pub struct SecondaryCache<S: Storage> {
+    shared: Arc<Shared<S>>,
+    admission: AdmissionPolicy,
+    // the keys of the blocks evicted once for `AdmissionPolicy::SecondEviction`
+    ghost: LRUCache<Vec<u8>, ()>,
+    sender: Sender<Admitted>,
+}
+
+// An evicted block waiting to be written, with its record flags
+type Admitted = (Vec<u8>, Arc<Block>, u8);
+
+impl<S: Storage + 'static> SecondaryCache<S> {
+    /// Creates a `SecondaryCache` taking at most `capacity` bytes in `dir`.
+    /// All the existing contents of `dir` are removed.
+    pub fn new<P: AsRef<Path>>(
+        storage: S,
+        dir: P,
+        capacity: usize,
+        admission: AdmissionPolicy,
+    ) -> TemplateResult<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        if storage.exists(&dir) {
+            storage.remove_dir(&dir, true)?;
+        }
+        storage.mkdir_all(&dir)?;
+        let shared = Arc::new(Shared {
+            storage,
+            dir,
+            segment_size: (capacity / NUM_SEGMENTS).max(1),
+            state: Mutex::new(SegmentSet {
+                index: HashMap::default(),
+                segments: VecDeque::new(),
+            }),
+            pending: AtomicUsize::new(0),
+        });
+        let (sender, receiver) = crossbeam_channel::bounded(ADMISSION_QUEUE_SIZE);
+        let writer = SegmentWriter {
+            shared: shared.clone(),
+            active: None,
+            next_number: 1,
+        };
+        thread::Builder::new()
+            .name("secondary-cache".to_owned())
+            .spawn(move || writer.run(receiver))
+            .map_err(TemplateKVError::IO)?;
+        Ok(Self {
+            shared,
+            admission,
+            ghost: LRUCache::new((capacity / ESTIMATED_BLOCK_SIZE).max(1)),
+            sender,
+        })
+    }
+}
+
+impl<S: Storage> SecondaryCache<S> {
+    /// Offers a block evicted from the memory with `priority` to the cache. The block is written
+    /// asynchronously if admitted and it could be dropped if the writing falls behind.
+    pub fn admit(&self, key: &[u8], block: &Arc<Block>, priority: CachePriority) {
+        if self.shared.state.lock().unwrap().index.contains_key(key) {
+            return;
+        }
+        if self.admission == AdmissionPolicy::SecondEviction {
+            let key = key.to_vec();
+            if self.ghost.get(&key).is_none() {
+                self.ghost.insert(key, (), 1);
+                return;
+            }
+            self.ghost.erase(&key);
+        }
+        let mut flags = 0;
+        if block.is_raw() {
+            flags |= FLAG_RAW;
+        }
+        if priority == CachePriority::High {
+            flags |= FLAG_HIGH_PRIORITY;
+        }
+        self.shared.pending.fetch_add(1, Ordering::AcqRel);
+        if self
+            .sender
+            .try_send((key.to_vec(), block.clone(), flags))
+            .is_err()
+        {
+            self.shared.pending.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Returns the block of `key` and the priority it was cached with if it's in the cache.
+    /// An unreadable record is removed and considered as a miss.
+    pub fn lookup(&self, key: &[u8]) -> Option<(Arc<Block>, CachePriority)> {
+        let (location, reader) = {
+            let state = self.shared.state.lock().unwrap();
+            let location = *state.index.get(key)?;
+            let segment = state
+                .segments
+                .iter()
+                .find(|s| s.number == location.segment)?;
+            (location, segment.reader.clone())
+        };
+        let mut record = vec![0; location.len];
+        let block = reader
+            .read_exact_at(&mut record, location.offset)
+            .and_then(|_| decode_record(&record))
+            .and_then(|(flags, data)| {
+                let block = if flags & FLAG_RAW != 0 {
+                    Block::new_raw(data)
+                } else {
+                    Block::new(data)?
+                };
+                let priority = if flags & FLAG_HIGH_PRIORITY != 0 {
+                    CachePriority::High
+                } else {
+                    CachePriority::Low
+                };
+                Ok((block, priority))
+            });
+        match block {
+            Ok((b, priority)) => Some((Arc::new(b), priority)),
+            Err(e) => {
+                warn!("drop an unreadable block in the secondary cache: {:?}", e);
+                self.erase(key);
+                None
+            }
+        }
+    }
+
+    /// Removes the block of `key` from the cache
+    pub fn erase(&self, key: &[u8]) {
+        self.shared.state.lock().unwrap().index.remove(key);
+    }
+
+    /// Returns the bytes taken by the segment files
+    pub fn usage(&self) -> usize {
+        let state = self.shared.state.lock().unwrap();
+        state.segments.iter().map(|s| s.size).sum()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn wait_for_pending(&self) {
+        while self.shared.pending.load(Ordering::Acquire) > 0 {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+}
+
+// Appends the admitted blocks into the newest segment in the background
+struct SegmentWriter<S: Storage> {
+    shared: Arc<Shared<S>>,
+    // the newest segment and its writer
+    active: Option<(u64, S::F)>,
+    next_number: u64,
+}
+
+impl<S: Storage> SegmentWriter<S> {
+    fn run(mut self, receiver: Receiver<Admitted>) {
+        // Exits once the `SecondaryCache` is dropped
+        for (key, block, flags) in receiver.iter() {
+            if let Err(e) = self.append(key, &block, flags) {
+                warn!("failed to write the secondary cache: {:?}", e);
+                // Starts a new segment rather than appending after a partial record
+                self.active = None;
+            }
+            self.shared.pending.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    fn append(&mut self, key: Vec<u8>, block: &Block, flags: u8) -> TemplateResult<()> {
+        if self.shared.state.lock().unwrap().index.contains_key(&key) {
+            return Ok(());
+        }
+        let record = encode_record(flags, block.data())?;
+        if record.len() > self.shared.segment_size {
+            return Ok(());
+        }
+        // The newest segment is always the active one
+        let fits = self.active.is_some() && {
+            let state = self.shared.state.lock().unwrap();
+            state
+                .segments
+                .back()
+                .is_some_and(|s| s.size + record.len() <= self.shared.segment_size)
+        };
+        if !fits {
+            self.roll()?;
+        }
+        let (number, file) = self.active.as_mut().unwrap();
+        file.write(&record)?;
+        file.flush()?;
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(segment) = state.segments.back_mut() {
+            let offset = segment.size;
+            segment.size += record.len();
+            segment.keys.push(key.clone());
+            state.index.insert(
+                key,
+                Location {
+                    segment: *number,
+                    offset: offset as u64,
+                    len: record.len(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    // Starts a new segment and drops the oldest ones beyond `NUM_SEGMENTS`
+    fn roll(&mut self) -> TemplateResult<()> {
+        self.active = None;
+        let number = self.next_number;
+        self.next_number += 1;
+        let name = segment_name(&self.shared.dir, number);
+        let file = self.shared.storage.create(&name)?;
+        let reader = Arc::new(self.shared.storage.open(&name)?);
+        let mut obsolete = vec![];
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.segments.push_back(Segment {
+                number,
+                reader,
+                size: 0,
+                keys: vec![],
+            });
+            while state.segments.len() > NUM_SEGMENTS {
+                let segment = state.segments.pop_front().unwrap();
+                for key in segment.keys {
+                    // The key could be rewritten into a newer segment after an erase
+                    if state
+                        .index
+                        .get(&key)
+                        .is_some_and(|l| l.segment == segment.number)
+                    {
+                        state.index.remove(&key);
+                    }
+                }
+                obsolete.push(segment.number);
+            }
+        }
+        // The readers being used keep working on the removed files
+        for number in obsolete {
+            if let Err(e) = self
+                .shared
+                .storage
+                .remove(segment_name(&self.shared.dir, number))
+            {
+                warn!(
+                    "failed to remove the secondary cache segment {}: {:?}",
+                    number, e
+                );
+            }
+        }
+        self.active = Some((number, file));
+        Ok(())
+    }
+}
+
+fn segment_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.cache", number))
+}
+
+fn encode_record(flags: u8, data: &[u8]) -> TemplateResult<Vec<u8>> {
+    let mut record = vec![0; RECORD_HEADER_SIZE + max_compress_len(data.len())];
+    record[RECORD_CRC_SIZE] = flags;
+    let mut enc = snap::raw::Encoder::new();
+    match enc.compress(data, &mut record[RECORD_HEADER_SIZE..]) {
+        Ok(size) => record.truncate(RECORD_HEADER_SIZE + size),
+        Err(e) => return Err(TemplateKVError::CompressionFailed(e)),
+    }
+    let crc = mask(hash(&record[RECORD_CRC_SIZE..]));
+    encode_fixed_32(&mut record, crc);
+    Ok(record)
+}
+
+// Returns the flags and the contents of a record
+fn decode_record(record: &[u8]) -> TemplateResult<(u8, Vec<u8>)> {
+    if record.len() < RECORD_HEADER_SIZE
+        || unmask(decode_fixed_32(record)) != hash(&record[RECORD_CRC_SIZE..])
+    {
+        return Err(TemplateKVError::Corruption(
+            "secondary cache record checksum mismatch".to_owned(),
+        ));
+    }
+    let compressed = &record[RECORD_HEADER_SIZE..];
+    let mut data = match snap::raw::decompress_len(compressed) {
+        Ok(len) => vec![0; len],
+        Err(e) => return Err(TemplateKVError::CompressionFailed(e)),
+    };
+    let mut dec = snap::raw::Decoder::new();
+    if let Err(e) = dec.decompress(compressed, &mut data) {
+        return Err(TemplateKVError::CompressionFailed(e));
+    }
+    Ok((record[RECORD_CRC_SIZE], data))
+}
+
+type PrimaryCache = ShardedCache<Vec<u8>, Arc<Block>, LRUCache<Vec<u8>, Arc<Block>>>;
+
+/// A block cache of a sharded `LRUCache` in memory backed by a `SecondaryCache` on disk.
+///
+/// The blocks evicted from the memory are offered to the `SecondaryCache`, and a block
+/// found only on the disk is promoted into the memory again.
+pub struct TieredCache<S: Storage> {
+    primary: PrimaryCache,
+    secondary: Arc<SecondaryCache<S>>,
+}
+
+impl<S: Storage + 'static> TieredCache<S> {
+    /// Creates a `TieredCache` with `shards` in-memory shards of `shard_capacity` bytes each
+    #[must_use]
+    pub fn new(shards: usize, shard_capacity: usize, secondary: Arc<SecondaryCache<S>>) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let secondary = secondary.clone();
+                LRUCache::new(shard_capacity).with_evict_listener(Box::new(
+                    move |key: &Vec<u8>, block: &Arc<Block>, priority| {
+                        secondary.admit(key, block, priority)
+                    },
+                ))
+            })
+            .collect();
+        Self {
+            primary: ShardedCache::new(shards),
+            secondary,
+        }
+    }
+
+    /// Returns the `SecondaryCache` behind the memory
+    pub fn secondary(&self) -> &Arc<SecondaryCache<S>> {
+        &self.secondary
+    }
+}
+
+impl<S: Storage> CacheSync<Vec<u8>, Arc<Block>> for TieredCache<S> {
+    fn insert(&self, key: Vec<u8>, value: Arc<Block>, charge: usize) -> Option<Arc<Block>> {
+        self.primary.insert(key, value, charge)
+    }
+
+    fn insert_with_priority(
+        &self,
+        key: Vec<u8>,
+        value: Arc<Block>,
+        charge: usize,
+        priority: CachePriority,
+    ) -> TemplateResult<Option<Arc<Block>>> {
+        self.primary
+            .insert_with_priority(key, value, charge, priority)
+    }
+
+    fn get(&self, key: &Vec<u8>) -> Option<Arc<Block>> {
+        if let Some(b) = self.primary.get(key) {
+            return Some(b);
+        }
+        let (b, priority) = self.secondary.lookup(key)?;
+        if let Err(e) =
+            self.primary
+                .insert_with_priority(key.clone(), b.clone(), b.size(), priority)
+        {
+            warn!(
+                "failed to promote a block from the secondary cache: {:?}",
+                e
+            );
+        }
+        Some(b)
+    }
+
+    fn erase(&self, key: &Vec<u8>) {
+        self.primary.erase(key);
+        self.secondary.erase(key);
+    }
+
+    fn total_charge(&self) -> usize {
+        self.primary.total_charge()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::mem::MemStorage, util::coding::put_fixed_32};
+
+    const CAPACITY: usize = 64 << 10;
+
+    // Half of the contents are compressible
+    fn new_block(i: u32, size: usize) -> Arc<Block> {
+        let mut x = u64::from(i) + 1;
+        let mut data: Vec<u8> = (0..size)
+            .map(|j| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                if j < size / 2 {
+                    x as u8
+                } else {
+                    0
+                }
+            })
+            .collect();
+        // no restart point
+        put_fixed_32(&mut data, 0);
+        Arc::new(Block::new(data).unwrap())
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("block{:06}", i).into_bytes()
+    }
+
+    fn new_cache(admission: AdmissionPolicy) -> SecondaryCache<MemStorage> {
+        SecondaryCache::new(MemStorage::default(), "cache", CAPACITY, admission).unwrap()
+    }
+
+    #[test]
+    fn test_secondary_cache_admit_and_lookup() {
+        let cache = new_cache(AdmissionPolicy::All);
+        assert!(cache.lookup(&key(1)).is_none());
+        for i in 0..10 {
+            cache.admit(&key(i), &new_block(i, 1000), CachePriority::Low);
+        }
+        cache.wait_for_pending();
+        for i in 0..10 {
+            let (b, priority) = cache.lookup(&key(i)).unwrap();
+            assert_eq!(b.data(), new_block(i, 1000).data());
+            assert_eq!(priority, CachePriority::Low);
+        }
+        // The blocks are compressed
+        assert!(cache.usage() < 10 * 1000);
+        cache.erase(&key(3));
+        assert!(cache.lookup(&key(3)).is_none());
+        assert!(cache.lookup(&key(4)).is_some());
+    }
+
+    #[test]
+    fn test_secondary_cache_second_eviction() {
+        let cache = new_cache(AdmissionPolicy::SecondEviction);
+        let block = new_block(1, 1000);
+        cache.admit(&key(1), &block, CachePriority::Low);
+        cache.wait_for_pending();
+        assert!(cache.lookup(&key(1)).is_none());
+        cache.admit(&key(1), &block, CachePriority::Low);
+        cache.wait_for_pending();
+        assert!(cache.lookup(&key(1)).is_some());
+    }
+
+    #[test]
+    fn test_secondary_cache_capacity() {
+        let cache = new_cache(AdmissionPolicy::All);
+        let n = 200;
+        for i in 0..n {
+            cache.admit(&key(i), &new_block(i, 1000), CachePriority::Low);
+            if i % 10 == 0 {
+                // Avoid overflowing the admission queue
+                cache.wait_for_pending();
+            }
+        }
+        cache.wait_for_pending();
+        assert!(cache.usage() <= CAPACITY);
+        assert!(cache.lookup(&key(0)).is_none());
+        assert!(cache.lookup(&key(n - 1)).is_some());
+        let hits = (0..n).filter(|i| cache.lookup(&key(*i)).is_some()).count();
+        assert!(hits > 0 && hits < n as usize);
+        // Too large to fit in a segment
+        cache.admit(&key(n), &new_block(n, CAPACITY), CachePriority::Low);
+        cache.wait_for_pending();
+        assert!(cache.lookup(&key(n)).is_none());
+    }
+
+    #[test]
+    fn test_secondary_cache_corrupted_record() {
+        let record = encode_record(FLAG_RAW, b"hello world").unwrap();
+        assert_eq!(
+            decode_record(&record).unwrap(),
+            (FLAG_RAW, b"hello world".to_vec())
+        );
+        for i in 0..record.len() {
+            let mut corrupted = record.clone();
+            corrupted[i] ^= 0x80;
+            assert!(decode_record(&corrupted).is_err(), "byte {}", i);
+        }
+        assert!(decode_record(&record[..2]).is_err());
+    }
+
+    #[test]
+    fn test_tiered_cache() {
+        let secondary = Arc::new(new_cache(AdmissionPolicy::All));
+        let cache = TieredCache::new(1, 4000, secondary.clone());
+        for i in 0..10 {
+            cache.insert(key(i), new_block(i, 1000), 1000);
+        }
+        assert!(cache.total_charge() <= 5000);
+        // The evicted blocks are still served from the disk and promoted
+        for i in 0..10 {
+            // The promotions evict other blocks into the disk asynchronously
+            secondary.wait_for_pending();
+            let b = cache.get(&key(i)).unwrap();
+            assert_eq!(b.data(), new_block(i, 1000).data());
+        }
+        secondary.wait_for_pending();
+        cache.erase(&key(9));
+        assert!(cache.get(&key(9)).is_none());
+    }
+
+    #[test]
+    fn test_tiered_cache_raw_block_priority() {
+        let secondary = Arc::new(new_cache(AdmissionPolicy::All));
+        let cache = TieredCache::new(1, 2000, secondary.clone());
+        let high = |i| {
+            cache
+                .insert_with_priority(key(i), new_block(i, 1000), 1000, CachePriority::High)
+                .unwrap();
+        };
+        // A filter block evicted by other high priority blocks
+        let filter = Arc::new(Block::new_raw(vec![7; 1000]));
+        cache
+            .insert_with_priority(key(0), filter, 1000, CachePriority::High)
+            .unwrap();
+        high(1);
+        high(2);
+        secondary.wait_for_pending();
+        assert!(cache.primary.get(&key(0)).is_none());
+
+        // Is served from the disk as it is, and promoted with its priority
+        let b = cache.get(&key(0)).unwrap();
+        assert!(b.is_raw());
+        assert_eq!(b.data(), &[7; 1000][..]);
+        for i in 3..6 {
+            cache.insert(key(i), new_block(i, 1000), 1000);
+        }
+        assert!(cache.primary.get(&key(0)).is_some());
+    }
+}
@@ -5,7 +5,8 @@ use std::{
     sync::Arc,
 };
 
-use super::CacheSync;
+use super::{CachePriority, CacheSync};
+use crate::error::TemplateResult;
 
 /// A sharded cache container by key hash
 pub struct ShardedCache<K, V, C>
@@ -54,6 +55,17 @@ where
         self.shards[idx].insert(key, value, charge)
     }
 
+    fn insert_with_priority(
+        &self,
+        key: K,
+        value: V,
+        charge: usize,
+        priority: CachePriority,
+    ) -> TemplateResult<Option<V>> {
+        let idx = self.find_shard(&key);
+        self.shards[idx].insert_with_priority(key, value, charge, priority)
+    }
+
     fn get(&self, key: &K) -> Option<V> {
         let idx = self.find_shard(key);
         self.shards[idx].get(key)
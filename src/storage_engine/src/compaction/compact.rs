@@ -14,7 +14,7 @@ use crate::{
         version_set::{total_file_size, FileIterFactory, SSTableIters},
     },
     memtable::key_format::{InternalKey, InternalKeyComparator},
-    options::{Options, ReadOptions},
+    options::{MutableOptions, Options, ReadOptions},
     sstable::table::TableBuilder,
     storage::{File, Storage},
     util::comparator::Comparator,
@@ -72,16 +72,20 @@ impl CompactionInputs {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CompactionReason {
     MaxSize,
     SeekLimit,
     Manual,
+    // The file is older than `periodic_compaction_seconds`
+    Periodic,
 }
 
 /// A Compaction encapsulates information about a compaction
 pub struct Compaction<F: File, C: Comparator> {
     options: Arc<Options<C>>,
+    // The mutable options in effect when the compaction is picked
+    mutable_options: MutableOptions,
     pub reason: CompactionReason,
     // Target level to be compacted
     pub level: usize,
@@ -122,11 +126,17 @@ pub struct Compaction<F: File, C: Comparator> {
 }
 
 impl<O: File, C: Comparator + 'static> Compaction<O, C> {
-    pub fn new(options: Arc<Options<C>>, level: usize, reason: CompactionReason) -> Self {
+    pub fn new(
+        options: Arc<Options<C>>,
+        mutable_options: MutableOptions,
+        level: usize,
+        reason: CompactionReason,
+    ) -> Self {
         let max_levels = options.max_levels;
         Self {
             reason,
             options,
+            mutable_options,
             level,
             input_version: None,
             edit: VersionEdit::new(max_levels),
@@ -143,12 +153,15 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
     }
 
     /// Is this a trivial compaction that can be implemented by just
-    /// moving a single input file to the next level (no merging or splitting)
+    /// moving a single input file to the next level (no merging or splitting).
+    /// A periodic compaction is never trivial since it's meant to rewrite the file.
     // TODO: improve this to satisfy more complicate moving
     pub fn is_trivial_move(&self) -> bool {
-        self.inputs.base.len() == 1
+        self.reason != CompactionReason::Periodic
+            && self.inputs.base.len() == 1
             && self.inputs.parent.is_empty()
-            && total_file_size(&self.grand_parents) <= self.options.max_grandparent_overlap_bytes()
+            && total_file_size(&self.grand_parents)
+                <= self.mutable_options.max_grandparent_overlap_bytes()
     }
 
     /// Create an iterator that reads over all the compaction input tables with merged order.
@@ -236,7 +249,7 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
             self.grand_parent_index += 1;
         }
         self.seen_key = true;
-        if self.overlapped_bytes > self.options.max_grandparent_overlap_bytes() {
+        if self.overlapped_bytes > self.mutable_options.max_grandparent_overlap_bytes() {
             // Too much overlap for current output, start new output
             self.overlapped_bytes = 0;
             return true;
@@ -299,7 +312,9 @@ impl<O: File, C: Comparator + 'static> Compaction<O, C> {
     /// Calculate the written bytes
     #[inline]
     pub fn bytes_written(&self) -> u64 {
-        self.outputs.iter().fold(0, |sum, file| sum + file.number)
+        self.outputs
+            .iter()
+            .fold(0, |sum, file| sum + file.file_size)
     }
 }
 
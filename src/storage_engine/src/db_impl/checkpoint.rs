@@ -0,0 +1,290 @@
+use std::io::SeekFrom;
+
+use super::template_impl::{DBImpl, TemplateDB};
+use crate::{
+    error::{TemplateKVError, TemplateResult},
+    manager::{
+        filename::{generate_filename, parse_filename, update_current, FileType},
+        version_edit::VersionEdit,
+    },
+    storage::{File, Storage},
+    util::comparator::Comparator,
+    wal::wal_record_writer::Writer,
+};
+
+// The size of the chunks a file is copied in
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The files making up a checkpoint
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckpointInfo {
+    /// The last sequence covered by the checkpoint
+    pub last_sequence: u64,
+    /// The numbers of the sst files copied
+    pub table_files: Vec<u64>,
+    /// The numbers of the WAL files copied
+    pub log_files: Vec<u64>,
+    /// The number of the MANIFEST file written in the checkpoint
+    pub manifest_number: u64,
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
+    /// Create a consistent copy of the db in `dir`, which can be opened as an independent db.
+    ///
+    /// The checkpoint contains all the writes completed before the call. `dir` is created if
+    /// missing and must not hold a db already.
+    pub fn create_checkpoint(&self, dir: &str) -> TemplateResult<CheckpointInfo> {
+        self.inner.create_checkpoint(dir)
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> DBImpl<S, C> {
+    fn create_checkpoint(&self, dir: &str) -> TemplateResult<CheckpointInfo> {
+        if self
+            .env
+            .exists(generate_filename(dir, FileType::Current, 0))
+        {
+            return Err(TemplateKVError::InvalidArgument(format!(
+                "{} already holds a db",
+                dir
+            )));
+        }
+        self.env.mkdir_all(dir)?;
+        let mut edit = VersionEdit::new(self.options.max_levels);
+        let mut info = CheckpointInfo::default();
+        // The WAL records are appended with the versions lock held, so the sizes of the WAL files
+        // recorded here end at a record boundary and cover every write not in the sst files of
+        // `current`. The files are copied after the lock is released.
+        // In the pipelined write mode the WAL also holds the groups not inserted into the
+        // memtable yet, whose sequences are published once they are inserted.
+        let mut logs = vec![];
+        let current = {
+            let mut versions = self.wait_for_pending_mem_writes(self.versions.lock().unwrap());
+            let files = self.env.list(&self.db_path)?;
+            let mut latest_options = None;
+            for file in &files {
+                match parse_filename(file) {
+                    Some((FileType::Log, number))
+                        if number >= versions.log_number()
+                            || (number != 0 && number == versions.prev_log_number()) =>
+                    {
+                        let len = self.env.open(file)?.len()?;
+                        logs.push((number, len));
+                    }
+                    Some((FileType::Options, number)) => {
+                        latest_options = latest_options.max(Some(number))
+                    }
+                    _ => {}
+                }
+            }
+            // The OPTIONS file is small and would be replaced by `set_options` otherwise
+            if let Some(number) = latest_options {
+                self.copy_file(dir, FileType::Options, number, None)?;
+            }
+            info.last_sequence = versions.last_sequence();
+            info.manifest_number = versions.get_next_file_number();
+            edit.set_log_number(versions.log_number());
+            edit.set_prev_log_number(versions.prev_log_number());
+            edit.set_next_file(info.manifest_number + 1);
+            edit.set_last_sequence(info.last_sequence);
+            versions
+                .pinned_logs
+                .extend(logs.iter().map(|(number, _)| *number));
+            // Holding the version keeps its files from being deleted by compactions
+            versions.current()
+        };
+        let copied = logs
+            .iter()
+            .try_for_each(|(number, len)| self.copy_file(dir, FileType::Log, *number, Some(*len)));
+        {
+            let mut versions = self.versions.lock().unwrap();
+            for (number, _) in &logs {
+                if let Some(i) = versions.pinned_logs.iter().position(|n| n == number) {
+                    versions.pinned_logs.swap_remove(i);
+                }
+            }
+        }
+        copied?;
+        info.log_files = logs.into_iter().map(|(number, _)| number).collect();
+        info.log_files.sort_unstable();
+
+        edit.set_comparator_name(String::from(
+            self.internal_comparator.user_comparator.name(),
+        ));
+        for (level, files) in current.files.iter().enumerate() {
+            for f in files {
+                self.copy_file(dir, FileType::Table, f.number, None)?;
+                edit.add_file_meta(level, f);
+                info.table_files.push(f.number);
+            }
+        }
+
+        let manifest = generate_filename(dir, FileType::Manifest, info.manifest_number);
+        let mut writer = Writer::new(self.env.create(&manifest)?);
+        let mut record = vec![];
+        edit.encode_to(&mut record);
+        writer.add_record(&record)?;
+        writer.sync()?;
+        update_current(&self.env, dir, info.manifest_number)?;
+        info!(
+            "Create checkpoint at {} with {} table files and {} log files",
+            dir,
+            info.table_files.len(),
+            info.log_files.len()
+        );
+        Ok(info)
+    }
+
+    // Copies a file of the db into `dir`. Only the first `len` bytes are copied if given.
+    fn copy_file(
+        &self,
+        dir: &str,
+        file_type: FileType,
+        number: u64,
+        len: Option<u64>,
+    ) -> TemplateResult<()> {
+        let mut src = self
+            .env
+            .open(generate_filename(&self.db_path, file_type, number))?;
+        // The handle might share the reading position with others
+        src.seek(SeekFrom::Start(0))?;
+        let mut dst = self.env.create(generate_filename(dir, file_type, number))?;
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        let mut remaining = len.unwrap_or(u64::MAX);
+        while remaining > 0 {
+            let to_read = buf
+                .len()
+                .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let n = src.read(&mut buf[..to_read])?;
+            if n == 0 {
+                break;
+            }
+            dst.write(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        dst.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        db_trait::DB,
+        options::{Options, ReadOptions, WriteOptions},
+        storage::mem::MemStorage,
+        util::comparator::BytewiseComparator,
+    };
+
+    #[test]
+    fn test_create_checkpoint() {
+        let store = MemStorage::default();
+        let mut db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "checkpoint_src",
+            store.clone(),
+        )
+        .unwrap();
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), b"sst")
+                .unwrap();
+        }
+        db.inner.force_compact_mem_table().unwrap();
+        // These only live in the WAL
+        for i in 50..150 {
+            let key = format!("key{:03}", i);
+            db.put(WriteOptions::default(), key.as_bytes(), b"wal")
+                .unwrap();
+        }
+        let info = db.create_checkpoint("checkpoint_dst").unwrap();
+        assert!(!info.table_files.is_empty());
+        assert!(!info.log_files.is_empty());
+        assert!(db.create_checkpoint("checkpoint_dst").is_err());
+
+        // Later writes do not show up in the checkpoint
+        db.put(WriteOptions::default(), b"key000", b"new").unwrap();
+        db.close().unwrap();
+
+        let mut checkpoint = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "checkpoint_dst",
+            store,
+        )
+        .unwrap();
+        for i in 0..150 {
+            let key = format!("key{:03}", i);
+            let expected: &[u8] = if i < 50 { b"sst" } else { b"wal" };
+            assert_eq!(
+                checkpoint
+                    .get(ReadOptions::default(), key.as_bytes())
+                    .unwrap()
+                    .as_deref(),
+                Some(expected),
+                "{}",
+                key
+            );
+        }
+        checkpoint.close().unwrap();
+    }
+
+    #[test]
+    fn test_create_checkpoint_during_writes() {
+        for pipelined in [false, true] {
+            let store = MemStorage::default();
+            let mut opts = Options::<BytewiseComparator>::default();
+            // Switch to new WAL files while the checkpoint is copying
+            opts.write_buffer_size = 64 << 10;
+            opts.enable_pipelined_write = pipelined;
+            let mut db = TemplateDB::open_db(opts, "checkpoint_busy_src", store.clone()).unwrap();
+            for i in 0..1000 {
+                let key = format!("before{:04}", i);
+                db.put(WriteOptions::default(), key.as_bytes(), &[b'v'; 100])
+                    .unwrap();
+            }
+            let done = AtomicBool::new(false);
+            let info = thread::scope(|s| {
+                s.spawn(|| {
+                    let mut i = 0;
+                    while !done.load(Ordering::Acquire) {
+                        let key = format!("during{:06}", i);
+                        db.put(WriteOptions::default(), key.as_bytes(), &[b'v'; 100])
+                            .unwrap();
+                        i += 1;
+                    }
+                });
+                let info = db.create_checkpoint("checkpoint_busy_dst");
+                done.store(true, Ordering::Release);
+                info
+            })
+            .unwrap();
+            db.close().unwrap();
+
+            // The WAL files copied end at a record boundary
+            let mut checkpoint = TemplateDB::open_db(
+                Options::<BytewiseComparator>::default(),
+                "checkpoint_busy_dst",
+                store,
+            )
+            .unwrap();
+            for i in 0..1000 {
+                let key = format!("before{:04}", i);
+                assert!(checkpoint
+                    .get(ReadOptions::default(), key.as_bytes())
+                    .unwrap()
+                    .is_some());
+            }
+            // None of the writes after the checkpoint shows up
+            assert_eq!(
+                checkpoint.inner.versions.lock().unwrap().last_sequence(),
+                info.last_sequence
+            );
+            checkpoint.close().unwrap();
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+use crate::manager::filename::FileType;
+
+/// The result of a full-database checksum verification.
+/// See `TemplateDB::verify_checksums` for details.
+#[derive(Debug, Default)]
+pub struct ChecksumReport {
+    /// Number of live sst files verified
+    pub tables_checked: usize,
+    /// Number of sst blocks (data, filter, metaindex and index blocks) verified
+    pub blocks_checked: usize,
+    /// Number of retained WAL files verified
+    pub logs_checked: usize,
+    /// Number of WAL records verified
+    pub records_checked: usize,
+    /// Total bytes read from the underlying storage
+    pub bytes_checked: u64,
+    /// Every file found corrupted
+    pub corruptions: Vec<CorruptedFile>,
+}
+
+impl ChecksumReport {
+    /// Returns true if no corruption has been found
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+impl fmt::Display for ChecksumReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} tables ({} blocks), {} logs ({} records), {} bytes checked, {} corrupted",
+            self.tables_checked,
+            self.blocks_checked,
+            self.logs_checked,
+            self.records_checked,
+            self.bytes_checked,
+            self.corruptions.len()
+        )?;
+        for c in self.corruptions.iter() {
+            write!(f, "; {}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// A file failed in checksum verification
+#[derive(Debug)]
+pub struct CorruptedFile {
+    pub file_type: FileType,
+    pub number: u64,
+    /// The level of the sst file in current version. Always `None` for a WAL file.
+    pub level: Option<usize>,
+    pub reason: String,
+}
+
+impl fmt::Display for CorruptedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Some(level) => write!(
+                f,
+                "{:?} #{}@{}: {}",
+                self.file_type, self.number, level, self.reason
+            ),
+            None => write!(f, "{:?} #{}: {}", self.file_type, self.number, self.reason),
+        }
+    }
+}
+
+// Limits the reading rate of a verification pass by sleeping once we get ahead of the budget
+pub(crate) struct ScrubThrottle {
+    bytes_per_second: u64,
+    start: Instant,
+    consumed: u64,
+}
+
+impl ScrubThrottle {
+    // The longest single sleep so that a shutting down db is noticed in time
+    const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            start: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    // Accounts `bytes` read and blocks current thread for a while if reading too fast.
+    // Returns false if `should_stop` tells us to give up waiting.
+    pub(crate) fn consume<F: Fn() -> bool>(&mut self, bytes: u64, should_stop: F) -> bool {
+        if self.bytes_per_second == 0 {
+            return !should_stop();
+        }
+        self.consumed += bytes;
+        let expected = Duration::from_secs_f64(self.consumed as f64 / self.bytes_per_second as f64);
+        loop {
+            if should_stop() {
+                return false;
+            }
+            let elapsed = self.start.elapsed();
+            if elapsed >= expected {
+                return true;
+            }
+            thread::sleep((expected - elapsed).min(Self::MAX_SLEEP));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+// A crash-recovery test harness based on `FaultInjectionStorage`.
+//
+// The harness keeps writing random puts and deletes with small memtables so that memtable
+// compactions and level compactions keep running in background, crashes the storage after a
+// random number of operations and then validates the recovered db against an `ExpectedState`.
+//
+// All the randomness comes from one seeded RNG. The seed is printed at the start of each test
+// and a failure could be replayed by setting `CRASH_TEST_SEED` to it.
+
+use std::env;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::template_impl::TemplateDB;
+use crate::{
+    db_trait::DB,
+    options::{Options, ReadOptions, WriteOptions},
+    storage::{
+        fault::{FaultInjectionStorage, FaultOp},
+        mem::MemStorage,
+    },
+    util::comparator::BytewiseComparator,
+};
+
+const DB_PATH: &str = "crash_test";
+const KEY_COUNT: usize = 64;
+const VALUE_SIZE: usize = 1000;
+const ROUNDS: usize = 5;
+// The maximum number of operations before crashing in a round
+const MAX_OPS_PER_ROUND: usize = 2000;
+
+type CrashTestDB = TemplateDB<FaultInjectionStorage<MemStorage>, BytewiseComparator>;
+
+// The expected state of the db across crashes
+struct ExpectedState {
+    // The values surely persisted
+    durable: Vec<Option<String>>,
+    // The writes after the last durable point in order. Any prefix of them might survive a
+    // crash since a memtable compaction also makes the writes durable.
+    pending: Vec<(usize, Option<String>)>,
+}
+
+impl ExpectedState {
+    fn new() -> Self {
+        Self {
+            durable: vec![None; KEY_COUNT],
+            pending: vec![],
+        }
+    }
+
+    // Records a write that might be applied. A synced write makes all the writes durable.
+    fn write(&mut self, key: usize, value: Option<String>, synced: bool) {
+        self.pending.push((key, value));
+        if synced {
+            for (k, v) in self.pending.drain(..) {
+                self.durable[k] = v;
+            }
+        }
+    }
+
+    // Returns true if `actual` is the durable state plus a prefix of the pending writes
+    fn matches(&self, actual: &[Option<String>]) -> bool {
+        let mut state = self.durable.clone();
+        if state == actual {
+            return true;
+        }
+        for (k, v) in self.pending.iter() {
+            state[*k] = v.clone();
+            if state == actual {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Takes the recovered state as the new durable state
+    fn reset(&mut self, actual: Vec<Option<String>>) {
+        self.durable = actual;
+        self.pending.clear();
+    }
+}
+
+struct CrashTest {
+    storage: FaultInjectionStorage<MemStorage>,
+    expected: ExpectedState,
+    rng: StdRng,
+    // Makes every value unique
+    counter: usize,
+}
+
+impl CrashTest {
+    fn new(name: &str) -> Self {
+        let seed = match env::var("CRASH_TEST_SEED") {
+            Ok(seed) => seed.parse().expect("CRASH_TEST_SEED should be an u64"),
+            Err(_) => rand::random(),
+        };
+        println!("{}: CRASH_TEST_SEED={}", name, seed);
+        Self {
+            storage: FaultInjectionStorage::new(MemStorage::default()),
+            expected: ExpectedState::new(),
+            rng: StdRng::seed_from_u64(seed),
+            counter: 0,
+        }
+    }
+
+    // Opens the db and validates the recovered contents
+    fn open_and_verify(&mut self) -> CrashTestDB {
+        let mut opt = Options::default();
+        // Something small to keep compactions running
+        opt.write_buffer_size = 64 << 10;
+        let db = TemplateDB::open_db(opt, DB_PATH, self.storage.clone()).unwrap();
+        let actual: Vec<Option<String>> = (0..KEY_COUNT)
+            .map(|k| {
+                db.get(ReadOptions::default(), key(k).as_bytes())
+                    .unwrap()
+                    .map(|v| String::from_utf8(v).unwrap())
+            })
+            .collect();
+        assert!(
+            self.expected.matches(&actual),
+            "recovered state does not match any expected one: {:?}",
+            actual
+                .iter()
+                .map(|v| v.as_ref().map(|v| &v[..8]))
+                .collect::<Vec<_>>()
+        );
+        self.expected.reset(actual);
+        db
+    }
+
+    // Writes random entries until `ops` entries are written or a write fails
+    fn write(&mut self, db: &CrashTestDB, ops: usize) {
+        for _ in 0..ops {
+            let k = self.rng.gen_range(0, KEY_COUNT);
+            let sync = self.rng.gen_range(0, 10) == 0;
+            let opt = WriteOptions { sync };
+            let value = if self.rng.gen_range(0, 5) == 0 {
+                None
+            } else {
+                self.counter += 1;
+                Some(format!("{:08}{}", self.counter, "v".repeat(VALUE_SIZE)))
+            };
+            let res = match &value {
+                Some(v) => db.put(opt, key(k).as_bytes(), v.as_bytes()),
+                None => db.delete(opt, key(k).as_bytes()),
+            };
+            self.expected.write(k, value, sync && res.is_ok());
+            if res.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Simulates a power loss and shuts the db down
+    fn crash(&mut self, mut db: CrashTestDB) {
+        self.storage.crash().unwrap();
+        let _ = db.close();
+        self.storage.restart();
+    }
+}
+
+fn key(i: usize) -> String {
+    format!("key{:04}", i)
+}
+
+#[test]
+fn test_crash_during_writes_and_compactions() {
+    let mut t = CrashTest::new("test_crash_during_writes_and_compactions");
+    for _ in 0..ROUNDS {
+        let db = t.open_and_verify();
+        let ops = t.rng.gen_range(1, MAX_OPS_PER_ROUND);
+        t.write(&db, ops);
+        t.crash(db);
+    }
+    t.open_and_verify();
+}
+
+#[test]
+fn test_crash_after_injected_write_errors() {
+    let mut t = CrashTest::new("test_crash_after_injected_write_errors");
+    for _ in 0..ROUNDS {
+        let db = t.open_and_verify();
+        let ops = t.rng.gen_range(1, MAX_OPS_PER_ROUND);
+        t.storage.inject_error(FaultOp::Write, 500);
+        t.write(&db, ops);
+        t.storage.inject_error(FaultOp::Write, 0);
+        t.crash(db);
+    }
+    t.open_and_verify();
+}
@@ -1,4 +1,9 @@
+pub mod checkpoint;
+pub mod checksum;
+#[cfg(test)]
+mod crash_test;
 pub mod template_impl;
+pub mod watch;
 
 #[cfg(test)]
 mod tests {
@@ -12,7 +17,7 @@ mod tests {
             Arc, Mutex,
         },
         thread,
-        time::Duration,
+        time::{Duration, Instant},
     };
 
     use log::LevelFilter;
@@ -20,20 +25,43 @@ mod tests {
 
     use super::template_impl::TemplateDB;
     use crate::{
-        cache::{bloom_filter_cache::BloomFilter, lru_cache::LRUCache},
+        cache::{
+            blocked_bloom_filter::BlockedBloomFilter,
+            bloom_filter_cache::BloomFilter,
+            clock_cache::ClockCache,
+            lru_cache::LRUCache,
+            ribbon_filter::RibbonFilter,
+            secondary_cache::{AdmissionPolicy, SecondaryCache, TieredCache},
+        },
+        compaction::compact::CompactionReason,
         db_trait::DB,
         error::{TemplateKVError, TemplateResult},
         iterator::Iterator,
+        listener::{
+            BackgroundErrorReason, CompactionJobInfo, EventListener, FlushJobInfo,
+            TableFileCreationInfo, TableFileCreationReason, TableFileDeletionInfo,
+            WriteStallCondition, WriteStallInfo,
+        },
         manager::{
-            filename::{parse_filename, FileType},
+            filename::{generate_filename, parse_filename, FileType},
             snapshot::Snapshot,
         },
         memtable::{
+            batch::WriteBatch,
             key_format::{InternalKey, ParsedInternalKey},
+            memtable::MemTable,
             value_format::ValueType,
         },
-        options::{CompressionType, Options, ReadOptions, WriteOptions, MAX_KEY_SEQUENCE},
-        storage::{mem::MemStorage, Storage},
+        options::{
+            CompressionType, IndexType, Options, ReadOptions, WriteOptions, MAX_KEY_SEQUENCE,
+        },
+        statistics::Statistics,
+        storage::{
+            encrypted::{EncryptedStorage, StaticKeyProvider, ENCRYPTION_KEY_SIZE},
+            fault::{FaultInjectionStorage, FaultOp},
+            mem::MemStorage,
+            File, Storage,
+        },
         util::comparator::{BytewiseComparator, Comparator},
     };
 
@@ -72,6 +100,12 @@ mod tests {
         FilterPolicy,
         // No compression enabled
         UnCompressed,
+        // Use partitioned index and filter blocks
+        TwoLevelIndex,
+        // Use `ClockCache` as the block cache
+        ClockCache,
+        // Pipeline the WAL and memtable writes and insert the batches of a group concurrently
+        PipelinedWrite,
     }
 
     impl From<u8> for TestOption {
@@ -99,6 +133,23 @@ mod tests {
                 o.compression = CompressionType::NoCompression;
                 o
             }
+            TestOption::TwoLevelIndex => {
+                let mut o = Options::default();
+                o.index_type = IndexType::TwoLevel;
+                o.metadata_block_size = 1024;
+                o
+            }
+            TestOption::ClockCache => {
+                let mut o = Options::default();
+                o.block_cache = Some(Arc::new(ClockCache::new(8 << 20, 4 << 10, false)));
+                o
+            }
+            TestOption::PipelinedWrite => {
+                let mut o = Options::default();
+                o.enable_pipelined_write = true;
+                o.allow_concurrent_memtable_write = true;
+                o
+            }
         }
     }
 
@@ -127,6 +178,9 @@ mod tests {
             TestOption::Reuse,
             TestOption::FilterPolicy,
             TestOption::UnCompressed,
+            TestOption::TwoLevelIndex,
+            TestOption::ClockCache,
+            TestOption::PipelinedWrite,
         ]
         .into_iter()
         .map(|opt| {
@@ -373,6 +427,22 @@ mod tests {
             }
             Ok(false)
         }
+
+        // Flip a bit at `offset` of a sst file and returns the file number
+        fn corrupt_one_sst_file(&self, offset: usize) -> TemplateResult<Option<u64>> {
+            let files = self.store.list(&self.inner.db_path)?;
+            for f in files {
+                if let Some((FileType::Table, number)) = parse_filename(&f) {
+                    let mut contents = vec![];
+                    self.store.open(&f)?.read_all(&mut contents)?;
+                    contents[offset] ^= 0x80;
+                    self.store.remove(&f)?;
+                    self.store.create(&f)?.write(&contents)?;
+                    return Ok(Some(number));
+                }
+            }
+            Ok(None)
+        }
     }
 
     impl Default for DBTest {
@@ -1578,6 +1648,407 @@ mod tests {
         assert!(reads <= 3 * n / 100);
     }
 
+    #[test]
+    fn test_db_filter_policy_per_level() {
+        let mut store = MemStorage::default();
+        store.count_random_reads = true;
+        let stats = Arc::new(Statistics::default());
+        let mut opts = Options::<BytewiseComparator>::default();
+        opts.block_cache = Some(Arc::new(LRUCache::new(0)));
+        opts.filter_policy = Some(Arc::new(BlockedBloomFilter::new(10)));
+        // The memtable compactions use level 0's policy and the others use Ribbon
+        opts.filter_policy_per_level = vec![None];
+        for _ in 1..opts.max_levels {
+            opts.filter_policy_per_level
+                .push(Some(Arc::new(RibbonFilter::new(10))));
+        }
+        opts.statistics = Some(stats.clone());
+        let db = TemplateDB::open_db(opts, "filter_per_level_test", store.clone()).unwrap();
+        let n = 10000;
+        for i in 0..n {
+            db.put(
+                WriteOptions::default(),
+                key(i).as_bytes(),
+                key(i).as_bytes(),
+            )
+            .unwrap();
+        }
+        db.compact_range(Some(b"a"), Some(b"z")).unwrap();
+        // Push the table down to the bottommost level
+        for level in 0..db.options().max_levels - 1 {
+            db.compact_range_at(level, None, None).unwrap();
+        }
+        for i in 0..n {
+            db.put(WriteOptions::default(), key(i).as_bytes(), b"new")
+                .unwrap();
+        }
+        db.inner.force_compact_mem_table().unwrap();
+        let usage = stats.filter_usage();
+        let names: Vec<&str> = usage.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["TemplateDB.BlockedBloomFilter", "TemplateDB.RibbonFilter"]
+        );
+        assert!(usage.iter().all(|(_, u)| u.keys >= n as u64 && u.bytes > 0));
+
+        store.delay_data_sync.store(true, Ordering::Release);
+        for i in 0..n {
+            let v = db.get(ReadOptions::default(), key(i).as_bytes()).unwrap();
+            assert_eq!(v, Some(b"new".to_vec()), "key {}", key(i));
+        }
+        store.random_read_counter.store(0, Ordering::Relaxed);
+        for i in 0..n {
+            assert_eq!(
+                None,
+                db.get(ReadOptions::default(), (key(i) + ".missing").as_bytes())
+                    .unwrap()
+            )
+        }
+        let reads = store.random_read_counter.load(Ordering::Relaxed);
+        assert!(reads <= 5 * n / 100, "{} reads", reads);
+    }
+
+    #[test]
+    fn test_compaction_stats() {
+        let store = MemStorage::default();
+        let stats = Arc::new(Statistics::default());
+        let mut opts = Options::<BytewiseComparator>::default();
+        opts.statistics = Some(stats.clone());
+        let db = TemplateDB::open_db(opts, "compaction_stats_test", store).unwrap();
+        let n = 1000;
+        for i in 0..n {
+            db.put(
+                WriteOptions::default(),
+                key(i).as_bytes(),
+                key(i).as_bytes(),
+            )
+            .unwrap();
+        }
+        db.inner.force_compact_mem_table().unwrap();
+        let flushed = stats.compaction_stats();
+        assert_eq!(flushed.len(), 1);
+        let (flush_level, flush) = flushed[0];
+        assert_eq!(flush.count, 1);
+        assert_eq!(flush.bytes_read, 0);
+        let level_files = db.level_file_stats();
+        assert_eq!(level_files[flush_level], (1, flush.bytes_written));
+
+        db.compact_range_at(flush_level, None, None).unwrap();
+        let compacted = stats.compaction_stats();
+        let (level, compaction) = compacted.last().unwrap();
+        assert_eq!(*level, flush_level + 1);
+        assert_eq!(compaction.bytes_read, flush.bytes_written);
+        let level_files = db.level_file_stats();
+        assert_eq!(level_files[flush_level], (0, 0));
+        assert_eq!(level_files[*level].1, compaction.bytes_written);
+    }
+
+    #[test]
+    fn test_pipelined_and_concurrent_writes() {
+        for (pipelined, concurrent) in [(true, false), (false, true), (true, true)] {
+            let mut opts = Options::<BytewiseComparator>::default();
+            opts.enable_pipelined_write = pipelined;
+            opts.allow_concurrent_memtable_write = concurrent;
+            // Rotate the memtable several times while the writes are in flight
+            opts.write_buffer_size = 64 << 10;
+            let db =
+                TemplateDB::open_db(opts, "pipelined_write_test", MemStorage::default()).unwrap();
+            let (threads, n) = (8, 500);
+            thread::scope(|s| {
+                for t in 0..threads {
+                    let db = &db;
+                    s.spawn(move || {
+                        for i in 0..n {
+                            let mut batch = WriteBatch::default();
+                            batch.put(key(t * n + i).as_bytes(), b"v1");
+                            batch.put(key(t * n + i).as_bytes(), b"v2");
+                            db.write(WriteOptions::default(), batch).unwrap();
+                            // A write is visible once it returns
+                            assert_eq!(
+                                db.get(ReadOptions::default(), key(t * n + i).as_bytes())
+                                    .unwrap(),
+                                Some(b"v2".to_vec())
+                            );
+                        }
+                    });
+                }
+            });
+            // Every entry has its own sequence
+            assert_eq!(
+                db.inner.versions.lock().unwrap().last_sequence(),
+                (threads * n * 2) as u64
+            );
+            for i in 0..threads * n {
+                assert_eq!(
+                    db.get(ReadOptions::default(), key(i).as_bytes()).unwrap(),
+                    Some(b"v2".to_vec())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_db_reads_using_secondary_cache() {
+        let mut store = MemStorage::default();
+        store.count_random_reads = true;
+        let secondary = Arc::new(
+            SecondaryCache::new(
+                MemStorage::default(),
+                "secondary_cache",
+                8 << 20,
+                AdmissionPolicy::All,
+            )
+            .unwrap(),
+        );
+        let mut opts = Options::<BytewiseComparator>::default();
+        // Far less memory than the blocks
+        opts.block_cache = Some(Arc::new(TieredCache::new(1, 16 << 10, secondary.clone())));
+        let db = TemplateDB::open_db(opts, "secondary_cache_test", store.clone()).unwrap();
+        let n = 10000;
+        for i in 0..n {
+            db.put(
+                WriteOptions::default(),
+                key(i).as_bytes(),
+                key(i).as_bytes(),
+            )
+            .unwrap();
+        }
+        db.compact_range(Some(b"a"), Some(b"z")).unwrap();
+        store.delay_data_sync.store(true, Ordering::Release);
+        store.random_read_counter.store(0, Ordering::Relaxed);
+        for i in 0..n {
+            let v = db.get(ReadOptions::default(), key(i).as_bytes()).unwrap();
+            assert_eq!(v, Some(key(i).into_bytes()), "key {}", key(i));
+        }
+        let first_reads = store.random_read_counter.load(Ordering::Relaxed);
+        secondary.wait_for_pending();
+        assert!(secondary.usage() > 0);
+
+        // The blocks evicted from the memory are read from the secondary cache
+        store.random_read_counter.store(0, Ordering::Relaxed);
+        for i in 0..n {
+            let v = db.get(ReadOptions::default(), key(i).as_bytes()).unwrap();
+            assert_eq!(v, Some(key(i).into_bytes()), "key {}", key(i));
+        }
+        let reads = store.random_read_counter.load(Ordering::Relaxed);
+        assert!(
+            reads * 10 <= first_reads,
+            "{} reads vs {} reads without the secondary cache",
+            reads,
+            first_reads
+        );
+    }
+
+    #[test]
+    fn test_db_on_encrypted_storage() {
+        let raw = MemStorage::default();
+        let provider = Arc::new(StaticKeyProvider::new(1, [1; ENCRYPTION_KEY_SIZE]));
+        let store = EncryptedStorage::new(raw.clone(), provider.clone());
+        let value = |i: usize| format!("secret value {}", i);
+        let opts = Options::<BytewiseComparator>::default();
+        let mut db = TemplateDB::open_db(opts.clone(), "encrypted_test", store.clone()).unwrap();
+        for i in 0..1000 {
+            db.put(
+                WriteOptions::default(),
+                key(i).as_bytes(),
+                value(i).as_bytes(),
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        // The files created after the rotation use the new key
+        provider.add_key(2, [2; ENCRYPTION_KEY_SIZE]);
+        provider.set_current(2).unwrap();
+        for i in 1000..2000 {
+            db.put(
+                WriteOptions::default(),
+                key(i).as_bytes(),
+                value(i).as_bytes(),
+            )
+            .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        db.close().unwrap();
+
+        let files = raw.list("encrypted_test").unwrap();
+        assert!(!files.is_empty());
+        for f in files {
+            let mut contents = vec![];
+            raw.open(&f).unwrap().read_all(&mut contents).unwrap();
+            assert!(
+                !contents.windows(12).any(|w| w == b"secret value"),
+                "{:?} is not encrypted",
+                f
+            );
+        }
+
+        let mut db = TemplateDB::open_db(opts.clone(), "encrypted_test", store).unwrap();
+        for i in 0..2000 {
+            let v = db.get(ReadOptions::default(), key(i).as_bytes()).unwrap();
+            assert_eq!(v, Some(value(i).into_bytes()), "key {}", key(i));
+        }
+        db.close().unwrap();
+        let store = EncryptedStorage::new(
+            raw,
+            Arc::new(StaticKeyProvider::new(3, [3; ENCRYPTION_KEY_SIZE])),
+        );
+        assert!(TemplateDB::open_db(opts, "encrypted_test", store).is_err());
+    }
+
+    #[derive(Default)]
+    struct EventCollector {
+        flushes: Mutex<Vec<FlushJobInfo>>,
+        compactions: Mutex<Vec<CompactionJobInfo>>,
+        created: Mutex<Vec<TableFileCreationInfo>>,
+        deleted: Mutex<Vec<u64>>,
+        stalls: Mutex<Vec<(WriteStallCondition, WriteStallCondition)>>,
+        errors: Mutex<Vec<BackgroundErrorReason>>,
+    }
+
+    impl EventListener for EventCollector {
+        fn on_flush_completed(&self, info: &FlushJobInfo, error: Option<&TemplateKVError>) {
+            if error.is_none() {
+                self.flushes.lock().unwrap().push(info.clone());
+            }
+        }
+
+        fn on_compaction_completed(
+            &self,
+            info: &CompactionJobInfo,
+            error: Option<&TemplateKVError>,
+        ) {
+            if error.is_none() {
+                self.compactions.lock().unwrap().push(info.clone());
+            }
+        }
+
+        fn on_table_file_created(&self, info: &TableFileCreationInfo) {
+            self.created.lock().unwrap().push(info.clone());
+        }
+
+        fn on_table_file_deleted(
+            &self,
+            info: &TableFileDeletionInfo,
+            error: Option<&TemplateKVError>,
+        ) {
+            if error.is_none() {
+                self.deleted.lock().unwrap().push(info.file_number);
+            }
+        }
+
+        fn on_stall_conditions_changed(&self, info: &WriteStallInfo) {
+            self.stalls
+                .lock()
+                .unwrap()
+                .push((info.previous, info.current));
+        }
+
+        fn on_background_error(&self, reason: BackgroundErrorReason, _error: &TemplateKVError) {
+            self.errors.lock().unwrap().push(reason);
+        }
+    }
+
+    #[test]
+    fn test_event_listener() {
+        let listener = Arc::new(EventCollector::default());
+        let mut opts = Options::<BytewiseComparator>::default();
+        opts.l0_slowdown_writes_threshold = 1;
+        opts.listeners.push(listener.clone());
+        let db = TemplateDB::open_db(opts, "listener_test", MemStorage::default()).unwrap();
+        // The overlapping flushes finally stay in level 0
+        for i in 0..5 {
+            db.put(WriteOptions::default(), b"a", b"v").unwrap();
+            db.put(WriteOptions::default(), b"z", b"v").unwrap();
+            db.inner.force_compact_mem_table().unwrap();
+            let flushes = listener.flushes.lock().unwrap();
+            assert_eq!(flushes.len(), i + 1);
+            let output = flushes[i].output.as_ref().unwrap();
+            assert_eq!(flushes[i].num_entries, 2);
+            assert_eq!(output.smallest_key, b"a");
+            assert_eq!(output.largest_key, b"z");
+            assert_eq!(output.reason, TableFileCreationReason::Flush);
+            if db.inner.versions.lock().unwrap().level_files_count(0) > 0 {
+                break;
+            }
+        }
+        assert!(listener.stalls.lock().unwrap().is_empty());
+        db.put(WriteOptions::default(), b"m", b"v").unwrap();
+        assert_eq!(
+            *listener.stalls.lock().unwrap(),
+            vec![(WriteStallCondition::Normal, WriteStallCondition::Delayed)]
+        );
+
+        db.compact_range(None, None).unwrap();
+        let compactions = listener.compactions.lock().unwrap().clone();
+        assert!(compactions
+            .iter()
+            .any(|c| c.reason == CompactionReason::Manual && !c.output_files.is_empty()));
+        let created = listener.created.lock().unwrap().clone();
+        let deleted = listener.deleted.lock().unwrap().clone();
+        for c in compactions.iter().filter(|c| !c.is_trivial_move) {
+            assert!(c.input_bytes > 0 && c.output_bytes > 0);
+            assert!(c.input_files.iter().all(|f| deleted.contains(f)));
+            assert!(c.output_files.iter().all(|f| created
+                .iter()
+                .any(|t| t.file_number == *f && t.reason == TableFileCreationReason::Compaction)));
+        }
+        db.put(WriteOptions::default(), b"n", b"v").unwrap();
+        assert_eq!(
+            listener.stalls.lock().unwrap().last(),
+            Some(&(WriteStallCondition::Delayed, WriteStallCondition::Normal))
+        );
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        let listener = Arc::new(EventCollector::default());
+        let mut opts = Options::<BytewiseComparator>::default();
+        opts.listeners.push(listener.clone());
+        let store = FaultInjectionStorage::new(MemStorage::default());
+        let db = TemplateDB::open_db(opts, "failed_flush", store.clone()).unwrap();
+        db.put(WriteOptions::default(), b"a", b"v").unwrap();
+        // Hand the memtable to the compaction thread without rotating the log, which would
+        // fail first
+        store.inject_error(FaultOp::Create, 1);
+        {
+            let mut mem = db.inner.mem.write().unwrap();
+            let memtable = mem::replace(
+                &mut *mem,
+                MemTable::new(1 << 20, db.inner.internal_comparator.clone()),
+            );
+            *db.inner.im_mem.write().unwrap() = Some(memtable);
+        }
+        db.inner
+            .background_compaction_scheduled
+            .store(true, Ordering::Release);
+        db.inner.do_compaction.0.send(()).unwrap();
+        let start = Instant::now();
+        while db.inner.bg_error.read().unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The failed flush is neither retried nor reported again until the error is taken
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            *listener.errors.lock().unwrap(),
+            vec![BackgroundErrorReason::Flush]
+        );
+        assert!(!db
+            .inner
+            .background_compaction_scheduled
+            .load(Ordering::Acquire));
+        assert!(db.put(WriteOptions::default(), b"b", b"v").is_err());
+
+        store.inject_error(FaultOp::Create, 0);
+        db.inner.force_compact_mem_table().unwrap();
+        assert!(db.inner.im_mem.read().unwrap().is_none());
+        assert_eq!(listener.errors.lock().unwrap().len(), 1);
+        assert_eq!(
+            db.get(ReadOptions::default(), b"a").unwrap(),
+            Some(b"v".to_vec())
+        );
+    }
+
     const THREAD_COUNT: usize = 4;
     const TEST_SECONDS: usize = 10;
     const KEY_NUM: usize = 1000;
@@ -1681,6 +2152,384 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_verify_checksums() {
+        for t in default_cases() {
+            t.put_entries(vec![("a", "va"), ("b", "vb")]);
+            t.inner.force_compact_mem_table().unwrap();
+            t.put("c", "vc").unwrap();
+            let report = t.verify_checksums().unwrap();
+            assert!(report.is_ok(), "{}", report);
+            assert_eq!(report.tables_checked, 1);
+            // at least a data block and an index block
+            assert!(report.blocks_checked >= 2, "{}", report);
+            assert!(report.records_checked >= 1, "{}", report);
+
+            let number = t.corrupt_one_sst_file(0).unwrap().unwrap();
+            let report = t.verify_checksums().unwrap();
+            assert_eq!(report.corruptions.len(), 1, "{}", report);
+            let corrupted = &report.corruptions[0];
+            assert_eq!(corrupted.file_type, FileType::Table);
+            assert_eq!(corrupted.number, number);
+            assert_eq!(corrupted.level, Some(2));
+        }
+    }
+
+    #[test]
+    fn test_background_scrub_records_corruption() {
+        for t in cases(|mut opt| {
+            opt.background_scrub_interval = Some(Duration::from_millis(100));
+            opt.statistics = Some(Arc::new(Statistics::default()));
+            opt
+        }) {
+            t.put("foo", "v1").unwrap();
+            t.inner.force_compact_mem_table().unwrap();
+            assert!(t.put("foo", "v2").is_ok());
+            assert!(t.corrupt_one_sst_file(0).unwrap().is_some());
+            let stats = t.opt.statistics.clone().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while stats.scrub_stats().corrupted_files == 0 {
+                assert!(
+                    Instant::now() < deadline,
+                    "the scrubber has not found the corruption: {:?}",
+                    stats.scrub_stats()
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(t.put("foo", "v3").is_err());
+        }
+    }
+
+    // Returns the numbers of all the OPTIONS files in db
+    fn options_file_numbers(t: &DBTest) -> Vec<u64> {
+        let mut numbers: Vec<u64> = t
+            .store
+            .list(&t.inner.db_path)
+            .unwrap()
+            .into_iter()
+            .filter_map(|f| match parse_filename(f) {
+                Some((FileType::Options, number)) => Some(number),
+                _ => None,
+            })
+            .collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    #[test]
+    fn test_options_file() {
+        for mut t in default_cases() {
+            let numbers = options_file_numbers(&t);
+            assert_eq!(numbers.len(), 1);
+            t.put("foo", "v1").unwrap();
+            t.reopen().unwrap();
+            let reopened = options_file_numbers(&t);
+            assert_eq!(reopened.len(), 1);
+            assert!(reopened[0] > numbers[0]);
+            t.assert_get("foo", Some("v1"));
+
+            t.opt.max_levels += 1;
+            match t.reopen() {
+                Ok(_) => panic!("reopening with different max_levels should fail"),
+                Err(e) => assert!(e.to_string().contains("max_levels"), "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_options_file() {
+        for mut t in default_cases() {
+            // An invalid mutable option persisted is a corruption
+            let number = options_file_numbers(&t)[0];
+            let file_name = generate_filename(&t.inner.db_path, FileType::Options, number);
+            let mut content = vec![];
+            t.store
+                .open(&file_name)
+                .unwrap()
+                .read_all(&mut content)
+                .unwrap();
+            let content = String::from_utf8(content)
+                .unwrap()
+                .lines()
+                .map(|line| match line.split_once('=') {
+                    Some(("compression", _)) => "compression=zstd\n".to_owned(),
+                    _ => format!("{}\n", line),
+                })
+                .collect::<String>();
+            t.store.remove(&file_name).unwrap();
+            let mut f = t.store.create(&file_name).unwrap();
+            f.write(content.as_bytes()).unwrap();
+            f.close().unwrap();
+            match t.reopen() {
+                Ok(_) => panic!("reopening with an invalid OPTIONS file should fail"),
+                Err(e) => assert!(matches!(e, TemplateKVError::Corruption(_)), "{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_options() {
+        for t in default_cases() {
+            let before = options_file_numbers(&t);
+            t.set_options(&[
+                ("write_buffer_size", "1048576"),
+                ("l0_stop_writes_threshold", "20"),
+                ("max_file_size", "4194304"),
+                ("compression", "NoCompression"),
+            ])
+            .unwrap();
+            let m = t.inner.mutable_options();
+            assert_eq!(m.write_buffer_size, 1 << 20);
+            assert_eq!(m.l0_stop_writes_threshold, 20);
+            assert_eq!(m.max_file_size, 4 << 20);
+            assert!(matches!(m.compression, CompressionType::NoCompression));
+            let after = options_file_numbers(&t);
+            assert_eq!(after.len(), 1);
+            assert!(after[0] > before[0]);
+
+            // Invalid changes are rejected as a whole
+            for changes in [
+                vec![("write_buffer_size", "2097152"), ("max_levels", "3")],
+                vec![("write_buffer_size", "2097152"), ("compression", "zstd")],
+                vec![("unknown", "1")],
+                vec![
+                    ("write_buffer_size", "2097152"),
+                    ("l0_slowdown_writes_threshold", "21"),
+                ],
+            ] {
+                assert!(matches!(
+                    t.set_options(&changes),
+                    Err(TemplateKVError::InvalidArgument(_))
+                ));
+                assert_eq!(t.inner.mutable_options().write_buffer_size, 1 << 20);
+            }
+
+            for i in 0..100 {
+                t.put(&key(i), &rand_string(10_000)).unwrap();
+            }
+            t.assert_put_get("foo", "bar");
+        }
+    }
+
+    #[test]
+    fn test_set_options_failed_write() {
+        let store = FaultInjectionStorage::new(MemStorage::default());
+        let mut db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "set_options_failed_write",
+            store.clone(),
+        )
+        .unwrap();
+        let before = db.mutable_options();
+        store.inject_error(FaultOp::Rename, 1);
+        assert!(db.set_options(&[("write_buffer_size", "1048576")]).is_err());
+        // The changes are not published since the OPTIONS file is not written
+        assert_eq!(
+            db.mutable_options().write_buffer_size,
+            before.write_buffer_size
+        );
+        assert_eq!(
+            db.inner
+                .versions
+                .lock()
+                .unwrap()
+                .mutable_options()
+                .write_buffer_size,
+            before.write_buffer_size
+        );
+        store.inject_error(FaultOp::Rename, 0);
+        db.set_options(&[("write_buffer_size", "1048576")]).unwrap();
+        assert_eq!(db.mutable_options().write_buffer_size, 1 << 20);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        for t in default_cases() {
+            t.put("foo", "v1").unwrap();
+            t.inner.force_compact_mem_table().unwrap();
+            t.put("bar", "v2").unwrap();
+            let files = t.store.list(&t.inner.db_path).unwrap();
+            let mut db =
+                TemplateDB::open_read_only(t.opt.clone(), &t.inner.db_path, t.store.clone())
+                    .unwrap();
+            let get = |k: &str| {
+                db.get(ReadOptions::default(), k.as_bytes())
+                    .unwrap()
+                    .map(|v| String::from_utf8(v).unwrap())
+            };
+            assert_eq!(get("foo"), Some("v1".to_owned()));
+            assert_eq!(get("bar"), Some("v2".to_owned()));
+            assert!(matches!(
+                db.put(WriteOptions::default(), b"foo", b"v3"),
+                Err(TemplateKVError::NotSupported(_))
+            ));
+            assert!(matches!(
+                db.compact_range(None, None),
+                Err(TemplateKVError::NotSupported(_))
+            ));
+            assert!(matches!(
+                db.try_catch_up_with_primary(),
+                Err(TemplateKVError::NotSupported(_))
+            ));
+            assert_eq!(t.store.list(&t.inner.db_path).unwrap(), files);
+            db.close().unwrap();
+            // The primary is not affected
+            t.assert_put_get("foo", "v3");
+        }
+    }
+
+    #[test]
+    fn test_open_read_only_missing_db() {
+        let res = TemplateDB::open_read_only(
+            Options::<BytewiseComparator>::default(),
+            "missing",
+            MemStorage::default(),
+        );
+        assert!(matches!(res, Err(TemplateKVError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_secondary_catch_up_with_primary() {
+        for t in default_cases() {
+            t.put("foo", "v1").unwrap();
+            let secondary =
+                TemplateDB::open_as_secondary(t.opt.clone(), &t.inner.db_path, t.store.clone())
+                    .unwrap();
+            let get = |k: &str| {
+                secondary
+                    .get(ReadOptions::default(), k.as_bytes())
+                    .unwrap()
+                    .map(|v| String::from_utf8(v).unwrap())
+            };
+            assert_eq!(get("foo"), Some("v1".to_owned()));
+
+            // Changes in WAL
+            t.put("foo", "v2").unwrap();
+            t.put("bar", "v1").unwrap();
+            assert_eq!(get("foo"), Some("v1".to_owned()));
+            assert_eq!(get("bar"), None);
+            secondary.try_catch_up_with_primary().unwrap();
+            assert_eq!(get("foo"), Some("v2".to_owned()));
+            assert_eq!(get("bar"), Some("v1".to_owned()));
+
+            // Changes in MANIFEST
+            t.inner.force_compact_mem_table().unwrap();
+            t.delete("foo").unwrap();
+            t.inner.force_compact_mem_table().unwrap();
+            secondary.try_catch_up_with_primary().unwrap();
+            assert_eq!(get("foo"), None);
+            assert_eq!(get("bar"), Some("v1".to_owned()));
+            assert_eq!(secondary.file_count_per_level(), t.file_count_per_level());
+
+            t.compact(None, None);
+            secondary.try_catch_up_with_primary().unwrap();
+            assert_eq!(secondary.file_count_per_level(), t.file_count_per_level());
+            assert_eq!(get("bar"), Some("v1".to_owned()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_put_get_delete() {
+        use crate::db_trait::AsyncDB;
+
+        for t in default_cases() {
+            let db = &t.db;
+            AsyncDB::put(db, WriteOptions::default(), b"foo", b"v1")
+                .await
+                .unwrap();
+            AsyncDB::put(db, WriteOptions::default(), b"bar", b"v2")
+                .await
+                .unwrap();
+            assert_eq!(
+                AsyncDB::get(db, ReadOptions::default(), b"foo")
+                    .await
+                    .unwrap(),
+                Some(b"v1".to_vec())
+            );
+            t.inner.force_compact_mem_table().unwrap();
+            AsyncDB::delete(db, WriteOptions::default(), b"bar")
+                .await
+                .unwrap();
+            let mut batch = WriteBatch::default();
+            batch.put(b"baz", b"v3");
+            AsyncDB::write(db, WriteOptions { sync: true }, batch)
+                .await
+                .unwrap();
+            let keys: [&[u8]; 4] = [b"foo", b"bar", b"baz", b"missing"];
+            assert_eq!(
+                AsyncDB::multi_get(db, ReadOptions::default(), &keys)
+                    .await
+                    .unwrap(),
+                vec![Some(b"v1".to_vec()), None, Some(b"v3".to_vec()), None]
+            );
+            t.assert_get("baz", Some("v3"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_concurrent_writers() {
+        use crate::db_trait::AsyncDB;
+
+        // All the writers share the single thread of the runtime, so none of them is allowed
+        // to block while waiting in the batch queue
+        for t in default_cases() {
+            let handles: Vec<_> = (0..100)
+                .map(|i| {
+                    let db = t.db.clone();
+                    tokio::spawn(async move {
+                        let k = format!("key{}", i);
+                        AsyncDB::put(&db, WriteOptions::default(), k.as_bytes(), b"v").await
+                    })
+                })
+                .collect();
+            for h in futures::future::join_all(handles).await {
+                h.unwrap().unwrap();
+            }
+            for i in 0..100 {
+                t.assert_get(&format!("key{}", i), Some("v"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_iter() {
+        use futures::StreamExt;
+
+        use crate::db_trait::AsyncDB;
+
+        for t in default_cases() {
+            t.put_entries(vec![("b", "v2"), ("a", "v1")]);
+            t.inner.force_compact_mem_table().unwrap();
+            t.put_entries(vec![("c", "v3")]);
+            let stream = AsyncDB::iter(&t.db, ReadOptions::default()).unwrap();
+            // Invisible to the stream
+            t.put_entries(vec![("d", "v4")]);
+            t.delete("a").unwrap();
+            let entries: Vec<_> = stream
+                .map(|kv| {
+                    kv.map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap()))
+                })
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<TemplateResult<_>>()
+                .unwrap();
+            assert_eq!(
+                entries,
+                vec![
+                    ("a".to_owned(), "v1".to_owned()),
+                    ("b".to_owned(), "v2".to_owned()),
+                    ("c".to_owned(), "v3".to_owned()),
+                ]
+            );
+            // Dropping a stream early is fine
+            let mut stream = AsyncDB::iter(&t.db, ReadOptions::default()).unwrap();
+            let (k, _) = stream.next().await.unwrap().unwrap();
+            assert_eq!(k, b"b".to_vec());
+        }
+    }
+
     #[test]
     fn test_multi_thread() {
         for t in default_cases() {
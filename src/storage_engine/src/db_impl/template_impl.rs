@@ -1,23 +1,32 @@
 use std::{
     cmp::Ordering as CmpOrdering,
     collections::VecDeque,
+    io::SeekFrom,
     mem,
     path::Path,
+    pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Condvar, Mutex, MutexGuard, RwLock,
     },
+    task::{Context, Poll},
     thread,
     time::{Duration, Instant},
 };
 
 use crossbeam::sync::ShardedLock;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use futures::Stream;
+use tokio::sync::{mpsc, oneshot};
 
 use crate::{
     cache::table_cache::TableCache,
     compaction::compact::{Compaction, CompactionStats, ManualCompaction},
-    db_trait::DB,
+    db_impl::{
+        checksum::{ChecksumReport, CorruptedFile, ScrubThrottle},
+        watch::WatchHub,
+    },
+    db_trait::{AsyncDB, DB},
     error::{TemplateKVError, TemplateResult},
     iterator::{
         db_iter::{DBIterator, DBIteratorCore},
@@ -25,22 +34,30 @@ use crate::{
         memtable_iter::MemTableIterator,
         Iterator,
     },
+    listener::{
+        BackgroundErrorReason, CompactionJobInfo, EventListener, FlushJobInfo,
+        TableFileCreationInfo, TableFileCreationReason, TableFileDeletionInfo, WriteStallCondition,
+        WriteStallInfo,
+    },
     manager::{
         filename::{generate_filename, parse_filename, update_current, FileType},
         snapshot::Snapshot,
         version::Version,
         version_edit::{FileMetaData, VersionEdit},
-        version_set::{SSTableIters, VersionSet},
+        version_set::{total_file_size, SSTableIters, VersionSet},
     },
     memtable::{
         batch::WriteBatch,
+        inlineskiplist::MAX_NODE_SIZE,
         key_format::{InternalKey, InternalKeyComparator, LookupKey, ParsedInternalKey},
         memtable::MemTable,
         value_format::{ValueType, VALUE_TYPE_FOR_SEEK},
     },
-    options::{Options, ReadOptions, WriteOptions, MAX_KEY_SEQUENCE},
-    sstable::table::TableBuilder,
-    storage::{File, Storage},
+    options::{
+        CompressionType, MutableOptions, Options, ReadOptions, WriteOptions, MAX_KEY_SEQUENCE,
+    },
+    sstable::table::{verify_table, TableBuilder},
+    storage::{do_write_string_to_file, File, Storage},
     util::{comparator::Comparator, reporter::LogReporter},
     wal::{wal_record_format::HEADER_SIZE, wal_record_reader::Reader, wal_record_writer::Writer},
 };
@@ -50,11 +67,28 @@ pub struct TemplateDB<S: Storage + Clone + 'static, C: Comparator> {
     pub inner: Arc<DBImpl<S, C>>,
     shutdown_batch_processing_thread: (Sender<()>, Receiver<()>),
     shutdown_compaction_thread: (Sender<()>, Receiver<()>),
+    shutdown_scrub_thread: (Sender<()>, Receiver<()>),
 }
 
 /// The iterator yields all the user keys and user values in db
 pub type TemplateDBIterator<S, C> = DBIterator<InternalIterator<S, C>, S, C>;
 
+/// The stream yields all the user keys and user values in db. See `AsyncDB::iter`.
+pub struct TemplateDBStream {
+    recv: mpsc::Receiver<TemplateResult<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl Stream for TemplateDBStream {
+    type Item = TemplateResult<(Vec<u8>, Vec<u8>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+// The number of kv pairs buffered ahead by the thread feeding a `TemplateDBStream`
+const STREAM_BUFFER_SIZE: usize = 64;
+
 // The iterator yields all the internal keys and internal values in db
 type InternalIterator<S, C> = KMergeIter<
     DBIteratorCore<InternalKeyComparator<C>, MemTableIterator<C>, KMergeIter<SSTableIters<S, C>>>,
@@ -104,17 +138,28 @@ impl<S: Storage + Clone, C: Comparator + 'static> DB for TemplateDB<S, C> {
             return Ok(());
         }
         self.inner.is_shutting_down.store(true, Ordering::Relaxed);
+        if self.inner.access_mode != AccessMode::Primary {
+            // No background threads running
+            self.inner.close()?;
+            info!("DB {} closed", &self.inner.db_path);
+            return Ok(());
+        }
         self.inner.schedule_close_batch();
         let _ = self.shutdown_batch_processing_thread.1.recv();
         // Send a signal to avoid blocking forever
         let _ = self.inner.do_compaction.0.send(());
         let _ = self.shutdown_compaction_thread.1.recv();
+        let _ = self.inner.do_scrub.0.send(());
+        if self.inner.options.background_scrub_interval.is_some() {
+            let _ = self.shutdown_scrub_thread.1.recv();
+        }
         self.inner.close()?;
         info!("DB {} closed", &self.inner.db_path);
         Ok(())
     }
 
     fn destroy(&mut self) -> TemplateResult<()> {
+        self.inner.check_writable("destroy")?;
         info!("Start destroying: {}", &self.inner.db_path);
         let db = self.inner.clone();
         self.close()?;
@@ -127,6 +172,103 @@ impl<S: Storage + Clone, C: Comparator + 'static> DB for TemplateDB<S, C> {
     }
 }
 
+#[async_trait::async_trait]
+impl<S: Storage + Clone, C: Comparator + 'static> AsyncDB for TemplateDB<S, C> {
+    type Stream = TemplateDBStream;
+
+    async fn put(&self, options: WriteOptions, key: &[u8], value: &[u8]) -> TemplateResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+        AsyncDB::write(self, options, batch).await
+    }
+
+    async fn get(&self, options: ReadOptions, key: &[u8]) -> TemplateResult<Option<Vec<u8>>> {
+        let db = self.inner.clone();
+        let key = key.to_vec();
+        spawn_blocking_read(move || db.get(options, &key)).await
+    }
+
+    async fn multi_get(
+        &self,
+        options: ReadOptions,
+        keys: &[&[u8]],
+    ) -> TemplateResult<Vec<Option<Vec<u8>>>> {
+        let db = self.inner.clone();
+        let keys: Vec<Vec<u8>> = keys.iter().map(|k| k.to_vec()).collect();
+        spawn_blocking_read(move || {
+            db.with_pinned_snapshot(options, |options| {
+                keys.iter().map(|k| db.get(options, k)).collect()
+            })
+        })
+        .await
+    }
+
+    async fn delete(&self, options: WriteOptions, key: &[u8]) -> TemplateResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(key);
+        AsyncDB::write(self, options, batch).await
+    }
+
+    async fn write(&self, options: WriteOptions, batch: WriteBatch) -> TemplateResult<()> {
+        self.inner.schedule_batch_async(options, batch).await
+    }
+
+    // The contents are read by a blocking thread of tokio ahead of the consumer, so this must
+    // be called inside a tokio runtime.
+    fn iter(&self, options: ReadOptions) -> TemplateResult<Self::Stream> {
+        if self.inner.is_shutting_down.load(Ordering::Acquire) {
+            return Err(TemplateKVError::DBClosed("iter request".to_owned()));
+        }
+        // Pin the view now rather than when the blocking thread gets scheduled
+        let snapshot = options.snapshot.is_none().then(|| self.inner.snapshot());
+        let (send, recv) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut options = options;
+            if let Some(s) = &snapshot {
+                options.snapshot = Some(**s);
+            }
+            match DB::iter(&db, options) {
+                Ok(mut iter) => {
+                    iter.seek_to_first();
+                    while iter.valid() {
+                        let kv = (iter.key().to_vec(), iter.value().to_vec());
+                        if send.blocking_send(Ok(kv)).is_err() {
+                            // The stream has been dropped
+                            break;
+                        }
+                        iter.next();
+                    }
+                    if let Err(e) = iter.status() {
+                        let _ = send.blocking_send(Err(e));
+                    }
+                }
+                Err(e) => {
+                    let _ = send.blocking_send(Err(e));
+                }
+            }
+            if let Some(s) = snapshot {
+                let _ = db.release_snapshot(s);
+            }
+        });
+        Ok(TemplateDBStream { recv })
+    }
+}
+
+// Run a read touching the storage on the blocking threads of tokio
+async fn spawn_blocking_read<T, F>(f: F) -> TemplateResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> TemplateResult<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| {
+        Err(TemplateKVError::Customized(format!(
+            "blocking read task failed: {}",
+            e
+        )))
+    })
+}
+
 impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
     /// Create a new `TemplateDB`
     pub fn open_db<P: AsRef<Path>>(
@@ -141,7 +283,7 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
         };
         options.initialize(&db_path, &storage);
         debug!("Open db: '{:?}'", &db_path);
-        let mut db = DBImpl::new(options, db_path, storage);
+        let mut db = DBImpl::new(options, db_path, storage, AccessMode::Primary);
         let (mut edit, should_save_manifest) = db.recover()?;
         let mut versions = db.versions.lock().unwrap();
         if versions.record_writer.is_none() {
@@ -155,6 +297,8 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
             edit.set_log_number(new_log_number);
             versions.set_log_number(new_log_number);
         }
+        let mutable = db.mutable_options();
+        db.write_options_file(&mut versions, &mutable)?;
         if should_save_manifest {
             edit.set_prev_log_number(0);
             edit.set_log_number(versions.log_number());
@@ -167,15 +311,106 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
             inner: Arc::new(db),
             shutdown_batch_processing_thread: crossbeam_channel::bounded(1),
             shutdown_compaction_thread: crossbeam_channel::bounded(1),
+            shutdown_scrub_thread: crossbeam_channel::bounded(1),
         };
         template_db.process_compaction();
         template_db.process_batch();
+        if let Some(interval) = template_db.inner.options.background_scrub_interval {
+            template_db.process_scrub(interval);
+        }
         // Schedule a compaction to current version for potential unfinished work
         debug!("Try to schedule a compaction on opening db");
         template_db.inner.maybe_schedule_compaction(current);
         Ok(template_db)
     }
 
+    /// Open the db at `db_path` for reading only.
+    ///
+    /// The db must exist. Nothing in the db directory is modified: the `LOCK` file is not
+    /// taken, the WAL files are replayed into memory instead of being flushed, and no
+    /// compaction is ever scheduled. All the writes return `NotSupported`.
+    /// The view of the db is fixed at opening.
+    pub fn open_read_only<P: AsRef<Path>>(
+        options: Options<C>,
+        db_path: P,
+        storage: S,
+    ) -> TemplateResult<Self> {
+        Self::open_without_lock(options, db_path, storage, AccessMode::ReadOnly)
+    }
+
+    /// Open the db at `db_path` as a secondary instance of the primary that is writing it.
+    ///
+    /// Just like `open_read_only`, but the view of the db could be refreshed by
+    /// `try_catch_up_with_primary`.
+    pub fn open_as_secondary<P: AsRef<Path>>(
+        options: Options<C>,
+        db_path: P,
+        storage: S,
+    ) -> TemplateResult<Self> {
+        Self::open_without_lock(options, db_path, storage, AccessMode::Secondary)
+    }
+
+    /// Refresh the view of a secondary instance with the changes made by the primary.
+    ///
+    /// The MANIFEST records appended since last catching up are applied to the current version
+    /// and the WAL files not yet compacted by the primary are replayed into a new memtable.
+    /// Note that the sst files removed by the primary's compactions could make the reads of the
+    /// iterators and snapshots created before fail.
+    pub fn try_catch_up_with_primary(&self) -> TemplateResult<()> {
+        if self.inner.access_mode != AccessMode::Secondary {
+            return Err(TemplateKVError::NotSupported(
+                "catching up with primary on a non-secondary db".to_owned(),
+            ));
+        }
+        self.inner.catch_up_with_primary()
+    }
+
+    fn open_without_lock<P: AsRef<Path>>(
+        mut options: Options<C>,
+        db_path: P,
+        storage: S,
+        mode: AccessMode,
+    ) -> TemplateResult<Self> {
+        let Ok(db_path) = db_path.as_ref().to_owned().into_os_string().into_string() else {
+            return Err(TemplateKVError::Customized(
+                "Invalid db path. Expect to use Unicode db path.".to_owned(),
+            ));
+        };
+        if !storage.exists(generate_filename(&db_path, FileType::Current, 0)) {
+            return Err(TemplateKVError::InvalidArgument(
+                db_path + " does not exist",
+            ));
+        }
+        // Never reopen the MANIFEST of the primary for appending
+        options.reuse_logs = false;
+        if options.logger.is_none() && !cfg!(debug_assertions) {
+            // The default logger truncates the `LOG` file of the primary
+            options.logger = Some(slog::Logger::root(slog::Discard, slog::o!()));
+        }
+        options.initialize(&db_path, &storage);
+        debug!("Open db: '{:?}' in {:?} mode", &db_path, mode);
+        let db = DBImpl::new(options, db_path, storage, mode);
+        let latest_options = db
+            .env
+            .list(&db.db_path)?
+            .into_iter()
+            .filter_map(|f| match parse_filename(f) {
+                Some((FileType::Options, number)) => Some(number),
+                _ => None,
+            })
+            .max();
+        if let Some(number) = latest_options {
+            db.check_options_file(number)?;
+        }
+        db.catch_up_with_primary()?;
+        Ok(TemplateDB {
+            inner: Arc::new(db),
+            shutdown_batch_processing_thread: crossbeam_channel::bounded(1),
+            shutdown_compaction_thread: crossbeam_channel::bounded(1),
+            shutdown_scrub_thread: crossbeam_channel::bounded(1),
+        })
+    }
+
     /// Schedule a compaction for the key range `[begin, end]`.
     pub fn compact_range(&self, begin: Option<&[u8]>, end: Option<&[u8]>) -> TemplateResult<()> {
         self.inner.compact_range(begin, end)
@@ -191,6 +426,31 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
         self.inner.manual_compact_range(level, begin, end)
     }
 
+    /// Verify the checksums of every block in all the live sst files (data, filter, metaindex
+    /// and index blocks) and every record in the retained WAL files.
+    ///
+    /// Unlike `ReadOptions::verify_checksums`, which only applies to the blocks touched by a
+    /// read, this walks the whole database. A corrupted file does not stop the verification
+    /// and is reported in the returned `ChecksumReport` with its file number and level.
+    pub fn verify_checksums(&self) -> TemplateResult<ChecksumReport> {
+        self.inner.verify_checksums(None)
+    }
+
+    /// Change the mutable options (`write_buffer_size`, `l0_slowdown_writes_threshold`,
+    /// `l0_stop_writes_threshold`, `max_file_size` and `compression`) without reopening the db.
+    /// Each change is a `(name, value)` pair where `value` uses the format of the `OPTIONS-*` file.
+    ///
+    /// Either all the changes are applied or none of them. The new options are persisted in a
+    /// new `OPTIONS-*` file.
+    pub fn set_options(&self, changes: &[(&str, &str)]) -> TemplateResult<()> {
+        self.inner.set_options(changes)
+    }
+
+    /// Returns the mutable options in effect
+    pub fn mutable_options(&self) -> MutableOptions {
+        self.inner.mutable_options()
+    }
+
     /// Returns true if the given snapshot is removed
     #[must_use]
     pub fn release_snapshot(&self, s: Arc<Snapshot>) -> bool {
@@ -198,6 +458,71 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
         vset.snapshots.release(s)
     }
 
+    /// Returns the number of files and the total file size of every level in the current version
+    pub fn level_file_stats(&self) -> Vec<(usize, u64)> {
+        let current = self.inner.versions.lock().unwrap().current();
+        current
+            .files
+            .iter()
+            .map(|files| (files.len(), total_file_size(files)))
+            .collect()
+    }
+
+    /// Returns the number of files in every level of the current version like `files[ 2 5 0 ]`
+    pub fn level_summary(&self) -> String {
+        self.inner
+            .versions
+            .lock()
+            .unwrap()
+            .current()
+            .level_summary()
+    }
+
+    /// Returns the level and the metadata of every sst file in the current version
+    pub fn live_files(&self) -> Vec<(usize, Arc<FileMetaData>)> {
+        let current = self.inner.versions.lock().unwrap().current();
+        current
+            .files
+            .iter()
+            .enumerate()
+            .flat_map(|(level, files)| files.iter().map(move |f| (level, f.clone())))
+            .collect()
+    }
+
+    /// Force the current memtable to be flushed into an sst file even if it is not full
+    pub fn flush_memtable(&self) -> TemplateResult<()> {
+        self.inner.force_compact_mem_table()
+    }
+
+    /// Returns the approximate file system space used by the keys in `[start, end)`
+    pub fn get_approximate_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        self.inner.get_approximate_size(start, end)
+    }
+
+    /// Returns the memory used by the memtable and the immutable memtable being flushed
+    pub fn memtable_memory_usage(&self) -> (usize, usize) {
+        let mem = self.inner.mem.read().unwrap().approximate_memory_usage();
+        let im_mem = self
+            .inner
+            .im_mem
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |m| m.approximate_memory_usage());
+        (mem, im_mem)
+    }
+
+    /// Returns the total size of the WAL files in the db directory, the retained ones included
+    pub fn wal_bytes(&self) -> TemplateResult<u64> {
+        let mut total = 0;
+        for file in self.inner.env.list(&self.inner.db_path)? {
+            if let Some((FileType::Log, _)) = parse_filename(&file) {
+                total += self.inner.env.open(&file)?.len()?;
+            }
+        }
+        Ok(total)
+    }
+
     // The thread take batches from the queue and apples them into memtable and WAL.
     //
     // Steps:
@@ -211,104 +536,71 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
         let db = self.inner.clone();
         let shutdown = self.shutdown_batch_processing_thread.0.clone();
         thread::Builder::new().name("batch process".to_owned()).spawn(move || {
-            loop {
-                if db.is_shutting_down.load(Ordering::Acquire) {
-                    // Cleanup all the batch queue
-                    let mut queue = db.batch_queue.lock().unwrap();
-                    while let Some(batch) = queue.pop_front() {
-                        let _ = batch.signal.send(Err(TemplateKVError::DBClosed(
-                            "DB is closing. Clean up all the batch in queue".to_owned(),
-                        )));
-                    }
-                    break;
-                }
-                let first = {
-                    let mut queue = db.batch_queue.lock().unwrap();
-                    while queue.is_empty() {
-                        // yields current thread and unlock queue
-                        queue = db.process_batch_sem.wait(queue).unwrap();
+            thread::scope(|scope| {
+                // In pipelined mode the groups are inserted into the memtable by another thread
+                // while this thread goes on with the WAL of the next group
+                let mem_writer = db.options.enable_pipelined_write.then(|| {
+                    let (send, recv) = crossbeam_channel::bounded::<MemTableWrite>(0);
+                    let db = &db;
+                    scope.spawn(move || {
+                        while let Ok(mut write) = recv.recv() {
+                            let res = db.insert_into_mem(
+                                &write.grouped,
+                                mem::take(&mut write.members),
+                                &write.signals,
+                            );
+                            db.finish_mem_write(write, res);
+                        }
+                    });
+                    send
+                });
+                // The last sequence allocated to a group, which might be not visible yet
+                let mut last_allocated = 0;
+                loop {
+                    if db.is_shutting_down.load(Ordering::Acquire) {
+                        // Cleanup all the batch queue
+                        let mut queue = db.batch_queue.lock().unwrap();
+                        while let Some(batch) = queue.pop_front() {
+                            let _ = batch.signal.send(Err(TemplateKVError::DBClosed(
+                                "DB is closing. Clean up all the batch in queue".to_owned(),
+                            )));
+                        }
+                        break;
                     }
-                    queue.pop_front().unwrap()
-                };
-                if first.stop_process {
-                    break;
-                }
-                let force = first.force_mem_compaction;
-                match db.make_room_for_write(force) {
-                    Ok(mut versions) => {
-                        let (mut grouped, signals) = db.group_batches(first);
-                        if !grouped.batch.is_empty() {
-                            let mut last_seq = versions.last_sequence();
-                            grouped.batch.set_sequence(last_seq + 1);
-                            last_seq += u64::from(grouped.batch.get_count());
-                            // `record_writer` must be initialized here
-                            let writer = versions.record_writer.as_mut().unwrap();
-                            let mut res = writer.add_record(grouped.batch.data());
-                            let mut sync_err = false;
-                            if res.is_ok() && grouped.options.sync {
-                                res = writer.sync();
-                                if res.is_err() {
-                                    sync_err = true;
-                                }
-                            }
-                            if res.is_ok() {
-                                let memtable = db.mem.read().unwrap();
-                                // Might encounter corruption err here
-                                res = grouped.batch.insert_into(&*memtable);
-                            }
-                            match res {
-                                Ok(()) => {
-                                    for signal in signals {
-                                        if let Err(e) = signal.send(Ok(())) {
-                                            error!(
-                                                "[process batch] Fail sending finshing signal to waiting batch: {}", e
-                                            );
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("[process batch] write batch failed: {}", e);
-                                    for signal in signals {
-                                        if let Err(e) = signal.send(Err(TemplateKVError::Customized(
-                                            "[process batch] write batch failed".to_owned(),
-                                        ))) {
-                                            error!(
-                                                "[process batch] Fail sending finshing signal to waiting batch: {}", e
-                                            )
-                                        }
-                                    }
-                                    if sync_err {
-                                        // The state of the log file is indeterminate: the log record we
-                                        // just added may or may not show up when the DB is re-opened.
-                                        // So we force the DB into a mode where all future writes fail.
-                                        db.record_bg_error(e);
-                                    }
-                                }
-                            }
-                            versions.set_last_sequence(last_seq);
-                        } else {
-                            // Notify waiting batches
-                            for signal in signals {
-                                if let Err(e) = signal.send(Ok(())) {
-                                    error!(
-                                        "[process batch] Fail sending finishing signal to waiting batch: {}", e
-                                    );
-                                }
-                            }
+                    let first = {
+                        let mut queue = db.batch_queue.lock().unwrap();
+                        while queue.is_empty() {
+                            // yields current thread and unlock queue
+                            queue = db.process_batch_sem.wait(queue).unwrap();
                         }
+                        queue.pop_front().unwrap()
+                    };
+                    if first.stop_process {
+                        break;
                     }
-                    Err(e) => {
-                        if let Err(e) = first.signal.send(Err(TemplateKVError::Customized(format!(
-                            "[process batch] TemplateKVError making room for write requests: {}",
-                            e
-                        )))) {
-                            error!(
-                                "[process batch] fail to send finishing signal to waiting batch: {}", e
-                            );
+                    let force = first.force_mem_compaction;
+                    match db.make_room_for_write(force) {
+                        Ok(versions) => db.write_group(
+                            versions,
+                            first,
+                            &mut last_allocated,
+                            mem_writer.as_ref(),
+                        ),
+                        Err(e) => {
+                            if let Err(e) = first.signal.send(Err(TemplateKVError::Customized(format!(
+                                "[process batch] TemplateKVError making room for write requests: {}",
+                                e
+                            )))) {
+                                error!(
+                                    "[process batch] fail to send finishing signal to waiting batch: {}", e
+                                );
+                            }
                         }
                     }
                 }
-            }
+                // Stop the memtable writer after it finishes the pending groups
+                drop(mem_writer);
+            });
             shutdown.send(()).unwrap();
             info!("batch processing thread shut down");
         }).unwrap();
@@ -323,7 +615,29 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
             .name("compaction".to_owned())
             .spawn(move || {
                 let mut done_compaction = false;
-                while let Ok(()) = db.do_compaction.1.recv() {
+                // Wake up regularly to find the files aged past `periodic_compaction_seconds`
+                let tick = db
+                    .options
+                    .periodic_compaction_seconds
+                    .map(|secs| Duration::from_secs((secs / 10).max(1)));
+                loop {
+                    let received = match tick {
+                        Some(tick) => db.do_compaction.1.recv_timeout(tick),
+                        None => db
+                            .do_compaction
+                            .1
+                            .recv()
+                            .map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match received {
+                        Ok(()) => {}
+                        Err(RecvTimeoutError::Timeout) => {
+                            let current = db.versions.lock().unwrap().current();
+                            db.maybe_schedule_compaction(current);
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
                     if db.is_shutting_down.load(Ordering::Acquire) {
                         // No more background work when shutting down
                         break;
@@ -349,6 +663,53 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
             .unwrap();
     }
 
+    // Verify the checksums of the whole db once per `interval` at the rate limited by
+    // `background_scrub_bytes_per_second`.
+    // A corruption found is recorded as a background error so that later writes fail.
+    fn process_scrub(&self, interval: Duration) {
+        let db = self.inner.clone();
+        let shutdown = self.shutdown_scrub_thread.0.clone();
+        thread::Builder::new()
+            .name("scrub".to_owned())
+            .spawn(move || {
+                loop {
+                    match db.do_scrub.1.recv_timeout(interval) {
+                        Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    if db.is_shutting_down.load(Ordering::Acquire) {
+                        break;
+                    } else if db.has_bg_error() {
+                        // No more background work after a background error
+                        continue;
+                    }
+                    let throttle = ScrubThrottle::new(db.options.background_scrub_bytes_per_second);
+                    match db.verify_checksums(Some(throttle)) {
+                        Ok(report) => {
+                            if report.is_ok() {
+                                info!("[scrub] {}", report);
+                            } else {
+                                error!("[scrub] corruption found: {}", report);
+                                db.record_bg_error(
+                                    BackgroundErrorReason::Scrub,
+                                    TemplateKVError::Corruption(report.to_string()),
+                                );
+                            }
+                            // Recorded after the background error so that it is visible to
+                            // whoever sees the corruption in the statistics
+                            if let Some(stats) = &db.options.statistics {
+                                stats.record_scrub(report.bytes_checked, report.corruptions.len());
+                            }
+                        }
+                        Err(e) => warn!("[scrub] verification aborted: {}", e),
+                    }
+                }
+                shutdown.send(()).unwrap();
+                info!("scrub thread shut down");
+            })
+            .unwrap();
+    }
+
     pub fn internal_iter(&self, read_opt: ReadOptions) -> TemplateResult<InternalIterator<S, C>> {
         let mut mem_iters = vec![self.inner.mem.read().unwrap().iter()];
         if let Some(im_mem) = self.inner.im_mem.read().unwrap().as_ref() {
@@ -369,6 +730,16 @@ impl<S: Storage + Clone, C: Comparator + 'static> TemplateDB<S, C> {
     }
 }
 
+// How a `DBImpl` accesses the db directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AccessMode {
+    // Owns the db directory by the `LOCK` file
+    Primary,
+    ReadOnly,
+    // Read-only but able to catch up with the primary
+    Secondary,
+}
+
 pub struct DBImpl<S: Storage + Clone, C: Comparator> {
     pub env: S,
     pub internal_comparator: InternalKeyComparator<C>,
@@ -382,6 +753,8 @@ pub struct DBImpl<S: Storage + Clone, C: Comparator> {
      */
     batch_queue: Mutex<VecDeque<BatchTask>>,
     pub process_batch_sem: Condvar,
+    // The number of the groups waiting for the memtable writer in the pipelined write mode
+    pending_mem_writes: AtomicUsize,
 
     // the table cache
     pub table_cache: TableCache<S, C>,
@@ -399,6 +772,13 @@ pub struct DBImpl<S: Storage + Clone, C: Comparator> {
     pub background_compaction_scheduled: AtomicBool,
     // signal of schedule a compaction
     pub do_compaction: (Sender<()>, Receiver<()>),
+    // signal of waking up the background scrubber
+    pub do_scrub: (Sender<()>, Receiver<()>),
+    // The number of the latest OPTIONS file
+    options_file_number: AtomicU64,
+    // The mutable options in effect, which start from `options` and are changed by
+    // `set_options`. The version set keeps a copy for picking and running compactions.
+    mutable_options: RwLock<MutableOptions>,
     // Though Memtable is thread safe with multiple readers and single writers and
     // all relative methods are using immutable borrowing,
     // we still need to mutate the field `mem` and `im_mem` in some situations.
@@ -407,8 +787,13 @@ pub struct DBImpl<S: Storage + Clone, C: Comparator> {
     pub im_mem: ShardedLock<Option<MemTable<C>>>,
     // Have we encountered a background error in paranoid mode
     pub bg_error: RwLock<Option<TemplateKVError>>,
+    // How the writes are throttled now
+    write_stall: Mutex<WriteStallCondition>,
     // Whether the db is closing
     pub is_shutting_down: AtomicBool,
+    access_mode: AccessMode,
+    // The live watchers of the changes
+    pub(crate) watch_hub: WatchHub,
 }
 
 impl<S: Storage + Clone, C: Comparator> Drop for DBImpl<S, C> {
@@ -423,6 +808,7 @@ impl<S: Storage + Clone, C: Comparator> Drop for DBImpl<S, C> {
 impl<S: Storage + Clone, C: Comparator> DBImpl<S, C> {
     fn close(&self) -> TemplateResult<()> {
         self.is_shutting_down.store(true, Ordering::Release);
+        self.watch_hub.close();
         match &self.db_lock {
             Some(lock) => lock.unlock(),
             None => Ok(()),
@@ -431,7 +817,7 @@ impl<S: Storage + Clone, C: Comparator> DBImpl<S, C> {
 }
 
 impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
-    fn new(options: Options<C>, db_path: String, storage: S) -> Self {
+    fn new(options: Options<C>, db_path: String, storage: S, access_mode: AccessMode) -> Self {
         let o = Arc::new(options);
         let icmp = InternalKeyComparator::new(o.comparator.clone());
         Self {
@@ -442,6 +828,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             db_lock: None,
             batch_queue: Mutex::new(VecDeque::new()),
             process_batch_sem: Condvar::new(),
+            pending_mem_writes: AtomicUsize::new(0),
             table_cache: TableCache::new(
                 db_path.clone(),
                 o.clone(),
@@ -453,16 +840,39 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             background_work_finished_signal: Condvar::new(),
             background_compaction_scheduled: AtomicBool::new(false),
             do_compaction: crossbeam_channel::unbounded(),
+            do_scrub: crossbeam_channel::unbounded(),
+            options_file_number: AtomicU64::new(0),
+            mutable_options: RwLock::new(MutableOptions::from(o.as_ref())),
             mem: ShardedLock::new(MemTable::new(o.write_buffer_size, icmp)),
             im_mem: ShardedLock::new(None),
             bg_error: RwLock::new(None),
+            write_stall: Mutex::new(WriteStallCondition::Normal),
             is_shutting_down: AtomicBool::new(false),
+            access_mode,
+            watch_hub: WatchHub::default(),
         }
     }
     fn snapshot(&self) -> Arc<Snapshot> {
         self.versions.lock().unwrap().new_snapshot()
     }
 
+    // Run the reads in `f` with a snapshot acquired if `options` does not specify one, so that
+    // they share the same view of db
+    fn with_pinned_snapshot<T, F: FnOnce(ReadOptions) -> T>(
+        &self,
+        mut options: ReadOptions,
+        f: F,
+    ) -> T {
+        if options.snapshot.is_some() {
+            return f(options);
+        }
+        let snapshot = self.snapshot();
+        options.snapshot = Some(*snapshot);
+        let res = f(options);
+        self.versions.lock().unwrap().snapshots.release(snapshot);
+        res
+    }
+
     fn get(&self, options: ReadOptions, key: &[u8]) -> TemplateResult<Option<Vec<u8>>> {
         if self.is_shutting_down.load(Ordering::Acquire) {
             return Err(TemplateKVError::DBClosed("get request".to_owned()));
@@ -574,8 +984,13 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         let mut expected_files = versions.live_files();
         let all_files = self.env.list(&self.db_path)?;
         let mut logs_to_recover = vec![];
+        let mut latest_options = None;
         for filename in all_files {
             if let Some((file_type, file_number)) = parse_filename(filename) {
+                if file_type == FileType::Options {
+                    latest_options = latest_options.max(Some(file_number));
+                    continue;
+                }
                 expected_files.remove(&file_number);
                 if file_type == FileType::Log && (file_number >= min_log || file_number == prev_log)
                 {
@@ -589,6 +1004,11 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                 expected_files
             )));
         }
+        if let Some(number) = latest_options {
+            self.check_options_file(number)?;
+            self.options_file_number.store(number, Ordering::Release);
+            versions.mark_file_number_used(number);
+        }
 
         // Recover in the order in which the logs were generated
         logs_to_recover.sort_unstable();
@@ -666,7 +1086,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             }
             if mem.is_none() {
                 mem = Some(MemTable::new(
-                    self.options.write_buffer_size,
+                    self.mutable_options().write_buffer_size,
                     self.internal_comparator.clone(),
                 ));
             }
@@ -683,7 +1103,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             if last_seq > max_sequence {
                 max_sequence = last_seq;
             }
-            if mem_ref.approximate_memory_usage() > self.options.write_buffer_size {
+            if mem_ref.approximate_memory_usage() > mem_ref.capacity() {
                 need_compaction = true;
                 *save_manifest = true;
                 let mut iter = mem_ref.iter();
@@ -712,7 +1132,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                 mem = None;
             } else {
                 *self.mem.write().unwrap() = MemTable::new(
-                    self.options.write_buffer_size,
+                    self.mutable_options().write_buffer_size,
                     self.internal_comparator.clone(),
                 );
             }
@@ -732,6 +1152,182 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         Ok(max_sequence)
     }
 
+    // Checks whether the db could be opened with current options against the OPTIONS file
+    fn check_options_file(&self, number: u64) -> TemplateResult<()> {
+        let file_name = generate_filename(&self.db_path, FileType::Options, number);
+        let mut file = self.env.open(&file_name)?;
+        let mut buf = vec![];
+        file.read_all(&mut buf)?;
+        match String::from_utf8(buf) {
+            Ok(content) => self.options.check_options_file(&content),
+            Err(e) => Err(TemplateKVError::Corruption(format!(
+                "Invalid OPTIONS file {} content: {}",
+                file_name, e
+            ))),
+        }
+    }
+
+    // Returns `NotSupported` if the db is not opened as the primary
+    fn check_writable(&self, op: &str) -> TemplateResult<()> {
+        if self.access_mode == AccessMode::Primary {
+            Ok(())
+        } else {
+            Err(TemplateKVError::NotSupported(format!(
+                "{} on a db opened in {:?} mode",
+                op, self.access_mode
+            )))
+        }
+    }
+
+    // Loads the latest state written by the primary without modifying the db directory.
+    // Used by the read-only and secondary instances.
+    fn catch_up_with_primary(&self) -> TemplateResult<()> {
+        if self.is_shutting_down.load(Ordering::Acquire) {
+            return Err(TemplateKVError::DBClosed(
+                "catch up with primary".to_owned(),
+            ));
+        }
+        let mut versions = self.versions.lock().unwrap();
+        if versions.catch_up_with_manifest()? {
+            debug!(
+                "Caught up with MANIFEST: {}",
+                versions.current().level_summary()
+            );
+        }
+        let (mem, max_sequence) = self.replay_logs_read_only(&versions)?;
+        *self.mem.write().unwrap() = mem;
+        if versions.last_sequence() < max_sequence {
+            versions.set_last_sequence(max_sequence);
+        }
+        Ok(())
+    }
+
+    // Replays all the WAL files not yet compacted into a new memtable without writing anything.
+    // Returns the memtable and the last sequence in the WAL files.
+    fn replay_logs_read_only(
+        &self,
+        versions: &VersionSet<S, C>,
+    ) -> TemplateResult<(MemTable<C>, u64)> {
+        let min_log = versions.log_number();
+        let prev_log = versions.prev_log_number();
+        let mut logs = vec![];
+        for filename in self.env.list(&self.db_path)? {
+            if let Some((FileType::Log, number)) = parse_filename(filename) {
+                if number >= min_log || number == prev_log {
+                    logs.push(number);
+                }
+            }
+        }
+        logs.sort_unstable();
+        // Collect all the batches first to make the memtable large enough since we are not
+        // able to flush it into level 0
+        let mut batches = vec![];
+        let mut entries = 0;
+        let mut max_sequence = 0;
+        for number in logs {
+            let file_name = generate_filename(&self.db_path, FileType::Log, number);
+            let mut log_file = match self.env.open(&file_name) {
+                Ok(f) => f,
+                Err(e) => {
+                    if self.options.paranoid_checks {
+                        return Err(e);
+                    }
+                    info!("ignore errors when replaying log file : {:?}", e);
+                    continue;
+                }
+            };
+            // The handle might share the reading position with the writer of primary
+            log_file.seek(SeekFrom::Start(0))?;
+            let reporter = LogReporter::new();
+            let mut reader = Reader::new(log_file, Some(Box::new(reporter.clone())), true, 0);
+            let mut record_buf = vec![];
+            while reader.read_record(&mut record_buf) {
+                reporter.result()?;
+                if record_buf.len() < HEADER_SIZE {
+                    return Err(TemplateKVError::Corruption(
+                        "log record too small".to_owned(),
+                    ));
+                }
+                let mut batch = WriteBatch::default();
+                batch.set_contents(&mut record_buf);
+                let last_seq = batch.get_sequence() + u64::from(batch.get_count()) - 1;
+                if last_seq > max_sequence {
+                    max_sequence = last_seq;
+                }
+                entries += batch.get_count() as usize;
+                batches.push(batch);
+            }
+        }
+        let mem = MemTable::new(
+            self.mutable_options().write_buffer_size + entries * MAX_NODE_SIZE,
+            self.internal_comparator.clone(),
+        );
+        for batch in batches {
+            if let Err(e) = batch.insert_into(&mem) {
+                if self.options.paranoid_checks {
+                    return Err(e);
+                }
+                info!("ignore errors when replaying log file : {:?}", e);
+            }
+        }
+        Ok((mem, max_sequence))
+    }
+
+    // Persists the options with the given mutable ones into a new OPTIONS file and removes the
+    // previous one.
+    // The file is written to a temp file first and renamed so that a crash never leaves a
+    // partial OPTIONS file behind.
+    fn write_options_file(
+        &self,
+        versions: &mut VersionSet<S, C>,
+        mutable: &MutableOptions,
+    ) -> TemplateResult<()> {
+        let number = versions.inc_next_file_number();
+        let tmp = generate_filename(&self.db_path, FileType::Temp, number);
+        let content = self.options.encode_options_file(mutable);
+        let result = do_write_string_to_file(&self.env, content, &tmp, true);
+        match &result {
+            Ok(()) => self.env.rename(
+                &tmp,
+                &generate_filename(&self.db_path, FileType::Options, number),
+            )?,
+            Err(_) => self.env.remove(&tmp)?,
+        }
+        result?;
+        let previous = self.options_file_number.swap(number, Ordering::AcqRel);
+        if previous != 0 && previous != number {
+            let file_name = generate_filename(&self.db_path, FileType::Options, previous);
+            // ignore the IO error here
+            if let Err(e) = self.env.remove(&file_name) {
+                error!("Delete file failed [filename {:?}]: {:?}", &file_name, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_options(&self, changes: &[(&str, &str)]) -> TemplateResult<()> {
+        if self.is_shutting_down.load(Ordering::Acquire) {
+            return Err(TemplateKVError::DBClosed("set_options".to_owned()));
+        }
+        self.check_writable("set_options")?;
+        let mut versions = self.versions.lock().unwrap();
+        let mut mutable = self.mutable_options();
+        for (name, value) in changes {
+            mutable.apply(name, value)?;
+        }
+        mutable.validate()?;
+        mutable.sanitize();
+        // Only publish the changes once they are persisted so that a failed write leaves the
+        // options in effect untouched
+        self.write_options_file(&mut versions, &mutable)?;
+        *self.mutable_options.write().unwrap() = mutable;
+        versions.set_mutable_options(mutable);
+        info!("Options changed: {:?}", mutable);
+        // Wake up the writers waiting for a room since the thresholds might be raised
+        self.background_work_finished_signal.notify_all();
+        Ok(())
+    }
+
     // Delete any unneeded files and stale in-memory entries.
     // This func could delete generated compaction files when the compaction is failed due some
     // reasons (e.g. block entry currupted)
@@ -742,13 +1338,33 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         versions.lock_live_files();
         // ignore IO error on purpose
         let files = self.env.list(&self.db_path)?;
+        // The latest obsolete logs are retained for the watchers to catch up
+        let mut obsolete_logs: Vec<u64> = files
+            .iter()
+            .filter_map(|file| match parse_filename(file) {
+                Some((FileType::Log, number))
+                    if number < versions.log_number() && number != versions.prev_log_number() =>
+                {
+                    Some(number)
+                }
+                _ => None,
+            })
+            .collect();
+        obsolete_logs.sort_unstable();
+        let retained_logs = &obsolete_logs[obsolete_logs
+            .len()
+            .saturating_sub(self.options.max_retained_wal_files)..];
         for file in &files {
             if let Some((file_type, number)) = parse_filename(file) {
                 let keep = match file_type {
                     FileType::Log => {
-                        number >= versions.log_number() || number == versions.prev_log_number()
+                        number >= versions.log_number()
+                            || number == versions.prev_log_number()
+                            || retained_logs.contains(&number)
+                            || versions.pinned_logs.contains(&number)
                     }
                     FileType::Manifest => number >= versions.manifest_number(),
+                    FileType::Options => number >= self.options_file_number.load(Ordering::Acquire),
                     // Any temp files that are currently being written to must
                     // be recorded in pending_outputs
                     FileType::Table | FileType::Temp => versions.pending_outputs.contains(&number),
@@ -763,9 +1379,17 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                         file_type, number, &file
                     );
                     // ignore the IO error here
-                    if let Err(e) = self.env.remove(file) {
+                    let res = self.env.remove(file);
+                    if let Err(e) = &res {
                         error!("Delete file failed [filename {:?}]: {:?}", &file, e);
                     }
+                    if file_type == FileType::Table {
+                        let info = TableFileDeletionInfo {
+                            db_path: self.db_path.clone(),
+                            file_number: number,
+                        };
+                        self.notify(|l| l.on_table_file_deleted(&info, res.as_ref().err()));
+                    }
                 }
             }
         }
@@ -776,17 +1400,34 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
     // Schedule a WriteBatch to close batch processing thread for gracefully shutting down db
     fn schedule_close_batch(&self) {
         let (send, _) = crossbeam_channel::bounded(0);
-        let task = BatchTask {
+        self.schedule_batch(BatchTask {
             stop_process: true,
             force_mem_compaction: false,
             batch: WriteBatch::default(),
-            signal: send,
+            signal: BatchSignal::Blocking(send),
             options: WriteOptions::default(),
-        };
+        });
+    }
+
+    // Push the task into the queue and wake up the thread in `process_batch`
+    fn schedule_batch(&self, task: BatchTask) {
         self.batch_queue.lock().unwrap().push_back(task);
         self.process_batch_sem.notify_all();
     }
 
+    // Returns false if there is nothing to do with the batch
+    fn should_schedule_batch(
+        &self,
+        batch: &WriteBatch,
+        force_mem_compaction: bool,
+    ) -> TemplateResult<bool> {
+        if self.is_shutting_down.load(Ordering::Acquire) {
+            return Err(TemplateKVError::DBClosed("schedule WriteBatch".to_owned()));
+        }
+        self.check_writable("schedule WriteBatch")?;
+        Ok(!batch.is_empty() || force_mem_compaction)
+    }
+
     // Schedule the WriteBatch and wait for the result from the receiver.
     // This function wakes up the thread in `process_batch`.
     // An empty `WriteBatch` will trigger a force memtable compaction.
@@ -796,29 +1437,208 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         batch: WriteBatch,
         force_mem_compaction: bool,
     ) -> TemplateResult<()> {
-        if self.is_shutting_down.load(Ordering::Acquire) {
-            return Err(TemplateKVError::DBClosed("schedule WriteBatch".to_owned()));
-        }
-        if batch.is_empty() && !force_mem_compaction {
+        if !self.should_schedule_batch(&batch, force_mem_compaction)? {
             return Ok(());
         }
         let (send, recv) = crossbeam_channel::bounded(0);
-        let task = BatchTask {
+        self.schedule_batch(BatchTask {
             stop_process: false,
             force_mem_compaction,
             batch,
-            signal: send,
+            signal: BatchSignal::Blocking(send),
             options,
+        });
+        loop {
+            match recv.recv() {
+                // Insert the batch into the memtable concurrently with the other writers of the
+                // group, and then wait until the whole group is inserted
+                Ok(BatchMessage::Insert { batch, done }) => {
+                    let _ = done.send(batch.insert_into(&*self.mem.read().unwrap()));
+                }
+                Ok(BatchMessage::Done(res)) => return res,
+                Err(e) => return Err(TemplateKVError::RecvError(e)),
+            }
+        }
+    }
+
+    // Like `schedule_batch_and_wait` but yields the current task instead of blocking the thread
+    // while the batch is waiting in the queue
+    async fn schedule_batch_async(
+        &self,
+        options: WriteOptions,
+        batch: WriteBatch,
+    ) -> TemplateResult<()> {
+        if !self.should_schedule_batch(&batch, false)? {
+            return Ok(());
+        }
+        let (send, recv) = oneshot::channel();
+        self.schedule_batch(BatchTask {
+            stop_process: false,
+            force_mem_compaction: false,
+            batch,
+            signal: BatchSignal::Async(send),
+            options,
+        });
+        recv.await.unwrap_or_else(|_| {
+            Err(TemplateKVError::DBClosed(
+                "batch processing thread stopped".to_owned(),
+            ))
+        })
+    }
+
+    // Writes the WAL record of the group led by `first` and inserts the group into the
+    // memtable, or hands the group over to `mem_writer` in the pipelined write mode.
+    // `last_allocated` is the last sequence allocated to the groups, which might be not
+    // visible yet.
+    fn write_group(
+        &self,
+        mut versions: MutexGuard<VersionSet<S, C>>,
+        first: BatchTask,
+        last_allocated: &mut u64,
+        mem_writer: Option<&Sender<MemTableWrite>>,
+    ) {
+        let (mut grouped, options, signals, members) = self.group_batches(first);
+        if grouped.is_empty() {
+            // Notify waiting batches
+            send_batch_results(signals, &Ok(()));
+            return;
+        }
+        let mut last_seq = versions.last_sequence().max(*last_allocated);
+        grouped.set_sequence(last_seq + 1);
+        last_seq += u64::from(grouped.get_count());
+        *last_allocated = last_seq;
+        // `record_writer` must be initialized here
+        let writer = versions.record_writer.as_mut().unwrap();
+        let mut res = writer.add_record(grouped.data());
+        let mut sync_err = false;
+        if res.is_ok() && options.sync {
+            res = writer.sync();
+            if res.is_err() {
+                sync_err = true;
+            }
+        }
+        let write = MemTableWrite {
+            grouped,
+            members,
+            last_seq,
+            signals,
         };
-        self.batch_queue.lock().unwrap().push_back(task);
-        self.process_batch_sem.notify_all();
-        recv.recv()
-            .unwrap_or_else(|e| Err(TemplateKVError::RecvError(e)))
+        match (res, mem_writer) {
+            (Ok(()), Some(mem_writer)) => {
+                // Let the next group write its WAL
+                self.pending_mem_writes.fetch_add(1, Ordering::AcqRel);
+                drop(versions);
+                if let Err(e) = mem_writer.send(write) {
+                    let res = Err(TemplateKVError::Customized(
+                        "memtable writer stopped".to_owned(),
+                    ));
+                    self.finish_mem_write(e.into_inner(), res);
+                }
+            }
+            (Ok(()), None) => {
+                // Might encounter corruption err here
+                let res = self.insert_into_mem(&write.grouped, write.members, &write.signals);
+                versions.set_last_sequence(last_seq);
+                if res.is_ok() {
+                    self.watch_hub.publish(write.grouped);
+                }
+                drop(versions);
+                send_batch_results(write.signals, &res);
+            }
+            (res, _) => {
+                send_batch_results(write.signals, &res);
+                // Never publish the sequences ahead of a pending group
+                if mem_writer.is_none() {
+                    versions.set_last_sequence(last_seq);
+                }
+                if let (true, Err(e)) = (sync_err, res) {
+                    // The state of the log file is indeterminate: the log record we
+                    // just added may or may not show up when the DB is re-opened.
+                    // So we force the DB into a mode where all future writes fail.
+                    self.record_bg_error(BackgroundErrorReason::WriteSync, e);
+                }
+            }
+        }
+    }
+
+    // Inserts the grouped batches into the current memtable. With
+    // `allow_concurrent_memtable_write` the group is split into its `members` again, and every
+    // blocking writer inserts its own batch while this thread inserts the batches of the async
+    // ones. Returns after all the batches are inserted.
+    fn insert_into_mem(
+        &self,
+        grouped: &WriteBatch,
+        members: Vec<WriteBatch>,
+        signals: &[BatchSignal],
+    ) -> TemplateResult<()> {
+        let memtable = self.mem.read().unwrap();
+        if members.len() <= 1 {
+            return grouped.insert_into(&*memtable);
+        }
+        let (done, results) = crossbeam_channel::unbounded();
+        let mut res = Ok(());
+        let mut sent = 0;
+        let mut seq = grouped.get_sequence();
+        for (mut batch, signal) in members.into_iter().zip(signals) {
+            batch.set_sequence(seq);
+            seq += u64::from(batch.get_count());
+            match signal.insert(batch, &done) {
+                Ok(()) => sent += 1,
+                Err(batch) => res = res.and(batch.insert_into(&*memtable)),
+            }
+        }
+        for _ in 0..sent {
+            let r = results.recv().unwrap_or_else(|_| {
+                Err(TemplateKVError::Customized(
+                    "the inserting writer has gone".to_owned(),
+                ))
+            });
+            res = res.and(r);
+        }
+        res
+    }
+
+    // Publishes the sequences of a group inserted by the memtable writer in the pipelined
+    // write mode and wakes up the writers of the group
+    fn finish_mem_write(&self, write: MemTableWrite, res: TemplateResult<()>) {
+        let MemTableWrite {
+            grouped,
+            last_seq,
+            signals,
+            ..
+        } = write;
+        {
+            let mut versions = self.versions.lock().unwrap();
+            versions.set_last_sequence(last_seq);
+            self.pending_mem_writes.fetch_sub(1, Ordering::AcqRel);
+            if res.is_ok() {
+                self.watch_hub.publish(grouped);
+            }
+        }
+        self.background_work_finished_signal.notify_all();
+        send_batch_results(signals, &res);
+    }
+
+    // Waits until the groups handed over to the memtable writer in the pipelined write mode
+    // are inserted, so that `last_sequence` covers all the WAL records written
+    pub(super) fn wait_for_pending_mem_writes<'a>(
+        &self,
+        mut versions: MutexGuard<'a, VersionSet<S, C>>,
+    ) -> MutexGuard<'a, VersionSet<S, C>> {
+        while self.pending_mem_writes.load(Ordering::Acquire) > 0 {
+            versions = self.background_work_finished_signal.wait(versions).unwrap();
+        }
+        versions
     }
 
     // Group a bunch of batches in the waiting queue
     // This will ignore the task with `force_mem_compaction` after batched
-    fn group_batches(&self, first: BatchTask) -> (BatchTask, Vec<Sender<TemplateResult<()>>>) {
+    // With `allow_concurrent_memtable_write` the grouped batches are returned as well, for
+    // their writers to insert them into the memtable.
+    fn group_batches(
+        &self,
+        first: BatchTask,
+    ) -> (WriteBatch, WriteOptions, Vec<BatchSignal>, Vec<WriteBatch>) {
         let mut size = first.batch.approximate_size();
         // Allow the group to grow up to a maximum size, but if the
         // original write is small, limit the growth so we do not slow
@@ -827,14 +1647,21 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         if size <= 128 << 10 {
             max_size = size + (128 << 10);
         }
-        let mut signals = vec![first.signal.clone()];
-        let mut grouped = first;
+        let BatchTask {
+            batch: mut grouped,
+            options,
+            signal,
+            ..
+        } = first;
+        let mut signals = vec![signal];
+        let concurrent = self.options.allow_concurrent_memtable_write;
+        let mut members = vec![];
 
         let mut queue = self.batch_queue.lock().unwrap();
         // Group several batches from queue
         while !queue.is_empty() {
             let current = queue.pop_front().unwrap();
-            if current.stop_process || (current.options.sync && !grouped.options.sync) {
+            if current.stop_process || (current.options.sync && !options.sync) {
                 // Do not include a stop process batch
                 // Do not include a sync write into a batch handled by a non-sync write.
                 queue.push_front(current);
@@ -843,12 +1670,34 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             size += current.batch.approximate_size();
             if size > max_size {
                 // Do not make batch too big
+                queue.push_front(current);
                 break;
             }
-            grouped.batch.append(current.batch);
-            signals.push(current.signal.clone());
+            if concurrent {
+                if members.is_empty() {
+                    members.push(grouped.clone());
+                }
+                grouped.append(current.batch.clone());
+                members.push(current.batch);
+            } else {
+                grouped.append(current.batch);
+            }
+            signals.push(current.signal);
         }
-        (grouped, signals)
+        (grouped, options, signals, members)
+    }
+
+    /// Returns the mutable options in effect
+    pub(crate) fn mutable_options(&self) -> MutableOptions {
+        *self.mutable_options.read().unwrap()
+    }
+
+    // Returns true if current memtable has not reached `write_buffer_size` yet
+    fn has_room_in_mem(&self) -> bool {
+        let mem = self.mem.read().unwrap();
+        // The memtable might be created before `write_buffer_size` is enlarged
+        mem.approximate_memory_usage()
+            <= self.mutable_options().write_buffer_size.min(mem.capacity())
     }
 
     // Make sure there is enough space in memtable.
@@ -862,8 +1711,10 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             if let Some(e) = self.take_bg_error() {
                 return Err(e);
             } else if allow_delay
-                && versions.level_files_count(0) >= self.options.l0_slowdown_writes_threshold
+                && versions.level_files_count(0)
+                    >= self.mutable_options().l0_slowdown_writes_threshold
             {
+                self.set_write_stall(WriteStallCondition::Delayed);
                 // We are getting close to hitting a hard limit on the number of
                 // L0 files.  Rather than delaying a single write by several
                 // seconds when we hit the hard limit, start delaying each
@@ -872,20 +1723,26 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                 // case it is sharing the same core as the writer.
                 thread::sleep(Duration::from_micros(1000));
                 allow_delay = false; // do not delay a single write more than once
-            } else if !force
-                && self.mem.read().unwrap().approximate_memory_usage()
-                    <= self.options.write_buffer_size
-            {
+            } else if !force && self.has_room_in_mem() {
                 // There is room in current memtable
                 break;
+            } else if self.pending_mem_writes.load(Ordering::Acquire) > 0 {
+                // The pending groups of the pipelined writes must go to the current memtable
+                versions = self.background_work_finished_signal.wait(versions).unwrap();
             } else if self.im_mem.read().unwrap().is_some() {
                 info!("Current memtable full; waiting...",);
+                self.set_write_stall(WriteStallCondition::Stopped);
+                // Retry the flush that failed with the background error taken above
+                self.maybe_schedule_compaction(versions.current());
                 versions = self.background_work_finished_signal.wait(versions).unwrap();
-            } else if versions.level_files_count(0) >= self.options.l0_stop_writes_threshold {
+            } else if versions.level_files_count(0)
+                >= self.mutable_options().l0_stop_writes_threshold
+            {
                 info!(
                     "Too many L0 files {}; waiting...",
                     versions.level_files_count(0)
                 );
+                self.set_write_stall(WriteStallCondition::Stopped);
                 versions = self.background_work_finished_signal.wait(versions).unwrap();
             } else {
                 let new_log_num = versions.get_next_file_number();
@@ -902,7 +1759,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                         let memtable = mem::replace(
                             &mut *mem,
                             MemTable::new(
-                                self.options.write_buffer_size,
+                                self.mutable_options().write_buffer_size,
                                 self.internal_comparator.clone(),
                             ),
                         );
@@ -914,6 +1771,14 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                 self.maybe_schedule_compaction(versions.current());
             }
         }
+        let condition = if versions.level_files_count(0)
+            >= self.mutable_options().l0_slowdown_writes_threshold
+        {
+            WriteStallCondition::Delayed
+        } else {
+            WriteStallCondition::Normal
+        };
+        self.set_write_stall(condition);
         Ok(versions)
     }
 
@@ -923,15 +1788,40 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         let mut versions = self.versions.lock().unwrap();
         let mut edit = VersionEdit::new(self.options.max_levels);
         let mut im_mem = self.im_mem.write().unwrap();
+        let mut info = FlushJobInfo {
+            db_path: self.db_path.clone(),
+            num_entries: im_mem.as_ref().unwrap().len(),
+            output: None,
+        };
+        self.notify(|l| l.on_flush_begin(&info));
         let mut iter = im_mem.as_ref().unwrap().iter();
-        versions.write_level_0_files(
+        if let Err(e) = versions.write_level_0_files(
             &self.db_path,
             &self.table_cache,
             &mut iter,
             &mut edit,
             true,
-        )?;
-        if self.is_shutting_down.load(Ordering::Acquire) {
+        ) {
+            self.notify(|l| l.on_flush_completed(&info, Some(&e)));
+            return Err(e);
+        }
+        info.output = edit
+            .file_delta
+            .new_files
+            .first()
+            .map(|(level, f)| TableFileCreationInfo {
+                db_path: self.db_path.clone(),
+                file_number: f.number,
+                file_size: f.file_size,
+                level: *level,
+                smallest_key: f.smallest.user_key().to_vec(),
+                largest_key: f.largest.user_key().to_vec(),
+                reason: TableFileCreationReason::Flush,
+            });
+        if let Some(output) = &info.output {
+            self.notify(|l| l.on_table_file_created(output));
+        }
+        let res = if self.is_shutting_down.load(Ordering::Acquire) {
             Err(TemplateKVError::DBClosed(
                 "when compacting memory table".to_owned(),
             ))
@@ -940,9 +1830,10 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             edit.log_number = Some(versions.log_number()); // earlier logs no longer needed
             let res = versions.log_and_apply(edit);
             *im_mem = None;
-            self.delete_obsolete_files(versions)?;
-            res
-        }
+            self.delete_obsolete_files(versions).and(res)
+        };
+        self.notify(|l| l.on_flush_completed(&info, res.as_ref().err()));
+        res
     }
 
     // Force current memtable contents(even if the memtable is not full) to be compacted into sst
@@ -998,6 +1889,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         begin: Option<&[u8]>,
         end: Option<&[u8]>,
     ) -> TemplateResult<()> {
+        self.check_writable("manual compaction")?;
         assert!(level + 1 < self.options.max_levels);
         let (sender, finished) = crossbeam_channel::bounded(1);
         {
@@ -1021,10 +1913,18 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
     // Returns true if a compaction is actually scheduled
     fn background_compaction(&self) -> bool {
         if self.im_mem.read().unwrap().is_some() {
-            if let Err(e) = self.compact_mem_table() {
-                warn!("Compact memtable error: {:?}", e);
+            match self.compact_mem_table() {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Compact memtable error: {:?}", e);
+                    // The flush is retried by the next write after it takes the error, since
+                    // `im_mem` is still there
+                    if !self.is_shutting_down.load(Ordering::Acquire) {
+                        self.record_bg_error(BackgroundErrorReason::Flush, e);
+                    }
+                    false
+                }
             }
-            true
         } else {
             let mut versions = self.versions.lock().unwrap();
             let mut is_manual = false;
@@ -1074,21 +1974,36 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                     compaction.inputs.desc_parent_files(),
                     level + 1
                 );
-                if !is_manual && compaction.is_trivial_move() {
+                let is_trivial_move = !is_manual && compaction.is_trivial_move();
+                let mut info = CompactionJobInfo {
+                    db_path: self.db_path.clone(),
+                    reason: compaction.reason,
+                    level,
+                    output_level: level + 1,
+                    input_files: compaction
+                        .inputs
+                        .base
+                        .iter()
+                        .chain(compaction.inputs.parent.iter())
+                        .map(|f| f.number)
+                        .collect(),
+                    output_files: vec![],
+                    input_bytes: compaction.bytes_read(),
+                    output_bytes: 0,
+                    is_trivial_move,
+                };
+                self.notify(|l| l.on_compaction_begin(&info));
+                if is_trivial_move {
                     // just move file to next level
                     let f = compaction.inputs.base.first().unwrap();
                     compaction.edit.delete_file(compaction.level, f.number);
-                    compaction.edit.add_file(
-                        compaction.level + 1,
-                        f.number,
-                        f.file_size,
-                        f.smallest.clone(),
-                        f.largest.clone(),
-                    );
+                    compaction.edit.add_file_meta(compaction.level + 1, f);
                     let res = versions.log_and_apply(compaction.edit);
                     if let Err(e) = res.as_ref() {
                         error!("Compaction error: {}", e);
                     }
+                    info.output_files.push(f.number);
+                    self.notify(|l| l.on_compaction_completed(&info, res.as_ref().err()));
                     let current_summary = versions.current().level_summary();
                     info!(
                         "Moved #{} to level-{} {} bytes, current level summary: {}",
@@ -1116,8 +2031,9 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                     }
                     // Unlock VersionSet here to avoid dead lock
                     mem::drop(versions);
-                    match self.do_compaction(compaction) {
+                    match self.do_compaction(compaction, &mut info) {
                         Ok(versions) => {
+                            self.notify(|l| l.on_compaction_completed(&info, None));
                             let res = self.delete_obsolete_files(versions);
                             if let Some(done) = done {
                                 done.send(res).unwrap();
@@ -1129,6 +2045,12 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                                 let _ = self.delete_obsolete_files(versions);
                             }
                             error!("Compaction error: {:?}", &e);
+                            self.notify(|l| l.on_compaction_completed(&info, Some(&e)));
+                            if !self.is_shutting_down.load(Ordering::Acquire) {
+                                self.notify(|l| {
+                                    l.on_background_error(BackgroundErrorReason::Compaction, &e)
+                                });
+                            }
                             if let Some(done) = done {
                                 done.send(Err(e)).unwrap();
                             }
@@ -1146,6 +2068,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
     fn do_compaction(
         &self,
         mut c: Compaction<S::F, C>,
+        info: &mut CompactionJobInfo,
     ) -> TemplateResult<MutexGuard<VersionSet<S, C>>> {
         let now = Instant::now();
         let mut input_iter =
@@ -1213,9 +2136,10 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                         // Keep updating the largest
                         c.outputs[last].largest = InternalKey::decoded_from(ikey);
                         c.builder.as_mut().unwrap().add(ikey, input_iter.value())?;
+                        c.outputs[last].properties.add(ikey);
                         let builder = c.builder.as_ref().unwrap();
                         // Rotate a new output file if the current one is big enough
-                        if builder.file_size() >= self.options.max_file_size {
+                        if builder.file_size() >= self.mutable_options().max_file_size {
                             self.finish_output_file(&mut c, input_iter.status())?;
                         }
                     }
@@ -1237,15 +2161,17 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         if let Some(builder) = c.builder.as_mut() {
             builder.close()
         }
-        info!(
-            "Compactions stats for Level{}: {:?}",
-            c.level,
-            CompactionStats {
-                micros: now.elapsed().as_micros() as u64 - mem_compaction_duration,
-                bytes_read: c.bytes_read(),
-                bytes_written: c.bytes_written(),
-            }
-        );
+        let stats = CompactionStats {
+            micros: now.elapsed().as_micros() as u64 - mem_compaction_duration,
+            bytes_read: c.bytes_read(),
+            bytes_written: c.bytes_written(),
+        };
+        info!("Compactions stats for Level{}: {:?}", c.level, stats);
+        if let Some(statistics) = &self.options.statistics {
+            statistics.record_compaction(c.level + 1, &stats);
+        }
+        info.output_files = c.outputs.iter().map(|f| f.number).collect();
+        info.output_bytes = c.total_bytes;
         let mut versions = self.versions.lock().unwrap();
         for output in c.outputs.iter() {
             versions.pending_outputs.remove(&output.number);
@@ -1266,15 +2192,155 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
         Ok(versions)
     }
 
-    // Replace the `bg_error` with new `TemplateKVError` if it's `None`
-    fn record_bg_error(&self, e: TemplateKVError) {
-        if !self.has_bg_error() {
-            let mut x = self.bg_error.write().unwrap();
+    // Verify the checksums of all the live sst files and the retained WAL files.
+    // Corruptions are collected into the report instead of failing fast. Returns an error only
+    // if the db is closed or the db directory is not listable.
+    fn verify_checksums(
+        &self,
+        mut throttle: Option<ScrubThrottle>,
+    ) -> TemplateResult<ChecksumReport> {
+        if self.is_shutting_down.load(Ordering::Acquire) {
+            return Err(TemplateKVError::DBClosed("verify checksums".to_owned()));
+        }
+        let (current, min_log, prev_log) = {
+            let versions = self.versions.lock().unwrap();
+            (
+                versions.current(),
+                versions.log_number(),
+                versions.prev_log_number(),
+            )
+        };
+        let mut report = ChecksumReport::default();
+        let mut bytes_checked = 0;
+        let mut on_read = |bytes: u64| -> TemplateResult<()> {
+            bytes_checked += bytes;
+            if let Some(t) = throttle.as_mut() {
+                if !t.consume(bytes, || self.is_shutting_down.load(Ordering::Acquire)) {
+                    return Err(TemplateKVError::DBClosed("verify checksums".to_owned()));
+                }
+            }
+            Ok(())
+        };
+        for (level, files) in current.files.iter().enumerate() {
+            for f in files.iter() {
+                let file_name = generate_filename(&self.db_path, FileType::Table, f.number);
+                let res = self
+                    .env
+                    .open(file_name.as_str())
+                    .and_then(|file| verify_table(&file, f.file_size, &mut on_read));
+                report.tables_checked += 1;
+                match res {
+                    Ok(blocks) => report.blocks_checked += blocks,
+                    Err(TemplateKVError::DBClosed(hint)) => {
+                        return Err(TemplateKVError::DBClosed(hint))
+                    }
+                    Err(e) => report.corruptions.push(CorruptedFile {
+                        file_type: FileType::Table,
+                        number: f.number,
+                        level: Some(level),
+                        reason: e.to_string(),
+                    }),
+                }
+            }
+        }
+        mem::drop(current);
+
+        let mut logs = vec![];
+        for filename in self.env.list(&self.db_path)? {
+            if let Some((FileType::Log, number)) = parse_filename(filename) {
+                if number >= min_log || number == prev_log {
+                    logs.push(number);
+                }
+            }
+        }
+        logs.sort_unstable();
+        for log_number in logs {
+            report.logs_checked += 1;
+            match self.verify_log_file(log_number, &mut on_read) {
+                Ok(records) => report.records_checked += records,
+                Err(TemplateKVError::DBClosed(hint)) => {
+                    return Err(TemplateKVError::DBClosed(hint))
+                }
+                Err(e) => report.corruptions.push(CorruptedFile {
+                    file_type: FileType::Log,
+                    number: log_number,
+                    level: None,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        report.bytes_checked = bytes_checked;
+        Ok(report)
+    }
+
+    // Read all the records in the given log file with checksum verification.
+    // Returns the number of records read.
+    fn verify_log_file(
+        &self,
+        log_number: u64,
+        on_read: &mut dyn FnMut(u64) -> TemplateResult<()>,
+    ) -> TemplateResult<usize> {
+        let file_name = generate_filename(&self.db_path, FileType::Log, log_number);
+        let mut log_file = self.env.open(file_name.as_str())?;
+        // The file handles might share the reading position (e.g. `MemStorage`) so always read
+        // from the beginning and reset the position when finished
+        log_file.seek(SeekFrom::Start(0))?;
+        let reporter = LogReporter::new();
+        let mut reader = Reader::new(log_file, Some(Box::new(reporter.clone())), true, 0);
+        let mut record_buf = vec![];
+        let mut records = 0;
+        let mut res = Ok(());
+        while reader.read_record(&mut record_buf) {
+            res = reporter.result().and_then(|_| {
+                if record_buf.len() < HEADER_SIZE {
+                    return Err(TemplateKVError::Corruption(
+                        "log record too small".to_owned(),
+                    ));
+                }
+                on_read(record_buf.len() as u64)
+            });
+            if res.is_err() {
+                break;
+            }
+            records += 1;
+        }
+        let _ = reader.into_file().seek(SeekFrom::Start(0));
+        res.and_then(|_| reporter.result()).map(|_| records)
+    }
+
+    // Replace the `bg_error` with new `TemplateKVError` if it's `None`.
+    // The listeners are only notified of the error actually recorded.
+    fn record_bg_error(&self, reason: BackgroundErrorReason, e: TemplateKVError) {
+        let mut x = self.bg_error.write().unwrap();
+        if x.is_none() {
+            self.notify(|l| l.on_background_error(reason, &e));
             *x = Some(e);
+            drop(x);
             self.background_work_finished_signal.notify_all();
         }
     }
 
+    // Calls `f` with every `EventListener` registered
+    fn notify<F: Fn(&dyn EventListener)>(&self, f: F) {
+        for listener in self.options.listeners.iter() {
+            f(listener.as_ref());
+        }
+    }
+
+    // Notifies the listeners if the write stall condition changes
+    fn set_write_stall(&self, condition: WriteStallCondition) {
+        let mut current = self.write_stall.lock().unwrap();
+        if *current != condition {
+            let info = WriteStallInfo {
+                db_path: self.db_path.clone(),
+                previous: *current,
+                current: condition,
+            };
+            *current = condition;
+            self.notify(|l| l.on_stall_conditions_changed(&info));
+        }
+    }
+
     fn take_bg_error(&self) -> Option<TemplateKVError> {
         self.bg_error.write().unwrap().take()
     }
@@ -1294,6 +2360,8 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
             // Already scheduled
             || self.is_shutting_down.load(Ordering::Acquire)
             // DB is being shutting down
+            || self.access_mode != AccessMode::Primary
+            // Never compact a db not owned
             || self.has_bg_error()
             // Got err
             || (self.im_mem.read().unwrap().is_none()
@@ -1351,6 +2419,16 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
                 f.smallest,
                 f.largest,
             );
+            let info = TableFileCreationInfo {
+                db_path: self.db_path.clone(),
+                file_number: f.number,
+                file_size: f.file_size,
+                level: c.level + 1,
+                smallest_key: f.smallest.user_key().to_vec(),
+                largest_key: f.largest.user_key().to_vec(),
+                reason: TableFileCreationReason::Compaction,
+            };
+            self.notify(|l| l.on_table_file_created(&info));
         }
         status
     }
@@ -1382,10 +2460,94 @@ struct BatchTask {
     stop_process: bool,
     force_mem_compaction: bool,
     batch: WriteBatch,
-    signal: Sender<TemplateResult<()>>,
+    signal: BatchSignal,
     options: WriteOptions,
 }
 
+// Tells the writer of a `BatchTask` the result of writing
+enum BatchSignal {
+    Blocking(Sender<BatchMessage>),
+    Async(oneshot::Sender<TemplateResult<()>>),
+}
+
+// What the batch processing thread tells a blocking writer
+enum BatchMessage {
+    // Insert `batch` into the current memtable and send the result to `done`
+    Insert {
+        batch: WriteBatch,
+        done: Sender<TemplateResult<()>>,
+    },
+    Done(TemplateResult<()>),
+}
+
+impl BatchSignal {
+    fn send(self, res: TemplateResult<()>) -> TemplateResult<()> {
+        let sent = match self {
+            BatchSignal::Blocking(s) => s.send(BatchMessage::Done(res)).is_ok(),
+            BatchSignal::Async(s) => s.send(res).is_ok(),
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(TemplateKVError::Customized(
+                "the waiting writer has gone".to_owned(),
+            ))
+        }
+    }
+
+    // Hands `batch` over to a blocking writer to insert it into the memtable. The batch is
+    // given back if the writer is async or has gone.
+    fn insert(
+        &self,
+        batch: WriteBatch,
+        done: &Sender<TemplateResult<()>>,
+    ) -> Result<(), WriteBatch> {
+        match self {
+            BatchSignal::Blocking(s) => {
+                let done = done.clone();
+                s.send(BatchMessage::Insert { batch, done })
+                    .map_err(|e| match e.into_inner() {
+                        BatchMessage::Insert { batch, .. } => batch,
+                        BatchMessage::Done(_) => unreachable!(),
+                    })
+            }
+            BatchSignal::Async(_) => Err(batch),
+        }
+    }
+}
+
+// Tells the writers of a group the result of writing the group
+fn send_batch_results(signals: Vec<BatchSignal>, res: &TemplateResult<()>) {
+    if let Err(e) = res {
+        warn!("[process batch] write batch failed: {}", e);
+    }
+    for signal in signals {
+        let res = match res {
+            Ok(()) => Ok(()),
+            Err(_) => Err(TemplateKVError::Customized(
+                "[process batch] write batch failed".to_owned(),
+            )),
+        };
+        if let Err(e) = signal.send(res) {
+            error!(
+                "[process batch] Fail sending finishing signal to waiting batch: {}",
+                e
+            );
+        }
+    }
+}
+
+// A group whose WAL record has been written, handed over to the memtable writer in the
+// pipelined write mode
+struct MemTableWrite {
+    grouped: WriteBatch,
+    // The batches in `grouped` with `allow_concurrent_memtable_write`
+    members: Vec<WriteBatch>,
+    // The sequence of the last entry in `grouped`
+    last_seq: u64,
+    signals: Vec<BatchSignal>,
+}
+
 // Build a Table file from the contents of `iter`.  The generated file
 // will be named according to `meta.number`.  On success, the rest of
 // meta will be filled with metadata about the generated table.
@@ -1393,6 +2555,7 @@ struct BatchTask {
 // zero, and no Table file will be produced.
 pub(crate) fn build_table<S: Storage + Clone, C: Comparator + 'static>(
     options: Arc<Options<C>>,
+    compression: CompressionType,
     storage: &S,
     db_path: &str,
     table_cache: &TableCache<S, C>,
@@ -1407,6 +2570,7 @@ pub(crate) fn build_table<S: Storage + Clone, C: Comparator + 'static>(
         let file = storage.create(file_name.as_str())?;
         let icmp = InternalKeyComparator::new(options.comparator.clone());
         let mut builder = TableBuilder::new(file, icmp.clone(), &options);
+        builder.set_compression(compression);
         let mut prev_key = vec![];
         meta.smallest = InternalKey::decoded_from(iter.key());
         while iter.valid() {
@@ -1416,6 +2580,7 @@ pub(crate) fn build_table<S: Storage + Clone, C: Comparator + 'static>(
                 status = s;
                 break;
             }
+            meta.properties.add(&key);
             prev_key = key;
             iter.next();
         }
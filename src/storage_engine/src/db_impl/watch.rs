@@ -0,0 +1,454 @@
+use std::{
+    io::SeekFrom,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use super::template_impl::{DBImpl, TemplateDB};
+use crate::{
+    error::{TemplateKVError, TemplateResult},
+    manager::filename::{generate_filename, parse_filename, FileType},
+    memtable::{batch::WriteBatch, value_format::ValueType},
+    storage::{File, Storage},
+    util::{comparator::Comparator, reporter::LogReporter},
+    wal::{wal_record_format::HEADER_SIZE, wal_record_reader::Reader},
+};
+
+// The number of events buffered ahead of the consumer of a `ChangeFeed`
+const FEED_BUFFER_SIZE: usize = 64;
+
+// How often a watcher without any new writes checks whether its consumer is gone
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The keys a watcher is interested in
+#[derive(Clone, Debug, Default)]
+pub enum WatchKeys {
+    #[default]
+    All,
+    /// The keys in `[start, end)`. A missing bound means the range is unbounded on that side.
+    Range {
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+    },
+    /// The keys starting with the prefix
+    Prefix(Vec<u8>),
+}
+
+impl WatchKeys {
+    fn matches<C: Comparator>(&self, ucmp: &C, key: &[u8]) -> bool {
+        match self {
+            WatchKeys::All => true,
+            WatchKeys::Range { start, end } => {
+                start
+                    .as_ref()
+                    .map_or(true, |s| ucmp.compare(key, s).is_ge())
+                    && end.as_ref().map_or(true, |e| ucmp.compare(key, e).is_lt())
+            }
+            WatchKeys::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+}
+
+/// Options for `TemplateDB::watch`
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    pub keys: WatchKeys,
+    /// The sequence of the first change to deliver. The changes older than the latest write
+    /// are read from the WAL files, so they must not be deleted yet (see
+    /// `Options::max_retained_wal_files`). `None` means the changes after the latest write.
+    ///
+    /// A watcher is resumed by passing the sequence of the last event received plus one.
+    pub start_sequence: Option<u64>,
+    /// The max number of write groups buffered for a watcher which is slower than the
+    /// writes. Once the buffer is full, the watcher falls back to reading the WAL files
+    /// until it catches up with the writes again, so the writes are never blocked by it.
+    pub buffer_size: usize,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            keys: WatchKeys::All,
+            start_sequence: None,
+            buffer_size: 256,
+        }
+    }
+}
+
+/// A put or a delete delivered by `TemplateDB::watch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub sequence: u64,
+    pub key: Vec<u8>,
+    /// `None` for a deletion
+    pub value: Option<Vec<u8>>,
+}
+
+/// The stream of the changes returned by `TemplateDB::watch`.
+/// The stream ends after yielding an error.
+pub struct ChangeFeed {
+    recv: mpsc::Receiver<TemplateResult<ChangeEvent>>,
+}
+
+impl Stream for ChangeFeed {
+    type Item = TemplateResult<ChangeEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+/// Broadcasts the write groups to the live watchers once they are visible
+#[derive(Default)]
+pub(crate) struct WatchHub {
+    subscribers: Mutex<Vec<Sender<Arc<WriteBatch>>>>,
+}
+
+impl WatchHub {
+    fn subscribe(&self, buffer_size: usize) -> Receiver<Arc<WriteBatch>> {
+        let (send, recv) = crossbeam_channel::bounded(buffer_size.max(1));
+        self.subscribers.lock().unwrap().push(send);
+        recv
+    }
+
+    /// Sends the group to every watcher. A watcher with a full buffer is dropped and has
+    /// to catch up from the WAL files.
+    ///
+    /// REQUIRES: the versions lock is held and the sequences of `batch` are published
+    pub(crate) fn publish(&self, batch: WriteBatch) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+        let batch = Arc::new(batch);
+        subscribers.retain(|s| s.try_send(batch.clone()).is_ok());
+    }
+
+    /// Disconnects all the watchers
+    pub(crate) fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> TemplateDB<S, C> {
+    /// Returns a feed of the puts and the deletes of the keys in `options.keys` in the order
+    /// of their sequences, starting from `options.start_sequence`.
+    ///
+    /// The changes are read by a dedicated thread ahead of the consumer, which stops once
+    /// the feed is dropped.
+    pub fn watch(&self, options: WatchOptions) -> TemplateResult<ChangeFeed> {
+        if self.inner.is_shutting_down.load(Ordering::Acquire) {
+            return Err(TemplateKVError::DBClosed("watch request".to_owned()));
+        }
+        let (live, last_seq) = self.inner.subscribe_changes(options.buffer_size);
+        let (send, recv) = mpsc::channel(FEED_BUFFER_SIZE);
+        let mut watcher = Watcher {
+            db: self.inner.clone(),
+            cursor: options.start_sequence.unwrap_or(last_seq + 1).max(1),
+            options,
+            send,
+        };
+        thread::Builder::new()
+            .name("watch".to_owned())
+            .spawn(move || {
+                if let Err(e) = watcher.run(live, last_seq) {
+                    let _ = watcher.send.blocking_send(Err(e));
+                }
+            })
+            .map_err(TemplateKVError::IO)?;
+        Ok(ChangeFeed { recv })
+    }
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> DBImpl<S, C> {
+    // Registers a live watcher. Returns the receiver of the groups written from now on and
+    // the last sequence written before.
+    fn subscribe_changes(&self, buffer_size: usize) -> (Receiver<Arc<WriteBatch>>, u64) {
+        // The groups are published under the versions lock, so none is missed or
+        // delivered both ways
+        let versions = self.versions.lock().unwrap();
+        let live = self.watch_hub.subscribe(buffer_size);
+        (live, versions.last_sequence())
+    }
+}
+
+struct Watcher<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: Arc<DBImpl<S, C>>,
+    options: WatchOptions,
+    // The sequence of the next change to deliver
+    cursor: u64,
+    send: mpsc::Sender<TemplateResult<ChangeEvent>>,
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> Watcher<S, C> {
+    // Delivers the changes until the consumer is gone
+    fn run(
+        &mut self,
+        mut live: Receiver<Arc<WriteBatch>>,
+        mut last_seq: u64,
+    ) -> TemplateResult<()> {
+        loop {
+            if self.cursor <= last_seq && !self.catch_up(last_seq)? {
+                return Ok(());
+            }
+            loop {
+                match live.recv_timeout(WATCH_POLL_INTERVAL) {
+                    Ok(batch) => {
+                        if !self.deliver(&batch)? {
+                            return Ok(());
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if self.send.is_closed() {
+                            return Ok(());
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            if self.db.is_shutting_down.load(Ordering::Acquire) {
+                return Err(TemplateKVError::DBClosed("watch".to_owned()));
+            }
+            // Dropped for falling behind the writes, so catch up from the WAL files again
+            (live, last_seq) = self.db.subscribe_changes(self.options.buffer_size);
+        }
+    }
+
+    // Delivers the changes up to sequence `to` from the WAL files.
+    // Returns false if the consumer is gone.
+    fn catch_up(&mut self, to: u64) -> TemplateResult<bool> {
+        let mut logs = vec![];
+        for filename in self.db.env.list(&self.db.db_path)? {
+            if let Some((FileType::Log, number)) = parse_filename(filename) {
+                logs.push(number);
+            }
+        }
+        logs.sort_unstable();
+        let mut first = true;
+        for number in logs {
+            let file_name = generate_filename(&self.db.db_path, FileType::Log, number);
+            let mut log_file = self.db.env.open(&file_name)?;
+            // The handle might share the reading position with the writer
+            log_file.seek(SeekFrom::Start(0))?;
+            let reporter = LogReporter::new();
+            let mut reader = Reader::new(log_file, Some(Box::new(reporter.clone())), true, 0);
+            let mut record_buf = vec![];
+            // The records up to `to` are complete since they are visible already
+            while reader.read_record(&mut record_buf) {
+                reporter.result()?;
+                if record_buf.len() < HEADER_SIZE {
+                    return Err(TemplateKVError::Corruption(
+                        "log record too small".to_owned(),
+                    ));
+                }
+                let mut batch = WriteBatch::default();
+                batch.set_contents(&mut record_buf);
+                if first {
+                    first = false;
+                    if batch.get_sequence() > self.cursor {
+                        return Err(self.not_retained());
+                    }
+                }
+                let last = batch.get_sequence() + u64::from(batch.get_count()) - 1;
+                if last >= self.cursor && !self.deliver(&batch)? {
+                    return Ok(false);
+                }
+                if last >= to {
+                    return Ok(true);
+                }
+            }
+        }
+        Err(self.not_retained())
+    }
+
+    fn not_retained(&self) -> TemplateKVError {
+        TemplateKVError::NotFound(Some(format!(
+            "the changes from sequence {} are not retained in the WAL files",
+            self.cursor
+        )))
+    }
+
+    // Delivers the changes in `batch` not delivered yet and moves the cursor past the batch.
+    // Returns false if the consumer is gone.
+    fn deliver(&mut self, batch: &WriteBatch) -> TemplateResult<bool> {
+        let ucmp = &self.db.options.comparator;
+        let mut events = vec![];
+        batch.iterate(|seq, value_type, key, value| {
+            if seq >= self.cursor && self.options.keys.matches(ucmp, key) {
+                events.push(ChangeEvent {
+                    sequence: seq,
+                    key: key.to_vec(),
+                    value: (value_type == ValueType::Value).then(|| value.to_vec()),
+                });
+            }
+        })?;
+        let next = batch.get_sequence() + u64::from(batch.get_count());
+        self.cursor = self.cursor.max(next);
+        for event in events {
+            // Blocks while the consumer is behind
+            if self.send.blocking_send(Ok(event)).is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        db_trait::DB,
+        options::{Options, WriteOptions},
+        storage::mem::MemStorage,
+        util::comparator::BytewiseComparator,
+    };
+
+    fn open_db(max_retained_wal_files: usize) -> TemplateDB<MemStorage, BytewiseComparator> {
+        let options = Options::<BytewiseComparator> {
+            max_retained_wal_files,
+            ..Default::default()
+        };
+        TemplateDB::open_db(options, "watch_test", MemStorage::default()).unwrap()
+    }
+
+    fn put(db: &TemplateDB<MemStorage, BytewiseComparator>, key: &str, value: &str) {
+        DB::put(
+            db,
+            WriteOptions::default(),
+            key.as_bytes(),
+            value.as_bytes(),
+        )
+        .unwrap();
+    }
+
+    fn delete(db: &TemplateDB<MemStorage, BytewiseComparator>, key: &str) {
+        DB::delete(db, WriteOptions::default(), key.as_bytes()).unwrap();
+    }
+
+    fn event(sequence: u64, key: &str, value: Option<&str>) -> ChangeEvent {
+        ChangeEvent {
+            sequence,
+            key: key.as_bytes().to_vec(),
+            value: value.map(|v| v.as_bytes().to_vec()),
+        }
+    }
+
+    async fn take(feed: &mut ChangeFeed, n: usize) -> Vec<ChangeEvent> {
+        let mut events = vec![];
+        for _ in 0..n {
+            events.push(feed.next().await.unwrap().unwrap());
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_watch_live_changes() {
+        let mut db = open_db(0);
+        put(&db, "a", "before");
+        let mut prefix_feed = db
+            .watch(WatchOptions {
+                keys: WatchKeys::Prefix(b"user/".to_vec()),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut range_feed = db
+            .watch(WatchOptions {
+                keys: WatchKeys::Range {
+                    start: Some(b"b".to_vec()),
+                    end: Some(b"d".to_vec()),
+                },
+                ..Default::default()
+            })
+            .unwrap();
+        put(&db, "user/1", "v1");
+        put(&db, "b", "vb");
+        delete(&db, "user/1");
+        put(&db, "d", "vd");
+        put(&db, "c", "vc");
+        assert_eq!(
+            take(&mut prefix_feed, 2).await,
+            vec![event(2, "user/1", Some("v1")), event(4, "user/1", None)]
+        );
+        assert_eq!(
+            take(&mut range_feed, 2).await,
+            vec![event(3, "b", Some("vb")), event(6, "c", Some("vc"))]
+        );
+        db.close().unwrap();
+        assert!(prefix_feed.next().await.unwrap().is_err());
+        assert!(prefix_feed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch_catch_up_from_wal() {
+        let mut db = open_db(4);
+        for i in 1..=5 {
+            put(&db, &format!("k{}", i), "v");
+        }
+        // The log holding the changes becomes obsolete but is retained
+        db.inner.force_compact_mem_table().unwrap();
+        put(&db, "k6", "v");
+        let mut feed = db
+            .watch(WatchOptions {
+                start_sequence: Some(3),
+                ..Default::default()
+            })
+            .unwrap();
+        put(&db, "k7", "v");
+        let sequences: Vec<_> = take(&mut feed, 5)
+            .await
+            .into_iter()
+            .map(|e| e.sequence)
+            .collect();
+        assert_eq!(sequences, vec![3, 4, 5, 6, 7]);
+        db.close().unwrap();
+
+        // The obsolete logs are deleted without retention
+        let mut db = open_db(0);
+        put(&db, "k1", "v");
+        db.inner.force_compact_mem_table().unwrap();
+        let mut feed = db
+            .watch(WatchOptions {
+                start_sequence: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        match feed.next().await.unwrap() {
+            Err(TemplateKVError::NotFound(_)) => {}
+            other => panic!("expect NotFound but got {:?}", other),
+        }
+        db.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_watcher_catches_up() {
+        let mut db = open_db(0);
+        let mut feed = db
+            .watch(WatchOptions {
+                buffer_size: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        // Overflow the buffers of the watcher while it is not consumed
+        let n = FEED_BUFFER_SIZE as u64 * 4;
+        for i in 1..=n {
+            put(&db, &format!("k{}", i), "v");
+        }
+        let sequences: Vec<_> = take(&mut feed, n as usize)
+            .await
+            .into_iter()
+            .map(|e| e.sequence)
+            .collect();
+        assert_eq!(sequences, (1..=n).collect::<Vec<_>>());
+        db.close().unwrap();
+    }
+}
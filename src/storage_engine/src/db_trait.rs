@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use futures::Stream;
+
 use crate::{
     error::TemplateResult,
     manager::snapshot::Snapshot,
@@ -44,3 +46,37 @@ pub trait DB {
     /// Acquire a `Snapshot` for reading DB
     fn snapshot(&self) -> Arc<Snapshot>;
 }
+
+/// The async counterpart of `DB` for callers living in an async runtime such as tokio.
+///
+/// Waiting for a write to be committed never blocks the thread polling the future, and the
+/// reads touching the underlying storage are moved off the async worker threads.
+#[async_trait::async_trait]
+pub trait AsyncDB: Send + Sync {
+    /// The stream that can yield all the kv pairs in `DB`
+    type Stream: Stream<Item = TemplateResult<(Vec<u8>, Vec<u8>)>> + Send + Unpin;
+
+    /// `put` sets the value for the given key. It overwrites any previous value
+    /// for that key; a DB is not a multi-map.
+    async fn put(&self, write_opt: WriteOptions, key: &[u8], value: &[u8]) -> TemplateResult<()>;
+
+    /// `get` gets the value for the given key. It returns `None` if the DB
+    /// does not contain the key.
+    async fn get(&self, read_opt: ReadOptions, key: &[u8]) -> TemplateResult<Option<Vec<u8>>>;
+
+    /// `multi_get` gets the values for the given keys in order from the same view of the DB.
+    async fn multi_get(
+        &self,
+        read_opt: ReadOptions,
+        keys: &[&[u8]],
+    ) -> TemplateResult<Vec<Option<Vec<u8>>>>;
+
+    /// `delete` deletes the value for the given key.
+    async fn delete(&self, write_opt: WriteOptions, key: &[u8]) -> TemplateResult<()>;
+
+    /// `write` applies the operations contained in the `WriteBatch` to the DB atomically.
+    async fn write(&self, write_opt: WriteOptions, batch: WriteBatch) -> TemplateResult<()>;
+
+    /// Return a stream over the contents of the database as of the time of calling.
+    fn iter(&self, read_opt: ReadOptions) -> TemplateResult<Self::Stream>;
+}
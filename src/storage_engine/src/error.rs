@@ -20,6 +20,9 @@ quick_error! {
         DBClosed(hint: String) {
             display("try to operate a closed db: {}", hint)
         }
+        NotSupported(hint: String) {
+            display("not supported: {}", hint)
+        }
         CompressionFailed(err: snap::Error) {
             display("compression failed: {}", err)
             cause(err)
@@ -32,6 +35,9 @@ quick_error! {
             display("{:?}", err)
             cause(err)
         }
+        MemoryLimit(hint: String) {
+            display("memory limit exceeded: {}", hint)
+        }
         Customized(hint: String) {
             display("{}", hint)
         }
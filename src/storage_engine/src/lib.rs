@@ -43,15 +43,19 @@ pub mod compaction;
 pub mod db_impl;
 pub mod db_trait;
 pub mod iterator;
+pub mod listener;
 mod logger;
 pub mod manager;
 pub mod memtable;
+pub mod metrics;
 pub mod operator;
 pub mod options;
 pub mod servers;
 pub mod services;
 pub mod sstable;
+pub mod statistics;
 pub mod storage;
+pub mod tools;
 pub mod util;
 pub mod wal;
 
@@ -60,6 +64,16 @@ pub mod memtable_service {
     tonic::include_proto!("memtable");
 }
 
+#[allow(clippy::all)]
+pub mod kv_service {
+    tonic::include_proto!("kv");
+}
+
+#[allow(clippy::all)]
+pub mod admin_service {
+    tonic::include_proto!("admin");
+}
+
 // // pub use batch::WriteBatch;
 // pub use cache::Cache;
 // // pub use compaction::ManualCompaction;
@@ -0,0 +1,118 @@
+use crate::{compaction::compact::CompactionReason, error::TemplateKVError};
+
+/// Callbacks of the events happening inside a db. Register listeners by
+/// `Options::listeners`.
+///
+/// The callbacks are invoked synchronously by the thread doing the work, sometimes with the
+/// db mutex held, so they should return quickly and must never call back into the db.
+pub trait EventListener: Send + Sync {
+    /// Called before the immutable memtable is flushed into a level 0 table
+    fn on_flush_begin(&self, _info: &FlushJobInfo) {}
+
+    /// Called after a flush finishes or fails with `error`
+    fn on_flush_completed(&self, _info: &FlushJobInfo, _error: Option<&TemplateKVError>) {}
+
+    /// Called before a compaction runs, including the trivial moves
+    fn on_compaction_begin(&self, _info: &CompactionJobInfo) {}
+
+    /// Called after a compaction finishes or fails with `error`
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo, _error: Option<&TemplateKVError>) {
+    }
+
+    /// Called after a table file is built by a flush or a compaction
+    fn on_table_file_created(&self, _info: &TableFileCreationInfo) {}
+
+    /// Called after an obsolete table file is deleted or fails to be deleted with `error`
+    fn on_table_file_deleted(
+        &self,
+        _info: &TableFileDeletionInfo,
+        _error: Option<&TemplateKVError>,
+    ) {
+    }
+
+    /// Called when the writes start or stop being delayed or stopped
+    fn on_stall_conditions_changed(&self, _info: &WriteStallInfo) {}
+
+    /// Called when a background work fails
+    fn on_background_error(&self, _reason: BackgroundErrorReason, _error: &TemplateKVError) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct FlushJobInfo {
+    pub db_path: String,
+    /// The entries in the memtable being flushed
+    pub num_entries: usize,
+    /// The table written. `None` before completion or if nothing has been written.
+    pub output: Option<TableFileCreationInfo>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactionJobInfo {
+    pub db_path: String,
+    pub reason: CompactionReason,
+    /// The level of the base input files
+    pub level: usize,
+    pub output_level: usize,
+    /// The numbers of the input table files
+    pub input_files: Vec<u64>,
+    /// The numbers of the output table files. Empty before completion.
+    pub output_files: Vec<u64>,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Whether the only input file is moved to the output level without rewriting
+    pub is_trivial_move: bool,
+}
+
+/// Why a table file is created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFileCreationReason {
+    Flush,
+    Compaction,
+}
+
+#[derive(Clone, Debug)]
+pub struct TableFileCreationInfo {
+    pub db_path: String,
+    pub file_number: u64,
+    pub file_size: u64,
+    pub level: usize,
+    /// The smallest user key in the table
+    pub smallest_key: Vec<u8>,
+    /// The largest user key in the table
+    pub largest_key: Vec<u8>,
+    pub reason: TableFileCreationReason,
+}
+
+#[derive(Clone, Debug)]
+pub struct TableFileDeletionInfo {
+    pub db_path: String,
+    pub file_number: u64,
+}
+
+/// How the writes are throttled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteStallCondition {
+    Normal,
+    /// Every write is delayed a little because level 0 is getting full
+    Delayed,
+    /// The writes wait for the memtable flush or the level 0 compaction
+    Stopped,
+}
+
+#[derive(Clone, Debug)]
+pub struct WriteStallInfo {
+    pub db_path: String,
+    pub previous: WriteStallCondition,
+    pub current: WriteStallCondition,
+}
+
+/// The background work failing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundErrorReason {
+    Flush,
+    Compaction,
+    /// Syncing the WAL fails and the db stops accepting writes
+    WriteSync,
+    /// The background scrubber finds corruptions
+    Scrub,
+}
@@ -15,11 +15,13 @@ use crate::{
 
 /// A `slog` based logger which can be used with `log` crate
 ///
+/// The records are filtered by the max level of `log`, so the level can be changed at runtime
+/// with `set_log_level`.
+///
 /// See `slog` at https://github.com/slog-rs/slog
 /// See `log` at https://github.com/rust-lang/log
 pub struct Logger {
     inner: slog::Logger,
-    level: LevelFilter,
 }
 
 impl Logger {
@@ -29,12 +31,7 @@ impl Logger {
     /// If `inner` is `None`
     ///     - In dev mode, use a std output
     ///     - In release mode, use a storage specific file with name `LOG`
-    pub fn new<S: Storage>(
-        inner: Option<slog::Logger>,
-        level: LevelFilter,
-        storage: &S,
-        db_path: &str,
-    ) -> Self {
+    pub fn new<S: Storage>(inner: Option<slog::Logger>, storage: &S, db_path: &str) -> Self {
         let inner = match inner {
             Some(l) => l,
             None => {
@@ -56,13 +53,21 @@ impl Logger {
                 }
             }
         };
-        Self { inner, level }
+        Self { inner }
     }
 }
 
+/// Changes the max level of the logs recorded and returns the previous one
+pub fn set_log_level(level: LevelFilter) -> LevelFilter {
+    let previous = log::max_level();
+    log::set_max_level(level);
+    info!("Log level changed from {:?} to {:?}", previous, level);
+    previous
+}
+
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level
+        metadata.level() <= log::max_level()
     }
 
     #[allow(unused_must_use)]
@@ -159,7 +164,7 @@ mod tests {
     fn test_default_logger() {
         let s = MemStorage::default();
         let db_path = "test";
-        let logger = Logger::new(None, LevelFilter::Debug, &s, db_path);
+        let logger = Logger::new(None, &s, db_path);
         // Ignore the error if the logger have been set
         let _ = log::set_logger(Box::leak(Box::new(logger)));
         log::set_max_level(LevelFilter::Debug);
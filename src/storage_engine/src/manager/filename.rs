@@ -24,6 +24,8 @@ pub enum FileType {
     InfoLog,
     /// `LOG.old` file records the last runtime logs.
     OldInfoLog,
+    /// `OPTIONS-*` file records all the `Options` the db is running with.
+    Options,
 }
 
 /// Returns a filename for a certain `FileType` by given sequence number and a `dirname`.
@@ -65,6 +67,11 @@ pub fn generate_filename(dirname: &str, filetype: FileType, seq: u64) -> String
             .into_os_string()
             .into_string()
             .unwrap(),
+        FileType::Options => dirname
+            .join(format!("OPTIONS-{:06}", seq))
+            .into_os_string()
+            .into_string()
+            .unwrap(),
     }
 }
 
@@ -83,16 +90,21 @@ pub fn parse_filename<P: AsRef<Path>>(filename: P) -> Option<(FileType, u64)> {
             _ => None,
         },
         Some(with_seq) => {
-            if with_seq.starts_with("MANIFEST") {
-                let strs: Vec<&str> = with_seq.split('-').collect();
-                if strs.len() != 2 {
+            for (prefix, file_type) in [
+                ("MANIFEST", FileType::Manifest),
+                ("OPTIONS", FileType::Options),
+            ] {
+                if with_seq.starts_with(prefix) {
+                    let strs: Vec<&str> = with_seq.split('-').collect();
+                    if strs.len() != 2 {
+                        return None;
+                    }
+                    if let Ok(seq) = strs[1].parse::<u64>() {
+                        return Some((file_type, seq));
+                    }
                     return None;
-                }
-                if let Ok(seq) = strs[1].parse::<u64>() {
-                    return Some((FileType::Manifest, seq));
-                }
-                return None;
-            };
+                };
+            }
             if let Ok(seq) = with_seq.parse::<u64>() {
                 match path
                     .extension()
@@ -153,6 +165,7 @@ mod tests {
                 (FileType::Temp, 100, "test\\000100.dbtmp"),
                 (FileType::InfoLog, 1, "test\\LOG"),
                 (FileType::OldInfoLog, 1, "test\\LOG.old"),
+                (FileType::Options, 7, "test\\OPTIONS-000007"),
             ]
         } else {
             vec![
@@ -164,6 +177,7 @@ mod tests {
                 (FileType::Temp, 100, "test/000100.dbtmp"),
                 (FileType::InfoLog, 1, "test/LOG"),
                 (FileType::OldInfoLog, 1, "test/LOG.old"),
+                (FileType::Options, 7, "test/OPTIONS-000007"),
             ]
         };
 
@@ -185,6 +199,7 @@ mod tests {
                 ("a\\b\\c\\CURRENT", Some((FileType::Current, 0))),
                 ("a\\b\\c\\LOG", Some((FileType::InfoLog, 0))),
                 ("a\\b\\c\\LOG.old", Some((FileType::OldInfoLog, 0))),
+                ("a\\b\\c\\OPTIONS-000012", Some((FileType::Options, 12))),
                 ("a\\b\\c\\test.123", None),
                 ("a\\b\\c\\LOG.", None),
                 ("a\\b\\c\\LOG.new", None),
@@ -203,6 +218,7 @@ mod tests {
                 ("a/b/c/CURRENT", Some((FileType::Current, 0))),
                 ("a/b/c/LOG", Some((FileType::InfoLog, 0))),
                 ("a/b/c/LOG.old", Some((FileType::OldInfoLog, 0))),
+                ("a/b/c/OPTIONS-000012", Some((FileType::Options, 12))),
                 // invalid conditions
                 ("a/b/c/test.123", None),
                 ("a/b/c/LOG.", None),
@@ -211,6 +227,7 @@ mod tests {
                 ("a/b/c/MANIFEST-abcedf", None),
                 ("a/b/c/MANIFEST", None),
                 ("a/b/c/MANIFEST-123123-abcdef", None),
+                ("a/b/c/OPTIONS-abc", None),
             ]
         };
 
@@ -32,8 +32,9 @@ impl From<u64> for Snapshot {
 pub struct SnapshotList {
     // The initialized snapshot with `MIN_SNAPSHOT` number.
     first: Arc<Snapshot>,
-    // All the newly allocated snapshots.
-    snapshots: Vec<Arc<Snapshot>>,
+    // All the newly allocated snapshots with the number of the acquirers not released yet.
+    // The same snapshot is handed out to the acquirers of the same sequence.
+    snapshots: Vec<(Arc<Snapshot>, usize)>,
 }
 
 impl Default for SnapshotList {
@@ -59,7 +60,7 @@ impl SnapshotList {
         if self.is_empty() {
             self.first.clone()
         } else {
-            self.snapshots.first().unwrap().clone()
+            self.snapshots.first().unwrap().0.clone()
         }
     }
 
@@ -68,45 +69,59 @@ impl SnapshotList {
         if self.is_empty() {
             self.first.clone()
         } else {
-            self.snapshots.last().unwrap().clone()
+            self.snapshots.last().unwrap().0.clone()
         }
     }
 
-    /// Creates a `Snapshot` and appends it to the end of the list
+    /// Creates a `Snapshot` and appends it to the end of the list, or hands out the newest one
+    /// again if it has the same sequence
     pub fn acquire(&mut self, seq: u64) -> Arc<Snapshot> {
         let last_seq = self.last_seq();
         assert!(seq >= last_seq, "[snapshot] the sequence number must be monotonically increasing : [new: {}], [last: {}]", seq, last_seq);
         if last_seq == seq {
-            self.newest()
+            if let Some((s, refs)) = self.snapshots.last_mut() {
+                *refs += 1;
+                return s.clone();
+            }
+            self.first.clone()
         } else {
             let s = Arc::new(Snapshot {
                 sequence_number: seq,
             });
-            self.snapshots.push(s.clone());
+            self.snapshots.push((s.clone(), 1));
             s
         }
     }
 
-    /// Remove redundant snapshots
+    /// Remove the snapshots dropped by all the acquirers without being released
     #[inline]
     pub fn gc(&mut self) {
-        self.snapshots.retain(|s| Arc::strong_count(s) > 1)
+        self.snapshots.retain(|(s, _)| Arc::strong_count(s) > 1)
     }
 
     #[inline]
     fn last_seq(&self) -> u64 {
         self.snapshots
             .last()
-            .map_or(self.first.sequence(), |s| s.sequence_number)
+            .map_or(self.first.sequence(), |(s, _)| s.sequence_number)
     }
 
-    /// Returns true if the given snapshot is removed from the lists
+    /// Releases the given snapshot for one of its acquirers. Returns true if it is removed from
+    /// the list since all the acquirers have released it.
     #[inline]
     pub fn release(&mut self, s: Arc<Snapshot>) -> bool {
-        match self.snapshots.as_slice().binary_search(&s) {
+        match self
+            .snapshots
+            .binary_search_by_key(&s.sequence_number, |(s, _)| s.sequence_number)
+        {
             Ok(i) => {
-                self.snapshots.remove(i);
-                true
+                self.snapshots[i].1 -= 1;
+                if self.snapshots[i].1 == 0 {
+                    self.snapshots.remove(i);
+                    true
+                } else {
+                    false
+                }
             }
             Err(_) => false,
         }
@@ -142,7 +157,7 @@ mod tests {
         s.acquire(3);
         s.gc();
         assert_eq!(1, s.snapshots.len());
-        assert_eq!(s2.sequence(), s.snapshots.pop().unwrap().sequence());
+        assert_eq!(s2.sequence(), s.snapshots.pop().unwrap().0.sequence());
     }
 
     #[test]
@@ -163,12 +178,17 @@ mod tests {
             s.acquire(i);
         }
         assert!(s.release(Arc::new(Snapshot { sequence_number: 2 })));
+        // Snapshot 1 is kept until both of its acquirers release it
+        assert!(!s.release(Arc::new(Snapshot { sequence_number: 1 })));
         assert_eq!(
             vec![1, 3],
             s.snapshots
-                .into_iter()
-                .map(|s| s.sequence_number)
+                .iter()
+                .map(|(s, _)| s.sequence_number)
                 .collect::<Vec<_>>()
         );
+        assert!(s.release(Arc::new(Snapshot { sequence_number: 1 })));
+        assert!(!s.release(Arc::new(Snapshot { sequence_number: 1 })));
+        assert_eq!(3, s.oldest().sequence());
     }
 }
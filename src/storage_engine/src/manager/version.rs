@@ -9,7 +9,10 @@ use std::{
     },
 };
 
-use super::{version_edit::FileMetaData, version_set::total_file_size};
+use super::{
+    version_edit::{unix_time_secs, FileMetaData},
+    version_set::total_file_size,
+};
 use crate::{
     cache::table_cache::TableCache,
     error::{TemplateKVError, TemplateResult},
@@ -227,7 +230,32 @@ impl<C: Comparator + 'static> Version<C> {
 
     /// Whether the version needs to be compacted
     pub fn needs_compaction(&self) -> bool {
-        self.compaction_score > 1.0 || self.file_to_compact.read().unwrap().is_some()
+        self.compaction_score > 1.0
+            || self.file_to_compact.read().unwrap().is_some()
+            || self.periodic_compaction_file().is_some()
+    }
+
+    /// Returns the oldest file created longer than `periodic_compaction_seconds` ago and its
+    /// level. The files in the last level and the files without a creation time are skipped.
+    pub fn periodic_compaction_file(&self) -> Option<(usize, Arc<FileMetaData>)> {
+        let period = self.options.periodic_compaction_seconds?;
+        let deadline = unix_time_secs().checked_sub(period)?;
+        let mut oldest: Option<(usize, Arc<FileMetaData>)> = None;
+        for level in 0..self.options.max_levels - 1 {
+            for f in self.files[level].iter() {
+                let created = f.properties.creation_time;
+                if created == 0 || created > deadline {
+                    continue;
+                }
+                if oldest
+                    .as_ref()
+                    .map_or(true, |(_, o)| created < o.properties.creation_time)
+                {
+                    oldest = Some((level, f.clone()));
+                }
+            }
+        }
+        oldest
     }
 
     /// Return a String includes number of files in every level
@@ -244,10 +272,13 @@ impl<C: Comparator + 'static> Version<C> {
 
     /// Return the level at which we should place a new memtable compaction
     /// result that covers the range `[smallest_user_key,largest_user_key]`.
+    /// The result is not pushed down beyond a level whose grandparent overlap exceeds
+    /// `max_grandparent_overlap_bytes`.
     pub fn pick_level_for_memtable_output(
         &self,
         smallest_ukey: &[u8],
         largest_ukey: &[u8],
+        max_grandparent_overlap_bytes: u64,
     ) -> usize {
         let mut level = 0;
         if !self.overlap_in_level(level, Some(smallest_ukey), Some(largest_ukey)) {
@@ -268,7 +299,7 @@ impl<C: Comparator + 'static> Version<C> {
                         Some(&smallest_i_key),
                         Some(&largest_i_key),
                     );
-                    if total_file_size(&overlaps) > self.options.max_grandparent_overlap_bytes() {
+                    if total_file_size(&overlaps) > max_grandparent_overlap_bytes {
                         break;
                     }
                 }
@@ -284,7 +315,14 @@ impl<C: Comparator + 'static> Version<C> {
         // pre-computed best level for next compaction
         let mut best_level = 0;
         let mut best_score = 0.0;
-        for level in 0..self.options.max_levels {
+        let level_bytes: Vec<u64> = self
+            .files
+            .iter()
+            .map(|files| total_file_size(files))
+            .collect();
+        let max_bytes = self.options.max_bytes_for_levels(&level_bytes);
+        // The last level has no level to be compacted into
+        for level in 0..self.options.max_levels - 1 {
             let score = {
                 if level == 0 {
                     // We treat level-0 specially by bounding the number of files
@@ -300,8 +338,7 @@ impl<C: Comparator + 'static> Version<C> {
                     // overwrites/deletions)
                     self.files[level].len() as f64 / self.options.l0_compaction_threshold as f64
                 } else {
-                    let level_bytes = total_file_size(self.files[level].as_ref());
-                    level_bytes as f64 / self.options.max_bytes_for_level(level) as f64
+                    level_bytes[level] as f64 / max_bytes[level] as f64
                 }
             };
             if score > best_score {
@@ -1,11 +1,13 @@
 use std::{
     fmt::{Debug, Formatter},
     sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     error::{TemplateKVError, TemplateResult},
-    memtable::key_format::InternalKey,
+    memtable::key_format::{InternalKey, ParsedInternalKey},
+    memtable::value_format::ValueType,
     util::{
         collection::HashSet,
         varint::{VarintU32, VarintU64},
@@ -24,6 +26,8 @@ enum Tag {
     NewFile = 7,
     // 8 was used for large value refs
     PrevLogNumber = 9,
+    // A `NewFile` followed by the `FileProperties`
+    NewFileWithProperties = 10,
     Unknown, // unknown tag
 }
 
@@ -38,6 +42,7 @@ impl From<u32> for Tag {
             6 => Tag::DeletedFile,
             7 => Tag::NewFile,
             9 => Tag::PrevLogNumber,
+            10 => Tag::NewFileWithProperties,
             _ => Tag::Unknown,
         }
     }
@@ -64,6 +69,47 @@ pub struct FileMetaData {
     pub smallest: InternalKey,
     // Largest internal key served by table
     pub largest: InternalKey,
+    pub properties: FileProperties,
+}
+
+/// The statistics of the entries in a table used to pick the files to compact.
+/// All zero for the tables recorded by an older version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileProperties {
+    pub num_entries: u64,
+    pub num_deletions: u64,
+    /// The largest sequence number of the entries
+    pub largest_seq: u64,
+    /// The seconds since the UNIX epoch when the table is built
+    pub creation_time: u64,
+}
+
+impl FileProperties {
+    /// Returns empty properties of a table built right now
+    pub fn created_now() -> Self {
+        Self {
+            creation_time: unix_time_secs(),
+            ..Default::default()
+        }
+    }
+
+    /// Accounts an entry with the internal key `ikey` added into the table
+    pub fn add(&mut self, ikey: &[u8]) {
+        self.num_entries += 1;
+        if let Some(parsed) = ParsedInternalKey::decode_from(ikey) {
+            if parsed.value_type == ValueType::Deletion {
+                self.num_deletions += 1;
+            }
+            self.largest_seq = self.largest_seq.max(parsed.seq);
+        }
+    }
+}
+
+/// Returns the seconds since the UNIX epoch
+pub(crate) fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
 impl FileMetaData {
@@ -77,6 +123,16 @@ impl FileMetaData {
         }
         self.allowed_seeks.store(allowed_seeks, Ordering::Release);
     }
+
+    /// Returns the file size with every deletion counted twice the average entry size, since
+    /// compacting a deletion also frees the space of the entry it shadows
+    pub fn compensated_size(&self) -> u64 {
+        let p = &self.properties;
+        if p.num_entries == 0 {
+            return self.file_size;
+        }
+        self.file_size + p.num_deletions * 2 * (self.file_size / p.num_entries)
+    }
 }
 
 impl PartialEq for FileMetaData {
@@ -97,6 +153,7 @@ impl Default for FileMetaData {
             number: 0,
             smallest: InternalKey::default(),
             largest: InternalKey::default(),
+            properties: FileProperties::default(),
         }
     }
 }
@@ -175,6 +232,22 @@ impl VersionEdit {
                 number: file_number,
                 smallest,
                 largest,
+                properties: FileProperties::default(),
+            },
+        ))
+    }
+
+    /// Add the specified file with the properties of `meta` at the specified level
+    pub fn add_file_meta(&mut self, level: usize, meta: &FileMetaData) {
+        self.file_delta.new_files.push((
+            level,
+            FileMetaData {
+                allowed_seeks: AtomicUsize::new(0),
+                file_size: meta.file_size,
+                number: meta.number,
+                smallest: meta.smallest.clone(),
+                largest: meta.largest.clone(),
+                properties: meta.properties,
             },
         ))
     }
@@ -247,12 +320,17 @@ impl VersionEdit {
         }
 
         for (level, file_meta) in self.file_delta.new_files.iter() {
-            VarintU32::put_varint(dst, Tag::NewFile as u32);
+            VarintU32::put_varint(dst, Tag::NewFileWithProperties as u32);
             VarintU32::put_varint(dst, *level as u32);
             VarintU64::put_varint(dst, file_meta.number);
             VarintU64::put_varint(dst, file_meta.file_size);
             VarintU32::put_varint_prefixed_slice(dst, file_meta.smallest.data());
             VarintU32::put_varint_prefixed_slice(dst, file_meta.largest.data());
+            let p = &file_meta.properties;
+            VarintU64::put_varint(dst, p.num_entries);
+            VarintU64::put_varint(dst, p.num_deletions);
+            VarintU64::put_varint(dst, p.largest_seq);
+            VarintU64::put_varint(dst, p.creation_time);
         }
     }
 
@@ -328,23 +406,32 @@ impl VersionEdit {
                         msg.push_str("deleted file");
                         break;
                     }
-                    Tag::NewFile => {
+                    t @ (Tag::NewFile | Tag::NewFileWithProperties) => {
                         if let Some(level) = get_level(self.max_levels, &mut s) {
                             if let Some(number) = VarintU64::drain_read(&mut s) {
                                 if let Some(file_size) = VarintU64::drain_read(&mut s) {
                                     if let Some(smallest) = get_internal_key(&mut s) {
                                         if let Some(largest) = get_internal_key(&mut s) {
-                                            self.file_delta.new_files.push((
-                                                level as usize,
-                                                FileMetaData {
-                                                    allowed_seeks: AtomicUsize::new(0),
-                                                    file_size,
-                                                    number,
-                                                    smallest,
-                                                    largest,
-                                                },
-                                            ));
-                                            continue;
+                                            let properties = match t {
+                                                Tag::NewFileWithProperties => {
+                                                    get_file_properties(&mut s)
+                                                }
+                                                _ => Some(FileProperties::default()),
+                                            };
+                                            if let Some(properties) = properties {
+                                                self.file_delta.new_files.push((
+                                                    level as usize,
+                                                    FileMetaData {
+                                                        allowed_seeks: AtomicUsize::new(0),
+                                                        file_size,
+                                                        number,
+                                                        smallest,
+                                                        largest,
+                                                        properties,
+                                                    },
+                                                ));
+                                                continue;
+                                            }
                                         }
                                     }
                                 }
@@ -409,8 +496,8 @@ impl Debug for VersionEdit {
         for (level, meta) in self.file_delta.new_files.iter() {
             write!(
                 f,
-                "\n  AddFile: @{} #{} {}bytes range: [{:?}, {:?}]",
-                level, meta.number, meta.file_size, meta.smallest, meta.largest
+                "\n  AddFile: @{} #{} {}bytes range: [{:?}, {:?}] {:?}",
+                level, meta.number, meta.file_size, meta.smallest, meta.largest, meta.properties
             )?;
         }
         write!(f, "\n}}\n")?;
@@ -419,8 +506,16 @@ impl Debug for VersionEdit {
 }
 
 fn get_internal_key(src: &mut &[u8]) -> Option<InternalKey> {
-    // VarintU32::get_varint_prefixed_slice(src).map(|s| InternalKey::decoded_from(s))
-    Some(InternalKey::decoded_from(src))
+    VarintU32::get_varint_prefixed_slice(src).map(InternalKey::decoded_from)
+}
+
+fn get_file_properties(src: &mut &[u8]) -> Option<FileProperties> {
+    Some(FileProperties {
+        num_entries: VarintU64::drain_read(src)?,
+        num_deletions: VarintU64::drain_read(src)?,
+        largest_seq: VarintU64::drain_read(src)?,
+        creation_time: VarintU64::drain_read(src)?,
+    })
 }
 
 fn get_level(max_levels: usize, src: &mut &[u8]) -> Option<u32> {
@@ -433,12 +528,12 @@ fn get_level(max_levels: usize, src: &mut &[u8]) -> Option<u32> {
     })
 }
 
-#[allow(dead_code)]
 #[cfg(test)]
 mod tests {
     use crate::{
-        manager::version_edit::VersionEdit,
-        // memtable::key_format::InternalKey,
+        manager::version_edit::{FileMetaData, FileProperties, Tag, VersionEdit},
+        memtable::{key_format::InternalKey, value_format::ValueType},
+        util::varint::{VarintU32, VarintU64},
     };
 
     fn assert_encode_decode(edit: &VersionEdit) {
@@ -448,40 +543,138 @@ mod tests {
         parsed.decoded_from(encoded.as_slice()).expect("");
         let mut encoded2 = vec![];
         parsed.encode_to(&mut encoded2);
-        assert_eq!(encoded, encoded2)
+        // The deleted files might be encoded in another order
+        assert_eq!(encoded.len(), encoded2.len());
+        assert_eq!(edit.comparator_name, parsed.comparator_name);
+        assert_eq!(edit.log_number, parsed.log_number);
+        assert_eq!(edit.next_file_number, parsed.next_file_number);
+        assert_eq!(edit.last_sequence, parsed.last_sequence);
+        assert_eq!(edit.prev_log_number, parsed.prev_log_number);
+        let (delta, parsed_delta) = (&edit.file_delta, &parsed.file_delta);
+        assert_eq!(delta.compaction_pointers, parsed_delta.compaction_pointers);
+        assert_eq!(delta.deleted_files, parsed_delta.deleted_files);
+        assert_eq!(delta.new_files.len(), parsed_delta.new_files.len());
+        for ((level, f), (parsed_level, parsed_f)) in
+            delta.new_files.iter().zip(parsed_delta.new_files.iter())
+        {
+            assert_eq!(level, parsed_level);
+            assert_eq!(f.number, parsed_f.number);
+            assert_eq!(f.file_size, parsed_f.file_size);
+            assert_eq!(f.smallest.data(), parsed_f.smallest.data());
+            assert_eq!(f.largest.data(), parsed_f.largest.data());
+            assert_eq!(f.properties, parsed_f.properties);
+        }
+    }
+
+    impl VersionEdit {
+        fn add_compaction_pointer(&mut self, level: usize, key: InternalKey) {
+            self.file_delta.compaction_pointers.push((level, key))
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let k_big = 1u64 << 50;
+        let mut edit = VersionEdit::new(7);
+        for i in 0..4 {
+            assert_encode_decode(&edit);
+            edit.add_file(
+                3,
+                k_big + 300 + i,
+                k_big + 400 + i,
+                InternalKey::new("foo".as_bytes(), k_big + 500 + i, ValueType::Value),
+                InternalKey::new("zoo".as_bytes(), k_big + 700 + i, ValueType::Deletion),
+            );
+            let mut meta = FileMetaData {
+                number: k_big + 600 + i,
+                file_size: k_big + 800 + i,
+                smallest: InternalKey::new(b"bar", k_big + 501 + i, ValueType::Deletion),
+                largest: InternalKey::new(b"baz", k_big + 701 + i, ValueType::Value),
+                ..Default::default()
+            };
+            meta.properties = FileProperties {
+                num_entries: 100 + i,
+                num_deletions: 10 + i,
+                largest_seq: k_big + 701 + i,
+                creation_time: 1_700_000_000 + i,
+            };
+            edit.add_file_meta(5, &meta);
+            edit.delete_file(4, k_big + 700 + i);
+            edit.add_compaction_pointer(
+                i as usize,
+                InternalKey::new("x".as_bytes(), k_big + 900 + i, ValueType::Value),
+            );
+        }
+        edit.set_comparator_name("foo".to_owned());
+        edit.set_log_number(k_big + 100);
+        edit.set_next_file(k_big + 200);
+        edit.set_last_sequence(k_big + 1000);
+        edit.set_prev_log_number(k_big + 50);
+        assert_encode_decode(&edit);
+    }
+
+    #[test]
+    fn test_decode_legacy_new_file() {
+        // A `NewFile` record written before the file properties are recorded
+        let smallest = InternalKey::new(b"foo", 5, ValueType::Value);
+        let largest = InternalKey::new(b"zoo", 9, ValueType::Deletion);
+        let mut encoded = vec![];
+        VarintU32::put_varint(&mut encoded, Tag::NewFile as u32);
+        VarintU32::put_varint(&mut encoded, 2);
+        VarintU64::put_varint(&mut encoded, 10);
+        VarintU64::put_varint(&mut encoded, 4096);
+        VarintU32::put_varint_prefixed_slice(&mut encoded, smallest.data());
+        VarintU32::put_varint_prefixed_slice(&mut encoded, largest.data());
+        let mut edit = VersionEdit::new(7);
+        edit.decoded_from(&encoded).unwrap();
+        assert_eq!(edit.file_delta.new_files.len(), 1);
+        let (level, f) = &edit.file_delta.new_files[0];
+        assert_eq!(*level, 2);
+        assert_eq!(f.number, 10);
+        assert_eq!(f.file_size, 4096);
+        // The keys are decoded without their length prefixes
+        assert_eq!(f.smallest.data(), smallest.data());
+        assert_eq!(f.smallest.user_key(), b"foo");
+        assert_eq!(f.largest.data(), largest.data());
+        assert_eq!(f.largest.user_key(), b"zoo");
+        assert_eq!(f.properties, FileProperties::default());
     }
 
-    // impl VersionEdit {
-    //     fn add_compaction_pointer(&mut self, level: usize, key: InternalKey) {
-    //         self.file_delta.compaction_pointers.push((level, key))
-    //     }
-    // }
-
-    // #[test]
-    // fn test_encode_decode() {
-    //     let k_big = 1u64 << 50;
-    //     let mut edit = VersionEdit::new(7);
-    //     for i in 0..4 {
-    //         assert_encode_decode(&edit);
-    //         edit.add_file(
-    //             3,
-    //             k_big + 300 + i,
-    //             k_big + 400 + i,
-    //             InternalKey::new("foo".as_bytes(), k_big + 500 + i, ValueType::Value),
-    //             InternalKey::new("zoo".as_bytes(), k_big + 700 + i, ValueType::Deletion),
-    //         );
-    //         edit.delete_file(4, k_big + 700 + i);
-    //         edit.add_compaction_pointer(
-    //             i as usize,
-    //             InternalKey::new("x".as_bytes(), k_big + 900 + i, ValueType::Value),
-    //         );
-    //     }
-    //     edit.set_comparator_name("foo".to_owned());
-    //     edit.set_log_number(k_big + 100);
-    //     edit.set_next_file(k_big + 200);
-    //     edit.set_last_sequence(k_big + 1000);
-    //     assert_encode_decode(&edit);
-    // }
+    #[test]
+    fn test_file_properties_encode_decode() {
+        let mut meta = FileMetaData {
+            number: 5,
+            file_size: 4096,
+            smallest: InternalKey::new(b"a", 3, ValueType::Value),
+            largest: InternalKey::new(b"c", 1, ValueType::Deletion),
+            ..Default::default()
+        };
+        meta.properties.add(meta.smallest.data());
+        meta.properties.add(meta.largest.data());
+        meta.properties.creation_time = 1_700_000_000;
+        assert_eq!(
+            meta.properties,
+            FileProperties {
+                num_entries: 2,
+                num_deletions: 1,
+                largest_seq: 3,
+                creation_time: 1_700_000_000,
+            }
+        );
+        // Each deletion counts for twice the average entry size
+        assert_eq!(meta.compensated_size(), 4096 + 2 * 2048);
+
+        let mut edit = VersionEdit::new(7);
+        edit.add_file_meta(2, &meta);
+        let mut encoded = vec![];
+        edit.encode_to(&mut encoded);
+        let mut parsed = VersionEdit::new(7);
+        parsed.decoded_from(&encoded).unwrap();
+        let (level, parsed_meta) = &parsed.file_delta.new_files[0];
+        assert_eq!(*level, 2);
+        assert_eq!(*parsed_meta, meta);
+        assert_eq!(parsed_meta.properties, meta.properties);
+    }
 
     #[test]
     fn test_set_comparator_name() {
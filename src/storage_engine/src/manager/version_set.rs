@@ -17,6 +17,7 @@
 
 use std::{
     cmp::Ordering as CmpOrdering,
+    io::SeekFrom,
     ops::Add,
     path::MAIN_SEPARATOR,
     sync::{atomic::Ordering, Arc},
@@ -27,7 +28,7 @@ use super::{
     filename::{parse_filename, update_current},
     snapshot::{Snapshot, SnapshotList},
     version::{LevelFileNumIterator, Version, FILE_META_LENGTH},
-    version_edit::{FileDelta, FileMetaData, VersionEdit},
+    version_edit::{FileDelta, FileMetaData, FileProperties, VersionEdit},
 };
 use crate::{
     cache::table_cache::TableCache,
@@ -43,7 +44,7 @@ use crate::{
     },
     manager::filename::{generate_filename, FileType},
     memtable::key_format::{InternalKey, InternalKeyComparator},
-    options::{Options, ReadOptions},
+    options::{CompactionPriority, MutableOptions, Options, ReadOptions},
     sstable::table::{TableBuilder, TableIterator},
     storage::{File, Storage},
     util::{
@@ -180,12 +181,18 @@ pub struct VersionSet<S: Storage + Clone, C: Comparator> {
     pub snapshots: SnapshotList,
     // Set of table files to protect them from deletion because they are part of ongoing compaction
     pub pending_outputs: HashSet<u64>,
+    // WAL files being copied by checkpoints, kept from deletion until copied. A file copied by
+    // several checkpoints at once shows up several times.
+    pub pinned_logs: Vec<u64>,
     // WAL writer
     pub record_writer: Option<Writer<S::F>>,
 
     db_path: String,
     storage: S,
     options: Arc<Options<C>>,
+    // The mutable options in effect, changed by `TemplateDB::set_options` with the lock of the
+    // version set held
+    mutable_options: MutableOptions,
     icmp: InternalKeyComparator<C>,
 
     // the next available file number
@@ -199,6 +206,9 @@ pub struct VersionSet<S: Storage + Clone, C: Comparator> {
     // the current manifest file number
     manifest_file_number: u64,
     manifest_writer: Option<Writer<S::F>>,
+    // The number of the MANIFEST file replayed by `recover` or `catch_up_with_manifest` and
+    // the offset right after the last record replayed
+    manifest_tail: Option<(u64, u64)>,
 
     versions: Vec<Arc<Version<C>>>,
 
@@ -219,13 +229,16 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         // Create an empty version as the first
         let first_v = Arc::new(Version::new(options.clone(), icmp.clone()));
         let versions = vec![first_v];
+        let mutable_options = MutableOptions::from(options.as_ref());
         Self {
             snapshots: SnapshotList::default(),
             pending_outputs: HashSet::default(),
+            pinned_logs: vec![],
             db_path,
             storage,
             record_writer: None,
             options,
+            mutable_options,
             icmp,
             next_file_number: 0,
             last_sequence: 0,
@@ -233,10 +246,22 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             prev_log_number: 0,
             manifest_file_number: 0,
             manifest_writer: None,
+            manifest_tail: None,
             versions,
             compaction_pointer,
         }
     }
+    /// Returns the mutable options in effect
+    #[inline]
+    pub fn mutable_options(&self) -> MutableOptions {
+        self.mutable_options
+    }
+
+    #[inline]
+    pub(crate) fn set_mutable_options(&mut self, mutable_options: MutableOptions) {
+        self.mutable_options = mutable_options;
+    }
+
     /// Returns the number of files in a certain level using latest version
     #[inline]
     pub fn level_files_count(&self, level: usize) -> usize {
@@ -483,13 +508,18 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             let mut total = 0;
             for (i, file) in overlapping_inputs.iter().enumerate() {
                 total += file.file_size;
-                if total >= version.options.max_file_size {
+                if total >= self.mutable_options.max_file_size {
                     overlapping_inputs.truncate(i + 1);
                     break;
                 }
             }
         }
-        let mut c = Compaction::new(self.options.clone(), level, CompactionReason::Manual);
+        let mut c = Compaction::new(
+            self.options.clone(),
+            self.mutable_options,
+            level,
+            CompactionReason::Manual,
+        );
         c.input_version = Some(version);
         c.inputs.base = overlapping_inputs;
         Some(self.setup_other_inputs(c))
@@ -522,38 +552,40 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
                     level,
                     self.options.max_levels
                 );
-                let mut compaction =
-                    Compaction::new(self.options.clone(), level, CompactionReason::MaxSize);
-                // Pick the first file that comes after compact_pointer[level]
-                for file in current.files[level].iter() {
-                    if self.compaction_pointer[level].is_empty()
-                        || self
-                            .icmp
-                            .compare(file.largest.data(), self.compaction_pointer[level].data())
-                            == CmpOrdering::Greater
-                    {
-                        compaction.inputs.add_base(file.clone());
-                        break;
-                    }
-                }
-                if compaction.inputs.base.is_empty() {
-                    if let Some(file) = current.files[0].first() {
-                        // Wrap-around to the beginning of the key space
-                        compaction.inputs.add_base(file.clone())
-                    }
+                let mut compaction = Compaction::new(
+                    self.options.clone(),
+                    self.mutable_options,
+                    level,
+                    CompactionReason::MaxSize,
+                );
+                if let Some(file) = self.pick_file_to_compact(&current, level) {
+                    compaction.inputs.add_base(file);
                 }
                 compaction
             } else if seek_compaction {
                 let level = current.file_to_compact_level.load(Ordering::Acquire);
                 if level < self.options.max_levels - 1 {
-                    let mut compaction =
-                        Compaction::new(self.options.clone(), level, CompactionReason::SeekLimit);
+                    let mut compaction = Compaction::new(
+                        self.options.clone(),
+                        self.mutable_options,
+                        level,
+                        CompactionReason::SeekLimit,
+                    );
                     compaction.inputs.add_base(file_to_compact);
                     compaction
                 } else {
                     // We've run out of the levels
                     return None;
                 }
+            } else if let Some((level, file)) = current.periodic_compaction_file() {
+                let mut compaction = Compaction::new(
+                    self.options.clone(),
+                    self.mutable_options,
+                    level,
+                    CompactionReason::Periodic,
+                );
+                compaction.inputs.add_base(file);
+                compaction
             } else {
                 return None;
             }
@@ -586,6 +618,54 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         Some(compaction)
     }
 
+    // Picks the file in `level` to start a size-triggered compaction with, according to
+    // `compaction_priority`
+    fn pick_file_to_compact(
+        &self,
+        current: &Version<C>,
+        level: usize,
+    ) -> Option<Arc<FileMetaData>> {
+        let files = &current.files[level];
+        match self.options.compaction_priority {
+            CompactionPriority::RoundRobin => files
+                .iter()
+                // Pick the first file that comes after compact_pointer[level]
+                .find(|file| {
+                    self.compaction_pointer[level].is_empty()
+                        || self
+                            .icmp
+                            .compare(file.largest.data(), self.compaction_pointer[level].data())
+                            == CmpOrdering::Greater
+                })
+                // Wrap-around to the beginning of the key space
+                .or_else(|| files.first())
+                .cloned(),
+            CompactionPriority::ByCompensatedSize => files
+                .iter()
+                .max_by_key(|file| file.compensated_size())
+                .cloned(),
+            CompactionPriority::OldestLargestSeqFirst => files
+                .iter()
+                .min_by_key(|file| file.properties.largest_seq)
+                .cloned(),
+            CompactionPriority::MinOverlappingRatio => {
+                let mut best: Option<(f64, &Arc<FileMetaData>)> = None;
+                for file in files.iter() {
+                    let overlapping = total_file_size(&current.get_overlapping_inputs(
+                        level + 1,
+                        Some(&file.smallest),
+                        Some(&file.largest),
+                    ));
+                    let ratio = overlapping as f64 / file.file_size.max(1) as f64;
+                    if best.map_or(true, |(r, _)| ratio < r) {
+                        best = Some((ratio, file));
+                    }
+                }
+                best.map(|(_, file)| file.clone())
+            }
+        }
+    }
+
     /// Persistent given memtable into a single sst file to level_0.
     /// If `into_base` is true, the file could be pushed into level1 or level2 if there's no too
     /// much overlapping.
@@ -600,11 +680,13 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         let now = SystemTime::now();
         let mut meta = FileMetaData {
             number: self.inc_next_file_number(),
+            properties: FileProperties::created_now(),
             ..Default::default()
         };
         info!("Level-0 table #{} : start building", meta.number);
         let build_result = build_table(
             self.options.clone(),
+            self.mutable_options.compression,
             &self.storage,
             db_path,
             table_cache,
@@ -624,29 +706,29 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             let largest_ukey = meta.largest.user_key();
             if into_base {
                 let base = self.current();
-                level = base.pick_level_for_memtable_output(smallest_ukey, largest_ukey);
+                level = base.pick_level_for_memtable_output(
+                    smallest_ukey,
+                    largest_ukey,
+                    self.mutable_options.max_grandparent_overlap_bytes(),
+                );
                 debug!(
                     "Pick up new level for table: level {}, table #{}",
                     level, meta.number
                 );
             }
-            edit.add_file(
-                level,
-                meta.number,
-                meta.file_size,
-                meta.smallest.clone(),
-                meta.largest.clone(),
-            );
+            edit.add_file_meta(level, &meta);
         }
-        info!(
-            "Compactions stats for Level{}: {:?}",
-            level,
-            CompactionStats {
-                micros: now.elapsed().unwrap().as_micros() as u64,
-                bytes_read: 0,
-                bytes_written: meta.file_size,
+        let stats = CompactionStats {
+            micros: now.elapsed().unwrap().as_micros() as u64,
+            bytes_read: 0,
+            bytes_written: meta.file_size,
+        };
+        info!("Compactions stats for Level{}: {:?}", level, stats);
+        if let Some(statistics) = &self.options.statistics {
+            if meta.file_size > 0 {
+                statistics.record_compaction(level, &stats);
             }
-        );
+        }
         build_result
     }
 
@@ -687,11 +769,15 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         self.pending_outputs.insert(file_number);
         let output = FileMetaData {
             number: file_number,
+            properties: FileProperties::created_now(),
             ..Default::default()
         };
         let file_name = generate_filename(&self.db_path, FileType::Table, file_number);
         let file = self.storage.create(file_name.as_str())?;
-        c.builder = Some(TableBuilder::new(file, self.icmp.clone(), &self.options));
+        let mut builder =
+            TableBuilder::new_for_level(file, self.icmp.clone(), &self.options, c.level + 1);
+        builder.set_compression(self.mutable_options.compression);
+        c.builder = Some(builder);
         c.outputs.push(output);
         Ok(())
     }
@@ -700,29 +786,10 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
     /// Returns whether we need a new MANIFEST file for later usage.
     pub fn recover(&mut self) -> TemplateResult<bool> {
         let env = self.storage.clone();
-        // Read "CURRENT" file, which contains a pointer to the current manifest file
-        let mut current = env.open(generate_filename(&self.db_path, FileType::Current, 0))?;
-        let mut buf = vec![];
-        current.read_all(&mut buf)?;
-        let (current_manifest, file_name) = match String::from_utf8(buf) {
-            Ok(s) => {
-                if s.is_empty() {
-                    return Err(TemplateKVError::Corruption(
-                        "CURRENT file is empty".to_owned(),
-                    ));
-                }
-                let mut file_name = self.db_path.to_owned();
-                file_name.push(MAIN_SEPARATOR);
-                let file_name = file_name.add(&s);
-                (env.open(&file_name)?, file_name)
-            }
-            Err(e) => {
-                return Err(TemplateKVError::Corruption(format!(
-                    "Invalid CURRENT file content: {}",
-                    e
-                )));
-            }
-        };
+        let file_name = self.current_manifest_file()?;
+        let mut current_manifest = env.open(&file_name)?;
+        // The handle might share the reading position with others
+        current_manifest.seek(SeekFrom::Start(0))?;
         let file_length = current_manifest.len()?;
         let base = Version::new(self.options.clone(), self.icmp.clone());
         let mut builder = VersionBuilder::new(self.options.max_levels, &base);
@@ -738,8 +805,10 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         let mut has_prev_log_number = false;
         let mut last_sequence = 0;
         let mut has_last_sequence = false;
+        let mut tail = 0;
         while reader.read_record(&mut buf) {
             reporter.result()?;
+            tail = reader.end_of_last_record();
             let mut edit = VersionEdit::new(self.options.max_levels);
             edit.decoded_from(&buf)?;
             debug!("Decoded manifest record: {:?}", &edit);
@@ -804,9 +873,92 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         self.last_sequence = last_sequence;
         self.log_number = log_number;
         self.prev_log_number = prev_log_number;
+        self.manifest_tail = parse_filename(&file_name).map(|(_, number)| (number, tail));
         Ok(!self.should_reuse_manifest(&file_name, file_length))
     }
 
+    /// Replays the MANIFEST records appended by another process (the primary) since the last
+    /// `recover` or `catch_up_with_manifest`. If the primary has switched to a new MANIFEST,
+    /// the new one is recovered from scratch.
+    /// Returns true if a new version is installed.
+    pub fn catch_up_with_manifest(&mut self) -> TemplateResult<bool> {
+        let file_name = self.current_manifest_file()?;
+        let number = parse_filename(&file_name).map(|(_, number)| number);
+        let offset = match self.manifest_tail {
+            Some((n, offset)) if Some(n) == number => offset,
+            _ => {
+                self.recover()?;
+                return Ok(true);
+            }
+        };
+        let mut manifest = self.storage.open(&file_name)?;
+        if manifest.len()? <= offset {
+            return Ok(false);
+        }
+        manifest.seek(SeekFrom::Start(0))?;
+        let base = self.current();
+        let mut builder = VersionBuilder::new(self.options.max_levels, &base);
+        let reporter = LogReporter::new();
+        let mut reader = Reader::new(manifest, Some(Box::new(reporter.clone())), true, offset);
+        let mut buf = vec![];
+        let mut tail = offset;
+        while reader.read_record(&mut buf) {
+            reporter.result()?;
+            tail = reader.end_of_last_record();
+            let mut edit = VersionEdit::new(self.options.max_levels);
+            edit.decoded_from(&buf)?;
+            debug!("Decoded manifest record: {:?}", &edit);
+            builder.accumulate(edit.file_delta, self);
+            if let Some(n) = edit.next_file_number {
+                self.mark_file_number_used(n);
+            }
+            if let Some(n) = edit.log_number {
+                self.log_number = n;
+            }
+            if let Some(n) = edit.prev_log_number {
+                self.prev_log_number = n;
+            }
+            if let Some(n) = edit.last_sequence {
+                self.last_sequence = self.last_sequence.max(n);
+            }
+        }
+        reporter.result()?;
+        self.manifest_tail = Some((number.unwrap(), tail));
+        if tail == offset {
+            return Ok(false);
+        }
+        let mut v = builder.apply_to_new(&self.icmp);
+        v.finalize();
+        self.append_new_version(v);
+        Ok(true)
+    }
+
+    // Reads the "CURRENT" file, which contains a pointer to the current manifest file.
+    // Returns the full path of the manifest file.
+    fn current_manifest_file(&self) -> TemplateResult<String> {
+        let mut current =
+            self.storage
+                .open(generate_filename(&self.db_path, FileType::Current, 0))?;
+        let mut buf = vec![];
+        current.read_all(&mut buf)?;
+        match String::from_utf8(buf) {
+            Ok(s) => {
+                if s.is_empty() {
+                    return Err(TemplateKVError::Corruption(
+                        "CURRENT file is empty".to_owned(),
+                    ));
+                }
+                let mut file_name = self.db_path.to_owned();
+                file_name.push(MAIN_SEPARATOR);
+                Ok(file_name.add(&s))
+            }
+            Err(e) => Err(TemplateKVError::Corruption(format!(
+                "Invalid CURRENT file content: {}",
+                e
+            ))),
+        }
+    }
+
     /// Forward to `num + 1` as the next file number
     pub fn mark_file_number_used(&mut self, num: u64) {
         if self.next_file_number <= num {
@@ -862,13 +1014,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
         // Save files
         for level in 0..self.options.max_levels {
             for file in self.current().files[level].iter() {
-                edit.add_file(
-                    level,
-                    file.number,
-                    file.file_size,
-                    file.smallest.clone(),
-                    file.largest.clone(),
-                );
+                edit.add_file_meta(level, file);
             }
         }
 
@@ -916,7 +1062,8 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             let next_size = total_file_size(&overlapping_next_level);
             // We do expand the current(`c.level`) inputs and not reach the compaction size limit
             if expanded0.len() > not_expand.len()
-                && next_size + expanded0_size <= self.options.expanded_compaction_byte_size_limit()
+                && next_size + expanded0_size
+                    <= self.mutable_options.expanded_compaction_byte_size_limit()
             {
                 let (new_smallest, new_largest) = base_range(&expanded0, c.level, &self.icmp);
                 // TODO: use a more sufficient way to checking expanding in L(n+1) ?
@@ -994,7 +1141,7 @@ impl<S: Storage + Clone + 'static, C: Comparator + 'static> VersionSet<S, C> {
             return false;
         }
         if let Some((file_type, file_number)) = parse_filename(manifest_file) {
-            if file_type != FileType::Manifest || file_size > self.options.max_file_size {
+            if file_type != FileType::Manifest || file_size > self.mutable_options.max_file_size {
                 // Make new compacted MANIFEST if old one is too big
                 return false;
             };
@@ -1448,6 +1595,7 @@ mod add_boundary_tests {
             number,
             smallest: InternalKey::new(number.to_string().as_bytes(), 1, ValueType::Value),
             largest: InternalKey::new(number.to_string().as_bytes(), 2, ValueType::Value),
+            properties: Default::default(),
         }
     }
 
@@ -1566,4 +1714,128 @@ mod add_boundary_tests {
             new_v.assert_files(expect);
         }
     }
+
+    fn new_sized_file(
+        number: u64,
+        smallest: &str,
+        largest: &str,
+        file_size: u64,
+        properties: FileProperties,
+    ) -> Arc<FileMetaData> {
+        Arc::new(FileMetaData {
+            number,
+            file_size,
+            smallest: InternalKey::new(smallest.as_bytes(), 1, ValueType::Value),
+            largest: InternalKey::new(largest.as_bytes(), 1, ValueType::Value),
+            properties,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_pick_file_by_compaction_priority() {
+        let props = |num_entries, num_deletions, largest_seq| FileProperties {
+            num_entries,
+            num_deletions,
+            largest_seq,
+            creation_time: 0,
+        };
+        // compensated sizes: 1000, 3000, 1500
+        // overlapping ratios: 5, 8, 0.006
+        let level1 = vec![
+            new_sized_file(1, "a", "c", 1000, props(10, 0, 30)),
+            new_sized_file(2, "d", "f", 1000, props(10, 10, 20)),
+            new_sized_file(3, "g", "i", 1500, props(10, 0, 10)),
+        ];
+        let level2 = vec![
+            new_sized_file(4, "a", "c", 5000, props(10, 0, 1)),
+            new_sized_file(5, "d", "f", 8000, props(10, 0, 1)),
+            new_sized_file(6, "g", "i", 10, props(10, 0, 1)),
+        ];
+        for (priority, expected) in [
+            (CompactionPriority::RoundRobin, 1),
+            (CompactionPriority::ByCompensatedSize, 2),
+            (CompactionPriority::OldestLargestSeqFirst, 3),
+            (CompactionPriority::MinOverlappingRatio, 3),
+        ] {
+            let mut opts = Options::<BytewiseComparator>::default();
+            opts.compaction_priority = priority;
+            let opts = Arc::new(opts);
+            let mut vset = VersionSet::new("test".to_owned(), opts.clone(), MemStorage::default());
+            let mut v = Version::new(opts, vset.icmp.clone());
+            v.files[1] = level1.clone();
+            v.files[2] = level2.clone();
+            vset.versions.push(Arc::new(v));
+            let current = vset.current();
+            let picked = vset.pick_file_to_compact(&current, 1).unwrap();
+            assert_eq!(picked.number, expected, "{:?}", priority);
+            if priority == CompactionPriority::RoundRobin {
+                vset.compaction_pointer[1] = level1[0].largest.clone();
+                let picked = vset.pick_file_to_compact(&current, 1).unwrap();
+                assert_eq!(picked.number, 2);
+                // Wrap-around to the first file of the level
+                vset.compaction_pointer[1] = level1[2].largest.clone();
+                let picked = vset.pick_file_to_compact(&current, 1).unwrap();
+                assert_eq!(picked.number, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dynamic_level_bytes() {
+        let mut opts = Options::<BytewiseComparator>::default();
+        opts.l1_max_bytes = 1000;
+        assert_eq!(
+            opts.max_bytes_for_levels(&[0, 0, 0, 50000, 0, 0, 0])[1..4],
+            [1000, 10000, 100000]
+        );
+        opts.level_compaction_dynamic_level_bytes = true;
+        let targets = opts.max_bytes_for_levels(&[0, 0, 0, 50000, 0, 0, 0]);
+        assert_eq!(targets[1..4], [500, 5000, 100000]);
+        // Never below `l1_max_bytes / max_bytes_for_level_multiplier`
+        let targets = opts.max_bytes_for_levels(&[0, 0, 0, 0, 20000, 0, 0]);
+        assert_eq!(targets[1..5], [100, 200, 2000, 1000000]);
+        opts.max_bytes_for_level_multiplier = 4.0;
+        let targets = opts.max_bytes_for_levels(&[0, 0, 0, 32000, 0, 0, 0]);
+        assert_eq!(targets[1..4], [2000, 8000, 16000]);
+
+        // A level 2 far below the static target still needs compaction in dynamic mode
+        opts.max_bytes_for_level_multiplier = 10.0;
+        let opts = Arc::new(opts);
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let mut v = Version::new(opts, icmp);
+        let props = FileProperties::default();
+        v.files[2] = vec![new_sized_file(1, "a", "b", 6000, props)];
+        v.files[3] = vec![new_sized_file(2, "a", "b", 50000, props)];
+        v.finalize();
+        assert_eq!(v.compaction_level, 2);
+        assert!(v.compaction_score > 1.0);
+    }
+
+    #[test]
+    fn test_periodic_compaction_file() {
+        let now = crate::manager::version_edit::unix_time_secs();
+        let created = |secs_ago: u64| FileProperties {
+            creation_time: now - secs_ago,
+            ..Default::default()
+        };
+        let mut opts = Options::<BytewiseComparator>::default();
+        opts.periodic_compaction_seconds = Some(3600);
+        let opts = Arc::new(opts);
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        let mut v = Version::new(opts.clone(), icmp);
+        v.files[1] = vec![
+            new_sized_file(1, "a", "b", 100, created(10)),
+            // Recorded by an older version without a creation time
+            new_sized_file(2, "c", "d", 100, FileProperties::default()),
+        ];
+        assert!(v.periodic_compaction_file().is_none());
+        v.files[2] = vec![new_sized_file(3, "a", "b", 100, created(4000))];
+        v.files[3] = vec![new_sized_file(4, "a", "b", 100, created(5000))];
+        // Files in the last level are never compacted periodically
+        v.files[opts.max_levels - 1] = vec![new_sized_file(5, "a", "b", 100, created(9000))];
+        let (level, file) = v.periodic_compaction_file().unwrap();
+        assert_eq!((level, file.number), (3, 4));
+        assert!(v.needs_compaction());
+    }
 }
@@ -101,6 +101,15 @@ impl WriteBatch {
 
     /// Insert all the records in the batch into the given `MemTable`
     pub fn insert_into<C: Comparator>(&self, mem: &MemTable<C>) -> TemplateResult<()> {
+        self.iterate(|seq, value_type, key, value| mem.add(seq, value_type, key, value))
+    }
+
+    /// Calls `handler` with the sequence number, the type, the key and the value of every
+    /// record in the batch in order. The value of a deletion is empty.
+    pub fn iterate<'a, H: FnMut(u64, ValueType, &'a [u8], &'a [u8])>(
+        &'a self,
+        mut handler: H,
+    ) -> TemplateResult<()> {
         if self.contents.len() < HEADER_SIZE {
             return Err(TemplateKVError::Corruption(
                 "[batch] malformed WriteBatch (too small)".to_owned(),
@@ -117,7 +126,7 @@ impl WriteBatch {
                 ValueType::Value => {
                     if let Some(key) = VarintU32::get_varint_prefixed_slice(&mut s) {
                         if let Some(value) = VarintU32::get_varint_prefixed_slice(&mut s) {
-                            mem.add(seq, ValueType::Value, key, value);
+                            handler(seq, ValueType::Value, key, value);
                             seq += 1;
                             continue;
                         }
@@ -128,7 +137,7 @@ impl WriteBatch {
                 }
                 ValueType::Deletion => {
                     if let Some(key) = VarintU32::get_varint_prefixed_slice(&mut s) {
-                        mem.add(seq, ValueType::Deletion, key, b"");
+                        handler(seq, ValueType::Deletion, key, b"");
                         seq += 1;
                         continue;
                     }
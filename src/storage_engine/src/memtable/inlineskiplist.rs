@@ -18,6 +18,9 @@ use crate::{
 const MAX_HEIGHT: usize = 20;
 const HEIGHT_INCREASE: u32 = u32::MAX / 3;
 
+/// The max arena space taken by a single node, including the padding for alignment
+pub const MAX_NODE_SIZE: usize = mem::size_of::<Node>() + mem::align_of::<Node>();
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct Node {
@@ -24,6 +24,8 @@ use crate::{
 pub struct MemTable<C: Comparator> {
     cmp: KeyComparator<C>,
     table: InlineSkipList<KeyComparator<C>, OffsetArena>,
+    // The size of the underlying arena
+    capacity: usize,
 }
 
 impl<C: Comparator> MemTable<C> {
@@ -32,7 +34,17 @@ impl<C: Comparator> MemTable<C> {
         let arena = OffsetArena::with_capacity(max_mem_size);
         let kcmp = KeyComparator { icmp };
         let table = InlineSkipList::new(kcmp.clone(), arena);
-        Self { cmp: kcmp, table }
+        Self {
+            cmp: kcmp,
+            table,
+            capacity: max_mem_size,
+        }
+    }
+
+    /// Returns the max memory size given when creating this table
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
     /// Returns an estimate of the number of bytes of data in use by this
@@ -0,0 +1,459 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write as _},
+    io,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tonic::{
+    codegen::{http, BoxFuture, Service},
+    server::NamedService,
+};
+
+use crate::{db_impl::template_impl::TemplateDB, storage::Storage, util::comparator::Comparator};
+
+// The upper bounds of the buckets of the RPC latency histograms in seconds
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+// The max size of the head of a scrape request
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+type Collector = Box<dyn Fn(&mut MetricsWriter) + Send + Sync>;
+
+/// The metrics of a server exposed in the Prometheus text format.
+///
+/// The RPCs are counted and timed by the registry itself. Everything else is read by the
+/// collectors registered when the metrics are scraped.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    rpcs: Mutex<BTreeMap<String, RpcStats>>,
+    collectors: Mutex<Vec<Collector>>,
+}
+
+#[derive(Default)]
+struct RpcStats {
+    ok: u64,
+    errors: u64,
+    // The number of the requests in every bucket of `LATENCY_BUCKETS` and the one above
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    total_seconds: f64,
+}
+
+impl MetricsRegistry {
+    /// Records a request of `rpc` taking `elapsed`
+    pub fn observe_rpc(&self, rpc: &str, elapsed: Duration, ok: bool) {
+        let seconds = elapsed.as_secs_f64();
+        let mut rpcs = self.rpcs.lock().unwrap();
+        let stats = match rpcs.get_mut(rpc) {
+            Some(stats) => stats,
+            None => rpcs.entry(rpc.to_owned()).or_default(),
+        };
+        if ok {
+            stats.ok += 1;
+        } else {
+            stats.errors += 1;
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| seconds <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        stats.buckets[bucket] += 1;
+        stats.total_seconds += seconds;
+    }
+
+    /// Adds a collector writing its metrics on every scrape
+    pub fn register<F>(&self, collector: F)
+    where
+        F: Fn(&mut MetricsWriter) + Send + Sync + 'static,
+    {
+        self.collectors.lock().unwrap().push(Box::new(collector));
+    }
+
+    /// Adds the memtable memory, the sst files per level, the block cache usage and the WAL size
+    /// of `db`
+    pub fn register_db<S: Storage + Clone + 'static, C: Comparator + 'static>(
+        &self,
+        db: TemplateDB<S, C>,
+    ) {
+        self.register(move |w| {
+            let (mem, im_mem) = db.memtable_memory_usage();
+            w.header(
+                "db_memtable_memory_bytes",
+                "The memory used by the memtables",
+                MetricType::Gauge,
+            );
+            w.sample("db_memtable_memory_bytes", &[("memtable", "active")], mem);
+            w.sample(
+                "db_memtable_memory_bytes",
+                &[("memtable", "immutable")],
+                im_mem,
+            );
+
+            let levels = db.level_file_stats();
+            w.header(
+                "db_level_files",
+                "The number of the sst files in a level",
+                MetricType::Gauge,
+            );
+            for (level, (files, _)) in levels.iter().enumerate() {
+                w.sample("db_level_files", &[("level", &level.to_string())], files);
+            }
+            w.header(
+                "db_level_bytes",
+                "The total size of the sst files in a level",
+                MetricType::Gauge,
+            );
+            for (level, (_, bytes)) in levels.iter().enumerate() {
+                w.sample("db_level_bytes", &[("level", &level.to_string())], bytes);
+            }
+
+            if let Some(cache) = &db.inner.options.block_cache {
+                w.header(
+                    "db_block_cache_usage_bytes",
+                    "The total charge of the entries in the block cache",
+                    MetricType::Gauge,
+                );
+                w.sample("db_block_cache_usage_bytes", &[], cache.total_charge());
+            }
+
+            match db.wal_bytes() {
+                Ok(bytes) => {
+                    w.header(
+                        "db_wal_bytes",
+                        "The total size of the WAL files",
+                        MetricType::Gauge,
+                    );
+                    w.sample("db_wal_bytes", &[], bytes);
+                }
+                Err(e) => warn!("Failed to collect the WAL size: {:?}", e),
+            }
+        });
+    }
+
+    /// Returns all the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut w = MetricsWriter::default();
+        {
+            let rpcs = self.rpcs.lock().unwrap();
+            w.header(
+                "rpc_requests_total",
+                "The number of the requests served",
+                MetricType::Counter,
+            );
+            for (rpc, stats) in rpcs.iter() {
+                w.sample(
+                    "rpc_requests_total",
+                    &[("rpc", rpc), ("result", "ok")],
+                    stats.ok,
+                );
+                w.sample(
+                    "rpc_requests_total",
+                    &[("rpc", rpc), ("result", "error")],
+                    stats.errors,
+                );
+            }
+            w.header(
+                "rpc_request_duration_seconds",
+                "The latency of the requests",
+                MetricType::Histogram,
+            );
+            for (rpc, stats) in rpcs.iter() {
+                let mut cumulative = 0;
+                for (le, count) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+                    cumulative += count;
+                    w.sample(
+                        "rpc_request_duration_seconds_bucket",
+                        &[("rpc", rpc), ("le", &le.to_string())],
+                        cumulative,
+                    );
+                }
+                let count = stats.ok + stats.errors;
+                w.sample(
+                    "rpc_request_duration_seconds_bucket",
+                    &[("rpc", rpc), ("le", "+Inf")],
+                    count,
+                );
+                w.sample(
+                    "rpc_request_duration_seconds_sum",
+                    &[("rpc", rpc)],
+                    stats.total_seconds,
+                );
+                w.sample("rpc_request_duration_seconds_count", &[("rpc", rpc)], count);
+            }
+        }
+        for collector in self.collectors.lock().unwrap().iter() {
+            collector(&mut w);
+        }
+        w.out
+    }
+}
+
+/// Wraps a gRPC service to count and time its requests in a registry.
+///
+/// A request is named by the method in its path and fails if its response carries a
+/// `grpc-status` other than OK. The latency of a streaming request ends at its response headers.
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    registry: Arc<MetricsRegistry>,
+}
+
+impl<S> MetricsService<S> {
+    pub fn new(inner: S, registry: Arc<MetricsRegistry>) -> Self {
+        Self { inner, registry }
+    }
+}
+
+impl<S, B, ResBody> Service<http::Request<B>> for MetricsService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        // The path is `/<package>.<service>/<method>`
+        let rpc = req
+            .uri()
+            .path()
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let registry = self.registry.clone();
+        let start = Instant::now();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let ok = match &res {
+                Ok(resp) => resp
+                    .headers()
+                    .get("grpc-status")
+                    .map_or(true, |status| status == "0"),
+                Err(_) => false,
+            };
+            registry.observe_rpc(&rpc, start.elapsed(), ok);
+            res
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for MetricsService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+/// The type of a metric family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// Writes the metric families in the Prometheus text format
+#[derive(Default)]
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    /// Starts a metric family. The samples of the family must follow right after it.
+    pub fn header(&mut self, name: &str, help: &str, metric_type: MetricType) {
+        let metric_type = match metric_type {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+        };
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, metric_type);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, v)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", label, escape_label_value(v));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+}
+
+fn escape_label_value(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` with the metrics of `registry` over HTTP/1.1 until the listener fails
+pub async fn serve_metrics(
+    listener: TcpListener,
+    registry: Arc<MetricsRegistry>,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_scrape(stream, &registry).await {
+                debug!("metrics scrape from {} failed: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+// Answers one request and closes the connection
+async fn serve_scrape(mut stream: TcpStream, registry: &MetricsRegistry) -> io::Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+        if head.len() > MAX_REQUEST_HEAD {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+    }
+    let request_line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            respond(&mut stream, "200 OK", &registry.render()).await
+        }
+        (Some(b"GET"), _) => respond(&mut stream, "404 Not Found", "").await,
+        _ => respond(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let registry = MetricsRegistry::default();
+        registry.observe_rpc("Get", Duration::from_millis(3), true);
+        registry.observe_rpc("Get", Duration::from_secs(20), false);
+        registry.register(|w| {
+            w.header("tenant_bytes", "The bytes of a tenant", MetricType::Gauge);
+            w.sample("tenant_bytes", &[("tenant", "a\"b")], 42);
+        });
+        let text = registry.render();
+        for line in [
+            "# TYPE rpc_requests_total counter",
+            "rpc_requests_total{rpc=\"Get\",result=\"ok\"} 1",
+            "rpc_requests_total{rpc=\"Get\",result=\"error\"} 1",
+            "# TYPE rpc_request_duration_seconds histogram",
+            "rpc_request_duration_seconds_bucket{rpc=\"Get\",le=\"0.001\"} 0",
+            "rpc_request_duration_seconds_bucket{rpc=\"Get\",le=\"0.005\"} 1",
+            "rpc_request_duration_seconds_bucket{rpc=\"Get\",le=\"10\"} 1",
+            "rpc_request_duration_seconds_bucket{rpc=\"Get\",le=\"+Inf\"} 2",
+            "rpc_request_duration_seconds_count{rpc=\"Get\"} 2",
+            "# TYPE tenant_bytes gauge",
+            "tenant_bytes{tenant=\"a\\\"b\"} 42",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+    }
+
+    #[derive(Clone)]
+    struct StatusService;
+
+    impl Service<http::Request<()>> for StatusService {
+        type Response = http::Response<()>;
+        type Error = std::convert::Infallible;
+        type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        // Answers with the status in the `status` header of the request
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            let mut resp = http::Response::new(());
+            if let Some(status) = req.headers().get("status") {
+                resp.headers_mut().insert("grpc-status", status.clone());
+            }
+            std::future::ready(Ok(resp))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_service() {
+        let registry = Arc::new(MetricsRegistry::default());
+        let mut service = MetricsService::new(StatusService, registry.clone());
+        for status in [None, Some("0"), Some("5")] {
+            let mut req = http::Request::builder().uri("/kv_service.KvService/Get");
+            if let Some(status) = status {
+                req = req.header("status", status);
+            }
+            service.call(req.body(()).unwrap()).await.unwrap();
+        }
+        let text = registry.render();
+        for line in [
+            "rpc_requests_total{rpc=\"Get\",result=\"ok\"} 2",
+            "rpc_requests_total{rpc=\"Get\",result=\"error\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        let registry = Arc::new(MetricsRegistry::default());
+        registry.observe_rpc("Put", Duration::from_millis(1), true);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, registry));
+
+        let scrape = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).await.unwrap();
+            resp
+        };
+        let resp = scrape("/metrics").await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+        assert!(resp.contains("rpc_requests_total{rpc=\"Put\",result=\"ok\"} 1"));
+        let resp = scrape("/").await;
+        assert!(resp.starts_with("HTTP/1.1 404"), "{}", resp);
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{fmt::Write, sync::Arc, time::Duration};
 
 use log::{LevelFilter, Log};
 
@@ -7,16 +7,22 @@ use crate::{
         bloom_filter_cache::BloomFilter, lru_cache::LRUCache, sharded_cache::ShardedCache,
         CacheSync, FilterPolicy,
     },
+    error::{TemplateKVError, TemplateResult},
+    listener::EventListener,
     logger::Logger,
     manager::snapshot::Snapshot,
     memtable::key_format::InternalFilterPolicy,
     sstable::block::Block,
+    statistics::Statistics,
     storage::{File, Storage},
-    util::comparator::Comparator,
+    util::{collection::HashMap, comparator::Comparator},
 };
 
 pub const DEFAULT_CACHE_SHARDS: usize = 8;
 
+// The capacity of the internal block cache created if `Options::block_cache` is null
+const DEFAULT_BLOCK_CACHE_CAPACITY: usize = 64 << 20;
+
 /// The max key sequence number. The value is 2^56 - 1 because the seq number
 /// only takes 56 bits when is serialized to `InternalKey`
 pub const MAX_KEY_SEQUENCE: u64 = (1u64 << 56) - 1;
@@ -25,6 +31,9 @@ pub const MAX_KEY_SEQUENCE: u64 = (1u64 << 56) - 1;
 /// 7bytes sequence number + 1byte type number
 pub const INTERNAL_KEY_TAIL: usize = 8;
 
+/// The header line of an `OPTIONS-*` file
+const OPTIONS_FILE_HEADER: &str = "# TemplateDB options file";
+
 #[derive(Clone, Copy, Debug, FromPrimitive)]
 pub enum CompressionType {
     NoCompression = 0,
@@ -38,6 +47,133 @@ impl From<u8> for CompressionType {
     }
 }
 
+/// The layout of the index and the filter of a table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexType {
+    /// A single index block and a single filter block, both loaded into memory when the table
+    /// is opened
+    Binary = 0,
+    /// The index is split into partitions indexed by a top-level index, and the filter is
+    /// partitioned along with the index. Only the top-level index is loaded when the table is
+    /// opened and the partitions are read on demand through the block cache.
+    TwoLevel = 1,
+}
+
+/// How a size-triggered compaction picks the file to compact in the level exceeding its target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionPriority {
+    /// The first file after the key where the last compaction of the level stopped, cycling
+    /// through the key space
+    RoundRobin,
+    /// The largest file, with the deletions weighted more since they free the space of the
+    /// entries they shadow in the lower levels
+    ByCompensatedSize,
+    /// The file whose newest entry is the oldest, which fits the workloads updating a hot
+    /// key range only
+    OldestLargestSeqFirst,
+    /// The file with the smallest ratio of the bytes overlapping in the next level to its own
+    /// size, which minimizes the write amplification
+    MinOverlappingRatio,
+}
+
+// The names of the options in `MutableOptions`
+const MUTABLE_OPTIONS: [&str; 5] = [
+    "write_buffer_size",
+    "l0_slowdown_writes_threshold",
+    "l0_stop_writes_threshold",
+    "max_file_size",
+    "compression",
+];
+
+/// The subset of `Options` that can be changed without reopening the db.
+/// See `TemplateDB::set_options` for details.
+#[derive(Clone, Copy, Debug)]
+pub struct MutableOptions {
+    pub write_buffer_size: usize,
+    pub l0_slowdown_writes_threshold: usize,
+    pub l0_stop_writes_threshold: usize,
+    pub max_file_size: u64,
+    pub compression: CompressionType,
+}
+
+impl MutableOptions {
+    /// Sets the option named `name` by parsing `value`.
+    /// Returns `InvalidArgument` if the option is unknown or immutable, or the value is invalid.
+    pub fn apply(&mut self, name: &str, value: &str) -> TemplateResult<()> {
+        match name {
+            "write_buffer_size" => self.write_buffer_size = parse_option(name, value)?,
+            "l0_slowdown_writes_threshold" => {
+                self.l0_slowdown_writes_threshold = parse_option(name, value)?
+            }
+            "l0_stop_writes_threshold" => {
+                self.l0_stop_writes_threshold = parse_option(name, value)?
+            }
+            "max_file_size" => self.max_file_size = parse_option(name, value)?,
+            "compression" => {
+                self.compression = match value {
+                    "NoCompression" => CompressionType::NoCompression,
+                    "SnappyCompression" => CompressionType::SnappyCompression,
+                    _ => {
+                        return Err(TemplateKVError::InvalidArgument(format!(
+                            "invalid value {:?} for option compression",
+                            value
+                        )))
+                    }
+                }
+            }
+            _ => {
+                return Err(TemplateKVError::InvalidArgument(format!(
+                    "unknown or immutable option {:?}",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Maximum number of bytes in all compacted files.  We avoid expanding
+    /// the lower level file set of a compaction if it would make the
+    /// total compaction cover more than this many bytes.
+    pub(crate) fn expanded_compaction_byte_size_limit(&self) -> u64 {
+        25 * self.max_file_size
+    }
+
+    /// Maximum bytes of overlaps in grandparent (i.e., level+2) before we
+    /// stop building a single file in a level-> level+1 compaction.
+    pub(crate) fn max_grandparent_overlap_bytes(&self) -> u64 {
+        10 * self.max_file_size
+    }
+
+    /// Returns `InvalidArgument` if the options contradict each other
+    pub fn validate(&self) -> TemplateResult<()> {
+        if self.l0_slowdown_writes_threshold > self.l0_stop_writes_threshold {
+            return Err(TemplateKVError::InvalidArgument(format!(
+                "l0_slowdown_writes_threshold {} exceeds l0_stop_writes_threshold {}",
+                self.l0_slowdown_writes_threshold, self.l0_stop_writes_threshold
+            )));
+        }
+        Ok(())
+    }
+
+    // Limits the ranges the same way as `Options::initialize`
+    pub(crate) fn sanitize(&mut self) {
+        self.write_buffer_size = clip_range(self.write_buffer_size, 64 << 10, 1 << 30);
+        self.max_file_size = clip_range(self.max_file_size, 1 << 20, 1 << 30);
+    }
+}
+
+impl<C: Comparator> From<&Options<C>> for MutableOptions {
+    fn from(o: &Options<C>) -> Self {
+        Self {
+            write_buffer_size: o.write_buffer_size,
+            l0_slowdown_writes_threshold: o.l0_slowdown_writes_threshold,
+            l0_stop_writes_threshold: o.l0_stop_writes_threshold,
+            max_file_size: o.max_file_size,
+            compression: o.compression,
+        }
+    }
+}
+
 /// Options to control the behavior of a database (passed to `DB::Open`)
 #[derive(Clone)]
 pub struct Options<C: Comparator> {
@@ -85,6 +221,26 @@ pub struct Options<C: Comparator> {
     /// number of bytes for a level is exceeded, compaction is requested.
     pub l1_max_bytes: u64,
 
+    /// The maximum bytes of level `n + 1` is `max_bytes_for_level_multiplier` times the maximum
+    /// bytes of level `n`
+    pub max_bytes_for_level_multiplier: f64,
+
+    /// If true, the maximum bytes of the levels are derived from the size of the bottommost
+    /// non-empty level instead of growing from `l1_max_bytes`: every level above it targets
+    /// `1 / max_bytes_for_level_multiplier` of the level below, but never less than
+    /// `l1_max_bytes / max_bytes_for_level_multiplier`. This keeps most of the data in the
+    /// bottommost level when the db is small or shrinking. The bottommost non-empty level keeps
+    /// the static target so that it still moves down as the db grows.
+    pub level_compaction_dynamic_level_bytes: bool,
+
+    /// How a size-triggered compaction picks the file to compact
+    pub compaction_priority: CompactionPriority,
+
+    /// If set, the files not in the last level and created longer than this many seconds ago
+    /// are compacted into the next level when there is no other compaction to do. A file is
+    /// never moved to the next level without being rewritten by this compaction.
+    pub periodic_compaction_seconds: Option<u64>,
+
     /// Maximum level to which a new compacted memtable is pushed if it
     /// does not create overlap.  We try to push to level 2 to avoid the
     /// relatively expensive level 0=>1 compactions and to avoid some
@@ -96,6 +252,14 @@ pub struct Options<C: Comparator> {
     /// Approximate gap in bytes between samples of data read during iteration
     pub read_bytes_period: u64,
 
+    /// If set, a background thread verifies the checksums of all the live sst files and
+    /// retained WAL files once per interval (see `TemplateDB::verify_checksums`). Any
+    /// corruption found is recorded as a background error.
+    pub background_scrub_interval: Option<Duration>,
+
+    /// The maximum bytes per second read by the background scrubber. 0 means unlimited.
+    pub background_scrub_bytes_per_second: u64,
+
     // -------------------
     // Parameters that affect performance:
     /// Amount of data to build up in memory (backed by an unsorted log
@@ -108,6 +272,16 @@ pub struct Options<C: Comparator> {
     /// the next time the database is opened.
     pub write_buffer_size: usize,
 
+    /// If true, the WAL record of a write group is appended while the previous group is still
+    /// being inserted into the memtable, instead of writing the WAL and the memtable of a group
+    /// one after another. A write still returns only after its entries are visible to reads.
+    pub enable_pipelined_write: bool,
+
+    /// If true, the batches of a write group are inserted into the memtable by their own
+    /// writers in parallel. The entries of the group become visible to reads at once after
+    /// all the batches are inserted.
+    pub allow_concurrent_memtable_write: bool,
+
     /// Number of open files that can be used by the DB.  You may need to
     /// increase this if your database has a large working set (budget
     /// one open file per 2MB of working set).
@@ -117,9 +291,17 @@ pub struct Options<C: Comparator> {
     // Control over blocks (user data is stored in a set of blocks, and
     // a block is the unit of reading from disk).
     /// If non-null, use the specified cache for blocks.
-    /// If null, we will automatically create and use an 8MB internal cache.
+    /// If null, we will automatically create and use a 64MB internal `LRUCache` split into
+    /// `block_cache_shards` shards.
+    /// A `ClockCache` scales better than the default sharded `LRUCache` under concurrent reads.
+    /// A `TieredCache` keeps the blocks evicted from the memory in a `SecondaryCache` on disk.
     pub block_cache: Option<Arc<dyn CacheSync<Vec<u8>, Arc<Block>>>>,
 
+    /// The number of shards of the internal block cache created if `block_cache` is null.
+    /// Every shard is guarded by its own lock, so more shards mean less contention between
+    /// concurrent reads.
+    pub block_cache_shards: usize,
+
     /// Number of sstables that remains out of table cache
     pub non_table_cache_files: usize,
 
@@ -152,9 +334,40 @@ pub struct Options<C: Comparator> {
     /// This can significantly speed up open.
     pub reuse_logs: bool,
 
+    /// The number of the WAL files kept after their contents are compacted into tables, so
+    /// that `TemplateDB::watch` is able to deliver the changes older than the memtable.
+    /// Default: 0
+    pub max_retained_wal_files: usize,
+
     /// If non-null, use the specified filter policy to reduce disk reads.
     pub filter_policy: Option<Arc<dyn FilterPolicy>>,
 
+    /// Overrides `filter_policy` for the tables built for particular levels. The i-th element
+    /// is used by level i, and a `None` or a missing element falls back to `filter_policy`.
+    /// The tables flushed from memtables are built for level 0.
+    ///
+    /// For example, using a `RibbonFilter` for the bottommost level only saves most of the
+    /// filter memory since most of the data lives there, while the upper levels keep the
+    /// faster `BloomFilter`. Tables remember their own policies by name, so all the policies
+    /// a table might have been built with should be kept here when moving them between levels.
+    pub filter_policy_per_level: Vec<Option<Arc<dyn FilterPolicy>>>,
+
+    /// If set, the db collects the statistics such as the bits per key of the filters and the
+    /// bytes of the compactions into it
+    pub statistics: Option<Arc<Statistics>>,
+
+    /// The listeners notified of the flushes, compactions, table file changes, write stalls
+    /// and background errors of the db
+    pub listeners: Vec<Arc<dyn EventListener>>,
+
+    /// The layout of the index and the filter of the tables to build. `IndexType::TwoLevel`
+    /// keeps the memory footprint of very large tables small. A table always remembers its
+    /// own layout so this can be changed between opens.
+    pub index_type: IndexType,
+
+    /// Approximate size of an index partition when `index_type` is `IndexType::TwoLevel`.
+    pub metadata_block_size: usize,
+
     /// The underlying logger
     /// In dev mode, default using a std output
     /// In release mode, default using a file `LOG` for output
@@ -165,31 +378,56 @@ pub struct Options<C: Comparator> {
 }
 
 impl<C: Comparator> Options<C> {
-    /// Maximum number of bytes in all compacted files.  We avoid expanding
-    /// the lower level file set of a compaction if it would make the
-    /// total compaction cover more than this many bytes.
-    pub(crate) fn expanded_compaction_byte_size_limit(&self) -> u64 {
-        25 * self.max_file_size
-    }
-
-    /// Maximum bytes of overlaps in grandparent (i.e., level+2) before we
-    /// stop building a single file in a level-> level+1 compaction.
-    pub(crate) fn max_grandparent_overlap_bytes(&self) -> u64 {
-        10 * self.max_file_size
-    }
-
     /// Maximum bytes of total files in a given level
     pub(crate) fn max_bytes_for_level(&self, mut level: usize) -> u64 {
         // Note: the result for level zero is not really used since we set
         // the level-0 compaction threshold based on number of files.
 
         // Result for both level-0 and level-1
-        let mut result = self.l1_max_bytes;
+        let mut result = self.l1_max_bytes as f64;
         while level > 1 {
-            result *= 10;
+            result *= self.max_bytes_for_level_multiplier;
             level -= 1;
         }
-        result
+        result as u64
+    }
+
+    /// Returns the maximum bytes of every level given the current bytes of every level.
+    /// See `level_compaction_dynamic_level_bytes` for details.
+    pub(crate) fn max_bytes_for_levels(&self, level_bytes: &[u64]) -> Vec<u64> {
+        let mut targets: Vec<u64> = (0..level_bytes.len())
+            .map(|level| self.max_bytes_for_level(level))
+            .collect();
+        if !self.level_compaction_dynamic_level_bytes {
+            return targets;
+        }
+        let bottommost = match (1..level_bytes.len()).rev().find(|l| level_bytes[*l] > 0) {
+            Some(level) => level,
+            None => return targets,
+        };
+        let min_target = self.l1_max_bytes as f64 / self.max_bytes_for_level_multiplier;
+        let mut target = level_bytes[bottommost] as f64;
+        for level in (1..bottommost).rev() {
+            target = (target / self.max_bytes_for_level_multiplier).max(min_target);
+            targets[level] = target as u64;
+        }
+        targets
+    }
+
+    /// Returns the filter policy for the tables built for `level`
+    pub(crate) fn filter_policy_for_level(&self, level: usize) -> Option<Arc<dyn FilterPolicy>> {
+        match self.filter_policy_per_level.get(level) {
+            Some(Some(fp)) => Some(fp.clone()),
+            _ => self.filter_policy.clone(),
+        }
+    }
+
+    /// Returns the configured filter policy named `name` if any
+    pub(crate) fn filter_policy_by_name(&self, name: &[u8]) -> Option<&Arc<dyn FilterPolicy>> {
+        self.filter_policy
+            .iter()
+            .chain(self.filter_policy_per_level.iter().flatten())
+            .find(|fp| fp.name().as_bytes() == name)
     }
 
     /// Reserve `non_table_cache_files` files or so for other uses and give the rest to TableCache
@@ -197,6 +435,156 @@ impl<C: Comparator> Options<C> {
         self.max_open_files - self.non_table_cache_files
     }
 
+    /// Encodes all the options into the content of an `OPTIONS-*` file, with the mutable ones
+    /// taken from `m` which are in effect. Each option takes a `name=value` line.
+    pub(crate) fn encode_options_file(&self, m: &MutableOptions) -> String {
+        let filter_policy = match &self.filter_policy {
+            Some(fp) => fp.name(),
+            None => "none",
+        };
+        let filter_policy_per_level = self
+            .filter_policy_per_level
+            .iter()
+            .map(|fp| fp.as_ref().map_or("none", |fp| fp.name()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let scrub_interval = match self.background_scrub_interval {
+            Some(d) => d.as_millis().to_string(),
+            None => "none".to_owned(),
+        };
+        let mut s = String::new();
+        let _ = writeln!(s, "{}", OPTIONS_FILE_HEADER);
+        let _ = writeln!(s, "comparator={}", self.comparator.name());
+        let _ = writeln!(s, "create_if_missing={}", self.create_if_missing);
+        let _ = writeln!(s, "error_if_exists={}", self.error_if_exists);
+        let _ = writeln!(s, "paranoid_checks={}", self.paranoid_checks);
+        let _ = writeln!(s, "max_levels={}", self.max_levels);
+        let _ = writeln!(
+            s,
+            "l0_compaction_threshold={}",
+            self.l0_compaction_threshold
+        );
+        let _ = writeln!(
+            s,
+            "l0_slowdown_writes_threshold={}",
+            m.l0_slowdown_writes_threshold
+        );
+        let _ = writeln!(s, "l0_stop_writes_threshold={}", m.l0_stop_writes_threshold);
+        let _ = writeln!(s, "l1_max_bytes={}", self.l1_max_bytes);
+        let _ = writeln!(
+            s,
+            "max_bytes_for_level_multiplier={}",
+            self.max_bytes_for_level_multiplier
+        );
+        let _ = writeln!(
+            s,
+            "level_compaction_dynamic_level_bytes={}",
+            self.level_compaction_dynamic_level_bytes
+        );
+        let _ = writeln!(s, "compaction_priority={:?}", self.compaction_priority);
+        let periodic_compaction_seconds = match self.periodic_compaction_seconds {
+            Some(secs) => secs.to_string(),
+            None => "none".to_owned(),
+        };
+        let _ = writeln!(
+            s,
+            "periodic_compaction_seconds={}",
+            periodic_compaction_seconds
+        );
+        let _ = writeln!(s, "max_mem_compact_level={}", self.max_mem_compact_level);
+        let _ = writeln!(s, "read_bytes_period={}", self.read_bytes_period);
+        let _ = writeln!(s, "background_scrub_interval_ms={}", scrub_interval);
+        let _ = writeln!(
+            s,
+            "background_scrub_bytes_per_second={}",
+            self.background_scrub_bytes_per_second
+        );
+        let _ = writeln!(s, "write_buffer_size={}", m.write_buffer_size);
+        let _ = writeln!(s, "enable_pipelined_write={}", self.enable_pipelined_write);
+        let _ = writeln!(
+            s,
+            "allow_concurrent_memtable_write={}",
+            self.allow_concurrent_memtable_write
+        );
+        let _ = writeln!(s, "max_open_files={}", self.max_open_files);
+        let _ = writeln!(s, "non_table_cache_files={}", self.non_table_cache_files);
+        let _ = writeln!(s, "block_size={}", self.block_size);
+        let _ = writeln!(s, "block_restart_interval={}", self.block_restart_interval);
+        let _ = writeln!(s, "max_file_size={}", m.max_file_size);
+        let _ = writeln!(s, "compression={:?}", m.compression);
+        let _ = writeln!(s, "reuse_logs={}", self.reuse_logs);
+        let _ = writeln!(s, "max_retained_wal_files={}", self.max_retained_wal_files);
+        let _ = writeln!(s, "filter_policy={}", filter_policy);
+        let _ = writeln!(s, "filter_policy_per_level={}", filter_policy_per_level);
+        let _ = writeln!(s, "index_type={:?}", self.index_type);
+        let _ = writeln!(s, "metadata_block_size={}", self.metadata_block_size);
+        let _ = writeln!(s, "logger_level={:?}", self.logger_level);
+        s
+    }
+
+    /// Checks whether the db described by the content of an `OPTIONS-*` file could be opened
+    /// with these options.
+    /// The comparator and the number of levels must stay the same and the persisted mutable
+    /// options must hold valid values. Every other option is free to change between opens and
+    /// a changed one is only logged.
+    pub(crate) fn check_options_file(&self, content: &str) -> TemplateResult<()> {
+        let persisted = decode_options_file(content)?;
+        let current = decode_options_file(&self.encode_options_file(&MutableOptions::from(self)))?;
+        for name in ["comparator", "max_levels"] {
+            match persisted.get(name) {
+                Some(v) if *v != current[name] => {
+                    return Err(TemplateKVError::InvalidArgument(format!(
+                        "option {} mismatch: {} in OPTIONS file but {} is given",
+                        name, v, current[name]
+                    )))
+                }
+                Some(_) => {}
+                None => {
+                    return Err(TemplateKVError::Corruption(format!(
+                        "option {} missing in OPTIONS file",
+                        name
+                    )))
+                }
+            }
+        }
+        let mut mutable = MutableOptions::from(self);
+        for (name, v) in persisted.iter() {
+            let Some(given) = current.get(name) else {
+                warn!("unknown option {} in OPTIONS file is ignored", name);
+                continue;
+            };
+            if MUTABLE_OPTIONS.contains(&name.as_str()) {
+                if let Err(e) = mutable.apply(name, v) {
+                    return Err(TemplateKVError::Corruption(format!(
+                        "invalid OPTIONS file: {}",
+                        e
+                    )));
+                }
+            }
+            if v == given {
+                continue;
+            }
+            if name == "filter_policy" && given != "none" {
+                warn!(
+                    "filter_policy changed from {} to {}, filters of existing tables are ignored",
+                    v, given
+                );
+            } else {
+                info!(
+                    "option {} changed from {} in OPTIONS file to {}",
+                    name, v, given
+                );
+            }
+        }
+        if let Err(e) = mutable.validate() {
+            return Err(TemplateKVError::Corruption(format!(
+                "invalid OPTIONS file: {}",
+                e
+            )));
+        }
+        Ok(())
+    }
+
     /// Initialize Options by limiting ranges of some flags, applying customized Logger and etc.
     pub(crate) fn initialize<O: File + 'static, S: Storage<F = O>>(
         &mut self,
@@ -206,16 +594,19 @@ impl<C: Comparator> Options<C> {
         if self.max_mem_compact_level < 2 {
             self.max_mem_compact_level = 2
         }
+        self.max_bytes_for_level_multiplier = self.max_bytes_for_level_multiplier.max(1.0);
         self.max_open_files =
-            Self::clip_range(self.max_open_files, 64 + self.non_table_cache_files, 50000);
-        self.write_buffer_size = Self::clip_range(self.write_buffer_size, 64 << 10, 1 << 30);
-        self.max_file_size = Self::clip_range(self.max_file_size, 1 << 20, 1 << 30);
-        self.block_size = Self::clip_range(self.block_size, 1 << 10, 4 << 20);
+            clip_range(self.max_open_files, 64 + self.non_table_cache_files, 50000);
+        self.write_buffer_size = clip_range(self.write_buffer_size, 64 << 10, 1 << 30);
+        self.max_file_size = clip_range(self.max_file_size, 1 << 20, 1 << 30);
+        self.block_size = clip_range(self.block_size, 1 << 10, 4 << 20);
+        self.metadata_block_size = clip_range(self.metadata_block_size, 1 << 10, 4 << 20);
         self.apply_logger(storage, db_path);
         if self.block_cache.is_none() {
+            let n = clip_range(self.block_cache_shards, 1, 1024);
             let mut shards = vec![];
-            for _ in 0..DEFAULT_CACHE_SHARDS {
-                shards.push(LRUCache::new(8 << 20));
+            for _ in 0..n {
+                shards.push(LRUCache::new(DEFAULT_BLOCK_CACHE_CAPACITY / n));
             }
             self.block_cache = Some(Arc::new(ShardedCache::new(shards)))
         }
@@ -225,27 +616,66 @@ impl<C: Comparator> Options<C> {
             let bf = BloomFilter::new(10);
             self.filter_policy = Some(Arc::new(InternalFilterPolicy::new(Arc::new(bf))))
         }
+        for fp in self.filter_policy_per_level.iter_mut() {
+            if let Some(p) = fp.take() {
+                *fp = Some(Arc::new(InternalFilterPolicy::new(p)));
+            }
+        }
     }
 
     fn apply_logger<S: Storage>(&mut self, storage: &S, db_path: &str) {
         let user_logger = self.logger.take();
-        let logger = Logger::new(user_logger, self.logger_level, storage, db_path);
+        let logger = Logger::new(user_logger, storage, db_path);
         let static_logger: &'static dyn Log = Box::leak(Box::new(logger));
         let _ = log::set_logger(static_logger); // global logger could be set
         log::set_max_level(self.logger_level);
         info!("Logger initialized: [level {:?}]", &self.logger_level);
     }
+}
 
-    fn clip_range<N: PartialOrd + Eq + Copy>(n: N, min: N, max: N) -> N {
-        let mut r = n;
-        if n > max {
-            r = max
+fn clip_range<N: PartialOrd + Eq + Copy>(n: N, min: N, max: N) -> N {
+    let mut r = n;
+    if n > max {
+        r = max
+    }
+    if n < min {
+        r = min
+    }
+    r
+}
+
+fn parse_option<N: std::str::FromStr>(name: &str, value: &str) -> TemplateResult<N> {
+    value.parse().map_err(|_| {
+        TemplateKVError::InvalidArgument(format!("invalid value {:?} for option {}", value, name))
+    })
+}
+
+/// Decodes the content of an `OPTIONS-*` file into `name -> value` pairs
+pub(crate) fn decode_options_file(content: &str) -> TemplateResult<HashMap<String, String>> {
+    let mut lines = content.lines();
+    if lines.next() != Some(OPTIONS_FILE_HEADER) {
+        return Err(TemplateKVError::Corruption(
+            "bad OPTIONS file header".to_owned(),
+        ));
+    }
+    let mut options = HashMap::default();
+    for line in lines {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-        if n < min {
-            r = min
+        match line.split_once('=') {
+            Some((name, value)) => {
+                options.insert(name.to_owned(), value.to_owned());
+            }
+            None => {
+                return Err(TemplateKVError::Corruption(format!(
+                    "bad OPTIONS file line {:?}",
+                    line
+                )))
+            }
         }
-        r
     }
+    Ok(options)
 }
 
 impl<C: Comparator> Default for Options<C> {
@@ -260,18 +690,33 @@ impl<C: Comparator> Default for Options<C> {
             l0_slowdown_writes_threshold: 8,
             l0_stop_writes_threshold: 12,
             l1_max_bytes: 64 * 1024 * 1024, // 64MB
+            max_bytes_for_level_multiplier: 10.0,
+            level_compaction_dynamic_level_bytes: false,
+            compaction_priority: CompactionPriority::RoundRobin,
+            periodic_compaction_seconds: None,
             max_mem_compact_level: 2,
             read_bytes_period: 1_048_576,
-            write_buffer_size: 4 * 1024 * 1024, // 4MB
+            background_scrub_interval: None,
+            background_scrub_bytes_per_second: 8 * 1024 * 1024, // 8MB/s
+            write_buffer_size: 4 * 1024 * 1024,                 // 4MB
+            enable_pipelined_write: false,
+            allow_concurrent_memtable_write: false,
             max_open_files: 500,
             block_cache: None,
+            block_cache_shards: DEFAULT_CACHE_SHARDS,
             non_table_cache_files: 10,
             block_size: 4 * 1024, // 4KB
             block_restart_interval: 16,
             max_file_size: 2 * 1024 * 1024, // 2MB
             compression: CompressionType::SnappyCompression,
             reuse_logs: false,
+            max_retained_wal_files: 0,
             filter_policy: None,
+            filter_policy_per_level: vec![],
+            statistics: None,
+            listeners: vec![],
+            index_type: IndexType::Binary,
+            metadata_block_size: 4 * 1024, // 4KB
             logger: None,
             logger_level: LevelFilter::Warn,
         }
@@ -0,0 +1,58 @@
+use actix::{Actor, AsyncContext, Context, WrapFuture};
+use tonic::transport::Server;
+
+use crate::{
+    admin_service::admin_service_server::AdminServiceServer,
+    services::admin_service::AdminServiceHandler, storage::Storage, util::comparator::Comparator,
+};
+
+/// Serves `AdminService` over gRPC at `rpc_addr` while the actor is alive
+pub struct AdminServer<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    admin_handler: AdminServiceHandler<S, C>,
+    rpc_addr: String,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl<S: Storage + Clone, C: Comparator> AdminServer<S, C> {
+    pub fn new(admin_handler: AdminServiceHandler<S, C>, rpc_addr: String) -> Self {
+        Self {
+            admin_handler,
+            rpc_addr,
+            shutdown: None,
+        }
+    }
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> Actor for AdminServer<S, C> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let grpc_addr = self.rpc_addr.parse().unwrap();
+        let grpc_service = AdminServiceServer::new(self.admin_handler.clone());
+        let server = Server::builder().add_service(grpc_service).serve(grpc_addr);
+        let mut server = Box::pin(server);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.shutdown = Some(tx);
+
+        ctx.spawn(
+            async move {
+                tokio::select! {
+                    res = server.as_mut() => {
+                        if let Err(e) = res {
+                            error!("tonic error on grpc in admin actor: {:?}", e);
+                        }
+                    }
+                    _ = rx => {}
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        actix::Running::Stop
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use actix::{Actor, AsyncContext, Context, WrapFuture};
+use tonic::transport::Server;
+
+use crate::{
+    kv_service::kv_service_server::KvServiceServer,
+    metrics::{MetricsRegistry, MetricsService},
+    services::kv_service::KvServiceHandler,
+    storage::Storage,
+    util::comparator::Comparator,
+};
+
+/// Serves `KvService` over gRPC at `rpc_addr` while the actor is alive
+pub struct KvServer<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    kv_handler: KvServiceHandler<S, C>,
+    rpc_addr: String,
+    metrics: Arc<MetricsRegistry>,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl<S: Storage + Clone, C: Comparator> KvServer<S, C> {
+    pub fn new(kv_handler: KvServiceHandler<S, C>, rpc_addr: String) -> Self {
+        Self {
+            kv_handler,
+            rpc_addr,
+            metrics: Arc::default(),
+            shutdown: None,
+        }
+    }
+
+    /// Records the requests served in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<MetricsRegistry>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> Actor for KvServer<S, C> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let grpc_addr = self.rpc_addr.parse().unwrap();
+        let grpc_service = MetricsService::new(
+            KvServiceServer::new(self.kv_handler.clone()),
+            self.metrics.clone(),
+        );
+        let server = Server::builder().add_service(grpc_service).serve(grpc_addr);
+        let mut server = Box::pin(server);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.shutdown = Some(tx);
+
+        ctx.spawn(
+            async move {
+                tokio::select! {
+                    res = server.as_mut() => {
+                        if let Err(e) = res {
+                            error!("tonic error on grpc in kv actor: {:?}", e);
+                        }
+                    }
+                    _ = rx => {}
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        // The snapshots of the clients can never be released once the server stops
+        self.kv_handler.release_all_snapshots();
+        actix::Running::Stop
+    }
+}
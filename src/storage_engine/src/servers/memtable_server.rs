@@ -8,6 +8,7 @@ use crate::{
         memtable_service_server::MemtableServiceServer, ListKvRequest, ListKvResponse,
         UpdateKvRequest, UpdateKvResponse,
     },
+    metrics::MetricsService,
     services::memtable_service::MemtableServiceHandler,
     util::comparator::Comparator,
 };
@@ -45,7 +46,10 @@ impl<C: Comparator + 'static> Actor for MemTableServer<C> {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         let grpc_addr = self.rpc_addr.parse().unwrap();
-        let grpc_service = MemtableServiceServer::new(self.memtable_handler.clone());
+        let grpc_service = MetricsService::new(
+            MemtableServiceServer::new(self.memtable_handler.clone()),
+            self.memtable_handler.metrics().clone(),
+        );
         let server = Server::builder().add_service(grpc_service).serve(grpc_addr);
         let mut server = Box::pin(server);
         let (tx, rx) = tokio::sync::oneshot::channel();
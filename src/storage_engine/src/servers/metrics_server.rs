@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use actix::{Actor, AsyncContext, Context, WrapFuture};
+use tokio::net::TcpListener;
+
+use crate::metrics::{serve_metrics, MetricsRegistry};
+
+/// Serves the metrics of `registry` for Prometheus at `http://<addr>/metrics` while the actor is
+/// alive
+pub struct MetricsServer {
+    registry: Arc<MetricsRegistry>,
+    addr: String,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl MetricsServer {
+    pub fn new(registry: Arc<MetricsRegistry>, addr: String) -> Self {
+        Self {
+            registry,
+            addr,
+            shutdown: None,
+        }
+    }
+}
+
+impl Actor for MetricsServer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let addr = self.addr.clone();
+        let registry = self.registry.clone();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.shutdown = Some(tx);
+
+        ctx.spawn(
+            async move {
+                let listener = match TcpListener::bind(&addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("Failed to bind the metrics endpoint on {}: {:?}", addr, e);
+                        return;
+                    }
+                };
+                tokio::select! {
+                    res = serve_metrics(listener, registry) => {
+                        if let Err(e) = res {
+                            error!("metrics endpoint error in metrics actor: {:?}", e);
+                        }
+                    }
+                    _ = rx => {}
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        actix::Running::Stop
+    }
+}
@@ -1 +1,5 @@
+pub mod admin_server;
+pub mod kv_server;
 pub mod memtable_server;
+pub mod metrics_server;
+pub mod redis_server;
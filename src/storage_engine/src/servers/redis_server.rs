@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use actix::{Actor, AsyncContext, Context, WrapFuture};
+use tokio::net::TcpListener;
+
+use crate::{
+    services::redis_service::RedisHandler, storage::Storage, util::comparator::Comparator,
+};
+
+// How often the expired strings are reclaimed, and the number of the strings examined each time
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(1);
+const RECLAIM_BATCH: usize = 1000;
+
+/// Serves the Redis protocol at `addr` while the actor is alive.
+/// The expired strings are reclaimed in the background meanwhile.
+pub struct RedisServer<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    handler: RedisHandler<S, C>,
+    addr: String,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl<S: Storage + Clone, C: Comparator> RedisServer<S, C> {
+    pub fn new(handler: RedisHandler<S, C>, addr: String) -> Self {
+        Self {
+            handler,
+            addr,
+            shutdown: None,
+        }
+    }
+}
+
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> Actor for RedisServer<S, C> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let handler = self.handler.clone();
+        let addr = self.addr.clone();
+        let (tx, mut rx) = tokio::sync::oneshot::channel();
+        self.shutdown = Some(tx);
+
+        ctx.spawn(
+            async move {
+                let listener = match TcpListener::bind(&addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("failed to bind redis server at {}: {:?}", addr, e);
+                        return;
+                    }
+                };
+                let mut reclaim = tokio::time::interval(RECLAIM_INTERVAL);
+                loop {
+                    tokio::select! {
+                        res = listener.accept() => match res {
+                            Ok((stream, peer)) => {
+                                let handler = handler.clone();
+                                tokio::spawn(async move {
+                                    if let Err(e) = handler.serve_connection(stream).await {
+                                        debug!("redis connection from {} closed: {:?}", peer, e);
+                                    }
+                                });
+                            }
+                            Err(e) => error!("failed to accept a redis connection: {:?}", e),
+                        },
+                        _ = reclaim.tick() => {
+                            let handler = handler.clone();
+                            tokio::task::spawn_blocking(move || {
+                                if let Err(e) = handler.reclaim_expired(RECLAIM_BATCH) {
+                                    warn!("failed to reclaim the expired strings: {:?}", e);
+                                }
+                            });
+                        }
+                        _ = &mut rx => break,
+                    }
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> actix::Running {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        actix::Running::Stop
+    }
+}
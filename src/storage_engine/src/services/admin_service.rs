@@ -0,0 +1,294 @@
+use log::LevelFilter;
+use tonic::Status;
+
+use crate::{
+    admin_service::{
+        admin_service_server::AdminService, CompactRangeRequest, CompactRangeResponse,
+        CreateCheckpointRequest, CreateCheckpointResponse, FlushMemTableRequest,
+        FlushMemTableResponse, GetApproximateSizeRequest, GetApproximateSizeResponse,
+        GetLevelSummaryRequest, GetLevelSummaryResponse, LevelStats, ListLiveFilesRequest,
+        ListLiveFilesResponse, LiveFile, SetLogLevelRequest, SetLogLevelResponse,
+    },
+    db_impl::template_impl::TemplateDB,
+    error::TemplateResult,
+    logger::set_log_level,
+    services::kv_service::to_status,
+    storage::Storage,
+    util::comparator::Comparator,
+};
+
+/// Serves `AdminService` with a `TemplateDB`
+pub struct AdminServiceHandler<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: TemplateDB<S, C>,
+}
+
+impl<S: Storage + Clone, C: Comparator> Clone for AdminServiceHandler<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> AdminServiceHandler<S, C> {
+    pub fn new(db: TemplateDB<S, C>) -> Self {
+        Self { db }
+    }
+
+    // Runs `f`, which might wait for compactions, on a blocking thread of tokio
+    async fn run_blocking<T, F>(&self, f: F) -> Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(TemplateDB<S, C>) -> TemplateResult<T> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || f(db))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(to_status)
+    }
+}
+
+#[tonic::async_trait]
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> AdminService
+    for AdminServiceHandler<S, C>
+{
+    async fn compact_range(
+        &self,
+        req: tonic::Request<CompactRangeRequest>,
+    ) -> Result<tonic::Response<CompactRangeResponse>, Status> {
+        let req = req.into_inner();
+        let max_levels = self.db.inner.options.max_levels;
+        if let Some(level) = req.level {
+            // The last level has no next level to compact into
+            if level as usize + 1 >= max_levels {
+                return Err(Status::invalid_argument(format!(
+                    "level {} is out of range [0, {})",
+                    level,
+                    max_levels - 1
+                )));
+            }
+        }
+        info!(
+            "Admin compact range [{:?}, {:?}] at level {:?}",
+            req.begin, req.end, req.level
+        );
+        self.run_blocking(move |db| {
+            let (begin, end) = (req.begin.as_deref(), req.end.as_deref());
+            match req.level {
+                Some(level) => db.compact_range_at(level as usize, begin, end),
+                None => db.compact_range(begin, end),
+            }
+        })
+        .await?;
+        Ok(tonic::Response::new(CompactRangeResponse {}))
+    }
+
+    async fn flush_mem_table(
+        &self,
+        _req: tonic::Request<FlushMemTableRequest>,
+    ) -> Result<tonic::Response<FlushMemTableResponse>, Status> {
+        info!("Admin flush memtable");
+        self.run_blocking(|db| db.flush_memtable()).await?;
+        Ok(tonic::Response::new(FlushMemTableResponse {}))
+    }
+
+    async fn get_approximate_size(
+        &self,
+        req: tonic::Request<GetApproximateSizeRequest>,
+    ) -> Result<tonic::Response<GetApproximateSizeResponse>, Status> {
+        let req = req.into_inner();
+        let size = self
+            .run_blocking(move |db| Ok(db.get_approximate_size(&req.start, &req.end)))
+            .await?;
+        Ok(tonic::Response::new(GetApproximateSizeResponse { size }))
+    }
+
+    async fn get_level_summary(
+        &self,
+        _req: tonic::Request<GetLevelSummaryRequest>,
+    ) -> Result<tonic::Response<GetLevelSummaryResponse>, Status> {
+        let levels = self
+            .db
+            .level_file_stats()
+            .into_iter()
+            .enumerate()
+            .map(|(level, (num_files, total_bytes))| LevelStats {
+                level: level as u32,
+                num_files: num_files as u64,
+                total_bytes,
+            })
+            .collect();
+        Ok(tonic::Response::new(GetLevelSummaryResponse {
+            summary: self.db.level_summary(),
+            levels,
+        }))
+    }
+
+    async fn list_live_files(
+        &self,
+        _req: tonic::Request<ListLiveFilesRequest>,
+    ) -> Result<tonic::Response<ListLiveFilesResponse>, Status> {
+        let files = self
+            .db
+            .live_files()
+            .into_iter()
+            .map(|(level, f)| LiveFile {
+                level: level as u32,
+                number: f.number,
+                file_size: f.file_size,
+                smallest_key: f.smallest.user_key().to_vec(),
+                largest_key: f.largest.user_key().to_vec(),
+                num_entries: f.properties.num_entries,
+                num_deletions: f.properties.num_deletions,
+                largest_seq: f.properties.largest_seq,
+                creation_time: f.properties.creation_time,
+            })
+            .collect();
+        Ok(tonic::Response::new(ListLiveFilesResponse { files }))
+    }
+
+    async fn create_checkpoint(
+        &self,
+        req: tonic::Request<CreateCheckpointRequest>,
+    ) -> Result<tonic::Response<CreateCheckpointResponse>, Status> {
+        let req = req.into_inner();
+        if req.dir.is_empty() {
+            return Err(Status::invalid_argument("empty checkpoint dir"));
+        }
+        let info = self
+            .run_blocking(move |db| db.create_checkpoint(&req.dir))
+            .await?;
+        Ok(tonic::Response::new(CreateCheckpointResponse {
+            last_sequence: info.last_sequence,
+            table_files: info.table_files,
+            log_files: info.log_files,
+        }))
+    }
+
+    async fn set_log_level(
+        &self,
+        req: tonic::Request<SetLogLevelRequest>,
+    ) -> Result<tonic::Response<SetLogLevelResponse>, Status> {
+        let req = req.into_inner();
+        let level: LevelFilter = req
+            .level
+            .parse()
+            .map_err(|_| Status::invalid_argument(format!("unknown log level {}", req.level)))?;
+        let previous = set_log_level(level);
+        Ok(tonic::Response::new(SetLogLevelResponse {
+            previous_level: previous.to_string().to_lowercase(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db_trait::DB,
+        options::{Options, WriteOptions},
+        storage::mem::MemStorage,
+        util::comparator::BytewiseComparator,
+    };
+
+    fn new_handler() -> AdminServiceHandler<MemStorage, BytewiseComparator> {
+        let db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "admin_service_test",
+            MemStorage::default(),
+        )
+        .unwrap();
+        AdminServiceHandler::new(db)
+    }
+
+    #[tokio::test]
+    async fn test_admin_service() {
+        let h = new_handler();
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            h.db.put(WriteOptions::default(), key.as_bytes(), &[0; 100])
+                .unwrap();
+        }
+        AdminService::flush_mem_table(&h, tonic::Request::new(FlushMemTableRequest {}))
+            .await
+            .unwrap();
+        let files = AdminService::list_live_files(&h, tonic::Request::new(ListLiveFilesRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .files;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].smallest_key, b"key000");
+        assert_eq!(files[0].largest_key, b"key099");
+        assert_eq!(files[0].num_entries, 100);
+
+        let summary =
+            AdminService::get_level_summary(&h, tonic::Request::new(GetLevelSummaryRequest {}))
+                .await
+                .unwrap()
+                .into_inner();
+        let num_files: u64 = summary.levels.iter().map(|l| l.num_files).sum();
+        assert_eq!(num_files, 1);
+        assert_eq!(summary.summary, h.db.level_summary());
+
+        let size = AdminService::get_approximate_size(
+            &h,
+            tonic::Request::new(GetApproximateSizeRequest {
+                start: b"key000".to_vec(),
+                end: b"key100".to_vec(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner()
+        .size;
+        assert!(size > 0);
+
+        let status = AdminService::compact_range(
+            &h,
+            tonic::Request::new(CompactRangeRequest {
+                begin: None,
+                end: None,
+                level: Some(h.db.inner.options.max_levels as u32),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let checkpoint = AdminService::create_checkpoint(
+            &h,
+            tonic::Request::new(CreateCheckpointRequest {
+                dir: "admin_service_checkpoint".to_owned(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(checkpoint.table_files, vec![files[0].number]);
+
+        let status = AdminService::set_log_level(
+            &h,
+            tonic::Request::new(SetLogLevelRequest {
+                level: "verbose".to_owned(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        // Keep the level of the other tests
+        let level = log::max_level().to_string();
+        let previous = AdminService::set_log_level(
+            &h,
+            tonic::Request::new(SetLogLevelRequest {
+                level: level.clone(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner()
+        .previous_level;
+        assert_eq!(previous, level.to_lowercase());
+    }
+}
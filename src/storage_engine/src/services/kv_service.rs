@@ -0,0 +1,814 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::Stream;
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::{
+    db_impl::{
+        template_impl::TemplateDB,
+        watch::{ChangeEvent, ChangeFeed, WatchKeys, WatchOptions},
+    },
+    db_trait::{AsyncDB, DB},
+    error::TemplateKVError,
+    iterator::Iterator,
+    kv_service::{
+        kv_service_server::KvService, AcquireSnapshotRequest, AcquireSnapshotResponse,
+        BatchRequest, BatchResponse, DeleteRequest, DeleteResponse, GetRequest, GetResponse,
+        KeyValue, MultiGetRequest, MultiGetResponse, MutationType, PutRequest, PutResponse,
+        ReleaseSnapshotRequest, ReleaseSnapshotResponse, ScanRequest, WatchEvent, WatchRequest,
+    },
+    manager::snapshot::Snapshot,
+    memtable::batch::WriteBatch,
+    options::{ReadOptions, WriteOptions},
+    storage::Storage,
+    util::{collection::HashMap, comparator::Comparator},
+};
+
+// The number of pairs buffered ahead of the client by a scan
+const SCAN_BUFFER_SIZE: usize = 64;
+
+/// How long a snapshot acquired by a client lives without being used by default
+pub const DEFAULT_SNAPSHOT_TTL: Duration = Duration::from_secs(600);
+
+/// Serves `KvService` with a `TemplateDB`
+pub struct KvServiceHandler<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: TemplateDB<S, C>,
+    // The snapshots acquired by the clients by id
+    snapshots: Arc<Mutex<HashMap<u64, ClientSnapshot<S, C>>>>,
+    next_snapshot_id: Arc<AtomicU64>,
+    snapshot_ttl: Duration,
+}
+
+impl<S: Storage + Clone, C: Comparator> Clone for KvServiceHandler<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            snapshots: self.snapshots.clone(),
+            next_snapshot_id: self.next_snapshot_id.clone(),
+            snapshot_ttl: self.snapshot_ttl,
+        }
+    }
+}
+
+// A snapshot acquired by a client
+struct ClientSnapshot<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    pinned: Arc<PinnedSnapshot<S, C>>,
+    last_used: Instant,
+}
+
+// Keeps a snapshot in the db until the client and all the reads using it drop it
+struct PinnedSnapshot<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: TemplateDB<S, C>,
+    snapshot: Arc<Snapshot>,
+}
+
+impl<S: Storage + Clone, C: Comparator> PinnedSnapshot<S, C> {
+    fn new(db: &TemplateDB<S, C>) -> Self {
+        Self {
+            db: db.clone(),
+            snapshot: db.snapshot(),
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator> Drop for PinnedSnapshot<S, C> {
+    fn drop(&mut self) {
+        // The db keeps a snapshot shared by several acquirers until all of them release it
+        let _ = self.db.release_snapshot(self.snapshot.clone());
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> KvServiceHandler<S, C> {
+    pub fn new(db: TemplateDB<S, C>) -> Self {
+        Self {
+            db,
+            snapshots: Arc::new(Mutex::new(HashMap::default())),
+            next_snapshot_id: Arc::new(AtomicU64::new(1)),
+            snapshot_ttl: DEFAULT_SNAPSHOT_TTL,
+        }
+    }
+
+    /// Sets how long a snapshot acquired by a client lives without being used. An expired
+    /// snapshot is released as if the client released it.
+    pub fn with_snapshot_ttl(mut self, ttl: Duration) -> Self {
+        self.snapshot_ttl = ttl;
+        self
+    }
+
+    /// Returns the db served
+    pub fn db(&self) -> &TemplateDB<S, C> {
+        &self.db
+    }
+
+    /// Releases all the snapshots still held by the clients. The reads in flight keep
+    /// theirs until they finish.
+    pub fn release_all_snapshots(&self) {
+        self.snapshots.lock().unwrap().clear();
+    }
+
+    // Returns the snapshot acquired by the client with `snapshot_id`, which must be held
+    // until the read using it finishes so that a release in the meantime does not pull the
+    // view from under the read
+    fn pin_snapshot(
+        &self,
+        snapshot_id: Option<u64>,
+    ) -> Result<Option<Arc<PinnedSnapshot<S, C>>>, Status> {
+        let id = match snapshot_id {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let mut snapshots = self.snapshots.lock().unwrap();
+        self.remove_expired(&mut snapshots);
+        match snapshots.get_mut(&id) {
+            Some(s) => {
+                s.last_used = Instant::now();
+                Ok(Some(s.pinned.clone()))
+            }
+            None => Err(Status::not_found(format!("unknown snapshot {}", id))),
+        }
+    }
+
+    // Drops the snapshots the clients have not used for `snapshot_ttl`
+    fn remove_expired(&self, snapshots: &mut HashMap<u64, ClientSnapshot<S, C>>) {
+        snapshots.retain(|id, s| {
+            let live = s.last_used.elapsed() < self.snapshot_ttl;
+            if !live {
+                info!(
+                    "snapshot {} of sequence {} expired",
+                    id,
+                    s.pinned.snapshot.sequence()
+                );
+            }
+            live
+        });
+    }
+}
+
+fn read_options<S: Storage + Clone, C: Comparator>(
+    snapshot: Option<&Arc<PinnedSnapshot<S, C>>>,
+) -> ReadOptions {
+    ReadOptions {
+        snapshot: snapshot.map(|s| *s.snapshot),
+        ..Default::default()
+    }
+}
+
+fn write_options(sync: bool) -> WriteOptions {
+    WriteOptions {
+        sync,
+        ..Default::default()
+    }
+}
+
+/// Converts an error of the db into the status replied
+pub(crate) fn to_status(e: TemplateKVError) -> Status {
+    match e {
+        TemplateKVError::NotFound(hint) => Status::not_found(hint.unwrap_or_default()),
+        TemplateKVError::InvalidArgument(hint) => Status::invalid_argument(hint),
+        TemplateKVError::NotSupported(hint) => Status::unimplemented(hint),
+        TemplateKVError::DBClosed(hint) => Status::unavailable(hint),
+        TemplateKVError::Corruption(hint) => Status::data_loss(hint),
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/// The stream of the pairs returned by `KvService::scan`
+pub struct ScanStream {
+    recv: mpsc::Receiver<Result<KeyValue, Status>>,
+}
+
+impl Stream for ScanStream {
+    type Item = Result<KeyValue, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+/// The stream of the events returned by `KvService::watch`
+pub struct WatchStream {
+    feed: ChangeFeed,
+}
+
+impl Stream for WatchStream {
+    type Item = Result<WatchEvent, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.feed)
+            .poll_next(cx)
+            .map(|event| event.map(|event| event.map(to_watch_event).map_err(to_status)))
+    }
+}
+
+fn to_watch_event(event: ChangeEvent) -> WatchEvent {
+    let r#type = match event.value {
+        Some(_) => MutationType::Put,
+        None => MutationType::Delete,
+    };
+    WatchEvent {
+        sequence: event.sequence,
+        r#type: r#type as i32,
+        key: event.key,
+        value: event.value.unwrap_or_default(),
+    }
+}
+
+#[tonic::async_trait]
+impl<S: Storage + Clone + 'static, C: Comparator + 'static> KvService for KvServiceHandler<S, C> {
+    type ScanStream = ScanStream;
+    type WatchStream = WatchStream;
+
+    async fn get(
+        &self,
+        req: tonic::Request<GetRequest>,
+    ) -> Result<tonic::Response<GetResponse>, Status> {
+        let req = req.into_inner();
+        let snapshot = self.pin_snapshot(req.snapshot_id)?;
+        let value = AsyncDB::get(&self.db, read_options(snapshot.as_ref()), &req.key)
+            .await
+            .map_err(to_status)?;
+        Ok(tonic::Response::new(GetResponse {
+            found: value.is_some(),
+            value: value.unwrap_or_default(),
+        }))
+    }
+
+    async fn multi_get(
+        &self,
+        req: tonic::Request<MultiGetRequest>,
+    ) -> Result<tonic::Response<MultiGetResponse>, Status> {
+        let req = req.into_inner();
+        let snapshot = self.pin_snapshot(req.snapshot_id)?;
+        let keys: Vec<&[u8]> = req.keys.iter().map(|k| k.as_slice()).collect();
+        let values = self
+            .db
+            .multi_get(read_options(snapshot.as_ref()), &keys)
+            .await
+            .map_err(to_status)?;
+        let values = values
+            .into_iter()
+            .map(|value| GetResponse {
+                found: value.is_some(),
+                value: value.unwrap_or_default(),
+            })
+            .collect();
+        Ok(tonic::Response::new(MultiGetResponse { values }))
+    }
+
+    async fn put(
+        &self,
+        req: tonic::Request<PutRequest>,
+    ) -> Result<tonic::Response<PutResponse>, Status> {
+        let req = req.into_inner();
+        AsyncDB::put(&self.db, write_options(req.sync), &req.key, &req.value)
+            .await
+            .map_err(to_status)?;
+        Ok(tonic::Response::new(PutResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        req: tonic::Request<DeleteRequest>,
+    ) -> Result<tonic::Response<DeleteResponse>, Status> {
+        let req = req.into_inner();
+        AsyncDB::delete(&self.db, write_options(req.sync), &req.key)
+            .await
+            .map_err(to_status)?;
+        Ok(tonic::Response::new(DeleteResponse {}))
+    }
+
+    async fn batch(
+        &self,
+        req: tonic::Request<BatchRequest>,
+    ) -> Result<tonic::Response<BatchResponse>, Status> {
+        let req = req.into_inner();
+        let mut batch = WriteBatch::default();
+        for m in req.mutations.iter() {
+            match MutationType::from_i32(m.r#type) {
+                Some(MutationType::Put) => batch.put(&m.key, &m.value),
+                Some(MutationType::Delete) => batch.delete(&m.key),
+                None => {
+                    return Err(Status::invalid_argument(format!(
+                        "unknown mutation type {}",
+                        m.r#type
+                    )))
+                }
+            }
+        }
+        AsyncDB::write(&self.db, write_options(req.sync), batch)
+            .await
+            .map_err(to_status)?;
+        Ok(tonic::Response::new(BatchResponse {}))
+    }
+
+    // The pairs are read by a blocking thread of tokio ahead of the client
+    async fn scan(
+        &self,
+        req: tonic::Request<ScanRequest>,
+    ) -> Result<tonic::Response<Self::ScanStream>, Status> {
+        let req = req.into_inner();
+        // Pin the view now rather than when the blocking thread gets scheduled
+        let snapshot = match self.pin_snapshot(req.snapshot_id)? {
+            Some(s) => s,
+            None => Arc::new(PinnedSnapshot::new(&self.db)),
+        };
+        let options = read_options(Some(&snapshot));
+        let db = self.db.clone();
+        let cmp = db.inner.options.comparator.clone();
+        let limit = if req.limit == 0 { u64::MAX } else { req.limit };
+        let (send, recv) = mpsc::channel(SCAN_BUFFER_SIZE);
+        tokio::task::spawn_blocking(move || {
+            match DB::iter(&db, options) {
+                Ok(mut iter) => {
+                    match &req.start {
+                        Some(start) => iter.seek(start),
+                        None => iter.seek_to_first(),
+                    }
+                    let mut count = 0;
+                    while iter.valid() && count < limit {
+                        if let Some(end) = &req.end {
+                            if cmp.compare(iter.key(), end).is_ge() {
+                                break;
+                            }
+                        }
+                        let kv = KeyValue {
+                            key: iter.key().to_vec(),
+                            value: iter.value().to_vec(),
+                        };
+                        if send.blocking_send(Ok(kv)).is_err() {
+                            // The client has gone
+                            break;
+                        }
+                        count += 1;
+                        iter.next();
+                    }
+                    if let Err(e) = iter.status() {
+                        let _ = send.blocking_send(Err(to_status(e)));
+                    }
+                }
+                Err(e) => {
+                    let _ = send.blocking_send(Err(to_status(e)));
+                }
+            }
+            drop(snapshot);
+        });
+        Ok(tonic::Response::new(ScanStream { recv }))
+    }
+
+    async fn acquire_snapshot(
+        &self,
+        _req: tonic::Request<AcquireSnapshotRequest>,
+    ) -> Result<tonic::Response<AcquireSnapshotResponse>, Status> {
+        let pinned = Arc::new(PinnedSnapshot::new(&self.db));
+        let sequence = pinned.snapshot.sequence();
+        let snapshot_id = self.next_snapshot_id.fetch_add(1, Ordering::Relaxed);
+        let mut snapshots = self.snapshots.lock().unwrap();
+        self.remove_expired(&mut snapshots);
+        snapshots.insert(
+            snapshot_id,
+            ClientSnapshot {
+                pinned,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(tonic::Response::new(AcquireSnapshotResponse {
+            snapshot_id,
+            sequence,
+        }))
+    }
+
+    async fn release_snapshot(
+        &self,
+        req: tonic::Request<ReleaseSnapshotRequest>,
+    ) -> Result<tonic::Response<ReleaseSnapshotResponse>, Status> {
+        let id = req.get_ref().snapshot_id;
+        // The reads in flight keep the snapshot until they finish
+        let snapshot = self.snapshots.lock().unwrap().remove(&id);
+        match snapshot {
+            Some(_) => Ok(tonic::Response::new(ReleaseSnapshotResponse {})),
+            None => Err(Status::not_found(format!("unknown snapshot {}", id))),
+        }
+    }
+
+    // The changes are read by a dedicated thread ahead of the client
+    async fn watch(
+        &self,
+        req: tonic::Request<WatchRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, Status> {
+        let req = req.into_inner();
+        let keys = match (req.prefix, req.start, req.end) {
+            (Some(prefix), None, None) => WatchKeys::Prefix(prefix),
+            (Some(_), _, _) => {
+                return Err(Status::invalid_argument(
+                    "a prefix can not be combined with a range",
+                ))
+            }
+            (None, start, end) => WatchKeys::Range { start, end },
+        };
+        let options = WatchOptions {
+            keys,
+            start_sequence: req.start_sequence,
+            ..Default::default()
+        };
+        let feed = self.db.watch(options).map_err(to_status)?;
+        Ok(tonic::Response::new(WatchStream { feed }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        kv_service::Mutation, options::Options, storage::mem::MemStorage,
+        util::comparator::BytewiseComparator,
+    };
+
+    fn new_handler() -> KvServiceHandler<MemStorage, BytewiseComparator> {
+        let db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "kv_service_test",
+            MemStorage::default(),
+        )
+        .unwrap();
+        KvServiceHandler::new(db)
+    }
+
+    async fn get(
+        h: &KvServiceHandler<MemStorage, BytewiseComparator>,
+        key: &[u8],
+        snapshot_id: Option<u64>,
+    ) -> Option<Vec<u8>> {
+        let resp = KvService::get(
+            h,
+            tonic::Request::new(GetRequest {
+                key: key.to_vec(),
+                snapshot_id,
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        resp.found.then_some(resp.value)
+    }
+
+    async fn scan(
+        h: &KvServiceHandler<MemStorage, BytewiseComparator>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: u64,
+    ) -> Vec<Vec<u8>> {
+        let stream = KvService::scan(
+            h,
+            tonic::Request::new(ScanRequest {
+                start: start.map(|s| s.to_vec()),
+                end: end.map(|s| s.to_vec()),
+                limit,
+                snapshot_id: None,
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        stream.map(|kv| kv.unwrap().key).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_point_operations() {
+        let h = new_handler();
+        // Keys and values are arbitrary bytes
+        let (key, value) = (vec![0u8, 255, 1], vec![128u8, 0]);
+        assert_eq!(get(&h, &key, None).await, None);
+        KvService::put(
+            &h,
+            tonic::Request::new(PutRequest {
+                key: key.clone(),
+                value: value.clone(),
+                sync: false,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(get(&h, &key, None).await, Some(value.clone()));
+
+        let resp = KvService::multi_get(
+            &h,
+            tonic::Request::new(MultiGetRequest {
+                keys: vec![b"missing".to_vec(), key.clone()],
+                snapshot_id: None,
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(
+            resp.values,
+            vec![GetResponse::default(), GetResponse { found: true, value },]
+        );
+
+        KvService::delete(
+            &h,
+            tonic::Request::new(DeleteRequest {
+                key: key.clone(),
+                sync: false,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(get(&h, &key, None).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_batch_and_scan() {
+        let h = new_handler();
+        let mutation = |r#type: MutationType, key: &str| Mutation {
+            r#type: r#type as i32,
+            key: key.as_bytes().to_vec(),
+            value: key.as_bytes().to_vec(),
+        };
+        let mutations = vec![
+            mutation(MutationType::Put, "a"),
+            mutation(MutationType::Put, "b"),
+            mutation(MutationType::Put, "c"),
+            mutation(MutationType::Put, "d"),
+            mutation(MutationType::Delete, "b"),
+        ];
+        KvService::batch(
+            &h,
+            tonic::Request::new(BatchRequest {
+                mutations,
+                sync: true,
+            }),
+        )
+        .await
+        .unwrap();
+        let keys = |ks: &[&str]| ks.iter().map(|k| k.as_bytes().to_vec()).collect::<Vec<_>>();
+        assert_eq!(scan(&h, None, None, 0).await, keys(&["a", "c", "d"]));
+        assert_eq!(scan(&h, Some(b"b"), Some(b"d"), 0).await, keys(&["c"]));
+        assert_eq!(scan(&h, Some(b"a"), None, 2).await, keys(&["a", "c"]));
+
+        let invalid = Mutation {
+            r#type: 100,
+            ..Default::default()
+        };
+        let status = KvService::batch(
+            &h,
+            tonic::Request::new(BatchRequest {
+                mutations: vec![mutation(MutationType::Put, "e"), invalid],
+                sync: false,
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        // Nothing in an invalid batch is applied
+        assert_eq!(get(&h, b"e", None).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let h = new_handler();
+        let put = |key: &str, value: &str| PutRequest {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            sync: false,
+        };
+        KvService::put(&h, tonic::Request::new(put("k", "v1")))
+            .await
+            .unwrap();
+        let snapshot =
+            KvService::acquire_snapshot(&h, tonic::Request::new(AcquireSnapshotRequest {}))
+                .await
+                .unwrap()
+                .into_inner();
+        KvService::put(&h, tonic::Request::new(put("k", "v2")))
+            .await
+            .unwrap();
+        assert_eq!(get(&h, b"k", None).await, Some(b"v2".to_vec()));
+        assert_eq!(
+            get(&h, b"k", Some(snapshot.snapshot_id)).await,
+            Some(b"v1".to_vec())
+        );
+
+        let release = || {
+            tonic::Request::new(ReleaseSnapshotRequest {
+                snapshot_id: snapshot.snapshot_id,
+            })
+        };
+        KvService::release_snapshot(&h, release()).await.unwrap();
+        let status = KvService::release_snapshot(&h, release())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = KvService::get(
+            &h,
+            tonic::Request::new(GetRequest {
+                key: b"k".to_vec(),
+                snapshot_id: Some(snapshot.snapshot_id),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_held_by_read() {
+        let h = new_handler();
+        KvService::put(
+            &h,
+            tonic::Request::new(PutRequest {
+                key: b"k".to_vec(),
+                value: b"v".to_vec(),
+                sync: false,
+            }),
+        )
+        .await
+        .unwrap();
+        let snapshot =
+            KvService::acquire_snapshot(&h, tonic::Request::new(AcquireSnapshotRequest {}))
+                .await
+                .unwrap()
+                .into_inner();
+        let oldest = || {
+            h.db.inner
+                .versions
+                .lock()
+                .unwrap()
+                .snapshots
+                .oldest()
+                .sequence()
+        };
+        assert_eq!(oldest(), snapshot.sequence);
+        // A read in flight keeps the snapshot after the client releases it
+        let pinned = h.pin_snapshot(Some(snapshot.snapshot_id)).unwrap();
+        KvService::release_snapshot(
+            &h,
+            tonic::Request::new(ReleaseSnapshotRequest {
+                snapshot_id: snapshot.snapshot_id,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(oldest(), snapshot.sequence);
+        drop(pinned);
+        assert_eq!(oldest(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_expired() {
+        let ttl = Duration::from_secs(60);
+        let h = new_handler().with_snapshot_ttl(ttl);
+        KvService::put(
+            &h,
+            tonic::Request::new(PutRequest {
+                key: b"k".to_vec(),
+                value: b"v".to_vec(),
+                sync: false,
+            }),
+        )
+        .await
+        .unwrap();
+        let acquire = || {
+            let h = h.clone();
+            async move {
+                KvService::acquire_snapshot(&h, tonic::Request::new(AcquireSnapshotRequest {}))
+                    .await
+                    .unwrap()
+                    .into_inner()
+            }
+        };
+        // Both clients get the same snapshot of the db
+        let (idle, used) = (acquire().await, acquire().await);
+        assert_eq!(idle.sequence, used.sequence);
+        h.snapshots
+            .lock()
+            .unwrap()
+            .get_mut(&idle.snapshot_id)
+            .unwrap()
+            .last_used -= ttl;
+        assert_eq!(
+            get(&h, b"k", Some(used.snapshot_id)).await,
+            Some(b"v".to_vec())
+        );
+        // The snapshot not used for the ttl is released
+        let status = KvService::get(
+            &h,
+            tonic::Request::new(GetRequest {
+                key: b"k".to_vec(),
+                snapshot_id: Some(idle.snapshot_id),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(h.snapshots.lock().unwrap().len(), 1);
+        let oldest = || {
+            h.db.inner
+                .versions
+                .lock()
+                .unwrap()
+                .snapshots
+                .oldest()
+                .sequence()
+        };
+        // The other client still holds the snapshot
+        assert_eq!(oldest(), used.sequence);
+        KvService::release_snapshot(
+            &h,
+            tonic::Request::new(ReleaseSnapshotRequest {
+                snapshot_id: used.snapshot_id,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(oldest(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let h = new_handler();
+        let put = |key: &'static str| {
+            let h = h.clone();
+            async move {
+                KvService::put(
+                    &h,
+                    tonic::Request::new(PutRequest {
+                        key: key.as_bytes().to_vec(),
+                        value: b"v".to_vec(),
+                        sync: false,
+                    }),
+                )
+                .await
+                .unwrap();
+            }
+        };
+        put("a/1").await;
+        let watch = |prefix: &str, start_sequence: Option<u64>| {
+            KvService::watch(
+                &h,
+                tonic::Request::new(WatchRequest {
+                    start: None,
+                    end: None,
+                    prefix: Some(prefix.as_bytes().to_vec()),
+                    start_sequence,
+                }),
+            )
+        };
+        let mut stream = watch("a/", None).await.unwrap().into_inner();
+        put("b/1").await;
+        put("a/2").await;
+        KvService::delete(
+            &h,
+            tonic::Request::new(DeleteRequest {
+                key: b"a/1".to_vec(),
+                sync: false,
+            }),
+        )
+        .await
+        .unwrap();
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.sequence, event.key, event.r#type),
+            (3, b"a/2".to_vec(), MutationType::Put as i32)
+        );
+        let event = stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.sequence, event.key, event.r#type),
+            (4, b"a/1".to_vec(), MutationType::Delete as i32)
+        );
+
+        // Resume from an earlier sequence
+        let mut stream = watch("a/", Some(1)).await.unwrap().into_inner();
+        let sequences: Vec<_> = (&mut stream)
+            .take(3)
+            .map(|e| e.unwrap().sequence)
+            .collect()
+            .await;
+        assert_eq!(sequences, vec![1, 3, 4]);
+
+        let status = KvService::watch(
+            &h,
+            tonic::Request::new(WatchRequest {
+                start: Some(b"a".to_vec()),
+                end: None,
+                prefix: Some(b"a".to_vec()),
+                start_sequence: None,
+            }),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -1,41 +1,125 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+use tokio::sync::mpsc;
 use tonic::Status;
 
 use crate::{
+    iterator::Iterator,
     memtable::{
-        key_format::{InternalKeyComparator, LookupKey},
+        key_format::{InternalKeyComparator, LookupKey, ParsedInternalKey},
         memtable::MemTable,
         value_format::ValueType,
     },
     memtable_service::{
-        memtable_service_server::MemtableService, ListKvRequest, ListKvResponse, UpdateKvRequest,
-        UpdateKvResponse,
+        memtable_service_server::MemtableService, CreateTenantRequest, CreateTenantResponse,
+        DropTenantRequest, DropTenantResponse, ListKvRequest, ListKvResponse, ListTenantsRequest,
+        ListTenantsResponse, ScanKvRequest, ScanKvResponse, TenantQuota, TenantStats,
+        UpdateKvRequest, UpdateKvResponse, ValueType as RpcValueType,
     },
-    util::comparator::Comparator,
+    metrics::{MetricType, MetricsRegistry, MetricsWriter},
+    options::MAX_KEY_SEQUENCE,
+    services::tenant::{Tenant, DEFAULT_TENANT},
+    util::{collection::HashMap, comparator::Comparator},
 };
 
-#[derive(Clone)]
+// The memtable size of the default tenant
+const DEFAULT_MEMTABLE_SIZE: usize = 1 << 32;
+
+/// The storage quota of a created tenant which does not set one
+pub const DEFAULT_TENANT_QUOTA_BYTES: u64 = 64 << 20;
+
+/// The largest storage quota of a created tenant
+pub const MAX_TENANT_QUOTA_BYTES: u64 = 1 << 30;
+
+/// The number of the tenants served at most by default, the default tenant included
+pub const DEFAULT_MAX_TENANTS: usize = 64;
+
+// The number of entries buffered ahead of the client by a scan
+const SCAN_BUFFER_SIZE: usize = 64;
+
+/// Serves `MemtableService` with a memtable per tenant
 pub struct MemtableServiceHandler<C: Comparator> {
-    memtable: MemTable<C>,
+    icmp: InternalKeyComparator<C>,
+    tenants: Arc<RwLock<HashMap<String, Arc<Tenant<C>>>>>,
+    max_tenants: usize,
+    metrics: Arc<MetricsRegistry>,
+}
+
+impl<C: Comparator> Clone for MemtableServiceHandler<C> {
+    fn clone(&self) -> Self {
+        Self {
+            icmp: self.icmp.clone(),
+            tenants: self.tenants.clone(),
+            max_tenants: self.max_tenants,
+            metrics: self.metrics.clone(),
+        }
+    }
 }
 
 impl<C: Comparator> Unpin for MemtableServiceHandler<C> {}
 
-impl<C: Comparator> MemtableServiceHandler<C> {
+impl<C: Comparator + 'static> MemtableServiceHandler<C> {
+    /// Creates a handler serving `memtable` as the default tenant without quotas
     pub fn new_with_memtable(memtable: MemTable<C>) -> Self {
-        Self { memtable }
+        let icmp = InternalKeyComparator::new(C::default());
+        let default =
+            Tenant::with_memtable(DEFAULT_TENANT.to_owned(), TenantQuota::default(), memtable);
+        let mut tenants = HashMap::default();
+        tenants.insert(DEFAULT_TENANT.to_owned(), Arc::new(default));
+        let tenants = Arc::new(RwLock::new(tenants));
+        let metrics = Arc::new(MetricsRegistry::default());
+        let collected = tenants.clone();
+        metrics.register(move |w| collect_tenants(&collected.read().unwrap(), w));
+        Self {
+            icmp,
+            tenants,
+            max_tenants: DEFAULT_MAX_TENANTS,
+            metrics,
+        }
+    }
+
+    /// Sets the number of the tenants served at most, the default tenant included
+    pub fn with_max_tenants(mut self, max_tenants: usize) -> Self {
+        self.max_tenants = max_tenants;
+        self
+    }
+
+    /// Returns the metrics of the requests and the tenants served
+    pub fn metrics(&self) -> &Arc<MetricsRegistry> {
+        &self.metrics
+    }
+
+    /// Returns the tenant serving the requests of `name`
+    pub fn tenant(&self, name: &str) -> Result<Arc<Tenant<C>>, Status> {
+        let name = if name.is_empty() {
+            DEFAULT_TENANT
+        } else {
+            name
+        };
+        self.tenants
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("unknown tenant {}", name)))
     }
 }
 
 impl<C> Default for MemtableServiceHandler<C>
 where
-    C: Comparator,
+    C: Comparator + 'static,
 {
     fn default() -> Self {
         let comparator = C::default();
         let icmp = InternalKeyComparator::new(comparator);
-        let memtable = MemTable::new(1 << 32, icmp);
+        let memtable = MemTable::new(DEFAULT_MEMTABLE_SIZE, icmp);
 
-        Self { memtable }
+        Self::new_with_memtable(memtable)
     }
 }
 
@@ -55,61 +139,634 @@ impl<C: Comparator + 'static> MemtableServiceHandler<C> {
     }
 }
 
+// Writes the memory, the entries and the request counters of every tenant
+fn collect_tenants<C: Comparator>(
+    tenants: &HashMap<String, Arc<Tenant<C>>>,
+    w: &mut MetricsWriter,
+) {
+    let mut infos: Vec<_> = tenants.values().map(|t| t.info()).collect();
+    infos.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+    let families: [(&str, &str, MetricType, fn(&TenantStats) -> u64); 7] = [
+        (
+            "memtable_tenant_memory_bytes",
+            "The memory used by the memtable of a tenant",
+            MetricType::Gauge,
+            |s| s.used_bytes,
+        ),
+        (
+            "memtable_tenant_entries",
+            "The number of the entries in the memtable of a tenant",
+            MetricType::Gauge,
+            |s| s.num_entries,
+        ),
+        (
+            "memtable_tenant_reads_total",
+            "The number of the reads served for a tenant",
+            MetricType::Counter,
+            |s| s.reads,
+        ),
+        (
+            "memtable_tenant_writes_total",
+            "The number of the writes served for a tenant",
+            MetricType::Counter,
+            |s| s.writes,
+        ),
+        (
+            "memtable_tenant_written_bytes_total",
+            "The bytes of the keys and values written for a tenant",
+            MetricType::Counter,
+            |s| s.bytes_written,
+        ),
+        (
+            "memtable_tenant_throttled_total",
+            "The number of the requests of a tenant rejected by the rate limit",
+            MetricType::Counter,
+            |s| s.throttled,
+        ),
+        (
+            "memtable_tenant_over_quota_total",
+            "The number of the writes of a tenant rejected by the storage quota",
+            MetricType::Counter,
+            |s| s.over_quota,
+        ),
+    ];
+    for (name, help, metric_type, value) in families {
+        w.header(name, help, metric_type);
+        for info in &infos {
+            if let Some(stats) = &info.stats {
+                w.sample(name, &[("tenant", &info.tenant)], value(stats));
+            }
+        }
+    }
+}
+
+pub struct ScanKvStream {
+    recv: mpsc::Receiver<Result<ScanKvResponse, Status>>,
+}
+
+impl Stream for ScanKvStream {
+    type Item = Result<ScanKvResponse, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
 #[tonic::async_trait]
 impl<C: Comparator + 'static> MemtableService for MemtableServiceHandler<C> {
+    type ScanKVStream = ScanKvStream;
+
     async fn list_kv(
         &self,
         req: tonic::Request<ListKvRequest>,
     ) -> Result<tonic::Response<ListKvResponse>, Status> {
         let req = req.get_ref();
-        let tenant: String = req.tenant.clone();
-        let seq = req.seq;
-        let key: String = req.key.clone();
-        info!("Now req is {:?} and {:?}", tenant, &key);
-        let result = self
-            .memtable
-            .get(&LookupKey::new(key.as_bytes(), seq))
-            .expect("memtable get failed");
-        if result.is_ok() {
-            let resp_value =
-                String::from_utf8(result.unwrap()).expect("memtable get result to string failed");
-            return Ok(tonic::Response::new(ListKvResponse { value: resp_value }));
+        let tenant = self.tenant(&req.tenant)?;
+        tenant.acquire_request()?;
+        tenant.stats().record_read();
+        // Any sequence beyond the max one reads the latest data
+        let seq = req.seq.min(MAX_KEY_SEQUENCE);
+        match tenant
+            .memtable()
+            .get(&LookupKey::new(req.key.as_bytes(), seq))
+        {
+            Some(Ok(value)) => {
+                let value = String::from_utf8(value)
+                    .map_err(|_| Status::data_loss(format!("invalid value of key {}", req.key)))?;
+                Ok(tonic::Response::new(ListKvResponse { value }))
+            }
+            Some(Err(_)) => Err(Status::not_found(format!(
+                "key {} is deleted at sequence {}",
+                req.key, seq
+            ))),
+            None => Err(Status::not_found(format!("key {} not found", req.key))),
         }
-        error!("memtable get failed");
-        return Err(Status::aborted("memtable process error"));
     }
 
     async fn update_kv(
         &self,
         req: tonic::Request<UpdateKvRequest>,
     ) -> Result<tonic::Response<UpdateKvResponse>, Status> {
-        let req = req.get_ref();
-        let tenant: String = req.tenant.clone();
-        let key: String = req.key.clone();
-        let value: Option<String> = req.value.clone();
+        let req = req.into_inner();
+        let t = self.tenant(&req.tenant)?;
+        t.acquire_request()?;
         let seq = req.seq;
-        let r_type = req.value_type();
-
-        info!(
-            "Now write kv on tenant: {:?}, key and value is {:?}, {:?}, on seq is {:?}",
-            tenant, key, value, seq
-        );
-        let value_type = match r_type {
-            crate::memtable_service::ValueType::NormalValue => 0,
-            crate::memtable_service::ValueType::Deletion => 1,
-            crate::memtable_service::ValueType::Unknown => 2,
+        let (value_type, value) = match req.value_type() {
+            RpcValueType::NormalValue => match &req.value {
+                Some(v) => (ValueType::Value, v.as_bytes()),
+                None => {
+                    return Err(Status::invalid_argument(format!(
+                        "missing the value of key {}",
+                        req.key
+                    )))
+                }
+            },
+            RpcValueType::Deletion => (ValueType::Deletion, &b""[..]),
+            RpcValueType::Unknown => {
+                return Err(Status::invalid_argument("unknown value type"));
+            }
         };
+        debug!(
+            "Now write kv on tenant: {:?}, key is {:?}, type is {:?}, on seq is {:?}",
+            t.name(),
+            req.key,
+            value_type,
+            seq
+        );
 
-        if let Some(v) = value {
-            self.memtable
-                .add(seq, ValueType::Value, key.as_bytes(), v.as_bytes());
-        };
+        let reservation = t.reserve_write(req.key.as_bytes(), value)?;
+        t.advance_seq(seq)?;
+        t.memtable().add(seq, value_type, req.key.as_bytes(), value);
+        reservation.commit();
+        t.stats().record_write(req.key.len() + value.len());
 
         Ok(tonic::Response::new(UpdateKvResponse {
-            tenant,
+            tenant: req.tenant.clone(),
             ack: true,
             seq,
-            value_type,
+            value_type: req.value_type,
         }))
     }
+
+    async fn scan_kv(
+        &self,
+        req: tonic::Request<ScanKvRequest>,
+    ) -> Result<tonic::Response<Self::ScanKVStream>, Status> {
+        let req = req.into_inner();
+        let tenant = self.tenant(&req.tenant)?;
+        tenant.acquire_request()?;
+        tenant.stats().record_read();
+        let seq = req.seq.min(MAX_KEY_SEQUENCE);
+        let limit = if req.limit == 0 { u64::MAX } else { req.limit };
+        let ucmp = self.icmp.user_comparator.clone();
+        let (send, recv) = mpsc::channel(SCAN_BUFFER_SIZE);
+        tokio::task::spawn_blocking(move || {
+            let mut iter = tenant.memtable().iter();
+            match &req.start {
+                Some(start) => iter.seek(LookupKey::new(start.as_bytes(), seq).internal_key()),
+                None => iter.seek_to_first(),
+            }
+            let mut count = 0;
+            // The user key of the newest entry visible at `seq` seen so far
+            let mut last_key: Option<Vec<u8>> = None;
+            while iter.valid() && count < limit {
+                let parsed = match ParsedInternalKey::decode_from(iter.key()) {
+                    Some(parsed) => parsed,
+                    None => {
+                        let _ = send.blocking_send(Err(Status::data_loss("corrupted entry")));
+                        return;
+                    }
+                };
+                if let Some(end) = &req.end {
+                    if ucmp.compare(parsed.user_key, end.as_bytes()).is_ge() {
+                        break;
+                    }
+                }
+                // Skip the entries written after `seq` and the ones shadowed by a newer entry
+                if parsed.seq > seq || last_key.as_deref() == Some(parsed.user_key) {
+                    iter.next();
+                    continue;
+                }
+                last_key = Some(parsed.user_key.to_vec());
+                if parsed.value_type == ValueType::Value {
+                    let entry = match (
+                        String::from_utf8(parsed.user_key.to_vec()),
+                        String::from_utf8(iter.value().to_vec()),
+                    ) {
+                        (Ok(key), Ok(value)) => Ok(ScanKvResponse {
+                            key,
+                            value,
+                            seq: parsed.seq,
+                        }),
+                        _ => Err(Status::data_loss("invalid key or value")),
+                    };
+                    let failed = entry.is_err();
+                    // The client has gone away
+                    if send.blocking_send(entry).is_err() || failed {
+                        return;
+                    }
+                    count += 1;
+                }
+                iter.next();
+            }
+        });
+        Ok(tonic::Response::new(ScanKvStream { recv }))
+    }
+
+    async fn create_tenant(
+        &self,
+        req: tonic::Request<CreateTenantRequest>,
+    ) -> Result<tonic::Response<CreateTenantResponse>, Status> {
+        let req = req.into_inner();
+        if req.tenant.is_empty() {
+            return Err(Status::invalid_argument("empty tenant name"));
+        }
+        let mut quota = req.quota.unwrap_or_default();
+        // The memtable of a tenant is never flushed, so a tenant always has a storage quota
+        if quota.max_bytes == 0 {
+            quota.max_bytes = DEFAULT_TENANT_QUOTA_BYTES;
+        }
+        // The memtable of a tenant takes the whole quota up front
+        if quota.max_bytes > MAX_TENANT_QUOTA_BYTES {
+            return Err(Status::invalid_argument(format!(
+                "storage quota {} exceeds the max quota {}",
+                quota.max_bytes, MAX_TENANT_QUOTA_BYTES
+            )));
+        }
+        let mut tenants = self.tenants.write().unwrap();
+        if tenants.contains_key(&req.tenant) {
+            return Err(Status::already_exists(format!(
+                "tenant {} already exists",
+                req.tenant
+            )));
+        }
+        if tenants.len() >= self.max_tenants {
+            return Err(Status::resource_exhausted(format!(
+                "{} tenants are served at most",
+                self.max_tenants
+            )));
+        }
+        let tenant = Tenant::new(
+            req.tenant.clone(),
+            quota,
+            self.icmp.clone(),
+            DEFAULT_TENANT_QUOTA_BYTES as usize,
+        );
+        info!("Create tenant {:?}", req.tenant);
+        tenants.insert(req.tenant, Arc::new(tenant));
+        Ok(tonic::Response::new(CreateTenantResponse {}))
+    }
+
+    async fn list_tenants(
+        &self,
+        _req: tonic::Request<ListTenantsRequest>,
+    ) -> Result<tonic::Response<ListTenantsResponse>, Status> {
+        let mut tenants: Vec<_> = self
+            .tenants
+            .read()
+            .unwrap()
+            .values()
+            .map(|t| t.info())
+            .collect();
+        tenants.sort_by(|a, b| a.tenant.cmp(&b.tenant));
+        Ok(tonic::Response::new(ListTenantsResponse { tenants }))
+    }
+
+    async fn drop_tenant(
+        &self,
+        req: tonic::Request<DropTenantRequest>,
+    ) -> Result<tonic::Response<DropTenantResponse>, Status> {
+        let name = &req.get_ref().tenant;
+        if name.is_empty() || name == DEFAULT_TENANT {
+            return Err(Status::failed_precondition(
+                "the default tenant can not be dropped",
+            ));
+        }
+        // The memtable is freed as a whole once the requests in flight finish with it
+        match self.tenants.write().unwrap().remove(name) {
+            Some(_) => {
+                info!("Drop tenant {:?}", name);
+                Ok(tonic::Response::new(DropTenantResponse {}))
+            }
+            None => Err(Status::not_found(format!("unknown tenant {}", name))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::comparator::BytewiseComparator;
+
+    async fn put(
+        h: &MemtableServiceHandler<BytewiseComparator>,
+        tenant: &str,
+        seq: u64,
+        key: &str,
+        value: &str,
+    ) -> Result<(), Status> {
+        MemtableService::update_kv(
+            h,
+            tonic::Request::new(UpdateKvRequest {
+                tenant: tenant.to_owned(),
+                seq,
+                value_type: RpcValueType::NormalValue as i32,
+                key: key.to_owned(),
+                value: Some(value.to_owned()),
+            }),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn delete(
+        h: &MemtableServiceHandler<BytewiseComparator>,
+        seq: u64,
+        key: &str,
+    ) -> Result<(), Status> {
+        MemtableService::update_kv(
+            h,
+            tonic::Request::new(UpdateKvRequest {
+                tenant: String::new(),
+                seq,
+                value_type: RpcValueType::Deletion as i32,
+                key: key.to_owned(),
+                value: None,
+            }),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn get_at(
+        h: &MemtableServiceHandler<BytewiseComparator>,
+        tenant: &str,
+        key: &str,
+        seq: u64,
+    ) -> Result<String, Status> {
+        MemtableService::list_kv(
+            h,
+            tonic::Request::new(ListKvRequest {
+                tenant: tenant.to_owned(),
+                seq,
+                key: key.to_owned(),
+            }),
+        )
+        .await
+        .map(|r| r.into_inner().value)
+    }
+
+    async fn get(
+        h: &MemtableServiceHandler<BytewiseComparator>,
+        tenant: &str,
+        key: &str,
+    ) -> Result<String, Status> {
+        get_at(h, tenant, key, u64::MAX).await
+    }
+
+    async fn scan(
+        h: &MemtableServiceHandler<BytewiseComparator>,
+        seq: u64,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: u64,
+    ) -> Vec<(String, String)> {
+        use futures::StreamExt;
+
+        let stream = MemtableService::scan_kv(
+            h,
+            tonic::Request::new(ScanKvRequest {
+                tenant: String::new(),
+                seq,
+                start: start.map(str::to_owned),
+                end: end.map(str::to_owned),
+                limit,
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        stream
+            .map(|e| {
+                let e = e.unwrap();
+                (e.key, e.value)
+            })
+            .collect()
+            .await
+    }
+
+    async fn create(
+        h: &MemtableServiceHandler<BytewiseComparator>,
+        tenant: &str,
+        quota: TenantQuota,
+    ) -> Result<(), Status> {
+        MemtableService::create_tenant(
+            h,
+            tonic::Request::new(CreateTenantRequest {
+                tenant: tenant.to_owned(),
+                quota: Some(quota),
+            }),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_tenant_isolation() {
+        let h = MemtableServiceHandler::<BytewiseComparator>::default();
+        let status = put(&h, "a", 1, "k", "v").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        create(&h, "a", TenantQuota::default()).await.unwrap();
+        create(&h, "b", TenantQuota::default()).await.unwrap();
+        let status = create(&h, "a", TenantQuota::default()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+        put(&h, "a", 1, "k", "from a").await.unwrap();
+        put(&h, "b", 1, "k", "from b").await.unwrap();
+        put(&h, "", 1, "k", "from default").await.unwrap();
+        assert_eq!(get(&h, "a", "k").await.unwrap(), "from a");
+        assert_eq!(get(&h, "b", "k").await.unwrap(), "from b");
+        assert_eq!(get(&h, DEFAULT_TENANT, "k").await.unwrap(), "from default");
+
+        let tenants = MemtableService::list_tenants(&h, tonic::Request::new(ListTenantsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let names: Vec<_> = tenants.tenants.iter().map(|t| t.tenant.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", DEFAULT_TENANT]);
+        let stats = tenants.tenants[0].stats.clone().unwrap();
+        assert_eq!((stats.reads, stats.writes, stats.num_entries), (1, 1, 1));
+        assert_eq!(stats.bytes_written, "kfrom a".len() as u64);
+
+        MemtableService::drop_tenant(
+            &h,
+            tonic::Request::new(DropTenantRequest {
+                tenant: "a".to_owned(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            get(&h, "a", "k").await.unwrap_err().code(),
+            tonic::Code::NotFound
+        );
+        assert_eq!(get(&h, "b", "k").await.unwrap(), "from b");
+        let status = MemtableService::drop_tenant(
+            &h,
+            tonic::Request::new(DropTenantRequest {
+                tenant: DEFAULT_TENANT.to_owned(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_limits() {
+        let h = MemtableServiceHandler::<BytewiseComparator>::default().with_max_tenants(3);
+        let quota = |max_bytes| TenantQuota {
+            max_bytes,
+            max_requests_per_second: 0,
+        };
+        let status = create(&h, "huge", quota(MAX_TENANT_QUOTA_BYTES + 1))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        create(&h, "a", quota(4096)).await.unwrap();
+        assert_eq!(h.tenant("a").unwrap().memtable().capacity(), 4096);
+        create(&h, "b", quota(0)).await.unwrap();
+        let b = h.tenant("b").unwrap();
+        assert_eq!(b.memtable().capacity() as u64, DEFAULT_TENANT_QUOTA_BYTES);
+        // The quota reported is the one in effect
+        assert_eq!(
+            b.info().quota.unwrap().max_bytes,
+            DEFAULT_TENANT_QUOTA_BYTES
+        );
+        drop(b);
+        let status = create(&h, "c", quota(0)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        // A dropped tenant makes room for a new one
+        MemtableService::drop_tenant(
+            &h,
+            tonic::Request::new(DropTenantRequest {
+                tenant: "a".to_owned(),
+            }),
+        )
+        .await
+        .unwrap();
+        create(&h, "c", quota(0)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tenant_quotas() {
+        let h = MemtableServiceHandler::<BytewiseComparator>::default();
+        create(
+            &h,
+            "small",
+            TenantQuota {
+                max_bytes: 4096,
+                max_requests_per_second: 0,
+            },
+        )
+        .await
+        .unwrap();
+        create(
+            &h,
+            "slow",
+            TenantQuota {
+                max_bytes: 0,
+                max_requests_per_second: 2,
+            },
+        )
+        .await
+        .unwrap();
+
+        let value = "v".repeat(1024);
+        let mut seq = 1;
+        let status = loop {
+            match put(&h, "small", seq, &format!("k{}", seq), &value).await {
+                Ok(()) => seq += 1,
+                Err(status) => break status,
+            }
+        };
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(seq > 1);
+        // The other tenants are not affected
+        put(&h, "", 1, "k", &value).await.unwrap();
+
+        put(&h, "slow", 1, "k", "v").await.unwrap();
+        get(&h, "slow", "k").await.unwrap();
+        let status = get(&h, "slow", "k").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_deletion_and_sequence() {
+        let h = MemtableServiceHandler::<BytewiseComparator>::default();
+        let status = get(&h, "", "k").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        put(&h, "", 1, "k", "v1").await.unwrap();
+        delete(&h, 2, "k").await.unwrap();
+        put(&h, "", 3, "k", "v3").await.unwrap();
+        assert_eq!(get_at(&h, "", "k", 1).await.unwrap(), "v1");
+        let status = get_at(&h, "", "k", 2).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(get(&h, "", "k").await.unwrap(), "v3");
+
+        // The sequences must keep increasing
+        for seq in [1, 3] {
+            let status = put(&h, "", seq, "other", "v").await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        }
+        let status = get(&h, "", "other").await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        // A value is required unless deleting
+        let status = MemtableService::update_kv(
+            &h,
+            tonic::Request::new(UpdateKvRequest {
+                tenant: String::new(),
+                seq: 4,
+                value_type: RpcValueType::NormalValue as i32,
+                key: "k".to_owned(),
+                value: None,
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        put(&h, "", 4, "other", "v").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_kv() {
+        let h = MemtableServiceHandler::<BytewiseComparator>::default();
+        put(&h, "", 1, "a", "a1").await.unwrap();
+        put(&h, "", 2, "b", "b2").await.unwrap();
+        put(&h, "", 3, "c", "c3").await.unwrap();
+        put(&h, "", 4, "a", "a4").await.unwrap();
+        delete(&h, 5, "b").await.unwrap();
+        put(&h, "", 6, "d", "d6").await.unwrap();
+
+        let pairs = |v: &[(&str, &str)]| -> Vec<(String, String)> {
+            v.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            scan(&h, u64::MAX, None, None, 0).await,
+            pairs(&[("a", "a4"), ("c", "c3"), ("d", "d6")])
+        );
+        assert_eq!(
+            scan(&h, 4, None, None, 0).await,
+            pairs(&[("a", "a4"), ("b", "b2"), ("c", "c3")])
+        );
+        assert_eq!(
+            scan(&h, 3, Some("b"), Some("d"), 0).await,
+            pairs(&[("b", "b2"), ("c", "c3")])
+        );
+        assert_eq!(
+            scan(&h, u64::MAX, Some("b"), None, 1).await,
+            pairs(&[("c", "c3")])
+        );
+        assert!(scan(&h, 0, None, None, 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let h = MemtableServiceHandler::<BytewiseComparator>::default();
+        put(&h, "", 1, "a", "1").await.unwrap();
+        assert_eq!(get(&h, "", "a").await.unwrap(), "1");
+        assert!(get(&h, "", "b").await.is_err());
+        let text = h.metrics().render();
+        for line in [
+            "memtable_tenant_writes_total{tenant=\"default\"} 1",
+            "memtable_tenant_reads_total{tenant=\"default\"} 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+    }
 }
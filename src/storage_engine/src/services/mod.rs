@@ -1 +1,6 @@
+pub mod admin_service;
+pub mod kv_service;
 pub mod memtable_service;
+pub mod redis_service;
+pub mod resp;
+pub mod tenant;
@@ -0,0 +1,1098 @@
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    db_impl::template_impl::TemplateDB,
+    db_trait::DB,
+    error::TemplateResult,
+    iterator::Iterator,
+    memtable::batch::WriteBatch,
+    options::{ReadOptions, WriteOptions},
+    services::resp::{parse_command, Reply},
+    storage::Storage,
+    util::{
+        coding::{decode_fixed_64, put_fixed_64},
+        collection::HashMap,
+        comparator::Comparator,
+    },
+};
+
+// The layout of the keys in the db:
+//
+//   string:     's' | key                                  => expire_at_ms (fixed64) | value
+//   hash field: 'h' | key length (big-endian u32) | key | field  => value
+//
+// `expire_at_ms` is 0 for a string without a TTL. Expired strings are hidden from the reads
+// and removed once overwritten, deleted or reclaimed by `RedisHandler::reclaim_expired`.
+const STRING_TAG: u8 = b's';
+const HASH_TAG: u8 = b'h';
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+// The number of the SCAN cursors remembered by a handler, see `ScanCursors`
+const MAX_SCAN_CURSORS: usize = 4096;
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+const NOT_INTEGER: &str = "ERR value is not an integer or out of range";
+const SYNTAX_ERROR: &str = "ERR syntax error";
+
+/// The state of a client connection
+#[derive(Clone, Copy, Debug)]
+pub struct Session {
+    /// The RESP version of the replies, switched by `HELLO`
+    pub protocol: u8,
+    /// Set by `QUIT`
+    pub closing: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            protocol: 2,
+            closing: false,
+        }
+    }
+}
+
+/// Serves the Redis string and hash commands with a `TemplateDB`
+pub struct RedisHandler<S: Storage + Clone + 'static, C: Comparator + 'static> {
+    db: TemplateDB<S, C>,
+    // Serializes the writes so that a read-modify-write like INCR is atomic
+    write_lock: Arc<Mutex<()>>,
+    // The string key `reclaim_expired` resumes from
+    reclaim_from: Arc<Mutex<Option<Vec<u8>>>>,
+    // The db keys the SCANs resume from
+    scan_cursors: Arc<Mutex<ScanCursors>>,
+}
+
+impl<S: Storage + Clone, C: Comparator> Clone for RedisHandler<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            write_lock: self.write_lock.clone(),
+            reclaim_from: self.reclaim_from.clone(),
+            scan_cursors: self.scan_cursors.clone(),
+        }
+    }
+}
+
+impl<S: Storage + Clone, C: Comparator + 'static> RedisHandler<S, C> {
+    pub fn new(db: TemplateDB<S, C>) -> Self {
+        Self {
+            db,
+            write_lock: Arc::new(Mutex::new(())),
+            reclaim_from: Arc::new(Mutex::new(None)),
+            scan_cursors: Arc::new(Mutex::new(ScanCursors::default())),
+        }
+    }
+
+    /// Deletes the expired strings among the next `limit` strings, resuming from where the
+    /// last call stopped and starting over after the last string. Returns the number of the
+    /// strings deleted.
+    pub fn reclaim_expired(&self, limit: usize) -> TemplateResult<usize> {
+        let mut from = self.reclaim_from.lock().unwrap();
+        let start = from.take().unwrap_or_else(|| vec![STRING_TAG]);
+        let now = now_ms();
+        let mut iter = self.db.iter(ReadOptions::default())?;
+        iter.seek(&start);
+        let (mut examined, mut expired) = (0, vec![]);
+        while iter.valid() && iter.key().first() == Some(&STRING_TAG) {
+            if examined == limit {
+                *from = Some(iter.key().to_vec());
+                break;
+            }
+            examined += 1;
+            if is_expired(iter.value(), now) {
+                expired.push(iter.key().to_vec());
+            }
+            iter.next();
+        }
+        iter.status()?;
+        drop(iter);
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        for key in &expired {
+            // Leave the strings set again since read
+            if let Some(raw) = self.db.get(ReadOptions::default(), key)? {
+                if is_expired(&raw, now) {
+                    batch.delete(key);
+                }
+            }
+        }
+        let deleted = batch.get_count() as usize;
+        self.db.write(WriteOptions::default(), batch)?;
+        Ok(deleted)
+    }
+
+    /// Serves the commands of a client until it quits or disconnects.
+    /// The commands are executed on the blocking threads of tokio.
+    pub async fn serve_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut buf = BytesMut::with_capacity(4096);
+        let mut out = vec![];
+        let mut session = Session::default();
+        loop {
+            // Run all the complete commands, pipelined ones included
+            loop {
+                let args = match parse_command(&mut buf) {
+                    Ok(Some(args)) => args,
+                    Ok(None) => break,
+                    Err(e) => {
+                        Reply::error(format!("ERR Protocol error: {}", e))
+                            .encode(session.protocol, &mut out);
+                        stream.write_all(&out).await?;
+                        return Ok(());
+                    }
+                };
+                if args.is_empty() {
+                    continue;
+                }
+                let handler = self.clone();
+                let (reply, s) = tokio::task::spawn_blocking(move || {
+                    let reply = handler.execute(&mut session, &args);
+                    (reply, session)
+                })
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                session = s;
+                reply.encode(session.protocol, &mut out);
+                if session.closing {
+                    stream.write_all(&out).await?;
+                    return Ok(());
+                }
+            }
+            if !out.is_empty() {
+                stream.write_all(&out).await?;
+                out.clear();
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes a command and returns the reply
+    pub fn execute(&self, session: &mut Session, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+        let arity_ok = match name.as_str() {
+            "PING" | "HELLO" | "COMMAND" | "CLIENT" | "QUIT" => true,
+            "ECHO" | "SELECT" | "GET" | "INCR" | "DECR" | "HGETALL" | "HLEN" => args.len() == 1,
+            "INCRBY" | "DECRBY" | "HGET" | "HEXISTS" => args.len() == 2,
+            "SET" | "SCAN" => !args.is_empty() && (name == "SCAN" || args.len() >= 2),
+            "DEL" | "MGET" => !args.is_empty(),
+            "MSET" => !args.is_empty() && args.len() % 2 == 0,
+            "HSET" => args.len() >= 3 && args.len() % 2 == 1,
+            "HDEL" | "HMGET" => args.len() >= 2,
+            _ => {
+                return Reply::error(format!(
+                    "ERR unknown command '{}'",
+                    name.to_ascii_lowercase()
+                ))
+            }
+        };
+        if !arity_ok {
+            return Reply::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ));
+        }
+        let res = match name.as_str() {
+            "PING" => Ok(match args.first() {
+                Some(msg) => Reply::Bulk(msg.clone()),
+                None => Reply::Simple("PONG".to_owned()),
+            }),
+            "ECHO" => Ok(Reply::Bulk(args[0].clone())),
+            "HELLO" => Ok(hello(session, args)),
+            "SELECT" => Ok(if args[0] == b"0" {
+                Reply::ok()
+            } else {
+                Reply::error("ERR DB index is out of range")
+            }),
+            "QUIT" => {
+                session.closing = true;
+                Ok(Reply::ok())
+            }
+            // Just enough for the clients probing the server
+            "COMMAND" => Ok(Reply::Array(vec![])),
+            "CLIENT" => Ok(Reply::ok()),
+            "GET" => self.get(&args[0]),
+            "SET" => self.set(args),
+            "DEL" => self.del(args),
+            "MGET" => self.mget(args),
+            "MSET" => self.mset(args),
+            "INCR" => self.incr_by(&args[0], Some(1)),
+            "DECR" => self.incr_by(&args[0], Some(-1)),
+            "INCRBY" => self.incr_by(&args[0], parse_i64(&args[1])),
+            "DECRBY" => self.incr_by(&args[0], parse_i64(&args[1]).and_then(i64::checked_neg)),
+            "SCAN" => self.scan(args),
+            "HSET" => self.hset(args),
+            "HGET" => self.hget(&args[0], &args[1]),
+            "HDEL" => self.hdel(args),
+            "HGETALL" => self.hgetall(&args[0]),
+            "HMGET" => self.hmget(args),
+            "HLEN" => self
+                .hash_fields(&args[0])
+                .map(|fields| Reply::Integer(fields.len() as i64)),
+            "HEXISTS" => self
+                .hash_field(&args[0], &args[1])
+                .map(|v| Reply::Integer(v.is_some() as i64)),
+            _ => unreachable!(),
+        };
+        res.unwrap_or_else(|e| Reply::error(format!("ERR {}", e)))
+    }
+
+    fn get(&self, key: &[u8]) -> TemplateResult<Reply> {
+        match self.get_string(ReadOptions::default(), key)? {
+            Some((value, _)) => Ok(Reply::Bulk(value)),
+            None if self.hash_exists(key)? => Ok(Reply::error(WRONGTYPE)),
+            None => Ok(Reply::Null),
+        }
+    }
+
+    // SET key value [NX | XX] [EX seconds | PX milliseconds]
+    fn set(&self, args: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let (key, value) = (&args[0], &args[1]);
+        let (mut nx, mut xx, mut expire_at) = (false, false, 0);
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" if !xx => nx = true,
+                b"XX" if !nx => xx = true,
+                unit @ (b"EX" | b"PX") if expire_at == 0 => {
+                    let ttl = match options.next().and_then(|v| parse_i64(v)) {
+                        Some(ttl) => ttl,
+                        None => return Ok(Reply::error(NOT_INTEGER)),
+                    };
+                    let ttl_ms = if unit == b"EX" {
+                        ttl.checked_mul(1000)
+                    } else {
+                        Some(ttl)
+                    };
+                    match ttl_ms.filter(|ms| *ms > 0) {
+                        Some(ms) => expire_at = now_ms().saturating_add(ms as u64),
+                        None => {
+                            return Ok(Reply::error("ERR invalid expire time in 'set' command"))
+                        }
+                    }
+                }
+                _ => return Ok(Reply::error(SYNTAX_ERROR)),
+            }
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        if nx || xx {
+            let exists =
+                self.get_string(ReadOptions::default(), key)?.is_some() || self.hash_exists(key)?;
+            if exists == nx {
+                return Ok(Reply::Null);
+            }
+        }
+        let mut batch = WriteBatch::default();
+        self.delete_hash(&mut batch, key)?;
+        batch.put(&string_key(key), &encode_string(value, expire_at));
+        self.db.write(WriteOptions::default(), batch)?;
+        Ok(Reply::ok())
+    }
+
+    fn del(&self, keys: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+        for key in keys {
+            let string_key = string_key(key);
+            if let Some(raw) = self.db.get(ReadOptions::default(), &string_key)? {
+                batch.delete(&string_key);
+                if decode_string(raw).is_some() {
+                    deleted += 1;
+                }
+            }
+            if self.delete_hash(&mut batch, key)? {
+                deleted += 1;
+            }
+        }
+        self.db.write(WriteOptions::default(), batch)?;
+        Ok(Reply::Integer(deleted))
+    }
+
+    fn mget(&self, keys: &[Vec<u8>]) -> TemplateResult<Reply> {
+        // Read all the keys from the same view
+        let snapshot = self.db.snapshot();
+        let options = ReadOptions {
+            snapshot: Some(*snapshot),
+            ..Default::default()
+        };
+        let values = keys
+            .iter()
+            .map(|key| {
+                self.get_string(options, key)
+                    .map(|v| v.map_or(Reply::Null, |(value, _)| Reply::Bulk(value)))
+            })
+            .collect::<TemplateResult<Vec<_>>>();
+        let _ = self.db.release_snapshot(snapshot);
+        Ok(Reply::Array(values?))
+    }
+
+    fn mset(&self, args: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let _guard = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        for pair in args.chunks(2) {
+            self.delete_hash(&mut batch, &pair[0])?;
+            batch.put(&string_key(&pair[0]), &encode_string(&pair[1], 0));
+        }
+        self.db.write(WriteOptions::default(), batch)?;
+        Ok(Reply::ok())
+    }
+
+    // Adds `delta` to the integer at `key` keeping its TTL
+    fn incr_by(&self, key: &[u8], delta: Option<i64>) -> TemplateResult<Reply> {
+        let delta = match delta {
+            Some(delta) => delta,
+            None => return Ok(Reply::error(NOT_INTEGER)),
+        };
+        let _guard = self.write_lock.lock().unwrap();
+        let (current, expire_at) = match self.get_string(ReadOptions::default(), key)? {
+            Some((value, expire_at)) => match parse_i64(&value) {
+                Some(v) => (v, expire_at),
+                None => return Ok(Reply::error(NOT_INTEGER)),
+            },
+            None if self.hash_exists(key)? => return Ok(Reply::error(WRONGTYPE)),
+            None => (0, 0),
+        };
+        let value = match current.checked_add(delta) {
+            Some(v) => v,
+            None => return Ok(Reply::error("ERR increment or decrement would overflow")),
+        };
+        self.db.put(
+            WriteOptions::default(),
+            &string_key(key),
+            &encode_string(value.to_string().as_bytes(), expire_at),
+        )?;
+        Ok(Reply::Integer(value))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    //
+    // A cursor stands for the db key to resume from, see `ScanCursors`.
+    fn scan(&self, args: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let cursor = match std::str::from_utf8(&args[0]).map(str::parse::<u64>) {
+            Ok(Ok(cursor)) => cursor,
+            _ => return Ok(Reply::error("ERR invalid cursor")),
+        };
+        let start = (cursor != 0).then(|| self.scan_cursors.lock().unwrap().get(cursor));
+        let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_ascii_uppercase().as_slice(), options.next()) {
+                (b"MATCH", Some(p)) => pattern = Some(p),
+                (b"COUNT", Some(c)) => match parse_i64(c).filter(|c| *c > 0) {
+                    Some(c) => count = c as usize,
+                    None => return Ok(Reply::error(SYNTAX_ERROR)),
+                },
+                _ => return Ok(Reply::error(SYNTAX_ERROR)),
+            }
+        }
+        let now = now_ms();
+        let mut iter = self.db.iter(ReadOptions::default())?;
+        match &start {
+            Some(key) => iter.seek(key),
+            None => iter.seek_to_first(),
+        }
+        let mut keys = vec![];
+        let mut examined = 0;
+        while iter.valid() && examined < count {
+            examined += 1;
+            let db_key = iter.key();
+            let key = match db_key.first() {
+                Some(&STRING_TAG) => {
+                    let key = db_key[1..].to_vec();
+                    let live = iter.value().len() >= 8 && !is_expired(iter.value(), now);
+                    iter.next();
+                    live.then_some(key)
+                }
+                Some(&HASH_TAG) => match split_hash_key(db_key) {
+                    Some((key, _)) => {
+                        let key = key.to_vec();
+                        // Skip the rest fields of the hash
+                        match prefix_successor(&hash_prefix(&key)) {
+                            Some(next) => iter.seek(&next),
+                            None => break,
+                        }
+                        Some(key)
+                    }
+                    None => {
+                        iter.next();
+                        None
+                    }
+                },
+                _ => {
+                    iter.next();
+                    None
+                }
+            };
+            if let Some(key) = key {
+                if pattern.map_or(true, |p| glob_match(p, &key)) {
+                    keys.push(Reply::Bulk(key));
+                }
+            }
+        }
+        iter.status()?;
+        let cursor = if iter.valid() {
+            self.scan_cursors.lock().unwrap().insert(iter.key())
+        } else {
+            0
+        };
+        Ok(Reply::Array(vec![
+            Reply::Bulk(cursor.to_string().into_bytes()),
+            Reply::Array(keys),
+        ]))
+    }
+
+    fn hset(&self, args: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let key = &args[0];
+        let _guard = self.write_lock.lock().unwrap();
+        if self.get_string(ReadOptions::default(), key)?.is_some() {
+            return Ok(Reply::error(WRONGTYPE));
+        }
+        let mut batch = WriteBatch::default();
+        let mut added = 0;
+        for pair in args[1..].chunks(2) {
+            if self.hash_field(key, &pair[0])?.is_none() {
+                added += 1;
+            }
+            batch.put(&hash_field_key(key, &pair[0]), &pair[1]);
+        }
+        // An expired string at the key is replaced
+        batch.delete(&string_key(key));
+        self.db.write(WriteOptions::default(), batch)?;
+        Ok(Reply::Integer(added))
+    }
+
+    fn hget(&self, key: &[u8], field: &[u8]) -> TemplateResult<Reply> {
+        match self.hash_field(key, field)? {
+            Some(value) => Ok(Reply::Bulk(value)),
+            None if self.get_string(ReadOptions::default(), key)?.is_some() => {
+                Ok(Reply::error(WRONGTYPE))
+            }
+            None => Ok(Reply::Null),
+        }
+    }
+
+    fn hdel(&self, args: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let key = &args[0];
+        let _guard = self.write_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+        for field in &args[1..] {
+            if self.hash_field(key, field)?.is_some() {
+                deleted += 1;
+                batch.delete(&hash_field_key(key, field));
+            }
+        }
+        self.db.write(WriteOptions::default(), batch)?;
+        Ok(Reply::Integer(deleted))
+    }
+
+    fn hgetall(&self, key: &[u8]) -> TemplateResult<Reply> {
+        let fields = self.hash_fields(key)?;
+        if fields.is_empty() && self.get_string(ReadOptions::default(), key)?.is_some() {
+            return Ok(Reply::error(WRONGTYPE));
+        }
+        Ok(Reply::Map(
+            fields
+                .into_iter()
+                .map(|(f, v)| (Reply::Bulk(f), Reply::Bulk(v)))
+                .collect(),
+        ))
+    }
+
+    fn hmget(&self, args: &[Vec<u8>]) -> TemplateResult<Reply> {
+        let values = args[1..]
+            .iter()
+            .map(|field| {
+                self.hash_field(&args[0], field)
+                    .map(|v| v.map_or(Reply::Null, Reply::Bulk))
+            })
+            .collect::<TemplateResult<Vec<_>>>()?;
+        Ok(Reply::Array(values))
+    }
+
+    // Returns the live string at `key` and its expire time
+    fn get_string(
+        &self,
+        options: ReadOptions,
+        key: &[u8],
+    ) -> TemplateResult<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .db
+            .get(options, &string_key(key))?
+            .and_then(decode_string))
+    }
+
+    fn hash_field(&self, key: &[u8], field: &[u8]) -> TemplateResult<Option<Vec<u8>>> {
+        self.db
+            .get(ReadOptions::default(), &hash_field_key(key, field))
+    }
+
+    fn hash_exists(&self, key: &[u8]) -> TemplateResult<bool> {
+        let prefix = hash_prefix(key);
+        let mut iter = self.db.iter(ReadOptions::default())?;
+        iter.seek(&prefix);
+        let exists = iter.valid() && iter.key().starts_with(&prefix);
+        iter.status()?;
+        Ok(exists)
+    }
+
+    // Returns all the fields and values of the hash at `key` in the order of the fields
+    fn hash_fields(&self, key: &[u8]) -> TemplateResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = hash_prefix(key);
+        let mut iter = self.db.iter(ReadOptions::default())?;
+        iter.seek(&prefix);
+        let mut fields = vec![];
+        while iter.valid() && iter.key().starts_with(&prefix) {
+            fields.push((iter.key()[prefix.len()..].to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        iter.status()?;
+        Ok(fields)
+    }
+
+    // Adds the deletions of all the fields of the hash at `key` into `batch`.
+    // Returns false if there is no such hash.
+    fn delete_hash(&self, batch: &mut WriteBatch, key: &[u8]) -> TemplateResult<bool> {
+        let fields = self.hash_fields(key)?;
+        for (field, _) in &fields {
+            batch.delete(&hash_field_key(key, field));
+        }
+        Ok(!fields.is_empty())
+    }
+}
+
+fn hello(session: &mut Session, args: &[Vec<u8>]) -> Reply {
+    if let Some(version) = args.first() {
+        match parse_i64(version) {
+            Some(v @ (2 | 3)) => session.protocol = v as u8,
+            _ => return Reply::error("NOPROTO unsupported protocol version"),
+        }
+    }
+    let field = |name: &str| Reply::Bulk(name.as_bytes().to_vec());
+    Reply::Map(vec![
+        (field("server"), field("redis")),
+        (field("version"), field("7.0.0")),
+        (field("proto"), Reply::Integer(i64::from(session.protocol))),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), Reply::Array(vec![])),
+    ])
+}
+
+fn parse_i64(v: &[u8]) -> Option<i64> {
+    std::str::from_utf8(v).ok()?.parse().ok()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn string_key(key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 1);
+    k.push(STRING_TAG);
+    k.extend_from_slice(key);
+    k
+}
+
+fn encode_string(value: &[u8], expire_at: u64) -> Vec<u8> {
+    let mut v = Vec::with_capacity(value.len() + 8);
+    put_fixed_64(&mut v, expire_at);
+    v.extend_from_slice(value);
+    v
+}
+
+// Returns the value and the expire time of a string unless it has expired
+fn decode_string(mut raw: Vec<u8>) -> Option<(Vec<u8>, u64)> {
+    if raw.len() < 8 || is_expired(&raw, now_ms()) {
+        return None;
+    }
+    let expire_at = decode_fixed_64(&raw);
+    raw.drain(..8);
+    Some((raw, expire_at))
+}
+
+// Returns true if the encoded string `raw` has expired by `now`
+fn is_expired(raw: &[u8], now: u64) -> bool {
+    match raw.get(..8) {
+        Some(expire_at) => {
+            let expire_at = decode_fixed_64(expire_at);
+            expire_at != 0 && expire_at < now
+        }
+        None => false,
+    }
+}
+
+// The db keys the SCANs resume from, which are mostly too long to be put in a cursor.
+// A cursor keeps the first 4 bytes of the key in its high half and an id given by the
+// handler in its low half. Only the last `MAX_SCAN_CURSORS` ids are remembered, so a
+// cursor forgotten or handed out by another handler resumes from the prefix instead, which
+// might return some keys again as SCAN allows.
+#[derive(Default)]
+struct ScanCursors {
+    keys: HashMap<u32, Vec<u8>>,
+    // The ids in the order given
+    ids: VecDeque<u32>,
+    last_id: u32,
+}
+
+impl ScanCursors {
+    // Returns the cursor resuming from `db_key`, which is never 0
+    fn insert(&mut self, db_key: &[u8]) -> u64 {
+        self.last_id = self.last_id.checked_add(1).unwrap_or(1);
+        let id = self.last_id;
+        if self.ids.len() == MAX_SCAN_CURSORS {
+            let oldest = self.ids.pop_front().unwrap();
+            self.keys.remove(&oldest);
+        }
+        self.ids.push_back(id);
+        self.keys.insert(id, db_key.to_vec());
+        u64::from(cursor_prefix(db_key)) << 32 | u64::from(id)
+    }
+
+    // Returns the db key to resume from for a cursor other than 0
+    fn get(&self, cursor: u64) -> Vec<u8> {
+        let prefix = (cursor >> 32) as u32;
+        match self.keys.get(&(cursor as u32)) {
+            Some(key) if cursor_prefix(key) == prefix => key.clone(),
+            _ => {
+                // No key sorts before the prefix without its padding
+                let mut key = prefix.to_be_bytes().to_vec();
+                while key.last() == Some(&0) {
+                    key.pop();
+                }
+                key
+            }
+        }
+    }
+}
+
+// The first 4 bytes of `db_key` padded with zeros
+fn cursor_prefix(db_key: &[u8]) -> u32 {
+    let mut prefix = [0; 4];
+    let n = db_key.len().min(4);
+    prefix[..n].copy_from_slice(&db_key[..n]);
+    u32::from_be_bytes(prefix)
+}
+
+fn hash_prefix(key: &[u8]) -> Vec<u8> {
+    let mut k = Vec::with_capacity(key.len() + 5);
+    k.push(HASH_TAG);
+    k.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    k.extend_from_slice(key);
+    k
+}
+
+fn hash_field_key(key: &[u8], field: &[u8]) -> Vec<u8> {
+    let mut k = hash_prefix(key);
+    k.extend_from_slice(field);
+    k
+}
+
+// Splits a hash field key into the key and the field
+fn split_hash_key(db_key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(db_key.get(1..5)?.try_into().ok()?) as usize;
+    let rest = &db_key[5..];
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+// Returns the smallest key greater than all the keys with `prefix`
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut k = prefix.to_vec();
+    while let Some(last) = k.pop() {
+        if last < u8::MAX {
+            k.push(last + 1);
+            return Some(k);
+        }
+    }
+    None
+}
+
+// Matches `s` against a glob-style pattern supporting `*`, `?`, `[...]` and `\`
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The positions to retry from after the last `*`
+    let mut backtrack: Option<(usize, usize)> = None;
+    while i < s.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, s[i]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(p + 2),
+            Some(c) => (*c == s[i]).then_some(p + 1),
+            None => None,
+        };
+        match (matched, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                i += 1;
+            }
+            (None, Some((star, from))) => {
+                // Let the `*` take one more byte
+                backtrack = Some((star, from + 1));
+                p = star + 1;
+                i = from + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|c| *c == b'*')
+}
+
+// Matches `c` against the class starting at `pattern[start]`, which is `[`.
+// Returns the position after the class if matched.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    loop {
+        match pattern.get(p) {
+            // An unclosed class matches till the end
+            None => break,
+            Some(b']') => {
+                p += 1;
+                break;
+            }
+            Some(b'\\') if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == c;
+                p += 2;
+            }
+            Some(&lo) if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() => {
+                let hi = pattern[p + 2];
+                let (lo, hi) = if lo <= hi { (lo, hi) } else { (hi, lo) };
+                matched |= (lo..=hi).contains(&c);
+                p += 3;
+            }
+            Some(&x) => {
+                matched |= x == c;
+                p += 1;
+            }
+        }
+    }
+    (matched != negate).then_some(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{options::Options, storage::mem::MemStorage, util::comparator::BytewiseComparator};
+
+    fn new_handler() -> RedisHandler<MemStorage, BytewiseComparator> {
+        let db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "redis_service_test",
+            MemStorage::default(),
+        )
+        .unwrap();
+        RedisHandler::new(db)
+    }
+
+    fn exec(h: &RedisHandler<MemStorage, BytewiseComparator>, cmd: &str) -> Reply {
+        let args: Vec<Vec<u8>> = cmd.split(' ').map(|a| a.as_bytes().to_vec()).collect();
+        h.execute(&mut Session::default(), &args)
+    }
+
+    fn bulk(v: &str) -> Reply {
+        Reply::Bulk(v.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("user:*", "user:1", true),
+            ("user:*", "users", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*o*d", "hello world", true),
+            ("h*o*d", "hello worlds", false),
+            ("h[ae]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+        ];
+        for (pattern, s, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), s.as_bytes()),
+                *expected,
+                "{} {}",
+                pattern,
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn test_string_commands() {
+        let h = new_handler();
+        assert_eq!(exec(&h, "GET k"), Reply::Null);
+        assert_eq!(exec(&h, "SET k v"), Reply::ok());
+        assert_eq!(exec(&h, "get k"), bulk("v"));
+        assert_eq!(exec(&h, "SET k v2 NX"), Reply::Null);
+        assert_eq!(exec(&h, "SET k2 v2 XX"), Reply::Null);
+        assert_eq!(exec(&h, "MSET k1 1 k2 2"), Reply::ok());
+        assert_eq!(
+            exec(&h, "MGET k k1 missing k2"),
+            Reply::Array(vec![bulk("v"), bulk("1"), Reply::Null, bulk("2")])
+        );
+        assert_eq!(exec(&h, "DEL k k1 missing"), Reply::Integer(2));
+        assert_eq!(exec(&h, "GET k"), Reply::Null);
+
+        assert_eq!(exec(&h, "INCR counter"), Reply::Integer(1));
+        assert_eq!(exec(&h, "INCRBY counter 10"), Reply::Integer(11));
+        assert_eq!(exec(&h, "DECR counter"), Reply::Integer(10));
+        assert_eq!(exec(&h, "INCR k2"), Reply::Integer(3));
+        assert_eq!(exec(&h, "SET k v"), Reply::ok());
+        assert_eq!(exec(&h, "INCR k"), Reply::error(NOT_INTEGER));
+        assert_eq!(exec(&h, &format!("SET big {}", i64::MAX)), Reply::ok());
+        assert!(matches!(exec(&h, "INCR big"), Reply::Error(_)));
+
+        // Expire
+        assert_eq!(exec(&h, "SET temp v PX 50"), Reply::ok());
+        assert_eq!(exec(&h, "SET temp2 v EX 100"), Reply::ok());
+        assert_eq!(exec(&h, "GET temp"), bulk("v"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(exec(&h, "GET temp"), Reply::Null);
+        assert_eq!(exec(&h, "GET temp2"), bulk("v"));
+        assert_eq!(exec(&h, "SET temp v NX"), Reply::ok());
+        assert!(matches!(exec(&h, "SET k v EX 0"), Reply::Error(_)));
+        assert_eq!(exec(&h, "SET k v EX"), Reply::error(NOT_INTEGER));
+        assert_eq!(exec(&h, "SET k v FOO"), Reply::error(SYNTAX_ERROR));
+        assert!(matches!(exec(&h, "GET"), Reply::Error(_)));
+        assert!(matches!(exec(&h, "FOO"), Reply::Error(_)));
+    }
+
+    #[test]
+    fn test_hash_commands() {
+        let h = new_handler();
+        assert_eq!(exec(&h, "HSET user name alice age 3"), Reply::Integer(2));
+        assert_eq!(exec(&h, "HSET user age 4 city x"), Reply::Integer(1));
+        assert_eq!(exec(&h, "HGET user age"), bulk("4"));
+        assert_eq!(exec(&h, "HGET user missing"), Reply::Null);
+        assert_eq!(exec(&h, "HLEN user"), Reply::Integer(3));
+        assert_eq!(exec(&h, "HEXISTS user city"), Reply::Integer(1));
+        assert_eq!(
+            exec(&h, "HMGET user name missing"),
+            Reply::Array(vec![bulk("alice"), Reply::Null])
+        );
+        assert_eq!(
+            exec(&h, "HGETALL user"),
+            Reply::Map(vec![
+                (bulk("age"), bulk("4")),
+                (bulk("city"), bulk("x")),
+                (bulk("name"), bulk("alice")),
+            ])
+        );
+        // A hash key never collides with a longer hash key
+        assert_eq!(exec(&h, "HSET user2 name bob"), Reply::Integer(1));
+        assert_eq!(exec(&h, "HLEN user"), Reply::Integer(3));
+
+        assert_eq!(exec(&h, "GET user"), Reply::error(WRONGTYPE));
+        assert_eq!(exec(&h, "SET s v"), Reply::ok());
+        assert_eq!(exec(&h, "HSET s f v"), Reply::error(WRONGTYPE));
+        assert_eq!(exec(&h, "HDEL user city missing"), Reply::Integer(1));
+        assert_eq!(exec(&h, "DEL user"), Reply::Integer(1));
+        assert_eq!(exec(&h, "HGETALL user"), Reply::Map(vec![]));
+        // SET replaces a hash
+        assert_eq!(exec(&h, "SET user2 v"), Reply::ok());
+        assert_eq!(exec(&h, "HLEN user2"), Reply::Integer(0));
+        assert_eq!(exec(&h, "GET user2"), bulk("v"));
+    }
+
+    #[test]
+    fn test_scan() {
+        let h = new_handler();
+        for i in 0..25 {
+            exec(&h, &format!("SET key:{:02} v", i));
+        }
+        exec(&h, "HSET hash:1 a 1 b 2 c 3");
+        exec(&h, "SET other v");
+
+        let mut cursor = "0".to_owned();
+        let mut keys = vec![];
+        loop {
+            let reply = exec(&h, &format!("SCAN {} MATCH key:* COUNT 7", cursor));
+            let (next, batch) = match reply {
+                Reply::Array(mut items) => (items.remove(0), items.remove(0)),
+                other => panic!("unexpected {:?}", other),
+            };
+            match batch {
+                Reply::Array(batch) => {
+                    assert!(batch.len() <= 7);
+                    keys.extend(batch);
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+            cursor = match next {
+                Reply::Bulk(c) => String::from_utf8(c).unwrap(),
+                other => panic!("unexpected {:?}", other),
+            };
+            if cursor == "0" {
+                break;
+            }
+        }
+        let expected: Vec<_> = (0..25).map(|i| bulk(&format!("key:{:02}", i))).collect();
+        assert_eq!(keys, expected);
+
+        // The hash is listed once
+        assert_eq!(
+            exec(&h, "SCAN 0 COUNT 100"),
+            Reply::Array(vec![
+                bulk("0"),
+                Reply::Array(
+                    std::iter::once(bulk("hash:1"))
+                        .chain(expected)
+                        .chain(std::iter::once(bulk("other")))
+                        .collect()
+                )
+            ])
+        );
+        assert_eq!(exec(&h, "SCAN -1"), Reply::error("ERR invalid cursor"));
+        assert_eq!(
+            exec(&h, "SCAN 18446744073709551616"),
+            Reply::error("ERR invalid cursor")
+        );
+        assert_eq!(exec(&h, "SCAN abc"), Reply::error("ERR invalid cursor"));
+
+        // A string too short to hold the expire time is skipped
+        h.db.put(WriteOptions::default(), &string_key(b"bad"), b"v")
+            .unwrap();
+        let reply = exec(&h, "SCAN 0 MATCH bad COUNT 100");
+        assert_eq!(reply, Reply::Array(vec![bulk("0"), Reply::Array(vec![])]));
+    }
+
+    #[test]
+    fn test_scan_cursor() {
+        let mut cursors = ScanCursors::default();
+        let long_key = b"skey:with a long name".to_vec();
+        let cursor = cursors.insert(&long_key);
+        assert_ne!(cursor, 0);
+        assert_eq!(cursors.get(cursor), long_key);
+        assert_eq!(cursors.get(cursors.insert(b"s")), b"s");
+        // A forgotten cursor resumes from the prefix of its key
+        for _ in 0..MAX_SCAN_CURSORS {
+            cursors.insert(b"hother");
+        }
+        assert_eq!(cursors.ids.len(), MAX_SCAN_CURSORS);
+        assert_eq!(cursors.get(cursor), b"skey");
+
+        // A cursor is not bound to the handler handing it out
+        let h = new_handler();
+        for i in 0..3 {
+            exec(&h, &format!("SET key:{} v", i));
+        }
+        let next = match exec(&h, "SCAN 0 COUNT 1") {
+            Reply::Array(items) => items[0].clone(),
+            other => panic!("unexpected {:?}", other),
+        };
+        let cursor = match next {
+            Reply::Bulk(c) => String::from_utf8(c).unwrap(),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(cursor.parse::<u64>().is_ok());
+        // The keys sharing the prefix are returned again
+        let other = RedisHandler::new(h.db.clone());
+        assert_eq!(
+            exec(&other, &format!("SCAN {} COUNT 100", cursor)),
+            Reply::Array(vec![
+                bulk("0"),
+                Reply::Array(vec![bulk("key:0"), bulk("key:1"), bulk("key:2")])
+            ])
+        );
+        assert_eq!(
+            exec(&h, &format!("SCAN {} COUNT 100", cursor)),
+            Reply::Array(vec![
+                bulk("0"),
+                Reply::Array(vec![bulk("key:1"), bulk("key:2")])
+            ])
+        );
+    }
+
+    #[test]
+    fn test_reclaim_expired() {
+        let h = new_handler();
+        for i in 0..5 {
+            exec(&h, &format!("SET temp:{} v PX 50", i));
+            exec(&h, &format!("SET keep:{} v", i));
+        }
+        exec(&h, "HSET hash f v");
+        thread::sleep(Duration::from_millis(100));
+        let raw = |key: &str| {
+            h.db.get(ReadOptions::default(), &string_key(key.as_bytes()))
+                .unwrap()
+        };
+        assert!(raw("temp:0").is_some());
+        // The strings are reclaimed a few at a time
+        let mut deleted = 0;
+        for _ in 0..4 {
+            let n = h.reclaim_expired(3).unwrap();
+            assert!(n <= 3);
+            deleted += n;
+        }
+        assert_eq!(deleted, 5);
+        for i in 0..5 {
+            assert!(raw(&format!("temp:{}", i)).is_none());
+            assert!(raw(&format!("keep:{}", i)).is_some());
+        }
+        assert_eq!(exec(&h, "HLEN hash"), Reply::Integer(1));
+        // Starts over after the last string
+        exec(&h, "SET temp:0 v PX 1");
+        thread::sleep(Duration::from_millis(10));
+        let deleted: usize = (0..4).map(|_| h.reclaim_expired(3).unwrap()).sum();
+        assert_eq!(deleted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_serve_connection() {
+        let h = new_handler();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            h.serve_connection(stream).await.unwrap();
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        // Pipelined commands in both the array and the inline formats
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\nGET k\r\nHELLO 3\r\nGET none\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        let mut resp = vec![];
+        client.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with("+OK\r\n$1\r\nv\r\n%6\r\n"), "{}", resp);
+        assert!(resp.ends_with("_\r\n+OK\r\n"), "{}", resp);
+    }
+}
@@ -0,0 +1,206 @@
+use bytes::{Buf, BytesMut};
+
+// The limits protecting the server from a malformed request
+const MAX_ARGS: i64 = 1024 * 1024;
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// A reply in the Redis serialization protocol (RESP)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    /// The message starts with an error code like `ERR`
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    /// A flat array of the keys and the values in RESP2
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    pub fn ok() -> Self {
+        Reply::Simple("OK".to_owned())
+    }
+
+    pub fn error(msg: impl Into<String>) -> Self {
+        Reply::Error(msg.into())
+    }
+
+    /// Appends the reply to `out` in the protocol version `protocol`, 2 or 3
+    pub fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => encode_line(out, b'+', s.as_bytes()),
+            Reply::Error(s) => encode_line(out, b'-', s.as_bytes()),
+            Reply::Integer(i) => encode_line(out, b':', i.to_string().as_bytes()),
+            Reply::Bulk(b) => {
+                encode_line(out, b'$', b.len().to_string().as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null => {
+                if protocol >= 3 {
+                    out.extend_from_slice(b"_\r\n");
+                } else {
+                    out.extend_from_slice(b"$-1\r\n");
+                }
+            }
+            Reply::Array(items) => {
+                encode_line(out, b'*', items.len().to_string().as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            Reply::Map(pairs) => {
+                if protocol >= 3 {
+                    encode_line(out, b'%', pairs.len().to_string().as_bytes());
+                } else {
+                    encode_line(out, b'*', (pairs.len() * 2).to_string().as_bytes());
+                }
+                for (k, v) in pairs {
+                    k.encode(protocol, out);
+                    v.encode(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+fn encode_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(line);
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Takes a command from the front of `buf`, either an array of bulk strings or an inline
+/// command. Returns `Ok(None)` if the command is not complete yet, or an error if `buf`
+/// does not hold a valid command.
+pub fn parse_command(buf: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>, String> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        let end = match buf.iter().position(|b| *b == b'\n') {
+            Some(end) => end,
+            None if buf.len() > MAX_INLINE_LEN => return Err("too big inline request".to_owned()),
+            None => return Ok(None),
+        };
+        let line = buf.split_to(end + 1);
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+    let (n, mut pos) = match read_int_line(buf, 1)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if !(0..=MAX_ARGS).contains(&n) {
+        return Err("invalid multibulk length".to_owned());
+    }
+    let mut args = Vec::with_capacity(n as usize);
+    for _ in 0..n {
+        if pos >= buf.len() {
+            return Ok(None);
+        }
+        if buf[pos] != b'$' {
+            return Err(format!("expected '$', got '{}'", buf[pos] as char));
+        }
+        let (len, start) = match read_int_line(buf, pos + 1)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if !(0..=MAX_BULK_LEN).contains(&len) {
+            return Err("invalid bulk length".to_owned());
+        }
+        let end = start + len as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err("bulk string not terminated by CRLF".to_owned());
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    buf.advance(pos);
+    Ok(Some(args))
+}
+
+// Reads the integer in the line starting at `pos`.
+// Returns the integer and the position after the line.
+fn read_int_line(buf: &[u8], pos: usize) -> Result<Option<(i64, usize)>, String> {
+    let end = match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+        Some(end) => pos + end,
+        None => return Ok(None),
+    };
+    std::str::from_utf8(&buf[pos..end])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(|n| Some((n, end + 2)))
+        .ok_or_else(|| "invalid length".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPI"[..]);
+        assert_eq!(
+            parse_command(&mut buf).unwrap(),
+            Some(vec![b"GET".to_vec(), b"k".to_vec()])
+        );
+        // Incomplete
+        assert_eq!(parse_command(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"NG\r\nset  k v\r\n");
+        assert_eq!(
+            parse_command(&mut buf).unwrap(),
+            Some(vec![b"PING".to_vec()])
+        );
+        assert_eq!(
+            parse_command(&mut buf).unwrap(),
+            Some(vec![b"set".to_vec(), b"k".to_vec(), b"v".to_vec()])
+        );
+        assert!(buf.is_empty());
+
+        // Binary safe
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\na\r\nb\r\n"[..]);
+        assert_eq!(
+            parse_command(&mut buf).unwrap(),
+            Some(vec![b"a\r\nb".to_vec()])
+        );
+
+        for invalid in [&b"*1\r\n:1\r\n"[..], b"*x\r\n", b"*1\r\n$1\r\nab\r\n"] {
+            assert!(parse_command(&mut BytesMut::from(invalid)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode_reply() {
+        let reply = Reply::Array(vec![
+            Reply::ok(),
+            Reply::error("ERR bad"),
+            Reply::Integer(-1),
+            Reply::Bulk(b"v".to_vec()),
+            Reply::Null,
+            Reply::Map(vec![(Reply::Bulk(b"f".to_vec()), Reply::Integer(1))]),
+        ]);
+        let mut resp2 = vec![];
+        reply.encode(2, &mut resp2);
+        assert_eq!(
+            resp2,
+            b"*6\r\n+OK\r\n-ERR bad\r\n:-1\r\n$1\r\nv\r\n$-1\r\n*2\r\n$1\r\nf\r\n:1\r\n".to_vec()
+        );
+        let mut resp3 = vec![];
+        reply.encode(3, &mut resp3);
+        assert_eq!(
+            resp3,
+            b"*6\r\n+OK\r\n-ERR bad\r\n:-1\r\n$1\r\nv\r\n_\r\n%1\r\n$1\r\nf\r\n:1\r\n".to_vec()
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use tonic::Status;
+
+use crate::{
+    memtable::{
+        inlineskiplist::MAX_NODE_SIZE, key_format::InternalKeyComparator, memtable::MemTable,
+    },
+    memtable_service::{TenantInfo, TenantQuota, TenantStats},
+    options::{INTERNAL_KEY_TAIL, MAX_KEY_SEQUENCE},
+    util::comparator::Comparator,
+};
+
+/// The tenant serving the requests with an empty `tenant`
+pub const DEFAULT_TENANT: &str = "default";
+
+/// An isolated keyspace of `MemtableService`.
+///
+/// Every tenant owns a separate memtable, so dropping a tenant releases all of
+/// its data at once instead of deleting the keys one by one.
+pub struct Tenant<C: Comparator> {
+    name: String,
+    memtable: MemTable<C>,
+    quota: TenantQuota,
+    limiter: Option<RequestRateLimiter>,
+    stats: TenantStatistics,
+    // The bytes taken by the entries added and the ones being added. An entry is charged its
+    // largest possible size, so this never falls behind the memory used by the memtable.
+    reserved: AtomicUsize,
+    // The sequence of the latest write
+    last_seq: AtomicU64,
+}
+
+impl<C: Comparator> Tenant<C> {
+    /// Creates a tenant with an empty memtable. The memtable is sized by the storage
+    /// quota if there is one, otherwise by `default_capacity`.
+    pub fn new(
+        name: String,
+        quota: TenantQuota,
+        icmp: InternalKeyComparator<C>,
+        default_capacity: usize,
+    ) -> Self {
+        let capacity = if quota.max_bytes > 0 {
+            quota.max_bytes as usize
+        } else {
+            default_capacity
+        };
+        Self::with_memtable(name, quota, MemTable::new(capacity, icmp))
+    }
+
+    pub fn with_memtable(name: String, quota: TenantQuota, memtable: MemTable<C>) -> Self {
+        let limiter = if quota.max_requests_per_second > 0 {
+            Some(RequestRateLimiter::new(quota.max_requests_per_second))
+        } else {
+            None
+        };
+        let reserved = AtomicUsize::new(memtable.approximate_memory_usage());
+        Self {
+            name,
+            memtable,
+            quota,
+            limiter,
+            stats: TenantStatistics::default(),
+            reserved,
+            last_seq: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn memtable(&self) -> &MemTable<C> {
+        &self.memtable
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &TenantStatistics {
+        &self.stats
+    }
+
+    /// Takes a request from the request-rate quota. Returns `ResourceExhausted` if
+    /// the tenant has run out of requests for now.
+    pub fn acquire_request(&self) -> Result<(), Status> {
+        if let Some(limiter) = &self.limiter {
+            if !limiter.try_acquire() {
+                self.stats.throttled.fetch_add(1, Ordering::Relaxed);
+                return Err(Status::resource_exhausted(format!(
+                    "tenant {} exceeds {} requests per second",
+                    self.name, self.quota.max_requests_per_second
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves the space of an entry of `key` and `value` in the storage quota and
+    /// the memtable of the tenant. The space is given back if the returned reservation
+    /// is dropped without being committed, so the concurrent writers never overrun the
+    /// quota together.
+    pub fn reserve_write(
+        &self,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<WriteReservation<'_, C>, Status> {
+        // The node taken in the arena is not known until inserted, so assume the largest.
+        // The entry also holds two varint32 length prefixes.
+        let entry_size = key.len() + value.len() + INTERNAL_KEY_TAIL + 10 + MAX_NODE_SIZE;
+        let limit = if self.quota.max_bytes > 0 {
+            (self.quota.max_bytes as usize).min(self.memtable.capacity())
+        } else {
+            self.memtable.capacity()
+        };
+        self.reserved
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |reserved| {
+                (reserved + entry_size <= limit).then_some(reserved + entry_size)
+            })
+            .map_err(|reserved| {
+                self.stats.over_quota.fetch_add(1, Ordering::Relaxed);
+                Status::resource_exhausted(format!(
+                    "tenant {} exceeds the storage quota: {} bytes used of {}",
+                    self.name, reserved, limit
+                ))
+            })?;
+        Ok(WriteReservation {
+            tenant: self,
+            size: entry_size,
+            committed: false,
+        })
+    }
+
+    /// Claims `seq` for a write. The sequences of the writes of a tenant must be
+    /// strictly increasing so that a newer write always shadows an older one.
+    pub fn advance_seq(&self, seq: u64) -> Result<(), Status> {
+        if seq > MAX_KEY_SEQUENCE {
+            return Err(Status::invalid_argument(format!(
+                "sequence {} exceeds the max sequence {}",
+                seq, MAX_KEY_SEQUENCE
+            )));
+        }
+        self.last_seq
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                (seq > last).then_some(seq)
+            })
+            .map(|_| ())
+            .map_err(|last| {
+                Status::failed_precondition(format!(
+                    "sequence {} of tenant {} is not greater than the last sequence {}",
+                    seq, self.name, last
+                ))
+            })
+    }
+
+    #[inline]
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::Acquire)
+    }
+
+    /// Returns the quota and the statistics of the tenant
+    pub fn info(&self) -> TenantInfo {
+        let mut stats = self.stats.snapshot();
+        stats.used_bytes = self.memtable.approximate_memory_usage() as u64;
+        stats.num_entries = self.memtable.len() as u64;
+        stats.last_seq = self.last_seq();
+        TenantInfo {
+            tenant: self.name.clone(),
+            quota: Some(self.quota.clone()),
+            stats: Some(stats),
+        }
+    }
+}
+
+/// The space of an entry reserved by `Tenant::reserve_write`
+pub struct WriteReservation<'a, C: Comparator> {
+    tenant: &'a Tenant<C>,
+    size: usize,
+    committed: bool,
+}
+
+impl<C: Comparator> WriteReservation<'_, C> {
+    /// Keeps the space for the entry added into the memtable
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl<C: Comparator> Drop for WriteReservation<'_, C> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.tenant.reserved.fetch_sub(self.size, Ordering::AcqRel);
+        }
+    }
+}
+
+/// The counters of the requests served for a tenant
+#[derive(Default, Debug)]
+pub struct TenantStatistics {
+    pub reads: AtomicU64,
+    pub writes: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub throttled: AtomicU64,
+    pub over_quota: AtomicU64,
+}
+
+impl TenantStatistics {
+    pub fn record_read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, bytes: usize) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> TenantStats {
+        TenantStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            over_quota: self.over_quota.load(Ordering::Relaxed),
+            used_bytes: 0,
+            num_entries: 0,
+            last_seq: 0,
+        }
+    }
+}
+
+/// A token bucket allowing bursts of up to one second of requests
+struct RequestRateLimiter {
+    rate: f64,
+    // (available tokens, last refill)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RequestRateLimiter {
+    fn new(requests_per_second: u64) -> Self {
+        let rate = requests_per_second as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.rate);
+        state.1 = now;
+        if state.0 >= 1.0 {
+            state.0 -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{memtable::value_format::ValueType, util::comparator::BytewiseComparator};
+
+    fn new_tenant(quota: TenantQuota) -> Tenant<BytewiseComparator> {
+        let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+        Tenant::new("t".to_owned(), quota, icmp, 1 << 20)
+    }
+
+    #[test]
+    fn test_request_rate_quota() {
+        let tenant = new_tenant(TenantQuota {
+            max_bytes: 0,
+            max_requests_per_second: 3,
+        });
+        for _ in 0..3 {
+            tenant.acquire_request().unwrap();
+        }
+        let status = tenant.acquire_request().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(tenant.info().stats.unwrap().throttled, 1);
+    }
+
+    #[test]
+    fn test_storage_quota() {
+        let tenant = new_tenant(TenantQuota {
+            max_bytes: 4096,
+            max_requests_per_second: 0,
+        });
+        assert_eq!(tenant.memtable().capacity(), 4096);
+        let value = vec![0u8; 256];
+        let mut written = 0;
+        while let Ok(reservation) = tenant.reserve_write(b"key", &value) {
+            written += 1;
+            tenant
+                .memtable()
+                .add(written, ValueType::Value, b"key", &value);
+            reservation.commit();
+        }
+        assert!(written > 0);
+        assert!(tenant.memtable().approximate_memory_usage() <= 4096);
+        let stats = tenant.info().stats.unwrap();
+        assert_eq!(stats.over_quota, 1);
+        assert_eq!(stats.num_entries, written);
+    }
+
+    #[test]
+    fn test_storage_quota_concurrent_writes() {
+        let tenant = new_tenant(TenantQuota {
+            max_bytes: 64 << 10,
+            max_requests_per_second: 0,
+        });
+        let seq = AtomicU64::new(0);
+        let threads = 8;
+        let written: u64 = thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    s.spawn(|| {
+                        let value = vec![0u8; 100];
+                        let mut written = 0;
+                        while let Ok(reservation) = tenant.reserve_write(b"key", &value) {
+                            let seq = seq.fetch_add(1, Ordering::Relaxed) + 1;
+                            tenant.memtable().add(seq, ValueType::Value, b"key", &value);
+                            reservation.commit();
+                            written += 1;
+                        }
+                        written
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert!(written > 0);
+        assert!(tenant.memtable().approximate_memory_usage() <= 64 << 10);
+        let stats = tenant.info().stats.unwrap();
+        assert_eq!(stats.over_quota, threads);
+        assert_eq!(stats.num_entries, written);
+    }
+
+    #[test]
+    fn test_reservation_released() {
+        let tenant = new_tenant(TenantQuota {
+            max_bytes: 4096,
+            max_requests_per_second: 0,
+        });
+        let value = vec![0u8; 900];
+        let reservations: Vec<_> = (0..3)
+            .map(|_| tenant.reserve_write(b"key", &value).unwrap())
+            .collect();
+        assert!(tenant.reserve_write(b"key", &value).is_err());
+        // The writes failed without adding the entries give back their space
+        drop(reservations);
+        for _ in 0..3 {
+            tenant.reserve_write(b"key", &value).unwrap().commit();
+        }
+        assert!(tenant.reserve_write(b"key", &value).is_err());
+    }
+
+    #[test]
+    fn test_advance_seq() {
+        let tenant = new_tenant(TenantQuota::default());
+        tenant.advance_seq(1).unwrap();
+        tenant.advance_seq(5).unwrap();
+        for seq in [0, 1, 5] {
+            let status = tenant.advance_seq(seq).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        }
+        let status = tenant.advance_seq(MAX_KEY_SEQUENCE + 1).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(tenant.last_seq(), 5);
+    }
+}
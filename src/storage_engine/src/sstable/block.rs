@@ -58,6 +58,34 @@ impl Block {
         ))
     }
 
+    /// Wraps contents not in the block format, like a filter block, so that they could be kept
+    /// in the block cache. Such a block has no entry to iterate and only `data` makes sense.
+    pub(crate) fn new_raw(data: Vec<u8>) -> Self {
+        Self {
+            restart_offset: data.len() as u32,
+            data: Arc::new(data),
+            restarts_len: 0,
+        }
+    }
+
+    /// Returns true if the block is made by `new_raw`
+    pub(crate) fn is_raw(&self) -> bool {
+        // The restart array of a block in the format takes 4 bytes at least
+        self.restart_offset as usize == self.data.len()
+    }
+
+    /// Returns the size of the block contents in bytes
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns the raw block contents
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
     /// Create a BlockIterator for current block.
     pub fn iter<C: Comparator>(&self, cmp: C) -> BlockIterator<C> {
         BlockIterator::new(
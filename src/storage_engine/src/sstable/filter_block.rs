@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use crate::{
     cache::FilterPolicy,
+    sstable::block::Block,
     util::coding::{decode_fixed_32, put_fixed_32},
 };
 
@@ -97,35 +98,44 @@ impl FilterBlockBuilder {
 
 pub struct FilterBlockReader {
     policy: Arc<dyn FilterPolicy>,
-    // all filter block data without filter meta
+    // the whole filter block, shared with the block cache
+    block: Arc<Block>,
+    // the length of all filter block data without filter meta
     // | ----- filter data ----- | ----- filter offsets ----|
     //                                   num * 4 bytes
-    data: Vec<u8>,
+    len: usize,
     // the amount of filter data
     num: usize,
     base_lg: usize,
 }
 
 impl FilterBlockReader {
-    pub fn new(policy: Arc<dyn FilterPolicy>, mut filter_block: Vec<u8>) -> Self {
+    pub fn new(policy: Arc<dyn FilterPolicy>, filter_block: Vec<u8>) -> Self {
+        Self::from_block(policy, Arc::new(Block::new_raw(filter_block)))
+    }
+
+    /// Creates a reader over a filter block wrapped by `Block::new_raw`
+    pub fn from_block(policy: Arc<dyn FilterPolicy>, block: Arc<Block>) -> Self {
         let mut r = FilterBlockReader {
             policy,
-            data: vec![],
+            block,
+            len: 0,
             num: 0,
             base_lg: 0,
         };
+        let filter_block = r.block.data();
         let n = filter_block.len();
         if n < FILTER_META_LENGTH {
             return r;
         }
-        r.num = decode_fixed_32(&filter_block[n - FILTER_META_LENGTH..n - 1]) as usize;
+        let num = decode_fixed_32(&filter_block[n - FILTER_META_LENGTH..n - 1]) as usize;
         // invalid filter offsets length
-        if r.num * FILTER_OFFSET_LEN + FILTER_META_LENGTH > n {
+        if num * FILTER_OFFSET_LEN + FILTER_META_LENGTH > n {
             return r;
         }
         r.base_lg = filter_block[n - 1] as usize;
-        filter_block.truncate(n - FILTER_META_LENGTH);
-        r.data = filter_block;
+        r.num = num;
+        r.len = n - FILTER_META_LENGTH;
         r
     }
 
@@ -133,9 +143,8 @@ impl FilterBlockReader {
     pub fn key_may_match(&self, block_offset: u64, key: &[u8]) -> bool {
         let i = block_offset as usize >> self.base_lg; // a >> b == a / (1 << b)
         if i < self.num {
-            let (filter, offsets) = &self
-                .data
-                .split_at(self.data.len() - self.num * FILTER_OFFSET_LEN);
+            let data = &self.block.data()[..self.len];
+            let (filter, offsets) = data.split_at(data.len() - self.num * FILTER_OFFSET_LEN);
             let start =
                 decode_fixed_32(&offsets[i * FILTER_OFFSET_LEN..(i + 1) * FILTER_OFFSET_LEN])
                     as usize;
@@ -149,7 +158,7 @@ impl FilterBlockReader {
                     ) as usize
                 }
             };
-            let filter = &self.data[start..end];
+            let filter = &data[start..end];
             return self.policy.may_contain(filter, key);
         }
         // errors are treated as potential matches
@@ -65,6 +65,22 @@
 ///     The magic are first 64-bit of SHA-1 sum of "http://code.google.com/p/leveldb/".
 /// ```
 ///
+/// ## Two-level index:
+///
+/// With `IndexType::TwoLevel`, the index block is split into index partitions of about
+/// `metadata_block_size` bytes. Each partition is written right after the data blocks it
+/// indexes and followed by a filter partition built from the keys of these data blocks. The
+/// index block in the footer becomes a top-level index mapping the last key of every index
+/// partition to the handle of the partition followed by the handle of its filter partition.
+/// The partitions are loaded on demand through the block cache. The layout is recorded by the
+/// `property.index_type` entry of the metaindex block.
+///
+/// ```text
+///     +-----+--------------+-----------------+------------------+-----+-----------------+-----------------+--------+
+///     | ... | data block n | index partition | filter partition | ... | metaindex block | top-level index | footer |
+///     +-----+--------------+-----------------+------------------+-----+-----------------+-----------------+--------+
+/// ```
+///
 /// NOTE: All fixed-length integer are little-endian.
 ///
 ///
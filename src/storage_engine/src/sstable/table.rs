@@ -3,26 +3,34 @@ use std::{cmp::Ordering, sync::Arc};
 use snap::raw::max_compress_len;
 
 use crate::{
-    cache::{CacheSync, FilterPolicy},
+    cache::{CachePriority, CacheSync, FilterPolicy},
     error::{TemplateKVError, TemplateResult},
     iterator::{
         concatenate_iter::{ConcatenateIterator, DerivedIterFactory},
         Iterator,
     },
-    options::{CompressionType, Options, ReadOptions},
+    options::{CompressionType, IndexType, Options, ReadOptions},
     sstable::{
         block::{Block, BlockBuilder, BlockIterator},
         filter_block::{FilterBlockBuilder, FilterBlockReader},
         BlockHandle, Footer, BLOCK_TRAILER_SIZE, FOOTER_ENCODED_LENGTH,
     },
+    statistics::Statistics,
     storage::File,
     util::{
         coding::{decode_fixed_32, put_fixed_32, put_fixed_64},
-        comparator::Comparator,
+        comparator::{BytewiseComparator, Comparator},
         crc32::{extend, hash, mask, unmask},
     },
 };
 
+// The metaindex key of the filter block
+const FILTER_PREFIX: &str = "filter.";
+// The metaindex key marking the filter partitions referenced by the top-level index
+const PARTITIONED_FILTER_PREFIX: &str = "partitioned_filter.";
+// The metaindex key of the table property recording the `IndexType`
+const INDEX_TYPE_PROPERTY: &str = "property.index_type";
+
 /// A `Table` is a sorted map from strings to strings, which must be immutable and persistent.
 /// A `Table` may be safely accessed from multiple threads
 /// without external synchronization.
@@ -30,8 +38,13 @@ pub struct Table<F: File> {
     file: F,
     file_number: u64,
     filter_reader: Option<FilterBlockReader>,
+    // The policy of the filter partitions. `None` if the table has no filter partition built
+    // by the policy in use.
+    partitioned_filter: Option<Arc<dyn FilterPolicy>>,
     meta_block_handle: Option<BlockHandle>,
-    index_block: Block,
+    index_type: IndexType,
+    // The top-level index if `index_type` is `IndexType::TwoLevel`
+    index_block: Arc<Block>,
     block_cache: Option<Arc<dyn CacheSync<Vec<u8>, Arc<Block>>>>,
 }
 
@@ -61,81 +74,165 @@ impl<F: File> Table<F> {
         )?;
         let (footer, _) = Footer::decode_from(footer_space.as_slice())?;
         // Read the index block
-        let index_block_contents =
-            read_block(&file, &footer.index_handle, options.paranoid_checks)?;
-        let index_block = Block::new(index_block_contents)?;
+        let index_block = read_meta_block_cached(
+            &file,
+            file_number,
+            &footer.index_handle,
+            &options,
+            Block::new,
+        )?;
         let mut t = Self {
             block_cache: options.block_cache.clone(),
             file,
             file_number,
             filter_reader: None,
+            partitioned_filter: None,
             meta_block_handle: None,
+            index_type: IndexType::Binary,
             index_block,
         };
         // Read meta block
-        if footer.meta_index_handle.size > 0 && options.filter_policy.is_some() {
-            // ignore the reading errors since meta info is not needed for operation
-            if let Ok(meta_block_contents) =
-                read_block(&t.file, &footer.meta_index_handle, options.paranoid_checks)
-            {
-                if let Ok(meta_block) = Block::new(meta_block_contents) {
-                    t.meta_block_handle = Some(footer.meta_index_handle);
-                    let mut iter = meta_block.iter(cmp);
-                    let filter_key = if let Some(fp) = &options.filter_policy {
-                        "filter.".to_owned() + fp.name()
-                    } else {
-                        String::from("")
-                    };
+        if footer.meta_index_handle.size > 0 {
+            // The index type is needed to read the table but the filter is not, so only the
+            // errors of reading the filter block are ignored
+            let meta_block = Block::new(read_block(
+                &t.file,
+                &footer.meta_index_handle,
+                options.paranoid_checks,
+            )?)?;
+            t.meta_block_handle = Some(footer.meta_index_handle);
+            let mut iter = meta_block.iter(cmp);
+            iter.seek_to_first();
+            while iter.valid() {
+                let key = iter.key();
+                if key == INDEX_TYPE_PROPERTY.as_bytes() {
+                    t.index_type = decode_index_type(iter.value())?;
+                } else if let Some(fp) = key
+                    .strip_prefix(FILTER_PREFIX.as_bytes())
+                    .and_then(|name| options.filter_policy_by_name(name))
+                {
                     // Read filter block
-                    iter.seek(filter_key.as_bytes());
-                    if iter.valid() && iter.key() == filter_key.as_bytes() {
-                        if let Ok((filter_handle, _)) = BlockHandle::decode_from(iter.value()) {
-                            if let Ok(filter_block) =
-                                read_block(&t.file, &filter_handle, options.paranoid_checks)
-                            {
-                                t.filter_reader = Some(FilterBlockReader::new(
-                                    options.filter_policy.clone().unwrap(),
-                                    filter_block,
-                                ));
-                            }
+                    if let Ok((filter_handle, _)) = BlockHandle::decode_from(iter.value()) {
+                        if let Ok(filter_block) = read_meta_block_cached(
+                            &t.file,
+                            file_number,
+                            &filter_handle,
+                            &options,
+                            |data| Ok(Block::new_raw(data)),
+                        ) {
+                            t.filter_reader =
+                                Some(FilterBlockReader::from_block(fp.clone(), filter_block));
                         }
                     }
+                } else if let Some(fp) = key
+                    .strip_prefix(PARTITIONED_FILTER_PREFIX.as_bytes())
+                    .and_then(|name| options.filter_policy_by_name(name))
+                {
+                    t.partitioned_filter = Some(fp.clone());
                 }
+                iter.next();
             }
+            iter.status()?;
         }
         Ok(t)
     }
 
+    /// Returns the layout of the index and the filter of this table
+    #[inline]
+    pub fn index_type(&self) -> IndexType {
+        self.index_type
+    }
+
+    /// Returns the entries of the metaindex block in order. The keys name the filter blocks
+    /// and the properties of this table.
+    pub fn meta_entries<TC: Comparator>(&self, cmp: TC) -> TemplateResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let handle = match &self.meta_block_handle {
+            Some(handle) => handle,
+            None => return Ok(vec![]),
+        };
+        let meta_block = Block::new(read_block(&self.file, handle, true)?)?;
+        let mut iter = meta_block.iter(cmp);
+        let mut entries = vec![];
+        iter.seek_to_first();
+        while iter.valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        iter.status()?;
+        Ok(entries)
+    }
+
+    // Reads the block identified by `handle` through the block cache if any. The index and
+    // filter partitions are cached with `CachePriority::High` to be retained preferentially
+    // over the data blocks.
+    fn read_block_cached(
+        &self,
+        handle: &BlockHandle,
+        options: ReadOptions,
+        priority: CachePriority,
+    ) -> TemplateResult<Arc<Block>> {
+        if let Some(cache) = &self.block_cache {
+            let cache_key_buffer = block_cache_key(self.file_number, handle);
+            if let Some(b) = cache.get(&cache_key_buffer) {
+                return Ok(b);
+            }
+            let data = read_block(&self.file, handle, options.verify_checksums)?;
+            let charge = data.len();
+            let b = Arc::new(Block::new(data)?);
+            if options.fill_cache {
+                // The block is still usable if the cache is full
+                let _ = cache.insert_with_priority(cache_key_buffer, b.clone(), charge, priority);
+            }
+            Ok(b)
+        } else {
+            let data = read_block(&self.file, handle, options.verify_checksums)?;
+            Ok(Arc::new(Block::new(data)?))
+        }
+    }
+
     // Converts an BlockHandle into an iterator over the contents of the corresponding block.
     fn block_reader<CC: Comparator>(
         &self,
         cmp: CC,
         data_block_handle: BlockHandle,
         options: ReadOptions,
+        priority: CachePriority,
     ) -> TemplateResult<BlockIterator<CC>> {
-        let iter = if let Some(cache) = &self.block_cache {
-            let mut cache_key_buffer = vec![0; 16];
-            put_fixed_64(&mut cache_key_buffer, self.file_number);
-            put_fixed_64(&mut cache_key_buffer, data_block_handle.offset);
-            if let Some(b) = cache.get(&cache_key_buffer) {
-                b.iter(cmp)
-            } else {
-                let data = read_block(&self.file, &data_block_handle, options.verify_checksums)?;
-                let charge = data.len();
-                let new_block = Block::new(data)?;
-                let b = Arc::new(new_block);
-                let iter = b.iter(cmp);
-                if options.fill_cache {
-                    cache.insert(cache_key_buffer, b, charge);
+        Ok(self
+            .read_block_cached(&data_block_handle, options, priority)?
+            .iter(cmp))
+    }
+
+    // Finds the handle of the data block that might contain `key` in the index partition
+    // referenced by `top_level_value`. Returns `None` if the filter partition tells the key is
+    // absent when `use_filter` is set.
+    fn seek_partition<TC: Comparator>(
+        &self,
+        cmp: TC,
+        key: &[u8],
+        top_level_value: &[u8],
+        use_filter: bool,
+        options: ReadOptions,
+    ) -> TemplateResult<Option<BlockHandle>> {
+        let (partition_handle, filter_handle) = decode_partition_handles(top_level_value)?;
+        if use_filter {
+            if let (Some(policy), Some(filter_handle)) = (&self.partitioned_filter, filter_handle) {
+                let filter =
+                    self.read_block_cached(&filter_handle, options, CachePriority::High)?;
+                if !partition_filter_may_match(policy.as_ref(), &filter, key) {
+                    return Ok(None);
                 }
-                iter
             }
-        } else {
-            let data = read_block(&self.file, &data_block_handle, options.verify_checksums)?;
-            let b = Block::new(data)?;
-            b.iter(cmp)
-        };
-        Ok(iter)
+        }
+        let mut partition_iter =
+            self.block_reader(cmp, partition_handle, options, CachePriority::High)?;
+        partition_iter.seek(key);
+        if !partition_iter.valid() {
+            partition_iter.status()?;
+            return Ok(None);
+        }
+        let (data_block_handle, _) = BlockHandle::decode_from(partition_iter.value())?;
+        Ok(Some(data_block_handle))
     }
 
     /// Finds the first entry with the key equal or greater than target and
@@ -151,37 +248,43 @@ impl<F: File> Table<F> {
         let mut index_iter = self.index_block.iter(cmp.clone());
         // seek to the first 'last key' bigger than 'key'
         index_iter.seek(key);
-        if index_iter.valid() {
-            // It's called 'maybe_contained' not only because the filter policy may report the falsy
-            // result, but also even if we've found a block with the last key bigger
-            // than the target the key may not be contained if the block is the first
-            // block of the sstable.
-            let mut maybe_contained = true;
-
-            let handle_val = index_iter.value();
-            // check the filter block
-            if let Some(filter) = &self.filter_reader {
-                if let Ok((handle, _)) = BlockHandle::decode_from(handle_val) {
+        if !index_iter.valid() {
+            index_iter.status()?;
+            return Ok(None);
+        }
+        // The key is only 'maybe contained' in the data block found not only because the filter
+        // policy may report the falsy result, but also even if we've found a block with the last
+        // key bigger than the target the key may not be contained if the block is the first
+        // block of the sstable.
+        let data_block_handle = match self.index_type {
+            IndexType::Binary => {
+                let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+                // check the filter block
+                if let Some(filter) = &self.filter_reader {
                     if !filter.key_may_match(handle.offset, key) {
-                        maybe_contained = false;
+                        return Ok(None);
                     }
                 }
+                handle
             }
-            if maybe_contained {
-                let (data_block_handle, _) = BlockHandle::decode_from(handle_val)?;
-                let mut block_iter = self.block_reader(cmp, data_block_handle, options)?;
-                block_iter.seek(key);
-                if block_iter.valid() {
-                    return Ok(Some(block_iter));
+            IndexType::TwoLevel => {
+                match self.seek_partition(cmp.clone(), key, index_iter.value(), true, options)? {
+                    Some(handle) => handle,
+                    None => return Ok(None),
                 }
-                block_iter.seek_to_first();
-                while block_iter.valid() {
-                    block_iter.next();
-                }
-                block_iter.status()?;
             }
+        };
+        let mut block_iter =
+            self.block_reader(cmp, data_block_handle, options, CachePriority::Low)?;
+        block_iter.seek(key);
+        if block_iter.valid() {
+            return Ok(Some(block_iter));
         }
-        index_iter.status()?;
+        block_iter.seek_to_first();
+        while block_iter.valid() {
+            block_iter.next();
+        }
+        block_iter.status()?;
         Ok(None)
     }
 
@@ -192,10 +295,20 @@ impl<F: File> Table<F> {
     /// E.g., the approximate offset of the last key in the table will
     /// be close to the file length.
     pub(crate) fn approximate_offset_of<TC: Comparator>(&self, cmp: TC, key: &[u8]) -> u64 {
-        let mut index_iter = self.index_block.iter(cmp);
+        let mut index_iter = self.index_block.iter(cmp.clone());
         index_iter.seek(key);
         if index_iter.valid() {
             let val = index_iter.value();
+            if self.index_type == IndexType::TwoLevel {
+                let options = ReadOptions {
+                    fill_cache: false,
+                    ..ReadOptions::default()
+                };
+                if let Ok(Some(h)) = self.seek_partition(cmp, key, val, false, options) {
+                    return h.offset;
+                }
+            }
+            // Falls back to the offset of the index partition for a two-level index
             if let Ok((h, _)) = BlockHandle::decode_from(val) {
                 return h.offset;
             }
@@ -207,10 +320,120 @@ impl<F: File> Table<F> {
     }
 }
 
+/// Reads every block of the table stored in bytes `[0..file_len)` of `file` with checksum
+/// verification: all the data blocks, the filter blocks referenced by the metaindex block, the
+/// metaindex block itself and the index block, plus the index and filter partitions of a
+/// two-level index. Every data block is also iterated to the end so that a malformed entry is
+/// reported as well.
+///
+/// `on_block` is called with the physical size of each verified block and can be used to
+/// throttle the scan. Returns the number of blocks verified.
+pub fn verify_table<F: File>(
+    file: &F,
+    file_len: u64,
+    on_block: &mut dyn FnMut(u64) -> TemplateResult<()>,
+) -> TemplateResult<usize> {
+    if file_len < FOOTER_ENCODED_LENGTH as u64 {
+        return Err(TemplateKVError::Corruption(
+            "file is too short to be an sstable".to_owned(),
+        ));
+    };
+    let mut footer_space = vec![0; FOOTER_ENCODED_LENGTH];
+    file.read_exact_at(
+        footer_space.as_mut_slice(),
+        file_len - FOOTER_ENCODED_LENGTH as u64,
+    )?;
+    let (footer, _) = Footer::decode_from(footer_space.as_slice())?;
+    // Only `seek_to_first` and `next` are used so any comparator works here
+    let cmp = BytewiseComparator::default();
+    let mut verified = 0;
+    let mut index_type = IndexType::Binary;
+    if footer.meta_index_handle.size > 0 {
+        let meta_block = verify_block(file, &footer.meta_index_handle, on_block)?;
+        verified += 1;
+        let mut iter = meta_block.iter(cmp);
+        iter.seek_to_first();
+        while iter.valid() {
+            if iter.key() == INDEX_TYPE_PROPERTY.as_bytes() {
+                index_type = decode_index_type(iter.value())?;
+            } else if iter.key().starts_with(FILTER_PREFIX.as_bytes()) {
+                let (filter_handle, _) = BlockHandle::decode_from(iter.value())?;
+                // The filter block has no restarts trailer so it's not a valid `Block`
+                read_block(file, &filter_handle, true)?;
+                on_block(filter_handle.size + BLOCK_TRAILER_SIZE as u64)?;
+                verified += 1;
+            }
+            iter.next();
+        }
+        iter.status()?;
+    }
+    let index_block = verify_block(file, &footer.index_handle, on_block)?;
+    verified += 1;
+    match index_type {
+        IndexType::Binary => verified += verify_data_blocks(file, &index_block, on_block)?,
+        IndexType::TwoLevel => {
+            let mut top_level_iter = index_block.iter(cmp);
+            top_level_iter.seek_to_first();
+            while top_level_iter.valid() {
+                let (partition_handle, filter_handle) =
+                    decode_partition_handles(top_level_iter.value())?;
+                if let Some(filter_handle) = filter_handle {
+                    verify_block(file, &filter_handle, on_block)?;
+                    verified += 1;
+                }
+                let partition = verify_block(file, &partition_handle, on_block)?;
+                verified += 1 + verify_data_blocks(file, &partition, on_block)?;
+                top_level_iter.next();
+            }
+            top_level_iter.status()?;
+        }
+    }
+    Ok(verified)
+}
+
+// Verifies all the data blocks referenced by the given index block.
+// Returns the number of blocks verified.
+fn verify_data_blocks<F: File>(
+    file: &F,
+    index_block: &Block,
+    on_block: &mut dyn FnMut(u64) -> TemplateResult<()>,
+) -> TemplateResult<usize> {
+    let cmp = BytewiseComparator::default();
+    let mut verified = 0;
+    let mut index_iter = index_block.iter(cmp);
+    index_iter.seek_to_first();
+    while index_iter.valid() {
+        let (data_block_handle, _) = BlockHandle::decode_from(index_iter.value())?;
+        let mut block_iter = verify_block(file, &data_block_handle, on_block)?.iter(cmp);
+        verified += 1;
+        block_iter.seek_to_first();
+        while block_iter.valid() {
+            block_iter.next();
+        }
+        block_iter.status()?;
+        index_iter.next();
+    }
+    index_iter.status()?;
+    Ok(verified)
+}
+
+// Reads the block identified by `handle` with checksum verification and reports its size
+fn verify_block<F: File>(
+    file: &F,
+    handle: &BlockHandle,
+    on_block: &mut dyn FnMut(u64) -> TemplateResult<()>,
+) -> TemplateResult<Block> {
+    let block = Block::new(read_block(file, handle, true)?)?;
+    on_block(handle.size + BLOCK_TRAILER_SIZE as u64)?;
+    Ok(block)
+}
+
 pub struct TableIterFactory<C: Comparator, F: File> {
     options: ReadOptions,
     table: Arc<Table<F>>,
     cmp: C,
+    // `CachePriority::High` for the index partitions
+    priority: CachePriority,
 }
 
 impl<C: Comparator, F: File> DerivedIterFactory for TableIterFactory<C, F> {
@@ -218,12 +441,92 @@ impl<C: Comparator, F: File> DerivedIterFactory for TableIterFactory<C, F> {
     fn derive(&self, value: &[u8]) -> TemplateResult<Self::Iter> {
         BlockHandle::decode_from(value).and_then(|(handle, _)| {
             self.table
-                .block_reader(self.cmp.clone(), handle, self.options)
+                .block_reader(self.cmp.clone(), handle, self.options, self.priority)
         })
     }
 }
 
-pub type TableIterator<C, F> = ConcatenateIterator<BlockIterator<C>, TableIterFactory<C, F>>;
+/// The iterator yields the handles of all the data blocks of a table by their last keys
+pub enum IndexIterator<C: Comparator, F: File> {
+    Binary(BlockIterator<C>),
+    // The index partitions are derived from the top-level index. `TableIterFactory` works here
+    // since a top-level entry starts with the handle of the partition.
+    TwoLevel(ConcatenateIterator<BlockIterator<C>, TableIterFactory<C, F>>),
+}
+
+macro_rules! delegate_index_iter {
+    ($self:ident, $iter:ident => $e:expr) => {
+        match $self {
+            IndexIterator::Binary($iter) => $e,
+            IndexIterator::TwoLevel($iter) => $e,
+        }
+    };
+}
+
+impl<C: Comparator, F: File> Iterator for IndexIterator<C, F> {
+    fn valid(&self) -> bool {
+        delegate_index_iter!(self, iter => iter.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        delegate_index_iter!(self, iter => iter.seek_to_first())
+    }
+
+    fn seek_to_last(&mut self) {
+        delegate_index_iter!(self, iter => iter.seek_to_last())
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        delegate_index_iter!(self, iter => iter.seek(target))
+    }
+
+    fn next(&mut self) {
+        delegate_index_iter!(self, iter => iter.next())
+    }
+
+    fn prev(&mut self) {
+        delegate_index_iter!(self, iter => iter.prev())
+    }
+
+    fn key(&self) -> &[u8] {
+        delegate_index_iter!(self, iter => iter.key())
+    }
+
+    fn value(&self) -> &[u8] {
+        delegate_index_iter!(self, iter => iter.value())
+    }
+
+    fn status(&mut self) -> TemplateResult<()> {
+        delegate_index_iter!(self, iter => iter.status())
+    }
+}
+
+/// Create an `IndexIterator` yielding the handles of all the data blocks in the given `table`
+///
+/// Entry format:
+///     key: the last internal key of the data block
+///     value: the encoded `BlockHandle` of the data block
+pub fn new_index_iterator<C: Comparator, F: File>(
+    cmp: C,
+    table: Arc<Table<F>>,
+    options: ReadOptions,
+) -> IndexIterator<C, F> {
+    let index_iter = table.index_block.iter(cmp.clone());
+    match table.index_type {
+        IndexType::Binary => IndexIterator::Binary(index_iter),
+        IndexType::TwoLevel => {
+            let partition_factory = TableIterFactory {
+                options,
+                table,
+                cmp,
+                priority: CachePriority::High,
+            };
+            IndexIterator::TwoLevel(ConcatenateIterator::new(index_iter, partition_factory))
+        }
+    }
+}
+
+pub type TableIterator<C, F> = ConcatenateIterator<IndexIterator<C, F>, TableIterFactory<C, F>>;
 
 /// Create a new `ConcatenateIterator` as table iterator.
 /// This iterator is able to yield all the key/values in the given `table` file
@@ -236,11 +539,12 @@ pub fn new_table_iterator<C: Comparator, F: File>(
     table: Arc<Table<F>>,
     options: ReadOptions,
 ) -> TableIterator<C, F> {
-    let index_iter = table.index_block.iter(cmp.clone());
+    let index_iter = new_index_iterator(cmp.clone(), table.clone(), options);
     let factory = TableIterFactory {
         options,
         table,
         cmp,
+        priority: CachePriority::Low,
     };
     ConcatenateIterator::new(index_iter, factory)
 }
@@ -272,22 +576,64 @@ pub struct TableBuilder<C: Comparator, F: File> {
     pending_index_entry: bool,
     // handle for current block to add to index block
     pending_handle: BlockHandle,
+    // `Some` if building a two-level index. `index_block` holds the current index partition.
+    partitions: Option<IndexPartitions<C>>,
 
     // Fields from `Options`
     block_size: usize,
     block_restart_interval: usize,
     compression: CompressionType,
     filter_policy: Option<Arc<dyn FilterPolicy>>,
+    metadata_block_size: usize,
+    statistics: Option<Arc<Statistics>>,
+
+    // The number of keys added into the filters and the bytes of the filters built so far
+    filter_keys: usize,
+    filter_bytes: usize,
+}
+
+// The states of building a two-level index and the filter partitions alongside
+struct IndexPartitions<C: Comparator> {
+    // Maps the last key of every index partition to the handle of the partition followed by
+    // the handle of its filter partition if any
+    top_level: BlockBuilder<C>,
+    // The last key added into the current index partition
+    last_index_key: Vec<u8>,
+    // The keys in the data blocks indexed by the current index partition
+    filter_keys: Vec<Vec<u8>>,
 }
 
 impl<C: Comparator, F: File> TableBuilder<C, F> {
     pub fn new<UC: Comparator>(file: F, cmp: C, options: &Arc<Options<UC>>) -> Self {
+        Self::new_for_level(file, cmp, options, 0)
+    }
+
+    /// Creates a `TableBuilder` for a table to be placed in `level`, which decides the filter
+    /// policy by `Options::filter_policy_per_level`
+    pub fn new_for_level<UC: Comparator>(
+        file: F,
+        cmp: C,
+        options: &Arc<Options<UC>>,
+        level: usize,
+    ) -> Self {
         let opt = options.clone();
+        let filter_policy = opt.filter_policy_for_level(level);
         let db_builder = BlockBuilder::new(options.block_restart_interval, cmp.clone());
         let ib_builder = BlockBuilder::new(options.block_restart_interval, cmp.clone());
+        let partitions = match opt.index_type {
+            IndexType::Binary => None,
+            IndexType::TwoLevel => Some(IndexPartitions {
+                top_level: BlockBuilder::new(options.block_restart_interval, cmp.clone()),
+                last_index_key: vec![],
+                filter_keys: vec![],
+            }),
+        };
         let fb = {
-            if let Some(policy) = opt.filter_policy.clone() {
-                let mut f = FilterBlockBuilder::new(policy.clone());
+            if partitions.is_some() {
+                // The filter is partitioned along with the index
+                None
+            } else if let Some(policy) = filter_policy.clone() {
+                let mut f = FilterBlockBuilder::new(policy);
                 f.start_block(0);
                 Some(f)
             } else {
@@ -306,13 +652,24 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             filter_block: fb,
             pending_index_entry: false,
             pending_handle: BlockHandle::new(0, 0),
+            partitions,
             compression: opt.compression,
             block_size: opt.block_size,
             block_restart_interval: opt.block_restart_interval,
-            filter_policy: opt.filter_policy.clone(),
+            filter_policy,
+            metadata_block_size: opt.metadata_block_size,
+            statistics: opt.statistics.clone(),
+            filter_keys: 0,
+            filter_bytes: 0,
         }
     }
 
+    /// Overrides the compression of the blocks given by the options, which might be changed by
+    /// `TemplateDB::set_options` since the db is opened
+    pub(crate) fn set_compression(&mut self, compression: CompressionType) {
+        self.compression = compression;
+    }
+
     /// Adds a key/value pair to the table being constructed.
     /// If the data block reaches the limit, it will be flushed
     /// If we just have flushed a new block data before, add an index entry into the index block.
@@ -331,10 +688,17 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             )
         }
         // Check whether we need to create a new index entry
-        self.maybe_append_index_block(Some(key));
+        if self.maybe_append_index_block(Some(key)) {
+            self.maybe_cut_partition(false)?;
+        }
         // Update filter block
         if let Some(fb) = self.filter_block.as_mut() {
-            fb.add_key(key)
+            fb.add_key(key);
+            self.filter_keys += 1;
+        }
+        if let (Some(p), Some(_)) = (self.partitions.as_mut(), &self.filter_policy) {
+            p.filter_keys.push(key.to_vec());
+            self.filter_keys += 1;
         }
         // TODO: avoid the copy
         self.last_key.resize(key.len(), 0);
@@ -390,11 +754,14 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         self.flush()?;
         self.assert_not_closed();
         self.closed = true;
+        self.maybe_append_index_block(None); // flush the last index first
+        self.maybe_cut_partition(true)?;
         // write filter block
         let mut filter_block_handler = BlockHandle::new(0, 0);
         let mut has_filter_block = false;
         if let Some(fb) = &mut self.filter_block {
             let data = fb.finish();
+            self.filter_bytes += data.len();
             write_raw_block(
                 &mut self.file,
                 data,
@@ -410,21 +777,32 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
         let mut meta_block_builder =
             BlockBuilder::new(self.block_restart_interval, self.cmp.clone());
         let meta_block = {
-            if has_filter_block {
-                let filter_key = if let Some(fp) = &self.filter_policy {
-                    "filter.".to_owned() + fp.name()
-                } else {
-                    String::from("")
-                };
-                meta_block_builder.add(filter_key.as_bytes(), &filter_block_handler.encoded());
+            // The keys must be added in order
+            if let Some(fp) = &self.filter_policy {
+                if has_filter_block {
+                    let filter_key = FILTER_PREFIX.to_owned() + fp.name();
+                    meta_block_builder.add(filter_key.as_bytes(), &filter_block_handler.encoded());
+                }
+                if self.partitions.is_some() {
+                    let filter_key = PARTITIONED_FILTER_PREFIX.to_owned() + fp.name();
+                    meta_block_builder.add(filter_key.as_bytes(), &[]);
+                }
             }
+            let index_type = if self.partitions.is_some() {
+                IndexType::TwoLevel
+            } else {
+                IndexType::Binary
+            };
+            meta_block_builder.add(INDEX_TYPE_PROPERTY.as_bytes(), &[index_type as u8]);
             meta_block_builder.finish()
         };
         self.write_block(meta_block, &mut meta_block_handle)?;
 
         // Write index block
-        self.maybe_append_index_block(None); // flush the last index first
-        let index_block = self.index_block.finish();
+        let index_block = match &mut self.partitions {
+            Some(p) => p.top_level.finish(),
+            None => self.index_block.finish(),
+        };
         let mut index_block_handle = BlockHandle::new(0, 0);
         let (c_index_block, ct) = compress_block(index_block, self.compression)?;
         write_raw_block(
@@ -434,7 +812,10 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             &mut index_block_handle,
             &mut self.offset,
         )?;
-        self.index_block.reset();
+        match &mut self.partitions {
+            Some(p) => p.top_level.reset(),
+            None => self.index_block.reset(),
+        }
         // write footer
         let footer = Footer::new(meta_block_handle, index_block_handle).encoded();
         self.file.write(footer.as_slice())?;
@@ -443,6 +824,9 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             self.file.flush()?;
             self.file.close()?;
         }
+        if let (Some(stats), Some(fp)) = (&self.statistics, &self.filter_policy) {
+            stats.record_filter(fp.name(), self.filter_keys, self.filter_bytes);
+        }
         Ok(())
     }
 
@@ -494,12 +878,59 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
             let mut handle_encoding = vec![];
             self.pending_handle.encoded_to(&mut handle_encoding);
             self.index_block.add(&s, &handle_encoding);
+            if let Some(p) = &mut self.partitions {
+                p.last_index_key = s;
+            }
             self.pending_index_entry = false;
             return true;
         }
         false
     }
 
+    // Writes the current index partition and its filter partition into the file if the index
+    // partition is big enough or `force` is true, and adds them into the top-level index
+    fn maybe_cut_partition(&mut self, force: bool) -> TemplateResult<()> {
+        let Some(p) = &mut self.partitions else {
+            return Ok(());
+        };
+        if self.index_block.is_empty()
+            || (!force && self.index_block.current_size_estimate() < self.metadata_block_size)
+        {
+            return Ok(());
+        }
+        let mut handles = vec![];
+        let mut partition_handle = BlockHandle::new(0, 0);
+        let (partition, ct) = compress_block(self.index_block.finish(), self.compression)?;
+        write_raw_block(
+            &mut self.file,
+            partition.as_slice(),
+            ct,
+            &mut partition_handle,
+            &mut self.offset,
+        )?;
+        self.index_block.reset();
+        partition_handle.encoded_to(&mut handles);
+        if let Some(policy) = &self.filter_policy {
+            // Wrap the filter into a block so that it can be cached in the block cache
+            let mut filter_block = BlockBuilder::new(1, BytewiseComparator::default());
+            let filter = policy.create_filter(&p.filter_keys);
+            self.filter_bytes += filter.len();
+            filter_block.add(&[], &filter);
+            p.filter_keys.clear();
+            let mut filter_handle = BlockHandle::new(0, 0);
+            write_raw_block(
+                &mut self.file,
+                filter_block.finish(),
+                CompressionType::NoCompression,
+                &mut filter_handle,
+                &mut self.offset,
+            )?;
+            filter_handle.encoded_to(&mut handles);
+        }
+        p.top_level.add(&p.last_index_key, &handles);
+        Ok(())
+    }
+
     fn write_block(&mut self, raw_block: &[u8], handle: &mut BlockHandle) -> TemplateResult<()> {
         let (data, compression) = compress_block(raw_block, self.compression)?;
         write_raw_block(&mut self.file, &data, compression, handle, &mut self.offset)?;
@@ -507,6 +938,74 @@ impl<C: Comparator, F: File> TableBuilder<C, F> {
     }
 }
 
+// Decodes the value of a top-level index entry: the handle of an index partition followed by
+// the handle of its filter partition if any
+fn decode_partition_handles(value: &[u8]) -> TemplateResult<(BlockHandle, Option<BlockHandle>)> {
+    let (partition_handle, n) = BlockHandle::decode_from(value)?;
+    let filter_handle = if n < value.len() {
+        Some(BlockHandle::decode_from(&value[n..])?.0)
+    } else {
+        None
+    };
+    Ok((partition_handle, filter_handle))
+}
+
+// Returns the key of the block identified by `handle` in the block cache
+fn block_cache_key(file_number: u64, handle: &BlockHandle) -> Vec<u8> {
+    let mut key = Vec::with_capacity(16);
+    put_fixed_64(&mut key, file_number);
+    put_fixed_64(&mut key, handle.offset);
+    key
+}
+
+// Reads the index or the filter block for `Table::open` through the block cache if any.
+// They are cached with `CachePriority::High` whatever the index layout is, so that they are
+// retained preferentially over the data blocks and a table reopened after evicted from the
+// table cache finds them there. `new_block` makes a `Block` of the contents read.
+fn read_meta_block_cached<F: File, C: Comparator>(
+    file: &F,
+    file_number: u64,
+    handle: &BlockHandle,
+    options: &Options<C>,
+    new_block: impl FnOnce(Vec<u8>) -> TemplateResult<Block>,
+) -> TemplateResult<Arc<Block>> {
+    let cache = match &options.block_cache {
+        Some(cache) => cache,
+        None => {
+            let data = read_block(file, handle, options.paranoid_checks)?;
+            return Ok(Arc::new(new_block(data)?));
+        }
+    };
+    let key = block_cache_key(file_number, handle);
+    if let Some(b) = cache.get(&key) {
+        return Ok(b);
+    }
+    let data = read_block(file, handle, options.paranoid_checks)?;
+    let charge = data.len();
+    let b = Arc::new(new_block(data)?);
+    // The block is still usable if the cache is full
+    let _ = cache.insert_with_priority(key, b.clone(), charge, CachePriority::High);
+    Ok(b)
+}
+
+// Returns true if the key might be in the filter partition
+fn partition_filter_may_match(policy: &dyn FilterPolicy, filter: &Block, key: &[u8]) -> bool {
+    let mut iter = filter.iter(BytewiseComparator::default());
+    iter.seek_to_first();
+    !iter.valid() || policy.may_contain(iter.value(), key)
+}
+
+fn decode_index_type(value: &[u8]) -> TemplateResult<IndexType> {
+    match value {
+        [0] => Ok(IndexType::Binary),
+        [1] => Ok(IndexType::TwoLevel),
+        _ => Err(TemplateKVError::Corruption(format!(
+            "unknown index type {:?}",
+            value
+        ))),
+    }
+}
+
 // Compresses the give raw block by configured compression algorithm.
 // Returns the compressed data and compression data.
 fn compress_block(
@@ -614,14 +1113,18 @@ mod tests {
     use std::sync::Arc;
 
     use crate::{
-        cache::bloom_filter_cache::BloomFilter,
+        cache::{
+            bloom_filter_cache::BloomFilter, lru_cache::LRUCache, ribbon_filter::RibbonFilter,
+            CacheSync,
+        },
         iterator::Iterator,
-        options::{Options, ReadOptions},
+        options::{IndexType, Options, ReadOptions},
         sstable::{
             block::Block,
-            table::{read_block, Table, TableBuilder},
+            table::{new_table_iterator, read_block, verify_table, Table, TableBuilder},
             BlockHandle,
         },
+        statistics::Statistics,
         storage::{mem::MemStorage, File, Storage},
         util::comparator::BytewiseComparator,
     };
@@ -645,7 +1148,7 @@ mod tests {
     }
 
     #[test]
-    fn test_build_empty_table_without_meta_block() {
+    fn test_build_empty_table_without_filter_block() {
         let s = MemStorage::default();
         let new_file = s.create("test").unwrap();
         let opt = Arc::new(Options::<BytewiseComparator>::default()); // no filter block on default
@@ -657,7 +1160,8 @@ mod tests {
         let cmp = BytewiseComparator::default();
         let table = Table::open(file, 0, file_len, opt, cmp).unwrap();
         assert!(table.filter_reader.is_none());
-        assert!(table.meta_block_handle.is_none()); // no filter block means no meta block
+        // The meta block only holds the table properties
+        assert_eq!(table.index_type(), IndexType::Binary);
         let read_opt = ReadOptions::default();
         let res = table.internal_get(read_opt, cmp, b"test").unwrap();
         assert!(res.is_none());
@@ -737,4 +1241,182 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_index_and_filter_blocks_cached() {
+        let s = MemStorage::default();
+        let cache: Arc<dyn CacheSync<Vec<u8>, Arc<Block>>> = Arc::new(LRUCache::new(1 << 20));
+        let mut o = Options::<BytewiseComparator>::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.block_cache = Some(cache.clone());
+        let opt = Arc::new(o);
+        let cmp = BytewiseComparator::default();
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), cmp, &opt);
+        for i in 0..1000 {
+            let key = format!("key{:06}", i);
+            tb.add(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file_len = s.open("test").unwrap().len().unwrap();
+        let table = Table::open(s.open("test").unwrap(), 0, file_len, opt.clone(), cmp).unwrap();
+        assert!(table.filter_reader.is_some());
+        // Both the index block and the filter block are charged
+        let charge = cache.total_charge();
+        assert!(charge > table.index_block.size());
+
+        // Flooding the cache with data blocks never evicts them
+        for i in 0..(1 << 12) {
+            cache.insert(
+                format!("data{}", i).into_bytes(),
+                Arc::new(Block::default()),
+                1 << 10,
+            );
+        }
+        assert_eq!(Arc::strong_count(&table.index_block), 2);
+        let reopened = Table::open(s.open("test").unwrap(), 0, file_len, opt.clone(), cmp).unwrap();
+        assert!(Arc::ptr_eq(&table.index_block, &reopened.index_block));
+    }
+
+    #[test]
+    fn test_two_level_index_table() {
+        let s = MemStorage::default();
+        let mut o = Options::<BytewiseComparator>::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.index_type = IndexType::TwoLevel;
+        o.block_size = 256;
+        o.metadata_block_size = 256;
+        let opt = Arc::new(o);
+        let cmp = BytewiseComparator::default();
+        let key = |i: usize| format!("key{:06}", i * 2);
+        let value = |i: usize| format!("value{}", i);
+        let n = 5000;
+        let mut tb = TableBuilder::new(s.create("test").unwrap(), cmp, &opt);
+        for i in 0..n {
+            tb.add(key(i).as_bytes(), value(i).as_bytes()).unwrap();
+        }
+        tb.finish(false).unwrap();
+        let file = s.open("test").unwrap();
+        let file_len = file.len().unwrap();
+        let table = Arc::new(Table::open(file, 0, file_len, opt.clone(), cmp).unwrap());
+        assert_eq!(table.index_type(), IndexType::TwoLevel);
+        assert!(table.filter_reader.is_none());
+        assert!(table.partitioned_filter.is_some());
+        let mut partitions = 0;
+        let mut top_level = table.index_block.iter(cmp);
+        top_level.seek_to_first();
+        while top_level.valid() {
+            partitions += 1;
+            top_level.next();
+        }
+        assert!(partitions > 1);
+
+        let read_opt = ReadOptions::default();
+        for i in 0..n {
+            let iter = table
+                .internal_get(read_opt, cmp, key(i).as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(iter.key(), key(i).as_bytes());
+            assert_eq!(iter.value(), value(i).as_bytes());
+        }
+        let mut filtered = 0;
+        for i in 0..n {
+            let missing = format!("key{:06}", i * 2 + 1);
+            match table
+                .internal_get(read_opt, cmp, missing.as_bytes())
+                .unwrap()
+            {
+                Some(iter) => assert_ne!(iter.key(), missing.as_bytes()),
+                None => filtered += 1,
+            }
+        }
+        // Most of the absent keys are filtered out by the filter partitions
+        assert!(filtered > n * 9 / 10, "only {} filtered", filtered);
+
+        let mut iter = new_table_iterator(cmp, table.clone(), read_opt);
+        iter.seek_to_first();
+        for i in 0..n {
+            assert!(iter.valid());
+            assert_eq!(iter.key(), key(i).as_bytes());
+            assert_eq!(iter.value(), value(i).as_bytes());
+            iter.next();
+        }
+        assert!(!iter.valid());
+        iter.seek(format!("key{:06}", 2501).as_bytes());
+        assert_eq!(iter.key(), key(1251).as_bytes());
+        iter.seek_to_last();
+        assert_eq!(iter.key(), key(n - 1).as_bytes());
+        iter.prev();
+        assert_eq!(iter.key(), key(n - 2).as_bytes());
+        iter.status().unwrap();
+
+        let first = table.approximate_offset_of(cmp, key(0).as_bytes());
+        let middle = table.approximate_offset_of(cmp, key(n / 2).as_bytes());
+        let last = table.approximate_offset_of(cmp, key(n - 1).as_bytes());
+        assert!(first < middle && middle < last && last < file_len);
+
+        // The top-level index, the metaindex block, every partition and every data block
+        let verified = verify_table(&s.open("test").unwrap(), file_len, &mut |_| Ok(())).unwrap();
+        assert!(verified > 2 + partitions * 2);
+    }
+
+    #[test]
+    fn test_filter_policy_per_level() {
+        let s = MemStorage::default();
+        let stats = Arc::new(Statistics::default());
+        let mut o = Options::<BytewiseComparator>::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        o.filter_policy_per_level = vec![None, None, Some(Arc::new(RibbonFilter::new(10)))];
+        o.statistics = Some(stats.clone());
+        o.index_type = IndexType::TwoLevel;
+        let opt = Arc::new(o);
+        let cmp = BytewiseComparator::default();
+        let key = |i: usize| format!("key{:06}", i * 2);
+        let n = 10000;
+        for level in 0..3 {
+            let file = s.create(format!("level{}", level)).unwrap();
+            let mut tb = TableBuilder::new_for_level(file, cmp, &opt, level);
+            for i in 0..n {
+                tb.add(key(i).as_bytes(), b"value").unwrap();
+            }
+            tb.finish(false).unwrap();
+        }
+        let usage = stats.filter_usage();
+        assert_eq!(usage.len(), 2);
+        let (bloom, ribbon) = (&usage[0], &usage[1]);
+        assert_eq!(bloom.0, "TemplateDB.BuiltinBloomFilter");
+        assert_eq!((bloom.1.tables, bloom.1.keys), (2, 2 * n as u64));
+        assert_eq!(ribbon.0, "TemplateDB.RibbonFilter");
+        assert_eq!((ribbon.1.tables, ribbon.1.keys), (1, n as u64));
+        assert!(ribbon.1.bits_per_key() < bloom.1.bits_per_key() * 0.8);
+        assert!(stats.report().contains("TemplateDB.RibbonFilter"));
+
+        // The filters work wherever the tables are
+        let open = |level: usize, opt: &Arc<Options<BytewiseComparator>>| {
+            let file = s.open(format!("level{}", level)).unwrap();
+            let file_len = file.len().unwrap();
+            Table::open(file, 0, file_len, opt.clone(), cmp).unwrap()
+        };
+        for level in 0..3 {
+            let table = open(level, &opt);
+            assert!(table.partitioned_filter.is_some());
+            let filtered = (0..n)
+                .filter(|i| {
+                    let missing = format!("key{:06}", i * 2 + 1);
+                    table
+                        .internal_get(ReadOptions::default(), cmp, missing.as_bytes())
+                        .unwrap()
+                        .is_none()
+                })
+                .count();
+            assert!(filtered > n * 9 / 10, "only {} filtered", filtered);
+        }
+
+        // The filters of an unknown policy are ignored
+        let mut o = Options::<BytewiseComparator>::default();
+        o.filter_policy = Some(Arc::new(BloomFilter::new(10)));
+        let opt = Arc::new(o);
+        assert!(open(0, &opt).partitioned_filter.is_some());
+        assert!(open(2, &opt).partitioned_filter.is_none());
+    }
 }
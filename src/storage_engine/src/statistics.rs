@@ -0,0 +1,236 @@
+use std::{fmt::Write, sync::Mutex};
+
+use crate::{compaction::compact::CompactionStats, util::collection::HashMap};
+
+/// The statistics collected by a db if `Options::statistics` is set.
+/// One `Statistics` could be shared by several dbs.
+#[derive(Default)]
+pub struct Statistics {
+    // filter policy name -> usage
+    filters: Mutex<HashMap<String, FilterUsage>>,
+    // output level -> compactions
+    compactions: Mutex<HashMap<usize, LevelCompactionStats>>,
+    scrubs: Mutex<ScrubStats>,
+}
+
+/// The accounting of the filters built by one `FilterPolicy`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilterUsage {
+    /// The number of tables built with the filters
+    pub tables: u64,
+    /// The number of keys added into the filters
+    pub keys: u64,
+    /// The total size of the filters in bytes
+    pub bytes: u64,
+}
+
+impl FilterUsage {
+    /// Returns the average bits a key takes in the filters
+    pub fn bits_per_key(&self) -> f64 {
+        if self.keys == 0 {
+            0.0
+        } else {
+            (self.bytes * 8) as f64 / self.keys as f64
+        }
+    }
+}
+
+/// The accounting of the compactions, including the memtable flushes, writing into one level
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelCompactionStats {
+    /// The number of compactions
+    pub count: u64,
+    /// The total microseconds the compactions take
+    pub micros: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// The accounting of the passes of the background scrubber
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// The number of finished verification passes
+    pub runs: u64,
+    /// The total bytes read by the passes
+    pub bytes_checked: u64,
+    /// The total number of corrupted files found by the passes
+    pub corrupted_files: u64,
+}
+
+impl Statistics {
+    /// Records a table built with `keys` keys in its filters of `bytes` bytes
+    pub(crate) fn record_filter(&self, policy: &str, keys: usize, bytes: usize) {
+        let mut filters = self.filters.lock().unwrap();
+        let usage = filters.entry(policy.to_owned()).or_default();
+        usage.tables += 1;
+        usage.keys += keys as u64;
+        usage.bytes += bytes as u64;
+    }
+
+    /// Records a compaction or a memtable flush writing into `level`
+    pub(crate) fn record_compaction(&self, level: usize, stats: &CompactionStats) {
+        let mut compactions = self.compactions.lock().unwrap();
+        let level_stats = compactions.entry(level).or_default();
+        level_stats.count += 1;
+        level_stats.micros += stats.micros;
+        level_stats.bytes_read += stats.bytes_read;
+        level_stats.bytes_written += stats.bytes_written;
+    }
+
+    /// Records a finished pass of the background scrubber
+    pub(crate) fn record_scrub(&self, bytes_checked: u64, corrupted_files: usize) {
+        let mut scrubs = self.scrubs.lock().unwrap();
+        scrubs.runs += 1;
+        scrubs.bytes_checked += bytes_checked;
+        scrubs.corrupted_files += corrupted_files as u64;
+    }
+
+    /// Returns the accounting of the background scrubber
+    pub fn scrub_stats(&self) -> ScrubStats {
+        *self.scrubs.lock().unwrap()
+    }
+
+    /// Returns the compactions writing into every level ordered by the level
+    pub fn compaction_stats(&self) -> Vec<(usize, LevelCompactionStats)> {
+        let mut stats: Vec<_> = self
+            .compactions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(level, stats)| (*level, *stats))
+            .collect();
+        stats.sort_by_key(|(level, _)| *level);
+        stats
+    }
+
+    /// Returns the usage of every filter policy ordered by the policy name
+    pub fn filter_usage(&self) -> Vec<(String, FilterUsage)> {
+        let mut usage: Vec<_> = self
+            .filters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, usage)| (name.clone(), *usage))
+            .collect();
+        usage.sort_by(|a, b| a.0.cmp(&b.0));
+        usage
+    }
+
+    /// Returns a human-readable report of all the statistics
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        for (name, usage) in self.filter_usage() {
+            let _ = writeln!(
+                s,
+                "filter {}: tables {}, keys {}, bytes {}, bits per key {:.2}",
+                name,
+                usage.tables,
+                usage.keys,
+                usage.bytes,
+                usage.bits_per_key()
+            );
+        }
+        for (level, stats) in self.compaction_stats() {
+            let _ = writeln!(
+                s,
+                "compaction level {}: count {}, micros {}, bytes read {}, bytes written {}",
+                level, stats.count, stats.micros, stats.bytes_read, stats.bytes_written
+            );
+        }
+        let scrubs = self.scrub_stats();
+        if scrubs.runs > 0 {
+            let _ = writeln!(
+                s,
+                "scrub: runs {}, bytes checked {}, corrupted files {}",
+                scrubs.runs, scrubs.bytes_checked, scrubs.corrupted_files
+            );
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_usage_report() {
+        let stats = Statistics::default();
+        assert!(stats.report().is_empty());
+        stats.record_filter("b", 100, 125);
+        stats.record_filter("a", 10, 0);
+        stats.record_filter("b", 100, 75);
+        assert_eq!(
+            stats.filter_usage(),
+            vec![
+                (
+                    "a".to_owned(),
+                    FilterUsage {
+                        tables: 1,
+                        keys: 10,
+                        bytes: 0
+                    }
+                ),
+                (
+                    "b".to_owned(),
+                    FilterUsage {
+                        tables: 2,
+                        keys: 200,
+                        bytes: 200
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            stats.report(),
+            "filter a: tables 1, keys 10, bytes 0, bits per key 0.00\n\
+             filter b: tables 2, keys 200, bytes 200, bits per key 8.00\n"
+        );
+    }
+
+    #[test]
+    fn test_compaction_stats_report() {
+        let stats = Statistics::default();
+        let record = |level, micros, bytes_read, bytes_written| {
+            stats.record_compaction(
+                level,
+                &CompactionStats {
+                    micros,
+                    bytes_read,
+                    bytes_written,
+                },
+            )
+        };
+        record(2, 10, 100, 90);
+        record(0, 5, 0, 50);
+        record(2, 20, 200, 150);
+        assert_eq!(
+            stats.compaction_stats(),
+            vec![
+                (
+                    0,
+                    LevelCompactionStats {
+                        count: 1,
+                        micros: 5,
+                        bytes_read: 0,
+                        bytes_written: 50
+                    }
+                ),
+                (
+                    2,
+                    LevelCompactionStats {
+                        count: 2,
+                        micros: 30,
+                        bytes_read: 300,
+                        bytes_written: 240
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            stats.report(),
+            "compaction level 0: count 1, micros 5, bytes read 0, bytes written 50\n\
+             compaction level 2: count 2, micros 30, bytes read 300, bytes written 240\n"
+        );
+    }
+}
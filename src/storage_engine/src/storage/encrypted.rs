@@ -0,0 +1,488 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use aes::Aes256;
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    Ctr128BE,
+};
+use rand::Rng;
+
+use crate::{
+    error::{TemplateKVError, TemplateResult},
+    storage::{File, Storage},
+    util::{
+        coding::{decode_fixed_32, put_fixed_32},
+        collection::HashMap,
+    },
+};
+
+/// The size of an AES-256 key
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+pub type EncryptionKey = [u8; ENCRYPTION_KEY_SIZE];
+
+const MAGIC: &[u8; 8] = b"TDBCRYPT";
+const IV_SIZE: usize = 16;
+// magic (8B) + key id (4B) + reserved (4B) + IV (16B)
+const HEADER_SIZE: u64 = 32;
+
+/// Provides the keys for an `EncryptedStorage`. Every key is identified by an id which is
+/// recorded in the header of the files encrypted by it.
+pub trait KeyProvider: Send + Sync {
+    /// Returns the id and the key to encrypt the new files
+    fn current_key(&self) -> TemplateResult<(u32, EncryptionKey)>;
+
+    /// Returns the key of `id` to decrypt an existing file
+    fn key(&self, id: u32) -> TemplateResult<EncryptionKey>;
+}
+
+/// A `KeyProvider` holding all the keys in memory.
+///
+/// A key is rotated by adding a new key and making it current. The old keys must be kept
+/// until no file encrypted by them is alive, see `EncryptedStorage::reencrypt`.
+pub struct StaticKeyProvider {
+    keys: RwLock<HashMap<u32, EncryptionKey>>,
+    current: AtomicU32,
+}
+
+impl StaticKeyProvider {
+    /// Creates a `StaticKeyProvider` using the given key as the current one
+    #[must_use]
+    pub fn new(id: u32, key: EncryptionKey) -> Self {
+        let mut keys = HashMap::default();
+        keys.insert(id, key);
+        Self {
+            keys: RwLock::new(keys),
+            current: AtomicU32::new(id),
+        }
+    }
+
+    /// Adds a key to decrypt the existing files
+    pub fn add_key(&self, id: u32, key: EncryptionKey) {
+        self.keys.write().unwrap().insert(id, key);
+    }
+
+    /// Makes the key of `id` the current one. The files created before keep their keys.
+    pub fn set_current(&self, id: u32) -> TemplateResult<()> {
+        if !self.keys.read().unwrap().contains_key(&id) {
+            return Err(TemplateKVError::InvalidArgument(format!(
+                "unknown encryption key {}",
+                id
+            )));
+        }
+        self.current.store(id, Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes a key which is no longer used by any file
+    pub fn remove_key(&self, id: u32) -> TemplateResult<()> {
+        if self.current.load(Ordering::Acquire) == id {
+            return Err(TemplateKVError::InvalidArgument(format!(
+                "can not remove the current encryption key {}",
+                id
+            )));
+        }
+        self.keys.write().unwrap().remove(&id);
+        Ok(())
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn current_key(&self) -> TemplateResult<(u32, EncryptionKey)> {
+        let id = self.current.load(Ordering::Acquire);
+        Ok((id, self.key(id)?))
+    }
+
+    fn key(&self, id: u32) -> TemplateResult<EncryptionKey> {
+        self.keys.read().unwrap().get(&id).copied().ok_or_else(|| {
+            TemplateKVError::InvalidArgument(format!("unknown encryption key {}", id))
+        })
+    }
+}
+
+/// A `Storage` wrapper encrypting all the files with AES-256 in CTR mode.
+///
+/// Every file starts with a plaintext header holding the id of the key and a random IV:
+///
+/// ```text
+/// +-------------------------+-------------+---------------+-----------+
+/// | magic "TDBCRYPT" (8B)   | key id (4B) | reserved (4B) | IV (16B)  |
+/// +-------------------------+-------------+---------------+-----------+
+/// ```
+///
+/// and the byte at offset `i` of the contents is XORed with the byte `i` of the key stream
+/// starting from the IV. So the files are still appendable and randomly readable, and the
+/// offsets seen by the callers exclude the header.
+#[derive(Clone)]
+pub struct EncryptedStorage<S: Storage> {
+    inner: S,
+    provider: Arc<dyn KeyProvider>,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, provider: Arc<dyn KeyProvider>) -> Self {
+        Self { inner, provider }
+    }
+
+    /// Returns the id of the key encrypting the named file
+    pub fn key_id<P: AsRef<Path>>(&self, name: P) -> TemplateResult<u32> {
+        let f = self.inner.open(&name)?;
+        read_header(&f).map(|(id, _)| id)
+    }
+
+    /// Rewrites the named file with the current key if it's encrypted by an old one.
+    /// Returns false if the file is already using the current key.
+    ///
+    /// The file must not be opened for writing meanwhile. The files rewritten by the db,
+    /// e.g. the tables being compacted and the logs, pick up a rotated key on their own.
+    pub fn reencrypt<P: AsRef<Path>>(&self, name: P) -> TemplateResult<bool> {
+        let (current, _) = self.provider.current_key()?;
+        if self.key_id(&name)? == current {
+            return Ok(false);
+        }
+        let src = self.open(&name)?;
+        let mut contents = vec![0; src.len()? as usize];
+        src.read_exact_at(&mut contents, 0)?;
+        let mut tmp_name = name.as_ref().as_os_str().to_owned();
+        tmp_name.push(".reencrypt");
+        let tmp_name = PathBuf::from(tmp_name);
+        let mut dst = self.create(&tmp_name)?;
+        dst.write(&contents)?;
+        dst.flush()?;
+        dst.close()?;
+        self.inner.rename(tmp_name.as_path(), name.as_ref())?;
+        Ok(true)
+    }
+}
+
+// Reads the key id and the IV from the header of an encrypted file
+fn read_header<F: File>(f: &F) -> TemplateResult<(u32, [u8; IV_SIZE])> {
+    let mut header = [0; HEADER_SIZE as usize];
+    if f.len()? < HEADER_SIZE || f.read_exact_at(&mut header, 0).is_err() {
+        return Err(TemplateKVError::Corruption(
+            "truncated encryption header".to_owned(),
+        ));
+    }
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(TemplateKVError::Corruption(
+            "bad encryption header magic".to_owned(),
+        ));
+    }
+    let id = decode_fixed_32(&header[MAGIC.len()..]);
+    let mut iv = [0; IV_SIZE];
+    iv.copy_from_slice(&header[HEADER_SIZE as usize - IV_SIZE..]);
+    Ok((id, iv))
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    type F = EncryptedFile<S::F>;
+
+    fn create<P: AsRef<Path>>(&self, name: P) -> TemplateResult<Self::F> {
+        let (id, key) = self.provider.current_key()?;
+        let mut iv = [0; IV_SIZE];
+        rand::thread_rng().fill(&mut iv);
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(MAGIC);
+        put_fixed_32(&mut header, id);
+        put_fixed_32(&mut header, 0);
+        header.extend_from_slice(&iv);
+        let mut inner = self.inner.create(name)?;
+        inner.write(&header)?;
+        // Make the header durable before any content, otherwise a crash could leave a file
+        // which is never able to be opened again
+        inner.flush()?;
+        Ok(EncryptedFile::new(inner, &key, &iv, 0))
+    }
+
+    fn open<P: AsRef<Path>>(&self, name: P) -> TemplateResult<Self::F> {
+        let mut inner = self.inner.open(name)?;
+        let (id, iv) = read_header(&inner)?;
+        let key = self.provider.key(id)?;
+        // The later writes append to the existing contents
+        inner.seek(SeekFrom::End(0))?;
+        let len = inner.len()? - HEADER_SIZE;
+        Ok(EncryptedFile::new(inner, &key, &iv, len))
+    }
+
+    fn remove<P: AsRef<Path>>(&self, name: P) -> TemplateResult<()> {
+        self.inner.remove(name)
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, dir: P, recursively: bool) -> TemplateResult<()> {
+        self.inner.remove_dir(dir, recursively)
+    }
+
+    fn exists<P: AsRef<Path>>(&self, name: P) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename<P: AsRef<Path>>(&self, old: P, new: P) -> TemplateResult<()> {
+        self.inner.rename(old, new)
+    }
+
+    fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> TemplateResult<()> {
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list<P: AsRef<Path>>(&self, dir: P) -> TemplateResult<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+}
+
+/// A `File` created by `EncryptedStorage`
+pub struct EncryptedFile<F: File> {
+    inner: F,
+    cipher: Ctr128BE<Aes256>,
+    // the offset of the next appended byte in the contents
+    write_offset: u64,
+    // the cursor of `read` and `seek` in the contents
+    read_offset: u64,
+}
+
+impl<F: File> EncryptedFile<F> {
+    fn new(inner: F, key: &EncryptionKey, iv: &[u8; IV_SIZE], write_offset: u64) -> Self {
+        Self {
+            inner,
+            cipher: Ctr128BE::<Aes256>::new(key.into(), iv.into()),
+            write_offset,
+            read_offset: 0,
+        }
+    }
+
+    // Encrypts or decrypts `buf` in place as the contents at `offset`
+    fn apply_keystream(&self, buf: &mut [u8], offset: u64) {
+        let mut cipher = self.cipher.clone();
+        cipher.seek(offset);
+        cipher.apply_keystream(buf);
+    }
+}
+
+impl<F: File> File for EncryptedFile<F> {
+    fn write(&mut self, buf: &[u8]) -> TemplateResult<usize> {
+        let mut encrypted = buf.to_vec();
+        self.apply_keystream(&mut encrypted, self.write_offset);
+        let n = self.inner.write(&encrypted)?;
+        self.write_offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> TemplateResult<()> {
+        self.inner.flush()
+    }
+
+    fn close(&mut self) -> TemplateResult<()> {
+        self.inner.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> TemplateResult<u64> {
+        let offset = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len()?.checked_add_signed(n),
+            SeekFrom::Current(n) => self.read_offset.checked_add_signed(n),
+        };
+        match offset {
+            Some(offset) => {
+                self.read_offset = offset;
+                Ok(offset)
+            }
+            None => Err(TemplateKVError::InvalidArgument(
+                "seek to a negative offset".to_owned(),
+            )),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> TemplateResult<usize> {
+        let remaining = self.len()?.saturating_sub(self.read_offset);
+        let n = buf.len().min(remaining as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = self.read_at(&mut buf[..n], self.read_offset)?;
+        self.read_offset += n as u64;
+        Ok(n)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> TemplateResult<usize> {
+        let start = buf.len();
+        let n = self.len()?.saturating_sub(self.read_offset) as usize;
+        buf.resize(start + n, 0);
+        self.read_exact_at(&mut buf[start..], self.read_offset)?;
+        self.read_offset += n as u64;
+        Ok(n)
+    }
+
+    fn len(&self) -> TemplateResult<u64> {
+        Ok(self.inner.len()?.saturating_sub(HEADER_SIZE))
+    }
+
+    fn lock(&self) -> TemplateResult<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> TemplateResult<()> {
+        self.inner.unlock()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> TemplateResult<usize> {
+        let n = self.inner.read_at(buf, offset + HEADER_SIZE)?;
+        self.apply_keystream(&mut buf[..n], offset);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{fault::FaultInjectionStorage, mem::MemStorage};
+
+    fn new_storage(provider: Arc<StaticKeyProvider>) -> (MemStorage, EncryptedStorage<MemStorage>) {
+        let inner = MemStorage::default();
+        let storage = EncryptedStorage::new(inner.clone(), provider);
+        (inner, storage)
+    }
+
+    fn read_raw(storage: &MemStorage, name: &str) -> Vec<u8> {
+        let mut buf = vec![];
+        storage.open(name).unwrap().read_all(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_encrypted_storage_read_write() {
+        let provider = Arc::new(StaticKeyProvider::new(1, [7; ENCRYPTION_KEY_SIZE]));
+        let (inner, storage) = new_storage(provider);
+        let mut f = storage.create("a").unwrap();
+        f.write(b"hello ").unwrap();
+        f.write(b"world").unwrap();
+        f.flush().unwrap();
+        assert_eq!(f.len().unwrap(), 11);
+
+        let raw = read_raw(&inner, "a");
+        assert_eq!(raw.len(), 11 + HEADER_SIZE as usize);
+        assert_eq!(&raw[..MAGIC.len()], MAGIC);
+        assert!(!raw.windows(5).any(|w| w == b"hello" || w == b"world"));
+
+        let mut f = storage.open("a").unwrap();
+        let mut buf = [0; 5];
+        f.read_exact_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf, b"world");
+        let mut buf = vec![];
+        f.read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"hello world");
+        f.seek(SeekFrom::Start(2)).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(f.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"llo");
+        // Appends after reopening
+        f.write(b"!").unwrap();
+        let mut buf = vec![];
+        storage.open("a").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"hello world!");
+    }
+
+    #[test]
+    fn test_encrypted_storage_unique_iv() {
+        let provider = Arc::new(StaticKeyProvider::new(1, [7; ENCRYPTION_KEY_SIZE]));
+        let (inner, storage) = new_storage(provider);
+        for name in ["a", "b"] {
+            storage
+                .create(name)
+                .unwrap()
+                .write(b"same contents")
+                .unwrap();
+        }
+        assert_ne!(read_raw(&inner, "a"), read_raw(&inner, "b"));
+    }
+
+    #[test]
+    fn test_encrypted_storage_rejects_bad_files() {
+        let provider = Arc::new(StaticKeyProvider::new(1, [7; ENCRYPTION_KEY_SIZE]));
+        let (inner, storage) = new_storage(provider.clone());
+        inner
+            .create("plain")
+            .unwrap()
+            .write(b"plain file contents with no header")
+            .unwrap();
+        assert!(matches!(
+            storage.open("plain"),
+            Err(TemplateKVError::Corruption(_))
+        ));
+        inner.create("empty").unwrap();
+        assert!(matches!(
+            storage.open("empty"),
+            Err(TemplateKVError::Corruption(_))
+        ));
+        storage.create("a").unwrap();
+        let other = EncryptedStorage::new(
+            inner,
+            Arc::new(StaticKeyProvider::new(2, [8; ENCRYPTION_KEY_SIZE])),
+        );
+        assert!(matches!(
+            other.open("a"),
+            Err(TemplateKVError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_storage_key_rotation() {
+        let provider = Arc::new(StaticKeyProvider::new(1, [7; ENCRYPTION_KEY_SIZE]));
+        let (_, storage) = new_storage(provider.clone());
+        storage
+            .create("old")
+            .unwrap()
+            .write(b"old contents")
+            .unwrap();
+        provider.add_key(2, [8; ENCRYPTION_KEY_SIZE]);
+        provider.set_current(2).unwrap();
+        assert!(provider.set_current(3).is_err());
+        storage
+            .create("new")
+            .unwrap()
+            .write(b"new contents")
+            .unwrap();
+        assert_eq!(storage.key_id("old").unwrap(), 1);
+        assert_eq!(storage.key_id("new").unwrap(), 2);
+
+        assert!(storage.reencrypt("old").unwrap());
+        assert!(!storage.reencrypt("new").unwrap());
+        assert_eq!(storage.key_id("old").unwrap(), 2);
+        assert!(!storage.exists("old.reencrypt"));
+        assert!(provider.remove_key(2).is_err());
+        provider.remove_key(1).unwrap();
+        for (name, expected) in [("old", b"old contents"), ("new", b"new contents")] {
+            let mut buf = vec![];
+            storage.open(name).unwrap().read_all(&mut buf).unwrap();
+            assert_eq!(buf, expected);
+        }
+    }
+
+    #[test]
+    fn test_encrypted_storage_header_survives_crash() {
+        let provider = Arc::new(StaticKeyProvider::new(1, [7; ENCRYPTION_KEY_SIZE]));
+        let fault = FaultInjectionStorage::new(MemStorage::default());
+        let storage = EncryptedStorage::new(fault.clone(), provider);
+        let mut f = storage.create("a").unwrap();
+        f.write(b"synced").unwrap();
+        f.flush().unwrap();
+        storage.create("b").unwrap().write(b"unsynced").unwrap();
+        fault.crash().unwrap();
+        fault.restart();
+
+        let mut buf = vec![];
+        storage.open("a").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"synced");
+        // The contents never synced are lost but the file is still readable
+        let mut f = storage.open("b").unwrap();
+        assert_eq!(f.len().unwrap(), 0);
+        f.write(b"again").unwrap();
+        let mut buf = vec![];
+        storage.open("b").unwrap().read_all(&mut buf).unwrap();
+        assert_eq!(buf, b"again");
+    }
+}
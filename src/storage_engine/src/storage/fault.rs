@@ -0,0 +1,354 @@
+use std::{
+    io::{Error as IOError, ErrorKind, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use rand::Rng;
+
+use crate::{
+    error::{TemplateKVError, TemplateResult},
+    storage::{File, Storage},
+    util::collection::HashMap,
+};
+
+/// The operations `FaultInjectionStorage` is able to inject IO errors into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultOp {
+    Create = 0,
+    Rename = 1,
+    Write = 2,
+    ReadAt = 3,
+}
+
+/// A `Storage` wrapper for crash-consistency testing.
+///
+/// It tracks how many bytes of every file written through it have been made durable by
+/// `File::flush`. A simulated `crash` drops everything written after the last `flush` and
+/// rejects all the later modifications until `restart` is called, just like the process
+/// being killed by a power loss.
+///
+/// Renaming is considered atomic and durable, and the files existing before wrapping are
+/// considered fully synced.
+#[derive(Clone)]
+pub struct FaultInjectionStorage<S: Storage> {
+    inner: S,
+    state: Arc<FaultState>,
+}
+
+struct FaultState {
+    inner: Mutex<FaultStateInner>,
+    // `FaultOp` -> fail one in n operations. 0 means never.
+    error_one_in: [AtomicU32; 4],
+}
+
+#[derive(Default)]
+struct FaultStateInner {
+    crashed: bool,
+    // The path and the synced length of each file tracked
+    synced: HashMap<PathBuf, (PathBuf, u64)>,
+}
+
+impl FaultState {
+    fn maybe_inject(&self, op: FaultOp) -> TemplateResult<()> {
+        let n = self.error_one_in[op as usize].load(Ordering::Acquire);
+        if n > 0 && rand::thread_rng().gen_range(0, n) == 0 {
+            return Err(TemplateKVError::IO(IOError::new(
+                ErrorKind::Other,
+                format!("injected {:?} error", op),
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Returns an error if the storage has crashed
+fn check_alive(state: &FaultStateInner) -> TemplateResult<()> {
+    if state.crashed {
+        Err(TemplateKVError::IO(IOError::new(
+            ErrorKind::Other,
+            "simulated crash",
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+// Normalizes the path so that "a/b" and "/a/b" are tracked as the same file
+fn track_key<P: AsRef<Path>>(path: P) -> PathBuf {
+    path.as_ref()
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+impl<S: Storage> FaultInjectionStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            state: Arc::new(FaultState {
+                inner: Mutex::new(FaultStateInner::default()),
+                error_one_in: Default::default(),
+            }),
+        }
+    }
+
+    /// Makes one in `one_in` operations of `op` fail with an IO error randomly.
+    /// 0 disables the injection and 1 makes every operation fail.
+    pub fn inject_error(&self, op: FaultOp, one_in: u32) {
+        self.state.error_one_in[op as usize].store(one_in, Ordering::Release);
+    }
+
+    /// Simulates a crash: every tracked file is truncated to the length synced by its last
+    /// `flush`, and all the later modifications fail until `restart`.
+    pub fn crash(&self) -> TemplateResult<()> {
+        let mut state = self.state.inner.lock().unwrap();
+        state.crashed = true;
+        for (name, synced) in state.synced.values() {
+            if !self.inner.exists(name) {
+                continue;
+            }
+            let mut buf = vec![];
+            self.inner.open(name)?.read_all(&mut buf)?;
+            if buf.len() as u64 <= *synced {
+                continue;
+            }
+            buf.truncate(*synced as usize);
+            // `MemStorage` keeps the contents of an existing file on creating
+            self.inner.remove(name)?;
+            let mut f = self.inner.create(name)?;
+            f.write(&buf)?;
+            f.flush()?;
+            f.close()?;
+        }
+        Ok(())
+    }
+
+    /// Accepts modifications again after a `crash`. All the files are considered synced.
+    pub fn restart(&self) {
+        let mut state = self.state.inner.lock().unwrap();
+        state.crashed = false;
+        state.synced.clear();
+    }
+
+    fn wrap(&self, inner: S::F, name: PathBuf) -> FaultInjectionFile<S::F> {
+        FaultInjectionFile {
+            inner,
+            name,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S: Storage> Storage for FaultInjectionStorage<S> {
+    type F = FaultInjectionFile<S::F>;
+
+    fn create<P: AsRef<Path>>(&self, name: P) -> TemplateResult<Self::F> {
+        self.state.maybe_inject(FaultOp::Create)?;
+        let mut state = self.state.inner.lock().unwrap();
+        check_alive(&state)?;
+        // Truncate the existing file like a real file system does
+        if self.inner.exists(&name) && !self.inner.open(&name)?.is_empty() {
+            self.inner.remove(&name)?;
+        }
+        let f = self.inner.create(&name)?;
+        let name = name.as_ref().to_owned();
+        state.synced.insert(track_key(&name), (name.clone(), 0));
+        Ok(self.wrap(f, name))
+    }
+
+    fn open<P: AsRef<Path>>(&self, name: P) -> TemplateResult<Self::F> {
+        let f = self.inner.open(&name)?;
+        let name = name.as_ref().to_owned();
+        let len = f.len()?;
+        self.state
+            .inner
+            .lock()
+            .unwrap()
+            .synced
+            .entry(track_key(&name))
+            .or_insert((name.clone(), len));
+        Ok(self.wrap(f, name))
+    }
+
+    fn remove<P: AsRef<Path>>(&self, name: P) -> TemplateResult<()> {
+        let mut state = self.state.inner.lock().unwrap();
+        check_alive(&state)?;
+        self.inner.remove(&name)?;
+        state.synced.remove(&track_key(&name));
+        Ok(())
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, dir: P, recursively: bool) -> TemplateResult<()> {
+        let mut state = self.state.inner.lock().unwrap();
+        check_alive(&state)?;
+        self.inner.remove_dir(&dir, recursively)?;
+        let dir = track_key(&dir);
+        state.synced.retain(|name, _| !name.starts_with(&dir));
+        Ok(())
+    }
+
+    fn exists<P: AsRef<Path>>(&self, name: P) -> bool {
+        self.inner.exists(name)
+    }
+
+    fn rename<P: AsRef<Path>>(&self, old: P, new: P) -> TemplateResult<()> {
+        self.state.maybe_inject(FaultOp::Rename)?;
+        let mut state = self.state.inner.lock().unwrap();
+        check_alive(&state)?;
+        self.inner.rename(&old, &new)?;
+        if let Some((_, synced)) = state.synced.remove(&track_key(&old)) {
+            let new = new.as_ref().to_owned();
+            state.synced.insert(track_key(&new), (new, synced));
+        } else {
+            state.synced.remove(&track_key(&new));
+        }
+        Ok(())
+    }
+
+    fn mkdir_all<P: AsRef<Path>>(&self, dir: P) -> TemplateResult<()> {
+        check_alive(&self.state.inner.lock().unwrap())?;
+        self.inner.mkdir_all(dir)
+    }
+
+    fn list<P: AsRef<Path>>(&self, dir: P) -> TemplateResult<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+}
+
+/// A `File` created by `FaultInjectionStorage`
+pub struct FaultInjectionFile<F: File> {
+    inner: F,
+    name: PathBuf,
+    state: Arc<FaultState>,
+}
+
+impl<F: File> File for FaultInjectionFile<F> {
+    fn write(&mut self, buf: &[u8]) -> TemplateResult<usize> {
+        self.state.maybe_inject(FaultOp::Write)?;
+        // Hold the lock to never write into a file being truncated by `crash`
+        let state = self.state.inner.lock().unwrap();
+        check_alive(&state)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> TemplateResult<()> {
+        let mut state = self.state.inner.lock().unwrap();
+        check_alive(&state)?;
+        self.inner.flush()?;
+        let len = self.inner.len()?;
+        state
+            .synced
+            .insert(track_key(&self.name), (self.name.clone(), len));
+        Ok(())
+    }
+
+    fn close(&mut self) -> TemplateResult<()> {
+        self.inner.close()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> TemplateResult<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> TemplateResult<usize> {
+        self.inner.read(buf)
+    }
+
+    fn read_all(&mut self, buf: &mut Vec<u8>) -> TemplateResult<usize> {
+        self.inner.read_all(buf)
+    }
+
+    fn len(&self) -> TemplateResult<u64> {
+        self.inner.len()
+    }
+
+    fn lock(&self) -> TemplateResult<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> TemplateResult<()> {
+        self.inner.unlock()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> TemplateResult<usize> {
+        self.state.maybe_inject(FaultOp::ReadAt)?;
+        self.inner.read_at(buf, offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem::MemStorage;
+
+    fn read_to_string<S: Storage>(storage: &S, name: &str) -> String {
+        let mut buf = vec![];
+        storage.open(name).unwrap().read_all(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_crash_drops_unsynced_data() {
+        let storage = FaultInjectionStorage::new(MemStorage::default());
+        let mut synced = storage.create("synced").unwrap();
+        synced.write(b"hello").unwrap();
+        synced.flush().unwrap();
+        synced.write(b" world").unwrap();
+        let mut unsynced = storage.create("unsynced").unwrap();
+        unsynced.write(b"hello").unwrap();
+        let mut renamed = storage.create("tmp").unwrap();
+        renamed.write(b"renamed").unwrap();
+        renamed.flush().unwrap();
+        storage.rename("tmp", "renamed").unwrap();
+
+        storage.crash().unwrap();
+        assert!(synced.write(b"!").is_err());
+        assert!(synced.flush().is_err());
+        assert!(storage.create("new").is_err());
+        assert!(storage.remove("synced").is_err());
+        assert_eq!(read_to_string(&storage, "synced"), "hello");
+        assert_eq!(read_to_string(&storage, "unsynced"), "");
+        assert_eq!(read_to_string(&storage, "renamed"), "renamed");
+
+        storage.restart();
+        let mut f = storage.open("synced").unwrap();
+        f.write(b" again").unwrap();
+        assert_eq!(read_to_string(&storage, "synced"), "hello again");
+        // The data existing before is considered synced
+        storage.crash().unwrap();
+        assert_eq!(read_to_string(&storage, "synced"), "hello");
+    }
+
+    #[test]
+    fn test_create_truncates_existing_file() {
+        let storage = FaultInjectionStorage::new(MemStorage::default());
+        let mut f = storage.create("a").unwrap();
+        f.write(b"garbage").unwrap();
+        let mut f = storage.create("a").unwrap();
+        f.write(b"data").unwrap();
+        assert_eq!(read_to_string(&storage, "a"), "data");
+    }
+
+    #[test]
+    fn test_inject_error() {
+        let storage = FaultInjectionStorage::new(MemStorage::default());
+        let mut f = storage.create("a").unwrap();
+        f.write(b"data").unwrap();
+        for one_in in [1, 0] {
+            let should_fail = one_in == 1;
+            storage.inject_error(FaultOp::Create, one_in);
+            assert_eq!(storage.create("b").is_err(), should_fail);
+            storage.inject_error(FaultOp::Rename, one_in);
+            assert_eq!(storage.rename("b", "c").is_err(), should_fail);
+            storage.inject_error(FaultOp::Write, one_in);
+            assert_eq!(f.write(b"data").is_err(), should_fail);
+            storage.inject_error(FaultOp::ReadAt, one_in);
+            assert_eq!(f.read_at(&mut [0; 4], 0).is_err(), should_fail);
+        }
+        assert_eq!(read_to_string(&storage, "a"), "datadata");
+    }
+}
@@ -1,3 +1,5 @@
+pub mod encrypted;
+pub mod fault;
 pub mod file;
 pub mod mem;
 
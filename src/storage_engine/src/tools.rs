@@ -0,0 +1,377 @@
+//! The offline inspection of the files of a db, used by the `ldb` binary.
+//!
+//! Every function here reads the given file directly and never opens the db, so it is safe to
+//! run against the directory of a running db or of a db which fails to open.
+
+use std::{io::Write, sync::Arc};
+
+use crate::{
+    error::{TemplateKVError, TemplateResult},
+    iterator::Iterator,
+    manager::version_edit::VersionEdit,
+    memtable::{
+        batch::{WriteBatch, HEADER_SIZE},
+        key_format::{InternalKeyComparator, ParsedInternalKey},
+        value_format::ValueType,
+    },
+    options::{Options, ReadOptions},
+    sstable::{
+        table::{new_index_iterator, new_table_iterator, Table},
+        BlockHandle,
+    },
+    storage::{File, Storage},
+    util::{comparator::BytewiseComparator, reporter::LogReporter},
+    wal::wal_record_reader::Reader,
+};
+
+// The metaindex keys starting with this prefix hold a value instead of a `BlockHandle`
+const PROPERTY_PREFIX: &[u8] = b"property.";
+
+macro_rules! out {
+    ($w:expr, $($arg:tt)*) => {
+        map_io_res!(writeln!($w, $($arg)*))
+    };
+}
+
+/// What `dump_table` prints
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TableDumpOptions {
+    /// The metaindex entries and the statistics collected by scanning the table
+    pub properties: bool,
+    /// The last key and the handle of every data block
+    pub index: bool,
+    /// Every key/value entry
+    pub entries: bool,
+    /// Verify the checksum of every data block read
+    pub verify_checksums: bool,
+}
+
+/// Escapes the non-printable bytes of `data` like `\x00`
+pub fn escape(data: &[u8]) -> String {
+    data.iter()
+        .flat_map(|b| std::ascii::escape_default(*b))
+        .map(char::from)
+        .collect()
+}
+
+/// Formats an internal key as `'user_key' @ seq : type`, or as the escaped raw bytes if the key
+/// can not be parsed
+pub fn format_internal_key(key: &[u8]) -> String {
+    match ParsedInternalKey::decode_from(key) {
+        Some(parsed) => format!(
+            "'{}' @ {} : {:?}",
+            escape(parsed.user_key),
+            parsed.seq,
+            parsed.value_type
+        ),
+        None => format!("corrupted key '{}'", escape(key)),
+    }
+}
+
+/// Dumps the sstable at `path`
+pub fn dump_table<S: Storage, W: Write>(
+    storage: &S,
+    path: &str,
+    dump_options: TableDumpOptions,
+    out: &mut W,
+) -> TemplateResult<()> {
+    let file = storage.open(path)?;
+    let file_len = file.len()?;
+    let icmp = InternalKeyComparator::new(BytewiseComparator::default());
+    let options = Arc::new(Options::<BytewiseComparator>::default());
+    let table = Arc::new(Table::open(file, 0, file_len, options, icmp.clone())?);
+    let read_options = ReadOptions {
+        verify_checksums: dump_options.verify_checksums,
+        fill_cache: false,
+        snapshot: None,
+    };
+    if dump_options.properties {
+        out!(out, "Properties:")?;
+        out!(out, "  file size: {}", file_len)?;
+        out!(out, "  index type: {:?}", table.index_type())?;
+        for (key, value) in table.meta_entries(BytewiseComparator::default())? {
+            if key.starts_with(PROPERTY_PREFIX) {
+                out!(out, "  {}: '{}'", escape(&key), escape(&value))?;
+            } else {
+                let (handle, _) = BlockHandle::decode_from(&value)?;
+                out!(
+                    out,
+                    "  {}: offset {} size {}",
+                    escape(&key),
+                    handle.offset,
+                    handle.size
+                )?;
+            }
+        }
+        let mut data_blocks = 0;
+        let mut index_iter = new_index_iterator(icmp.clone(), table.clone(), read_options);
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            data_blocks += 1;
+            index_iter.next();
+        }
+        index_iter.status()?;
+        out!(out, "  data blocks: {}", data_blocks)?;
+        let stats = TableStats::collect(&table, icmp.clone(), read_options)?;
+        out!(out, "  entries: {}", stats.entries)?;
+        out!(out, "  deletions: {}", stats.deletions)?;
+        out!(out, "  raw key size: {}", stats.raw_key_size)?;
+        out!(out, "  raw value size: {}", stats.raw_value_size)?;
+        if let (Some(smallest), Some(largest)) = (&stats.smallest, &stats.largest) {
+            out!(out, "  smallest key: {}", format_internal_key(smallest))?;
+            out!(out, "  largest key: {}", format_internal_key(largest))?;
+            out!(
+                out,
+                "  sequence range: [{}, {}]",
+                stats.smallest_seq,
+                stats.largest_seq
+            )?;
+        }
+    }
+    if dump_options.index {
+        out!(out, "Index:")?;
+        let mut index_iter = new_index_iterator(icmp.clone(), table.clone(), read_options);
+        index_iter.seek_to_first();
+        while index_iter.valid() {
+            let (handle, _) = BlockHandle::decode_from(index_iter.value())?;
+            out!(
+                out,
+                "  {} => offset {} size {}",
+                format_internal_key(index_iter.key()),
+                handle.offset,
+                handle.size
+            )?;
+            index_iter.next();
+        }
+        index_iter.status()?;
+    }
+    if dump_options.entries {
+        out!(out, "Entries:")?;
+        let mut iter = new_table_iterator(icmp, table, read_options);
+        iter.seek_to_first();
+        while iter.valid() {
+            out!(
+                out,
+                "  {} => '{}'",
+                format_internal_key(iter.key()),
+                escape(iter.value())
+            )?;
+            iter.next();
+        }
+        iter.status()?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct TableStats {
+    entries: u64,
+    deletions: u64,
+    raw_key_size: u64,
+    raw_value_size: u64,
+    smallest: Option<Vec<u8>>,
+    largest: Option<Vec<u8>>,
+    smallest_seq: u64,
+    largest_seq: u64,
+}
+
+impl TableStats {
+    fn collect<F: File>(
+        table: &Arc<Table<F>>,
+        icmp: InternalKeyComparator<BytewiseComparator>,
+        read_options: ReadOptions,
+    ) -> TemplateResult<Self> {
+        let mut stats = TableStats {
+            smallest_seq: u64::MAX,
+            ..Default::default()
+        };
+        let mut iter = new_table_iterator(icmp, table.clone(), read_options);
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            stats.entries += 1;
+            stats.raw_key_size += key.len() as u64;
+            stats.raw_value_size += iter.value().len() as u64;
+            if let Some(parsed) = ParsedInternalKey::decode_from(key) {
+                if parsed.value_type == ValueType::Deletion {
+                    stats.deletions += 1;
+                }
+                stats.smallest_seq = stats.smallest_seq.min(parsed.seq);
+                stats.largest_seq = stats.largest_seq.max(parsed.seq);
+            }
+            if stats.smallest.is_none() {
+                stats.smallest = Some(key.to_vec());
+            }
+            stats.largest = Some(key.to_vec());
+            iter.next();
+        }
+        iter.status()?;
+        Ok(stats)
+    }
+}
+
+/// Dumps every `WriteBatch` in the WAL file at `path`. A corrupted tail is reported as an error
+/// after the records read before it.
+pub fn dump_wal<S: Storage, W: Write>(storage: &S, path: &str, out: &mut W) -> TemplateResult<()> {
+    let file = storage.open(path)?;
+    let reporter = LogReporter::new();
+    let mut reader = Reader::new(file, Some(Box::new(reporter.clone())), true, 0);
+    let mut record = vec![];
+    let mut offset = 0;
+    while reader.read_record(&mut record) {
+        if record.len() < HEADER_SIZE {
+            out!(out, "--- offset {}; record too small", offset)?;
+            offset = reader.end_of_last_record();
+            continue;
+        }
+        let mut batch = WriteBatch::default();
+        batch.set_contents(&mut record);
+        out!(
+            out,
+            "--- offset {}; sequence {}; count {}",
+            offset,
+            batch.get_sequence(),
+            batch.get_count()
+        )?;
+        let mut lines = vec![];
+        let res = batch.iterate(|seq, value_type, key, value| {
+            lines.push(match value_type {
+                ValueType::Deletion => format!("  {} del '{}'", seq, escape(key)),
+                _ => format!("  {} put '{}' => '{}'", seq, escape(key), escape(value)),
+            });
+        });
+        for line in lines {
+            out!(out, "{}", line)?;
+        }
+        if let Err(e) = res {
+            out!(out, "  error: {}", e)?;
+        }
+        offset = reader.end_of_last_record();
+    }
+    reporter.result()
+}
+
+/// Dumps every `VersionEdit` in the MANIFEST file at `path`
+pub fn dump_manifest<S: Storage, W: Write>(
+    storage: &S,
+    path: &str,
+    out: &mut W,
+) -> TemplateResult<()> {
+    let file = storage.open(path)?;
+    let max_levels = Options::<BytewiseComparator>::default().max_levels;
+    let reporter = LogReporter::new();
+    let mut reader = Reader::new(file, Some(Box::new(reporter.clone())), true, 0);
+    let mut record = vec![];
+    let mut offset = 0;
+    while reader.read_record(&mut record) {
+        let mut edit = VersionEdit::new(max_levels);
+        edit.decoded_from(&record).map_err(|e| {
+            TemplateKVError::Corruption(format!("bad VersionEdit at offset {}: {}", offset, e))
+        })?;
+        out!(out, "--- offset {}", offset)?;
+        map_io_res!(write!(out, "{:?}", edit))?;
+        offset = reader.end_of_last_record();
+    }
+    reporter.result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db_impl::template_impl::TemplateDB,
+        db_trait::DB,
+        manager::filename::{parse_filename, FileType},
+        options::WriteOptions,
+        storage::mem::MemStorage,
+    };
+
+    fn files_of(store: &MemStorage, db_path: &str, file_type: FileType) -> Vec<String> {
+        let mut files: Vec<_> = store
+            .list(db_path)
+            .unwrap()
+            .into_iter()
+            .filter(|p| matches!(parse_filename(p), Some((t, _)) if t == file_type))
+            .map(|p| p.to_str().unwrap().to_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn dump<F: FnOnce(&mut Vec<u8>) -> TemplateResult<()>>(f: F) -> String {
+        let mut out = vec![];
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_dump_db_files() {
+        let store = MemStorage::default();
+        let mut db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "dump",
+            store.clone(),
+        )
+        .unwrap();
+        db.put(WriteOptions::default(), b"a", b"v1").unwrap();
+        db.put(WriteOptions::default(), b"b\x00", b"v2").unwrap();
+        db.delete(WriteOptions::default(), b"a").unwrap();
+        db.compact_range(None, None).unwrap();
+        db.put(WriteOptions::default(), b"c", b"v3").unwrap();
+        db.close().unwrap();
+
+        let options = TableDumpOptions {
+            properties: true,
+            index: true,
+            entries: true,
+            verify_checksums: true,
+        };
+        let tables = files_of(&store, "dump", FileType::Table);
+        assert!(!tables.is_empty());
+        let table = dump(|out| {
+            for t in tables.iter() {
+                dump_table(&store, t, options, out)?;
+            }
+            Ok(())
+        });
+        assert!(table.contains("  index type: Binary"), "{}", table);
+        assert!(table.contains("  data blocks: 1"), "{}", table);
+        assert!(table.contains("Index:\n  '"), "{}", table);
+        assert!(
+            table.contains("  'b\\x00' @ 2 : Value => 'v2'"),
+            "{}",
+            table
+        );
+
+        let logs = files_of(&store, "dump", FileType::Log);
+        let wal = dump(|out| dump_wal(&store, logs.last().unwrap(), out));
+        assert!(wal.contains("sequence 4; count 1"), "{}", wal);
+        assert!(wal.contains("  4 put 'c' => 'v3'"), "{}", wal);
+
+        let manifests = files_of(&store, "dump", FileType::Manifest);
+        let manifest = dump(|out| dump_manifest(&store, manifests.last().unwrap(), out));
+        assert!(manifest.contains("Comparator: "), "{}", manifest);
+        assert!(manifest.contains("AddFile: @"), "{}", manifest);
+    }
+
+    #[test]
+    fn test_dump_wal_deletions() {
+        let store = MemStorage::default();
+        let mut db = TemplateDB::open_db(
+            Options::<BytewiseComparator>::default(),
+            "dump_wal",
+            store.clone(),
+        )
+        .unwrap();
+        let mut batch = WriteBatch::default();
+        batch.put(b"k1", b"v1");
+        batch.delete(b"k2");
+        db.write(WriteOptions::default(), batch).unwrap();
+        db.close().unwrap();
+        let logs = files_of(&store, "dump_wal", FileType::Log);
+        let wal = dump(|out| dump_wal(&store, logs.last().unwrap(), out));
+        assert!(wal.contains("--- offset 0; sequence 1; count 2"), "{}", wal);
+        assert!(wal.contains("  1 put 'k1' => 'v1'"), "{}", wal);
+        assert!(wal.contains("  2 del 'k2'"), "{}", wal);
+    }
+}
@@ -218,6 +218,13 @@ impl<F: File> Reader<F> {
         }
     }
 
+    /// Returns the offset right after the last record returned by `read_record`.
+    /// A new `Reader` starting at this offset continues with the records appended later.
+    #[inline]
+    pub fn end_of_last_record(&self) -> u64 {
+        self.end_of_buffer_offset - self.buf_length as u64
+    }
+
     // Returns the last_record_offset.
     // Temporary for test.
     #[inline]